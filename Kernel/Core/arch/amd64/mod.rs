@@ -88,6 +88,68 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	use ::core::sync::atomic::{AtomicU8,Ordering};
+
+	const HAS_RDRAND: u8 = 1;
+	const HAS_RDSEED: u8 = 2;
+	const CHECKED: u8 = 0x80;
+	static FEATURES: AtomicU8 = AtomicU8::new(0);
+
+	fn features() -> u8 {
+		let v = FEATURES.load(Ordering::Relaxed);
+		if v != 0 {
+			return v;
+		}
+		use ::core::arch::x86_64::{__cpuid,__cpuid_count};
+		let mut v = CHECKED;
+		// SAFE: CPUID has no side-effects (and is always present on x86_64)
+		unsafe {
+			let max_leaf = __cpuid(0).eax;
+			if __cpuid(1).ecx & (1 << 30) != 0 {
+				v |= HAS_RDRAND;
+			}
+			if max_leaf >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 {
+				v |= HAS_RDSEED;
+			}
+		}
+		FEATURES.store(v, Ordering::Relaxed);
+		v
+	}
+
+	pub fn hw_random() -> Option<u64> {
+		let f = features();
+		// Prefer RDSEED (direct from the entropy source), falling back to RDRAND (a DRBG seeded from the same source)
+		// - Both can transiently fail (carry clear) if the hardware is exhausted, so retry a few times
+		if f & HAS_RDSEED != 0 {
+			for _ in 0 .. 10 {
+				let (v, ok): (u64, u8);
+				// SAFE: Only writes to the output registers
+				unsafe { ::core::arch::asm!("rdseed {}; setc {}", out(reg) v, out(reg_byte) ok, options(nomem, nostack)); }
+				if ok != 0 {
+					return Some(v);
+				}
+			}
+		}
+		if f & HAS_RDRAND != 0 {
+			for _ in 0 .. 10 {
+				let (v, ok): (u64, u8);
+				// SAFE: Only writes to the output registers
+				unsafe { ::core::arch::asm!("rdrand {}; setc {}", out(reg) v, out(reg_byte) ok, options(nomem, nostack)); }
+				if ok != 0 {
+					return Some(v);
+				}
+			}
+		}
+		None
+	}
+
+	pub fn cycle_count() -> u64 {
+		// SAFE: Reads the TSC, no side-effects
+		unsafe { ::core::arch::x86_64::_rdtsc() }
+	}
+}
+
 /// Print a backtrace, starting at the current location.
 pub fn print_backtrace()
 {
@@ -118,6 +118,16 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	pub fn hw_random() -> Option<u64> {
+		None
+	}
+	pub fn cycle_count() -> u64 {
+		// TODO: Use the generic timer (CNTVCT) or cycle counter where present, neither is guaranteed on ARMv7
+		super::time::cur_timestamp()
+	}
+}
+
 pub fn cpu_num() -> u32 {
 	0
 }
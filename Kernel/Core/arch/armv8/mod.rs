@@ -81,6 +81,19 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	pub fn hw_random() -> Option<u64> {
+		// TODO: Use `RNDR` when FEAT_RNG is present (ID_AA64ISAR0_EL1.RNDR)
+		None
+	}
+	pub fn cycle_count() -> u64 {
+		let v: u64;
+		// SAFE: Reads the virtual counter, no side-effects
+		unsafe { ::core::arch::asm!("mrs {}, CNTVCT_EL0", out(reg) v, options(nomem, nostack)); }
+		v
+	}
+}
+
 pub unsafe fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> ! {
 	extern "C" {
 		fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> !;
@@ -635,6 +635,16 @@ pub mod time {
 		(std::time::Instant::now() - ts0).as_millis() as u64
 	}
 }
+pub mod entropy {
+	pub fn hw_random() -> Option<u64> {
+		use ::std::hash::{BuildHasher,Hasher};
+		// `RandomState` is seeded from the OS
+		Some(::std::collections::hash_map::RandomState::new().build_hasher().finish())
+	}
+	pub fn cycle_count() -> u64 {
+		::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+	}
+}
 pub mod x86_io {
 	pub unsafe fn inb(_p: u16) -> u8 { 0 }
 	pub unsafe fn inw(_p: u16) -> u16 { 0 }
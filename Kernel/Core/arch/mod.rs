@@ -474,6 +474,23 @@ pub mod time {
 	}
 }
 
+/// Sources of randomness (used by `crate::entropy`)
+pub mod entropy {
+	use crate::arch::imp::entropy as imp;
+
+	/// Obtain a value from the CPU's random number generator, `None` if there isn't one (or it failed)
+	#[inline]
+	pub fn hw_random() -> Option<u64> {
+		imp::hw_random()
+	}
+
+	/// Return a fast-running counter (e.g. CPU cycles), used to measure timing jitter
+	#[inline]
+	pub fn cycle_count() -> u64 {
+		imp::cycle_count()
+	}
+}
+
 #[inline]
 pub fn puts(s: &str) {
 	imp::puts(s)
@@ -318,6 +318,19 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	pub fn hw_random() -> Option<u64> {
+		// TODO: Use the `seed` CSR when the Zkr extension is present
+		None
+	}
+	pub fn cycle_count() -> u64 {
+		let v: u64;
+		// SAFE: Reading a CSR with no side-effects
+		unsafe { ::core::arch::asm!("rdtime {}", lateout(reg) v); }
+		v
+	}
+}
+
 pub fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> ! {
 	// Create an exception frame
 	// SAFE: Validated 
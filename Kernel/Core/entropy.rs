@@ -0,0 +1,144 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/entropy.rs
+//! Kernel entropy source
+//!
+//! Random values are generated by hashing a counter with a secret key (SipHash-2-4). The key is seeded from the CPU's
+//! random number generator (where present) and timing jitter, and the timing of interrupts is mixed in as they happen.
+use ::core::sync::atomic::{AtomicUsize,Ordering};
+use crate::arch::sync::Spinlock;
+use crate::arch::entropy::{hw_random,cycle_count};
+
+/// Timing of recent events (mixed into the key on the next request)
+static EVENT_POOL: AtomicUsize = AtomicUsize::new(0);
+static STATE: Spinlock<State> = Spinlock::new(State { key: [0; 2], counter: 0, seeded: false });
+
+struct State
+{
+	key: [u64; 2],
+	counter: u64,
+	seeded: bool,
+}
+
+/// Record the timing of an event (e.g. an interrupt)
+pub fn add_event()
+{
+	let v = cycle_count() as usize;
+	let _ = EVENT_POOL.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |p| Some(p.rotate_left(7) ^ v));
+}
+
+/// Obtain a random 64-bit value
+pub fn get_u64() -> u64
+{
+	let mut lh = STATE.lock();
+	if !lh.seeded {
+		lh.seed();
+	}
+	lh.next()
+}
+/// Obtain a random 32-bit value
+pub fn get_u32() -> u32
+{
+	get_u64() as u32
+}
+/// Fill a buffer with random bytes
+pub fn fill(buf: &mut [u8])
+{
+	for c in buf.chunks_mut(8)
+	{
+		let v = get_u64().to_le_bytes();
+		c.copy_from_slice(&v[..c.len()]);
+	}
+}
+
+/// Keyed hash of a sequence of words (SipHash-2-4), for generating values that must be unpredictable without the key
+pub fn keyed_hash(key: &[u64; 2], data: &[u64]) -> u64
+{
+	let mut s = SipState::new(key);
+	for &w in data {
+		s.compress(w);
+	}
+	s.finish((data.len() as u64 * 8) << 56)
+}
+
+impl State
+{
+	fn seed(&mut self)
+	{
+		// Arbitrary starting value, in case there is no hardware source and the timer doesn't tick
+		self.key = [0x736f6d6570736575, 0x646f72616e646f6d];
+		for k in self.key.iter_mut() {
+			if let Some(v) = hw_random() {
+				*k ^= v;
+			}
+		}
+		// Timing jitter: The time taken by a short loop varies with cache state, interrupts, and memory contention
+		for i in 0 .. 64
+		{
+			let start = cycle_count();
+			let mut x = i as u64;
+			for _ in 0 .. 16 + (start & 0xF) {
+				x = ::core::hint::black_box(x.wrapping_mul(0x9E3779B97F4A7C15).rotate_left(5));
+			}
+			let end = cycle_count();
+			self.key[i % 2] = keyed_hash(&self.key, &[end.wrapping_sub(start), end, x, crate::arch::time::cur_timestamp()]);
+		}
+		self.seeded = true;
+	}
+	fn next(&mut self) -> u64
+	{
+		let events = EVENT_POOL.swap(0, Ordering::Relaxed) as u64;
+		self.counter += 1;
+		let mut rv = keyed_hash(&self.key, &[self.counter, events, cycle_count()]);
+		if let Some(v) = hw_random() {
+			rv ^= v;
+		}
+		// Update the key, so previous outputs can't be recovered from the current state
+		self.key = [
+			keyed_hash(&self.key, &[self.counter, events, 1]),
+			keyed_hash(&self.key, &[self.counter, events, 2]),
+			];
+		rv
+	}
+}
+
+/// SipHash state (https://www.aumasson.jp/siphash/siphash.pdf)
+struct SipState([u64; 4]);
+impl SipState
+{
+	fn new(key: &[u64; 2]) -> SipState
+	{
+		SipState([
+			key[0] ^ 0x736f6d6570736575,
+			key[1] ^ 0x646f72616e646f6d,
+			key[0] ^ 0x6c7967656e657261,
+			key[1] ^ 0x7465646279746573,
+			])
+	}
+	fn round(&mut self)
+	{
+		let v = &mut self.0;
+		v[0] = v[0].wrapping_add(v[1]); v[1] = v[1].rotate_left(13); v[1] ^= v[0]; v[0] = v[0].rotate_left(32);
+		v[2] = v[2].wrapping_add(v[3]); v[3] = v[3].rotate_left(16); v[3] ^= v[2];
+		v[0] = v[0].wrapping_add(v[3]); v[3] = v[3].rotate_left(21); v[3] ^= v[0];
+		v[2] = v[2].wrapping_add(v[1]); v[1] = v[1].rotate_left(17); v[1] ^= v[2]; v[2] = v[2].rotate_left(32);
+	}
+	fn compress(&mut self, m: u64)
+	{
+		self.0[3] ^= m;
+		self.round();
+		self.round();
+		self.0[0] ^= m;
+	}
+	fn finish(mut self, last: u64) -> u64
+	{
+		self.compress(last);
+		self.0[2] ^= 0xFF;
+		for _ in 0 .. 4 {
+			self.round();
+		}
+		self.0[0] ^ self.0[1] ^ self.0[2] ^ self.0[3]
+	}
+}
+
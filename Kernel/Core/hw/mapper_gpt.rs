@@ -0,0 +1,310 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/mapper_gpt.rs
+/// GUID Partition Table logical volume mapper
+use crate::prelude::*;
+use crate::lib::byteorder::{ByteOrder,LittleEndian};
+use crate::metadevs::storage;
+
+module_define!{MapperGPT, [Storage], init}
+
+static S_MAPPER: Mapper = Mapper;
+
+fn init()
+{
+	storage::register_mapper(&S_MAPPER);
+}
+
+struct Mapper;
+
+/// Binding level, must be stronger than the MBR mapper (which will see the protective MBR)
+const GPT_LEVEL: usize = 2;
+/// Minimum header size (the size of the fields defined by the UEFI spec)
+const HEADER_MIN_SIZE: usize = 92;
+/// Sanity limit on the size of the partition entry array
+const MAX_ENTRIES_SIZE: usize = 1024*1024;
+
+#[derive(Copy,Clone,PartialEq,Eq)]
+struct Guid([u8; 16]);
+
+#[derive(Debug)]
+struct Header
+{
+	my_lba: u64,
+	alternate_lba: u64,
+	first_usable: u64,
+	last_usable: u64,
+	disk_guid: Guid,
+	entries_lba: u64,
+	num_entries: u32,
+	entry_size: u32,
+	entries_crc: u32,
+}
+
+#[derive(Debug)]
+struct Entry
+{
+	_type_guid: Guid,
+	unique_guid: Guid,
+	first_lba: u64,
+	last_lba: u64,
+	_attributes: u64,
+	name: String,
+}
+
+impl storage::Mapper for Mapper
+{
+	fn name(&self) -> &str { "gpt" }
+
+	fn handles_pv(&self, pv: &dyn storage::PhysicalVolume) -> Result<usize,storage::IoError> {
+		match load_table(pv)
+		{
+		Some(_) => Ok(GPT_LEVEL),
+		None => Ok(0),
+		}
+	}
+
+	fn enum_volumes(&self, pv: &dyn storage::PhysicalVolume, new_volume_cb: &mut dyn FnMut(String, u64, u64)) -> Result<(),storage::IoError>
+	{
+		let (hdr, entries) = match load_table(pv)
+			{
+			Some(v) => v,
+			None => return Err( storage::IoError::InvalidParameter ),
+			};
+		log_debug!("{}: GPT disk {:?}, {} entries", pv.name(), hdr.disk_guid, hdr.num_entries);
+
+		let mut used_names: Vec<String> = Vec::new();
+		for (i,ent_data) in entries.chunks(hdr.entry_size as usize).enumerate()
+		{
+			let ent = match Entry::read(ent_data)
+				{
+				Some(v) => v,
+				None => continue,
+				};
+			log_debug!("#{}: {:?}", i, ent);
+			if ent.first_lba > ent.last_lba || ent.first_lba < hdr.first_usable || ent.last_lba > hdr.last_usable {
+				log_warning!("{}: GPT entry #{} ({:?}) has invalid range {:#x}-{:#x} (usable {:#x}-{:#x})",
+					pv.name(), i, ent.unique_guid, ent.first_lba, ent.last_lba, hdr.first_usable, hdr.last_usable);
+				continue ;
+			}
+
+			// Name by label if it's usable and unique, otherwise by the partition's unique GUID
+			let name = match ent.sanitised_name()
+				{
+				Some(label) if !used_names.contains(&label) => {
+					let rv = format!("{}:{}", pv.name(), label);
+					used_names.push(label);
+					rv
+					},
+				_ => format!("{}:{:?}", pv.name(), ent.unique_guid),
+				};
+			new_volume_cb( name, ent.first_lba, ent.last_lba - ent.first_lba + 1 );
+		}
+
+		Ok( () )
+	}
+}
+
+/// Read `dst.len()` bytes worth of blocks starting at `first`, handling short reads
+fn read_blocks(pv: &dyn storage::PhysicalVolume, first: u64, dst: &mut [u8]) -> Result<(),storage::IoError>
+{
+	let bs = pv.blocksize();
+	assert!(dst.len() % bs == 0);
+	let mut ofs = 0;
+	while ofs < dst.len()
+	{
+		let count = (dst.len() - ofs) / bs;
+		match crate::futures::block_on( pv.read(0, first + (ofs / bs) as u64, count, &mut dst[ofs..]) )?
+		{
+		0 => return Err( storage::IoError::Unknown("Zero-length read") ),
+		n => ofs += n * bs,
+		}
+	}
+	Ok( () )
+}
+
+/// Locate a valid header and partition array, preferring the primary and falling back to the backup
+///
+/// Read errors are treated the same as corruption (so a failing primary falls back to the backup)
+fn load_table(pv: &dyn storage::PhysicalVolume) -> Option<(Header,Vec<u8>)>
+{
+	let capacity = match pv.capacity()
+		{
+		Some(v) if v > 2 => v,
+		_ => return None,
+		};
+
+	let primary = Header::read(pv, 1);
+	if let Some(ref hdr) = primary {
+		if let Some(entries) = hdr.read_entries(pv) {
+			if hdr.alternate_lba >= capacity {
+				log_notice!("{}: GPT backup header LBA {:#x} is past the end of the disk ({:#x})", pv.name(), hdr.alternate_lba, capacity);
+			}
+			else if Header::read(pv, hdr.alternate_lba).is_none() {
+				log_notice!("{}: GPT backup header at {:#x} is invalid", pv.name(), hdr.alternate_lba);
+			}
+			return Some( (primary.unwrap(), entries) );
+		}
+		log_warning!("{}: GPT primary partition array is corrupt, trying backup", pv.name());
+	}
+
+	// Primary missing or corrupt, check the backup (at the location the primary claims, or the last block)
+	let backup_lba = match primary
+		{
+		Some(ref hdr) if hdr.alternate_lba < capacity => hdr.alternate_lba,
+		_ => capacity - 1,
+		};
+	if let Some(hdr) = Header::read(pv, backup_lba) {
+		if let Some(entries) = hdr.read_entries(pv) {
+			log_warning!("{}: Using GPT backup header at {:#x}", pv.name(), backup_lba);
+			return Some( (hdr, entries) );
+		}
+	}
+	None
+}
+
+impl Header
+{
+	/// Read and validate (signature, size, CRC and self-reference) a header at the given LBA
+	fn read(pv: &dyn storage::PhysicalVolume, lba: u64) -> Option<Header>
+	{
+		let bs = pv.blocksize();
+		let mut block = vec![0u8; bs];
+		if let Err(e) = read_blocks(pv, lba, &mut block) {
+			log_warning!("{}: Unable to read GPT header at {:#x}: {:?}", pv.name(), lba, e);
+			return None;
+		}
+
+		if &block[0..8] != b"EFI PART" {
+			return None;
+		}
+		let revision = LittleEndian::read_u32(&block[8..]);
+		let header_size = LittleEndian::read_u32(&block[12..]) as usize;
+		let header_crc = LittleEndian::read_u32(&block[16..]);
+		if header_size < HEADER_MIN_SIZE || header_size > bs {
+			log_warning!("{}: GPT header at {:#x} has invalid size {}", pv.name(), lba, header_size);
+			return None;
+		}
+		if revision >> 16 != 1 {
+			log_notice!("{}: GPT header at {:#x} has unknown revision {:#x}", pv.name(), lba, revision);
+		}
+		// - The CRC is calculated with the CRC field zeroed
+		LittleEndian::write_u32(&mut block[16..], 0);
+		let calc_crc = crate::lib::crc::IEEE.checksum(&block[..header_size]);
+		if calc_crc != header_crc {
+			log_warning!("{}: GPT header at {:#x} CRC mismatch ({:#x} != {:#x})", pv.name(), lba, calc_crc, header_crc);
+			return None;
+		}
+
+		let rv = Header {
+			my_lba: LittleEndian::read_u64(&block[24..]),
+			alternate_lba: LittleEndian::read_u64(&block[32..]),
+			first_usable: LittleEndian::read_u64(&block[40..]),
+			last_usable: LittleEndian::read_u64(&block[48..]),
+			disk_guid: Guid::from_slice(&block[56..72]),
+			entries_lba: LittleEndian::read_u64(&block[72..]),
+			num_entries: LittleEndian::read_u32(&block[80..]),
+			entry_size: LittleEndian::read_u32(&block[84..]),
+			entries_crc: LittleEndian::read_u32(&block[88..]),
+			};
+		if rv.my_lba != lba {
+			log_warning!("{}: GPT header at {:#x} claims to be at {:#x}", pv.name(), lba, rv.my_lba);
+			return None;
+		}
+		if rv.entry_size < 128 || !rv.entry_size.is_power_of_two() {
+			log_warning!("{}: GPT header at {:#x} has invalid entry size {}", pv.name(), lba, rv.entry_size);
+			return None;
+		}
+		if rv.num_entries as usize * rv.entry_size as usize > MAX_ENTRIES_SIZE {
+			log_warning!("{}: GPT header at {:#x} has too many entries ({} * {})", pv.name(), lba, rv.num_entries, rv.entry_size);
+			return None;
+		}
+		Some(rv)
+	}
+
+	/// Read the partition entry array, returning None if it can't be read or the CRC doesn't match
+	fn read_entries(&self, pv: &dyn storage::PhysicalVolume) -> Option<Vec<u8>>
+	{
+		let bs = pv.blocksize();
+		let len = self.num_entries as usize * self.entry_size as usize;
+		let mut data = vec![0u8; crate::lib::num::round_up(len, bs)];
+		if let Err(e) = read_blocks(pv, self.entries_lba, &mut data) {
+			log_warning!("{}: Unable to read GPT partition array at {:#x}: {:?}", pv.name(), self.entries_lba, e);
+			return None;
+		}
+		data.truncate(len);
+
+		let calc_crc = crate::lib::crc::IEEE.checksum(&data);
+		if calc_crc != self.entries_crc {
+			log_warning!("{}: GPT partition array at {:#x} CRC mismatch ({:#x} != {:#x})", pv.name(), self.entries_lba, calc_crc, self.entries_crc);
+			return None;
+		}
+		Some(data)
+	}
+}
+
+impl Entry
+{
+	fn read(data: &[u8]) -> Option<Entry>
+	{
+		assert!(data.len() >= 128);
+		let type_guid = Guid::from_slice(&data[0..16]);
+		if type_guid == Guid([0; 16]) {
+			return None;
+		}
+
+		let name_units = (0 .. 36)
+			.map(|i| LittleEndian::read_u16(&data[56 + i*2..]))
+			.take_while(|&v| v != 0)
+			;
+		let name = ::core::char::decode_utf16(name_units)
+			.map(|r| r.unwrap_or(::core::char::REPLACEMENT_CHARACTER))
+			.collect();
+
+		Some(Entry {
+			_type_guid: type_guid,
+			unique_guid: Guid::from_slice(&data[16..32]),
+			first_lba: LittleEndian::read_u64(&data[32..]),
+			last_lba: LittleEndian::read_u64(&data[40..]),
+			_attributes: LittleEndian::read_u64(&data[48..]),
+			name: name,
+			})
+	}
+
+	/// Returns the partition label, if it's suitable for use as a LV name
+	fn sanitised_name(&self) -> Option<String>
+	{
+		let name = self.name.trim();
+		if name == "" {
+			None
+		}
+		else {
+			Some( name.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' }).collect() )
+		}
+	}
+}
+
+impl Guid
+{
+	fn from_slice(data: &[u8]) -> Guid
+	{
+		let mut rv = [0; 16];
+		rv.copy_from_slice(&data[..16]);
+		Guid(rv)
+	}
+}
+impl ::core::fmt::Debug for Guid
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result
+	{
+		// First three fields are little-endian, the rest are a byte array
+		let d = &self.0;
+		write!(f, "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+			LittleEndian::read_u32(&d[0..]), LittleEndian::read_u16(&d[4..]), LittleEndian::read_u16(&d[6..]),
+			d[8], d[9], d[10], d[11], d[12], d[13], d[14], d[15]
+			)
+	}
+}
+
+// vim: ft=rust
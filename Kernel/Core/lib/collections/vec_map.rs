@@ -77,6 +77,10 @@ impl<K: Ord, V> VecMap<K,V>
 		Err(_) => None,
 		}
 	}
+	/// Remove all entries for which the predicate returns `false`
+	pub fn retain<F: FnMut(&K, &mut V)->bool>(&mut self, mut f: F) {
+		self.ents.retain_mut(|e| f(&e.0, &mut e.1))
+	}
 	
 	/// Return an 'entry' in the map, allowing cheap handling of insertion/lookup
 	pub fn entry(&mut self, key: K) -> Entry<'_, K, V>
@@ -0,0 +1,66 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/lib/crc.rs
+//! Table-driven CRC32 implementations (used by on-disk formats)
+
+/// A reflected 32-bit CRC with a precomputed lookup table
+pub struct Crc32
+{
+	table: [u32; 256],
+}
+
+/// IEEE 802.3 CRC32 (used by GPT, zlib, ...)
+pub static IEEE: Crc32 = Crc32::new(0xEDB88320);
+/// Castagnoli CRC32 (CRC32C, used by ext4 and jbd2 metadata checksums)
+pub static CASTAGNOLI: Crc32 = Crc32::new(0x82F63B78);
+
+impl Crc32
+{
+	/// Construct the lookup table for a polynomial (in reflected/LSB-first form)
+	pub const fn new(poly: u32) -> Crc32
+	{
+		let mut table = [0; 256];
+		let mut i = 0;
+		while i < 256
+		{
+			let mut v = i as u32;
+			let mut j = 0;
+			while j < 8
+			{
+				v = if v & 1 != 0 { (v >> 1) ^ poly } else { v >> 1 };
+				j += 1;
+			}
+			table[i] = v;
+			i += 1;
+		}
+		Crc32 { table: table }
+	}
+
+	/// Update a raw (non-inverted) CRC state with more data
+	pub fn update(&self, mut crc: u32, data: &[u8]) -> u32
+	{
+		for &b in data
+		{
+			crc = self.table[ ((crc ^ b as u32) & 0xFF) as usize ] ^ (crc >> 8);
+		}
+		crc
+	}
+
+	/// Calculate the standard (pre and post inverted) checksum of a buffer
+	pub fn checksum(&self, data: &[u8]) -> u32
+	{
+		!self.update(!0, data)
+	}
+}
+
+#[test]
+fn test_crc32_check_values()
+{
+	assert_eq!(IEEE.checksum(b"123456789"), 0xCBF43926);
+	assert_eq!(CASTAGNOLI.checksum(b"123456789"), 0xE3069283);
+	// Incremental updates must match a single pass
+	assert_eq!(!IEEE.update(IEEE.update(!0, b"1234"), b"56789"), 0xCBF43926);
+}
+
+// vim: ft=rust
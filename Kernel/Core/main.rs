@@ -84,6 +84,8 @@ pub mod memory;
 pub mod threads;
 /// Timekeeping (timers and wall time)
 pub mod time;
+/// Kernel entropy source (random numbers)
+pub mod entropy;
 
 /// Module management (loading and initialisation of kernel modules)
 pub mod modules;
@@ -27,6 +27,17 @@ static S_MAPALLOC : crate::sync::Mutex<(usize,PAddr)> = mutex_init!( (0,0) );
 static S_FREE_STACK : crate::sync::Mutex<PAddr> = mutex_init!( NOPAGE );
 // TODO: Reference counts (maybe require arch to expose that)
 
+/// Source of memory that can be released when physical memory runs out (e.g. caches)
+pub trait Reclaimable: Sync
+{
+	/// Release up to `count` pages, returning the number released
+	///
+	/// Called when an allocation fails, so must not block on any lock that could be held while allocating
+	fn reclaim(&self, count: usize) -> usize;
+}
+const MAX_RECLAIMABLE: usize = 4;
+static S_RECLAIMABLE: crate::sync::Spinlock<[Option<&'static dyn Reclaimable>; MAX_RECLAIMABLE]> = crate::sync::Spinlock::new([None; MAX_RECLAIMABLE]);
+
 /// A handle to a physical page (maintaining a reference to it, even when not mapped)
 pub struct FrameHandle(PAddr);
 
@@ -72,6 +83,14 @@ impl FrameHandle
 		::core::mem::forget(self);
 		rv
 	}
+	/// Returns true if this is the only reference to the frame (i.e. it's not mapped anywhere)
+	pub fn is_unique(&self) -> bool {
+		match phys_to_ram_frame(self.0)
+		{
+		Some(frame) => phys_track::get_multiref_count(frame) == 0,
+		None => false,
+		}
+	}
 }
 impl Clone for FrameHandle
 {
@@ -133,6 +152,9 @@ pub fn make_unique(page: PAddr, virt_addr: &[u8; PAGE_SIZE]) -> PAddr
 			let mut new_frame = virt::alloc_free().expect("TODO: handle OOM in make_unique");
 			// 2. Copy in content of old frame
 			new_frame.clone_from_slice( virt_addr );
+			// 3. Release the caller's reference to the old frame (it's still referenced elsewhere, so won't be freed)
+			// SAFE: The caller is replacing its mapping of `page` with the returned frame
+			unsafe { deref_frame(page); }
 			new_frame.into_frame().into_addr()
 		}
 	}
@@ -277,11 +299,44 @@ fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Er
 			return Ok( Some(handle) );
 		}
 	}
-	// 3. Fail
+	// 3. Try to release memory from caches, and try again
+	if reclaim(1) > 0 {
+		return allocate_int(address);
+	}
+	// 4. Fail
 	log_warning!("Out of physical memory");
 	Err( Error )
 }
 
+/// Register a source of reclaimable memory (called when an allocation would fail)
+pub fn register_reclaimable(source: &'static dyn Reclaimable)
+{
+	let mut lh = S_RECLAIMABLE.lock();
+	match lh.iter_mut().find(|s| s.is_none())
+	{
+	Some(slot) => *slot = Some(source),
+	None => log_error!("register_reclaimable: Too many sources (max {})", MAX_RECLAIMABLE),
+	}
+}
+/// Ask the registered sources to release `count` pages, returning the number released
+pub fn reclaim(count: usize) -> usize
+{
+	// Copy the list out of the spinlock, as the sources can block
+	let sources = *S_RECLAIMABLE.lock();
+	let mut rv = 0;
+	for s in sources.iter().filter_map(|s| *s)
+	{
+		if rv >= count {
+			break;
+		}
+		rv += s.reclaim(count - rv);
+	}
+	if rv > 0 {
+		log_debug!("reclaim({}): Released {} pages", count, rv);
+	}
+	rv
+}
+
 pub fn ref_frame(paddr: PAddr)
 {
 	if let Some(frame) = phys_to_ram_frame(paddr) {
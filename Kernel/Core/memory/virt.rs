@@ -220,13 +220,18 @@ impl Reservation
 		// SAFE: Unique, and owned
 		unsafe { ::core::slice::from_raw_parts_mut( (self.0 as usize + idx * PAGE_SIZE) as *mut u8, PAGE_SIZE) }
 	}
-	//pub fn map_at(&mut self, idx: usize, frame: FrameHandle) -> Result<(),FrameHandle> {
-	//	assert!(idx < self.1);
-	//	let addr = (self.0 as usize + idx * PAGE_SIZE) as *mut ();
-	//	::arch::memory::virt::unmap(addr);
-	//	::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
-	//	Ok( () )
-	//}
+	/// Replace the placeholder page at `idx` with an existing frame (e.g. one shared with other mappings)
+	pub fn map_at(&mut self, idx: usize, frame: crate::memory::phys::FrameHandle) {
+		assert!(idx < self.1);
+		let addr = (self.0 as usize + idx * PAGE_SIZE) as *mut ();
+		// SAFE: 'self' owns this region of memory, and the frame's reference is moved into the mapping
+		unsafe {
+			if let Some(paddr) = crate::arch::memory::virt::unmap(addr) {
+				crate::memory::phys::deref_frame(paddr);
+			}
+			crate::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
+		}
+	}
 	pub fn finalise(self, final_mode: ProtectionMode) -> Result<(),()> {
 		log_trace!("Reservation::finalise(final_mode={:?})", final_mode);
 		for addr in Pages(self.0, self.1) {
@@ -82,6 +82,20 @@ impl UnitMutex
 		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
 	}
 
+	/// Lock the mutex if it's not already held, returning `false` if it is
+	pub fn try_lock(&self, ty_name: &'static str) -> bool {
+		{
+			let mut lh = self.inner.lock();
+			if lh.holder.is_some() {
+				return false;
+			}
+			lh.holder = Some(crate::threads::get_thread_id());
+		}
+		Self::trace(self, ty_name, "try_lock - acquired");
+		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
+		true
+	}
+
 	/// UNSAFE: Must only be called when the controlled resource is being released
 	#[inline(never)]	// These are nice debugging points
 	pub unsafe fn unlock(&self, ty_name: &'static str) {
@@ -127,6 +141,15 @@ impl<T: ?Sized+Send> Mutex<T>
 		self.inner.lock(type_name!(Self));
 		return HeldMutex { lock: self };
 	}
+	/// Lock the mutex only if it's not already held (never blocks)
+	pub fn try_lock(&self) -> Option<HeldMutex<'_, T>> {
+		if self.inner.try_lock(type_name!(Self)) {
+			Some( HeldMutex { lock: self } )
+		}
+		else {
+			None
+		}
+	}
 
 	/// Obtain `&mut` to the contained data
 	pub fn get_mut(&mut self) -> &mut T {
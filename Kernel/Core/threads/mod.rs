@@ -12,7 +12,7 @@ mod worker_thread;
 
 mod sleep_object;
 
-pub use self::thread::{Thread,ThreadPtr,ThreadID,ProcessID};
+pub use self::thread::{Thread,ThreadPtr,ThreadID,ProcessID,Credentials};
 pub use self::thread::{ThreadHandle,ProcessHandle};
 pub use self::thread::new_idle_thread;
 
@@ -183,6 +183,34 @@ pub fn get_process_id() -> thread::ProcessID {
 	p.get_process_info().get_pid()
 }
 
+/// Get the credentials of the current process (root if there is no current thread)
+pub fn get_credentials() -> Credentials {
+	let p = crate::arch::threads::borrow_thread();
+	// SAFE: Checks for NULL, and the thread should be valid while executing
+	unsafe {
+		if p.is_null() {
+			Credentials::ROOT
+		}
+		else {
+			(*p).get_process_info().get_credentials()
+		}
+	}
+}
+
+/// Change the credentials of the current process (fails if not allowed, see `Credentials::can_switch_to`)
+pub fn set_credentials(creds: Credentials) -> Result<(),()> {
+	with_cur_thread(|t| {
+		let p = t.get_process_info();
+		if p.get_credentials().can_switch_to(creds) {
+			p.set_credentials(creds);
+			Ok( () )
+		}
+		else {
+			Err( () )
+		}
+		})
+}
+
 fn with_cur_thread<T, F: FnOnce(&thread::Thread)->T>(fcn: F) -> T
 {
 	// SAFE: Checks for NULL, and the thread should be valid while executing
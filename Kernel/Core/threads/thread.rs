@@ -47,6 +47,30 @@ impl ::core::fmt::Display for ProcessID {
 	}
 }
 
+/// User/group identity of a process, used for permission checks
+#[derive(Debug,PartialEq,Eq,Copy,Clone)]
+pub struct Credentials
+{
+	pub uid: u32,
+	pub gid: u32,
+}
+impl Credentials {
+	/// Superuser (bypasses permission checks)
+	pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+	pub fn is_root(&self) -> bool {
+		self.uid == 0
+	}
+	/// Can a process with these credentials give a process the `new` credentials (only the superuser can hand out other identities)
+	pub fn can_switch_to(&self, new: Credentials) -> bool {
+		self.is_root() || *self == new
+	}
+}
+impl ::core::fmt::Display for Credentials {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "{}:{}", self.uid, self.gid)
+	}
+}
+
 //#[deriving(PartialEq)]
 /// Thread run state
 pub enum RunState
@@ -71,6 +95,8 @@ pub struct Process
 	address_space: crate::memory::virt::AddressSpace,
 	// TODO: use of a tuple here looks a little crufty
 	exit_status: crate::sync::Mutex< (Option<u32>, Option<crate::threads::sleep_object::SleepObjectRef>) >,
+	/// Identity used for permission checks (inherited from the creating process)
+	credentials: crate::sync::RwLock<Credentials>,
 	pub proc_local_data: crate::sync::RwLock<Vec< crate::lib::mem::aref::Aref<dyn core::any::Any+Sync+Send> >>,
 }
 /// Handle to a process, used for spawning and communicating
@@ -158,6 +184,7 @@ impl Process
 			pid: ProcessID(0),
 			exit_status: Default::default(),
 			address_space: crate::memory::virt::AddressSpace::pid0(),
+			credentials: crate::sync::RwLock::new(Credentials::ROOT),
 			proc_local_data: crate::sync::RwLock::new( Vec::new() ),
 		})
 	}
@@ -168,6 +195,7 @@ impl Process
 			name: name.into(),
 			exit_status: Default::default(),
 			address_space: addr_space,
+			credentials: crate::sync::RwLock::new(super::get_credentials()),
 			proc_local_data: crate::sync::RwLock::new( Vec::new() ),
 		})
 	}
@@ -177,6 +205,11 @@ impl Process
 	}
 
 	pub fn get_pid(&self) -> ProcessID { self.pid }
+	pub fn get_credentials(&self) -> Credentials { *self.credentials.read() }
+	pub(super) fn set_credentials(&self, creds: Credentials) {
+		log_debug!("P({} {}) credentials set to {}", self.pid.0, self.name, creds);
+		*self.credentials.write() = creds;
+	}
 
 	pub fn mark_exit(&self, status: u32) -> Result<(),()> {
 		let mut lh = self.exit_status.lock();
@@ -237,6 +270,14 @@ impl ProcessHandle
 		super::yield_to(thread);
 	}
 
+	pub fn get_credentials(&self) -> Credentials {
+		self.0.get_credentials()
+	}
+	/// Change the process's user/group (caller is responsible for checking that it's allowed to)
+	pub fn set_credentials(&self, creds: Credentials) {
+		self.0.set_credentials(creds);
+	}
+
 	pub fn get_process_local<T>(&self) -> Option<crate::lib::mem::aref::ArefBorrow<T>>
 	where
 		T: Send+Sync+::core::any::Any+Default+'static
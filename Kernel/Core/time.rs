@@ -92,7 +92,8 @@ impl Timer
 		(self.expiry_time != !0 && self.expiry_time > ticks()).then(|| self.expiry_time)
 	}
 	pub fn is_expired(&self) -> bool {
-		self.expiry_time < ticks()
+		// NOTE: `<=` to match `get_expiry` (which returns `None` once the expiry time is reached)
+		self.expiry_time <= ticks()
 	}
 	/// Reset the timer to the given duration
 	pub fn reset(&mut self, duration: u64) -> bool {
@@ -15,7 +15,9 @@ use kernel::sync::{Mutex,RwLock,rwlock};
 // - Handles wrap logical volume handles
 // - Presents:
 //  > read/write (unbuffered)
-//  > read_inner/get/edit (buffered)
+//  > read_inner/get/get_blocks/edit (buffered)
+// - Cache entries are always a single page, keyed by the byte offset (in pages) within the volume
+//  > For volumes with blocks larger than a page, each block is split across multiple entries
 //
 // - The global cache is registered with the PMM as a source of reclaimable memory
 
@@ -31,6 +33,15 @@ pub struct CachedVolume
 /// A read-only handle to a block in the cache
 pub struct BlockHandleRead<'a>(MetaBlockHandle<'a>);
 
+/// A read-only handle to a range of blocks, which may span multiple cache entries
+pub struct MultiBlockHandle<'a>
+{
+	pages: Vec<BlockHandleRead<'a>>,
+	/// Offset of the first byte within the first page
+	ofs: usize,
+	len: usize,
+}
+
 /// A not-yet-locked handle to a block in the cache
 struct MetaBlockHandle<'a>(&'a CachedBlock);
 
@@ -45,7 +56,10 @@ struct Cache
 struct CachedBlock
 {
 	// Constant:
+	/// First volume block in (or overlapping) this page
 	index: u64,
+	/// Byte offset of this page within the block at `index` (non-zero only if blocks are larger than a page)
+	block_ofs: usize,
 	block_paddr: ::kernel::memory::phys::FrameHandle,
 
 	reference_count: AtomicUsize,
@@ -65,17 +79,23 @@ impl CachedVolume
 {
 	pub fn new(vol: VolumeHandle) -> CachedVolume
 	{
-		if vol.block_size() > ::kernel::PAGE_SIZE {
-			todo!("Support caching volumes with block sizes > page size");
-		}
-
+		assert!(vol.block_size().is_power_of_two(), "Volume block size {} not a power of two", vol.block_size());
 		CachedVolume {
 			vh: vol,
 			}
 	}
 
+	/// Number of volume blocks in a cache entry (1 if the blocks are larger than a page)
 	pub fn blocks_per_page(&self) -> u64 {
-		(PAGE_SIZE / self.vh.block_size()) as u64
+		u64::max(1, (PAGE_SIZE / self.vh.block_size()) as u64)
+	}
+	/// Number of cache entries covering a volume block (1 if the blocks are smaller than a page)
+	pub fn pages_per_block(&self) -> u64 {
+		u64::max(1, (self.vh.block_size() / PAGE_SIZE) as u64)
+	}
+	/// Cache entry (page) index containing the start of a block
+	fn page_for_block(&self, block: u64) -> u64 {
+		block * self.pages_per_block() / self.blocks_per_page()
 	}
 }
 
@@ -102,12 +122,11 @@ impl CachedVolume
 
 impl CachedVolume
 {
-	fn get_block_meta_opt(&self, block: u64) -> Option<MetaBlockHandle<'_>> {
-		let cache_block = block - block % self.blocks_per_page();
+	fn get_page_meta_opt(&self, page: u64) -> Option<MetaBlockHandle<'_>> {
 		let handle = {
 			use kernel::lib::vec_map::Entry;
 			let mut lh = S_BLOCK_CACHE.lock();
-			let handle = match lh.map.entry( (self.vh.idx(), cache_block) )
+			let handle = match lh.map.entry( (self.vh.idx(), page) )
 				{
 				Entry::Occupied(v) => v.into_mut().borrow(),
 				Entry::Vacant(_) => return None,
@@ -117,23 +136,30 @@ impl CachedVolume
 			};
 		Some(handle)
 	}
-	/// Obtain an unlocked block handle
-	async fn get_block_meta(&self, block: u64) -> Result<MetaBlockHandle<'_>, IoError>
+	fn get_block_meta_opt(&self, block: u64) -> Option<MetaBlockHandle<'_>> {
+		self.get_page_meta_opt(self.page_for_block(block))
+	}
+	/// Obtain an unlocked handle to a cache entry
+	async fn get_page_meta(&self, page: u64) -> Result<MetaBlockHandle<'_>, IoError>
 	{
-		let cache_block = block - block % self.blocks_per_page();
 		let handle = {
 			use kernel::lib::vec_map::Entry;
 			let mut lh = S_BLOCK_CACHE.lock();
-			let handle = match lh.map.entry( (self.vh.idx(), cache_block) )
+			let handle = match lh.map.entry( (self.vh.idx(), page) )
 				{
 				Entry::Occupied(v) => v.into_mut().borrow(),
-				Entry::Vacant(v) => v.insert( Box::new( CachedBlock::new(&self.vh, cache_block).await? ) ).borrow(),
+				Entry::Vacant(v) => v.insert( Box::new( CachedBlock::new(&self.vh, page).await? ) ).borrow(),
 				};
 			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
 			unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }
 			};
 		Ok(handle)
 	}
+	/// Obtain an unlocked block handle (for the page containing the start of the block)
+	async fn get_block_meta(&self, block: u64) -> Result<MetaBlockHandle<'_>, IoError>
+	{
+		self.get_page_meta(self.page_for_block(block)).await
+	}
 }
 
 /// Unbuffered IO methods. These just directly read/write from the volume.
@@ -141,6 +167,17 @@ impl CachedVolume
 {
 	/// Read blocks without populating the cache, but does check it
 	pub async fn read_blocks(&self, block: u64, data: &mut [u8]) -> Result<(), IoError> {
+		if self.block_size() > PAGE_SIZE {
+			self.vh.read_blocks(block, data).await?;
+			// Overlay any cached pages (which are the authoritative copy)
+			let first_page = self.page_for_block(block);
+			for (i, dst) in data.chunks_mut(PAGE_SIZE).enumerate() {
+				if let Some(h) = self.get_page_meta_opt(first_page + i as u64) {
+					dst.copy_from_slice( h.into_ro().data() );
+				}
+			}
+			return Ok( () );
+		}
 		let total_blocks = (data.len() / self.block_size()) as u64;
 		let mut cur_rel_block = 0;
 		while cur_rel_block < total_blocks {
@@ -163,6 +200,17 @@ impl CachedVolume
 
 	/// Write blocks without populating the cache, but does check it
 	pub async fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), IoError> {
+		if self.block_size() > PAGE_SIZE {
+			self.vh.write_blocks(block, data).await?;
+			// Keep any cached pages in sync with the disk
+			let first_page = self.page_for_block(block);
+			for (i, src) in data.chunks(PAGE_SIZE).enumerate() {
+				if let Some(h) = self.get_page_meta_opt(first_page + i as u64) {
+					h.overwrite(src);
+				}
+			}
+			return Ok( () );
+		}
 		let total_blocks = (data.len() / self.block_size()) as u64;
 		let mut cur_rel_block = 0;
 		while cur_rel_block < total_blocks {
@@ -205,58 +253,113 @@ impl CachedVolume
 		Ok( self.get_block_meta(block).await?.into_ro() )
 	}
 
+	/// Obtain a handle covering `count` blocks, which may span multiple cache entries
+	pub async fn get_blocks(&self, block: u64, count: usize) -> Result<MultiBlockHandle<'_>, IoError>
+	{
+		let start = block * self.block_size() as u64;
+		let len = count * self.block_size();
+		let first_page = start / PAGE_SIZE as u64;
+		let n_pages = ::kernel::lib::num::div_up(start as usize % PAGE_SIZE + len, PAGE_SIZE);
+		let mut pages = Vec::with_capacity(n_pages);
+		for i in 0 .. n_pages as u64 {
+			pages.push( self.get_page_meta(first_page + i).await?.into_ro() );
+		}
+		Ok(MultiBlockHandle {
+			pages: pages,
+			ofs: start as usize % PAGE_SIZE,
+			len: len,
+			})
+	}
+
 	/// Read out of a cached block
 	pub async fn read_inner(&self, block: u64, offset: usize, data: &mut [u8]) -> Result<(),IoError>
 	{
-		let cached_block = self.get_block(block).await?;
-		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
-
-		if offset >= self.block_size() {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
-		if offset >= cached_block.data().len() - blk_ofs {
-			return Err(IoError::InvalidParameter);
+		let mut pos = block * self.block_size() as u64 + offset as u64;
+		let mut data = data;
+		while !data.is_empty()
+		{
+			let cached_block = self.get_page_meta(pos / PAGE_SIZE as u64).await?.into_ro();
+			let page_ofs = pos as usize % PAGE_SIZE;
+			let bytes = usize::min(data.len(), PAGE_SIZE - page_ofs);
+			let (dst, tail) = data.split_at_mut(bytes);
+			dst.clone_from_slice( &cached_block.data()[page_ofs..][..bytes] );
+			data = tail;
+			pos += bytes as u64;
 		}
-		assert!(data.len() <= self.block_size() - offset);
-		let bytes = data.len();
-		data.clone_from_slice( &cached_block.data()[blk_ofs + offset .. ][ .. bytes] );
 		Ok( () )
 	}
 	/// Write into a cached block
 	pub async fn write_inner(&self, block: u64, offset: usize, data: &[u8]) -> Result<(), IoError>
 	{
-		let cached_block = self.get_block_meta(block).await?;
-		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
-
-		if offset >= self.block_size() {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
-		if blk_ofs + offset >= self.chunk_size() {
-			return Err(IoError::InvalidParameter);
+		let mut pos = block * self.block_size() as u64 + offset as u64;
+		let mut data = data;
+		while !data.is_empty()
+		{
+			let cached_block = self.get_page_meta(pos / PAGE_SIZE as u64).await?;
+			let page_ofs = pos as usize % PAGE_SIZE;
+			let bytes = usize::min(data.len(), PAGE_SIZE - page_ofs);
+			cached_block.edit(|block_data| {
+				block_data[page_ofs..][..bytes].clone_from_slice( &data[..bytes] );
+				});
+			data = &data[bytes..];
+			pos += bytes as u64;
 		}
-
-		cached_block.edit(|block_data| {
-			block_data[blk_ofs + offset ..].clone_from_slice( data );
-			Ok( () )
-			})
+		Ok( () )
 	}
 	/// Edit a cached block
+	///
+	/// If the range spans multiple cache entries, the data is collected into a temporary buffer and written back after `f` returns.
 	pub async fn edit<F: FnOnce(&mut [u8])->R,R>(&self, block: u64, count: usize, f: F) -> Result<R, IoError>
 	{
-		let cached_block = self.get_block_meta(block).await?;
-		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
-
-		if (block - cached_block.index()) as usize + count > self.blocks_per_page() as usize {
-			return Err(IoError::InvalidParameter);
-		}
+		let start = block * self.block_size() as u64;
+		let len = count * self.block_size();
+		let page_ofs = start as usize % PAGE_SIZE;
+		if page_ofs + len <= PAGE_SIZE
+		{
+			let cached_block = self.get_page_meta(start / PAGE_SIZE as u64).await?;
+			let rv = cached_block.edit(|block_data| {
+				f( &mut block_data[page_ofs ..][ .. len] )
+				});
 
-		let rv = cached_block.edit(|block_data| {
-			f( &mut block_data[blk_ofs ..][ .. count * self.block_size()] )
-			});
+			cached_block.0.flush(&self.vh).await?;
 
-		cached_block.0.flush(&self.vh).await?;
+			Ok( rv )
+		}
+		else
+		{
+			let first_page = start / PAGE_SIZE as u64;
+			let n_pages = ::kernel::lib::num::div_up(page_ofs + len, PAGE_SIZE);
+			let mut pages = Vec::with_capacity(n_pages);
+			for i in 0 .. n_pages as u64 {
+				pages.push( self.get_page_meta(first_page + i).await? );
+			}
 
-		Ok( rv )
+			// Gather, edit, then scatter back
+			let mut buf = vec![0u8; len];
+			let mut ofs = 0;
+			for (i,p) in pages.iter().enumerate() {
+				let src_ofs = if i == 0 { page_ofs } else { 0 };
+				let bytes = usize::min(len - ofs, PAGE_SIZE - src_ofs);
+				p.edit(|d| buf[ofs..][..bytes].copy_from_slice(&d[src_ofs..][..bytes]));
+				ofs += bytes;
+			}
+			let rv = f(&mut buf);
+			let mut ofs = 0;
+			for (i,p) in pages.iter().enumerate() {
+				let dst_ofs = if i == 0 { page_ofs } else { 0 };
+				let bytes = usize::min(len - ofs, PAGE_SIZE - dst_ofs);
+				p.edit(|d| d[dst_ofs..][..bytes].copy_from_slice(&buf[ofs..][..bytes]));
+				ofs += bytes;
+				p.0.flush(&self.vh).await?;
+			}
+			Ok( rv )
+		}
 	}
 }
 
@@ -271,16 +374,30 @@ fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::me
 /// An actual cached block
 impl CachedBlock
 {
-	async fn new(vol: &VolumeHandle, first_block: u64) -> Result<CachedBlock, IoError>
+	async fn new(vol: &VolumeHandle, page: u64) -> Result<CachedBlock, IoError>
 	{
 		let mut mapping = ::kernel::memory::page_cache::S_PAGE_CACHE.create().map_err(|_| IoError::Unknown("OOM"))?;
 
+		let byte_ofs = page * PAGE_SIZE as u64;
+		let first_block = byte_ofs / vol.block_size() as u64;
+		let block_ofs = (byte_ofs % vol.block_size() as u64) as usize;
 		// TODO: Defer disk read until after the cache entry is created
-		vol.read_blocks(first_block, mapping.data_mut()).await?;
-		log_trace!("Read {} block {}+{}", vol.name(), first_block, PAGE_SIZE / vol.block_size());
+		if vol.block_size() > PAGE_SIZE {
+			// Only part of the block is kept
+			// TODO: Populate the other pages of the block at the same time
+			let mut buf = vec![0; vol.block_size()];
+			vol.read_blocks(first_block, &mut buf).await?;
+			mapping.data_mut().copy_from_slice(&buf[block_ofs..][..PAGE_SIZE]);
+			log_trace!("Read {} block {} (+{:#x})", vol.name(), first_block, block_ofs);
+		}
+		else {
+			vol.read_blocks(first_block, mapping.data_mut()).await?;
+			log_trace!("Read {} block {}+{}", vol.name(), first_block, PAGE_SIZE / vol.block_size());
+		}
 		
 		Ok(CachedBlock {
 			index: first_block,
+			block_ofs: block_ofs,
 			block_paddr: mapping.get_frame_handle(),
 			reference_count: AtomicUsize::new(0),
 
@@ -296,7 +413,18 @@ impl CachedBlock
 		let lh = self.mapping.read();
 		if self.is_dirty.swap(false, Ordering::Acquire)
 		{
-			vol.write_blocks(self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()).await?;
+			let data = lh.as_ref().expect("CachedBlock::flush - None mapping").data();
+			if vol.block_size() > PAGE_SIZE {
+				// Read-modify-write of the containing block
+				// - Other pages in the block are flushed after every edit, so the on-disk copy is current
+				let mut buf = vec![0; vol.block_size()];
+				vol.read_blocks(self.index, &mut buf).await?;
+				buf[self.block_ofs..][..PAGE_SIZE].copy_from_slice(data);
+				vol.write_blocks(self.index, &buf).await?;
+			}
+			else {
+				vol.write_blocks(self.index, data).await?;
+			}
 		}
 		Ok( () )
 	}
@@ -330,6 +458,11 @@ impl<'a> MetaBlockHandle<'a>
 		self.0.is_dirty.store(true, Ordering::Relaxed);
 		f(dataptr)
 	}
+	/// Replace the contents with data that has already been written to disk
+	fn overwrite(&self, src: &[u8]) {
+		let mut lh = self.0.mapping.write();
+		lh.as_mut().expect("CachedBlock mapping is None").data_mut().copy_from_slice(src);
+	}
 
 	pub fn into_ro(self) -> BlockHandleRead<'a> {
 		let read_handle = self.0.mapping.read();
@@ -384,3 +517,50 @@ impl<'a> Drop for BlockHandleRead<'a>
 	}
 }
 
+impl<'a> MultiBlockHandle<'a>
+{
+	/// Total length of the covered range (in bytes)
+	pub fn len(&self) -> usize {
+		self.len
+	}
+	/// Iterate over the data as a sequence of contiguous slices (one per cache entry)
+	pub fn chunks(&self) -> impl Iterator<Item=&[u8]> + '_ {
+		let mut rem = self.len;
+		self.pages.iter().enumerate().map(move |(i, p)| {
+			let ofs = if i == 0 { self.ofs } else { 0 };
+			let bytes = usize::min(rem, PAGE_SIZE - ofs);
+			rem -= bytes;
+			&p.data()[ofs..][..bytes]
+			})
+	}
+	/// Copy data out of the range
+	pub fn read(&self, ofs: usize, dst: &mut [u8]) {
+		assert!(ofs + dst.len() <= self.len);
+		let mut pos = 0;
+		let mut dst = dst;
+		for c in self.chunks() {
+			if dst.is_empty() {
+				break;
+			}
+			if ofs < pos + c.len() {
+				let c = &c[ofs.saturating_sub(pos)..];
+				let bytes = usize::min(c.len(), dst.len());
+				let (d, tail) = dst.split_at_mut(bytes);
+				d.copy_from_slice(&c[..bytes]);
+				dst = tail;
+			}
+			pos += c.len();
+		}
+	}
+	/// Call `f` with the data as a single slice (only copying if the range spans multiple cache entries)
+	pub fn with_data<R>(&self, f: impl FnOnce(&[u8])->R) -> R {
+		if self.pages.len() == 1 {
+			f( &self.pages[0].data()[self.ofs..][..self.len] )
+		}
+		else {
+			let mut buf = vec![0; self.len];
+			self.read(0, &mut buf);
+			f(&buf)
+		}
+	}
+}
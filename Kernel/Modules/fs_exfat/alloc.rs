@@ -0,0 +1,266 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/alloc.rs
+//! Cluster allocation
+//!
+//! The allocation bitmap is the authoritative record of which clusters are in use. The FAT is only used to link the
+//! clusters of chains that aren't contiguous (i.e. stream entries without `NoFatChain`).
+use kernel::prelude::*;
+use kernel::metadevs::storage;
+
+/// Bad cluster marker (values above this are end-of-chain)
+const FAT_BAD: u32 = 0xFFFF_FFF7;
+/// End-of-chain marker (written)
+const FAT_EOC: u32 = 0xFFFF_FFFF;
+
+/// A cluster chain (the allocation of a file/directory)
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Chain
+{
+	/// First cluster (zero if nothing is allocated)
+	pub first: u32,
+	/// The clusters are contiguous, and the FAT isn't used
+	pub no_fat_chain: bool,
+}
+
+/// Cluster chain traversal
+impl super::FilesystemInner
+{
+	/// Get the cluster after `cluster` in a chain
+	///
+	/// NOTE: Contiguous chains have no terminator, the caller must bound the traversal using the data length
+	pub fn get_next_cluster(&self, chain: Chain, cluster: u32) -> ::vfs::Result<Option<u32>> {
+		if chain.no_fat_chain {
+			return if self.is_valid_cluster(cluster + 1) { Ok(Some(cluster + 1)) } else { Err(::vfs::Error::InconsistentFilesystem) };
+		}
+		match self.get_fat_entry(cluster)?
+		{
+		FAT_BAD => {
+			log_error!("Bad cluster marker in a chain ({:#x})", cluster);
+			Err(::vfs::Error::InconsistentFilesystem)
+			},
+		v if v > FAT_BAD => Ok(None),
+		v if self.is_valid_cluster(v) => Ok(Some(v)),
+		v => {
+			log_error!("Invalid FAT entry {:#x} for cluster {:#x}", v, cluster);
+			Err(::vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+	/// Get the `idx`th cluster of a chain
+	pub fn get_chain_cluster(&self, chain: Chain, idx: u64) -> ::vfs::Result<u32> {
+		if chain.first == 0 {
+			return Err(super::ERROR_SHORTCHAIN);
+		}
+		if !self.is_valid_cluster(chain.first) {
+			log_error!("Chain starts outside the cluster heap ({:#x})", chain.first);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		if chain.no_fat_chain {
+			return if self.is_valid_run(chain.first, idx + 1) { Ok(chain.first + idx as u32) } else { Err(::vfs::Error::InconsistentFilesystem) };
+		}
+		let mut cluster = chain.first;
+		for _ in 0 .. idx {
+			cluster = self.get_next_cluster(chain, cluster)?.ok_or(super::ERROR_SHORTCHAIN)?;
+		}
+		Ok(cluster)
+	}
+	/// Count the clusters in a FAT chain
+	pub fn chain_length(&self, chain: Chain) -> ::vfs::Result<u32> {
+		assert!(!chain.no_fat_chain);
+		let mut rv = 1;
+		let mut cluster = chain.first;
+		while let Some(c) = self.get_next_cluster(chain, cluster)? {
+			rv += 1;
+			if rv > self.cluster_count {
+				log_error!("Loop in FAT chain starting at {:#x}", chain.first);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			cluster = c;
+		}
+		Ok(rv)
+	}
+}
+
+/// Allocation and release
+impl super::FilesystemInner
+{
+	/// Append a cluster to a chain that currently has `n_clusters` clusters (the last one being `last`)
+	///
+	/// A contiguous chain is converted to a FAT chain if the following cluster isn't free.
+	pub fn append_cluster(&self, chain: &mut Chain, n_clusters: u64, last: Option<u32>) -> ::vfs::Result<u32> {
+		if self.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let mut hint = self.alloc_lock.lock();
+		let start = match last
+			{
+			Some(c) if c + 1 < self.cluster_count + 2 => c + 1,
+			_ => *hint,
+			};
+		let Some(new) = self.find_free_cluster(start)? else {
+			return Err(::vfs::Error::OutOfSpace);
+			};
+		self.set_bitmap(new, true)?;
+		*hint = new + 1;
+		match last
+		{
+		None => {
+			assert!(n_clusters == 0);
+			*chain = Chain { first: new, no_fat_chain: true };
+			},
+		Some(last) if chain.no_fat_chain => {
+			if new != last + 1 {
+				log_debug!("append_cluster: Converting {:#x}+{} to a FAT chain", chain.first, n_clusters);
+				for i in 0 .. n_clusters as u32 - 1 {
+					self.set_fat_entry(chain.first + i, chain.first + i + 1)?;
+				}
+				self.set_fat_entry(last, new)?;
+				self.set_fat_entry(new, FAT_EOC)?;
+				chain.no_fat_chain = false;
+			}
+			},
+		Some(last) => {
+			self.set_fat_entry(new, FAT_EOC)?;
+			self.set_fat_entry(last, new)?;
+			},
+		}
+		Ok(new)
+	}
+
+	/// Release all clusters after the first `keep` clusters of a chain (of `n_clusters` clusters)
+	pub fn truncate_chain(&self, chain: &mut Chain, n_clusters: u64, keep: u64) -> ::vfs::Result<()> {
+		if keep >= n_clusters {
+			return Ok( () );
+		}
+		// Check the range before releasing anything (the chain could have come from a corrupted entry)
+		if !self.is_valid_run(chain.first, if chain.no_fat_chain { n_clusters } else { 1 }) {
+			log_error!("truncate_chain({:?}): {} clusters is outside the cluster heap", chain, n_clusters);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let _lh = self.alloc_lock.lock();
+		if chain.no_fat_chain {
+			for i in keep .. n_clusters {
+				self.set_bitmap(chain.first + i as u32, false)?;
+			}
+		}
+		else {
+			let mut cluster = if keep == 0 {
+					chain.first
+				}
+				else {
+					let last = self.get_chain_cluster(*chain, keep - 1)?;
+					let next = self.get_next_cluster(*chain, last)?.ok_or(super::ERROR_SHORTCHAIN)?;
+					self.set_fat_entry(last, FAT_EOC)?;
+					next
+				};
+			for _ in keep .. n_clusters
+			{
+				let next = self.get_next_cluster(*chain, cluster)?;
+				self.set_fat_entry(cluster, 0)?;
+				self.set_bitmap(cluster, false)?;
+				match next
+				{
+				Some(c) => cluster = c,
+				None => break,
+				}
+			}
+		}
+		log_debug!("truncate_chain({:?}): Released {} clusters", chain, n_clusters - keep);
+		if keep == 0 {
+			*chain = Chain { first: 0, no_fat_chain: false };
+		}
+		Ok( () )
+	}
+}
+
+/// Low-level FAT and bitmap access
+impl super::FilesystemInner
+{
+	/// Get the sector and byte offset of a FAT entry
+	fn get_fat_addr(&self, cluster: u32) -> (u64, usize) {
+		let bs = self.vh.block_size() as u64;
+		let byte_ofs = cluster as u64 * 4;
+		(self.fat_start + byte_ofs / bs, (byte_ofs % bs) as usize)
+	}
+	fn get_fat_entry(&self, cluster: u32) -> Result<u32, storage::IoError> {
+		let (sector, ofs) = self.get_fat_addr(cluster);
+		let mut buf = [0; 4];
+		::kernel::futures::block_on(self.vh.read_inner(sector, ofs, &mut buf))?;
+		Ok( u32::from_le_bytes(buf) )
+	}
+	fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), storage::IoError> {
+		let (sector, ofs) = self.get_fat_addr(cluster);
+		::kernel::futures::block_on(self.vh.write_inner(sector, ofs, &value.to_le_bytes()))
+	}
+
+	/// Get the sector, byte offset and bit mask of a cluster's bitmap entry
+	fn get_bitmap_addr(&self, cluster: u32) -> (u64, usize, u8) {
+		let bit = (cluster - 2) as usize;
+		let byte = bit / 8;
+		let (sector, ofs) = self.get_cluster_addr(self.bitmap_clusters[byte / self.cluster_size], byte % self.cluster_size);
+		(sector, ofs, 1 << (bit % 8))
+	}
+	fn set_bitmap(&self, cluster: u32, used: bool) -> Result<(), storage::IoError> {
+		let (sector, ofs, mask) = self.get_bitmap_addr(cluster);
+		::kernel::futures::block_on(self.vh.edit(sector, 1, |data| {
+			let was_used = data[ofs] & mask != 0;
+			if was_used == used {
+				log_warning!("set_bitmap({:#x}, {}): Already {}", cluster, used, if used { "allocated" } else { "free" });
+			}
+			if used {
+				data[ofs] |= mask;
+			}
+			else {
+				data[ofs] &= !mask;
+			}
+			}))
+	}
+
+	/// Locate an unallocated cluster, starting at `start` and wrapping around
+	fn find_free_cluster(&self, start: u32) -> Result<Option<u32>, storage::IoError> {
+		let end = self.cluster_count + 2;
+		let start = if self.is_valid_cluster(start) { start } else { 2 };
+		if let Some(rv) = self.find_free_cluster_in(start, end)? {
+			return Ok(Some(rv));
+		}
+		self.find_free_cluster_in(2, start)
+	}
+	fn find_free_cluster_in(&self, start: u32, end: u32) -> Result<Option<u32>, storage::IoError> {
+		let bs = self.vh.block_size();
+		let mut c = start;
+		while c < end
+		{
+			// Scan to the end of the current sector
+			let (sector, ofs, _) = self.get_bitmap_addr(c);
+			let first_bit = (c - 2) % 8;
+			let count = u32::min( ((bs - ofs) * 8) as u32 - first_bit, end - c );
+			let blk = ::kernel::futures::block_on(self.vh.get_blocks(sector, 1))?;
+			let found = blk.with_data(|data| {
+				let mut i = 0;
+				while i < count
+				{
+					let bit = first_bit + i;
+					let byte = data[ofs + bit as usize / 8];
+					if bit % 8 == 0 && byte == 0xFF {
+						i += 8;
+						continue ;
+					}
+					if byte & (1 << (bit % 8)) == 0 {
+						return Some(c + i);
+					}
+					i += 1;
+				}
+				None
+				});
+			if let Some(rv) = found {
+				if rv < end {
+					return Ok(Some(rv));
+				}
+			}
+			c += count;
+		}
+		Ok(None)
+	}
+}
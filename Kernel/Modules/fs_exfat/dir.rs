@@ -0,0 +1,482 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/dir.rs
+//! Directory IO (entry sets)
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::mem::Arc;
+use kernel::lib::byte_str::ByteStr;
+use core::sync::atomic::Ordering;
+use ::vfs::node;
+use utf16::Str16;
+use crate::on_disk::{self, DIRENT_SIZE};
+use crate::{FilesystemInner,NodeInfo,StreamInfo,Chain};
+
+/// Maximum size of a directory
+const MAX_DIR_SIZE: u64 = 256 << 20;
+
+/// A parsed (and checksum validated) file entry set
+#[derive(Clone)]
+pub struct EntrySet
+{
+	/// Raw entries
+	pub raw: Vec<u8>,
+	pub file: on_disk::FileEnt,
+	pub stream: on_disk::StreamEnt,
+	pub name: Vec<u16>,
+}
+impl EntrySet
+{
+	fn parse(raw: Vec<u8>) -> Option<EntrySet> {
+		let file = on_disk::FileEnt::read(&raw[..DIRENT_SIZE]);
+		if raw.len() != (file.secondary_count as usize + 1) * DIRENT_SIZE || file.secondary_count < 2 {
+			return None;
+		}
+		let checksum = on_disk::entry_set_checksum(&raw);
+		if checksum != file.set_checksum {
+			log_notice!("Entry set checksum mismatch: {:#x} != exp {:#x}", checksum, file.set_checksum);
+			return None;
+		}
+		if raw[DIRENT_SIZE] != on_disk::ENT_STREAM {
+			log_notice!("File entry not followed by a stream extension (type {:#x})", raw[DIRENT_SIZE]);
+			return None;
+		}
+		let stream = on_disk::StreamEnt::read(&raw[DIRENT_SIZE..][..DIRENT_SIZE]);
+		let name_len = stream.name_length as usize;
+		let mut name = Vec::with_capacity(name_len);
+		for ent in raw[2*DIRENT_SIZE..].chunks(DIRENT_SIZE).take_while(|e| e[0] == on_disk::ENT_NAME) {
+			name.extend( on_disk::NameEnt::read(ent).chars.iter().copied() );
+		}
+		if name_len == 0 || name.len() < name_len {
+			log_notice!("File name too short ({} < {})", name.len(), name_len);
+			return None;
+		}
+		name.truncate(name_len);
+		Some(EntrySet { raw, file, stream, name })
+	}
+	/// Number of directory entries in the set
+	fn ent_count(&self) -> u32 {
+		(self.raw.len() / DIRENT_SIZE) as u32
+	}
+	fn name(&self) -> &Str16 {
+		Str16::new(&self.name).unwrap_or( Str16::new(&[]).unwrap() )
+	}
+}
+
+/// Get the addresses (sector, offset) of `count` consecutive entries in a directory
+fn get_entry_addrs(fs: &FilesystemInner, chain: Chain, index: u32, count: u32) -> node::Result<Vec<(u64, usize)>> {
+	let epc = (fs.cluster_size / DIRENT_SIZE) as u32;
+	let mut cluster = fs.get_chain_cluster(chain, (index / epc) as u64)?;
+	let mut rv = Vec::with_capacity(count as usize);
+	for i in index .. index + count
+	{
+		if i != index && i % epc == 0 {
+			cluster = fs.get_next_cluster(chain, cluster)?.ok_or(crate::ERROR_SHORTCHAIN)?;
+		}
+		rv.push( fs.get_cluster_addr(cluster, (i % epc) as usize * DIRENT_SIZE) );
+	}
+	Ok(rv)
+}
+
+/// Read the entry set starting at `index` in a directory
+pub fn read_entry_set(fs: &FilesystemInner, chain: Chain, index: u32) -> node::Result<Option<EntrySet>> {
+	let (sector, ofs) = get_entry_addrs(fs, chain, index, 1)?[0];
+	let mut primary = [0; DIRENT_SIZE];
+	::kernel::futures::block_on(fs.vh.read_inner(sector, ofs, &mut primary))?;
+	if primary[0] != on_disk::ENT_FILE {
+		return Ok(None);
+	}
+	let count = primary[1] as u32 + 1;
+	let mut raw = vec![0; count as usize * DIRENT_SIZE];
+	for ((sector, ofs), dst) in get_entry_addrs(fs, chain, index, count)?.into_iter().zip(raw.chunks_mut(DIRENT_SIZE)) {
+		::kernel::futures::block_on(fs.vh.read_inner(sector, ofs, dst))?;
+	}
+	Ok(EntrySet::parse(raw))
+}
+/// Write (some of) the entries of an entry set
+fn write_entries(fs: &FilesystemInner, chain: Chain, index: u32, raw: &[u8]) -> node::Result<()> {
+	let count = (raw.len() / DIRENT_SIZE) as u32;
+	for ((sector, ofs), src) in get_entry_addrs(fs, chain, index, count)?.into_iter().zip(raw.chunks(DIRENT_SIZE)) {
+		::kernel::futures::block_on(fs.vh.write_inner(sector, ofs, src))?;
+	}
+	Ok( () )
+}
+
+/// Write a node's current allocation to its stream extension entry (the caller holds the stream lock)
+pub fn update_stream(fs: &FilesystemInner, id: node::InodeId, info: &NodeInfo, stream: &StreamInfo) -> node::Result<()> {
+	// The root has no entry, and removed entries might have been re-used
+	if id == 0 || info.unlinked.load(Ordering::Relaxed) {
+		return Ok( () );
+	}
+	// NOTE: The parent directory isn't locked, the entry set can't move or be removed while the stream lock is held
+	let (dir_cluster, index) = crate::split_inode(id);
+	let dir_info = fs.get_dir_info(dir_cluster);
+	let chain = Chain { first: dir_cluster, no_fat_chain: dir_info.no_fat_chain.load(Ordering::Relaxed) };
+	let Some(mut set) = read_entry_set(fs, chain, index)? else {
+		log_error!("update_stream({:#x}): Entry set is no longer valid", id);
+		return Err(::vfs::Error::InconsistentFilesystem);
+		};
+	stream.to_ent(&mut set.stream);
+	set.stream.write(&mut set.raw[DIRENT_SIZE..][..DIRENT_SIZE]);
+	on_disk::update_set_checksum(&mut set.raw);
+	write_entries(fs, chain, index, &set.raw[..2*DIRENT_SIZE])
+}
+
+/// Iterate the raw entries of a directory (starting at entry `start`)
+fn iterate_ents<T>(fs: &FilesystemInner, stream: &StreamInfo, start: u32, mut cb: impl FnMut(u32, &[u8])->Option<T>) -> node::Result<Option<T>> {
+	let epc = (fs.cluster_size / DIRENT_SIZE) as u32;
+	let n_clusters = fs.clusters_for(stream.data_length);
+	let mut ci = (start / epc) as u64;
+	if ci >= n_clusters {
+		return Ok(None);
+	}
+	let mut cluster = fs.get_chain_cluster(stream.chain, ci)?;
+	let mut first = start % epc;
+	loop
+	{
+		let base = ci as u32 * epc;
+		let rv = ::kernel::futures::block_on(fs.with_cluster(cluster, |data| {
+			for i in first .. epc {
+				if let Some(v) = cb(base + i, &data[i as usize * DIRENT_SIZE..][..DIRENT_SIZE]) {
+					return Some(v);
+				}
+			}
+			None
+			}))?;
+		if rv.is_some() {
+			return Ok(rv);
+		}
+		ci += 1;
+		first = 0;
+		if ci >= n_clusters {
+			return Ok(None);
+		}
+		cluster = fs.get_next_cluster(stream.chain, cluster)?.ok_or(crate::ERROR_SHORTCHAIN)?;
+	}
+}
+
+/// Iterate the valid file entry sets in a directory
+///
+/// The callback is called with `None` for the end-of-directory marker (after which iteration stops)
+fn iterate_sets<T>(fs: &FilesystemInner, stream: &StreamInfo, start: u32, mut cb: impl FnMut(u32, Option<&EntrySet>)->Option<T>) -> node::Result<Option<T>> {
+	// Index, raw entries, expected length
+	let mut pending: Option<(u32, Vec<u8>, usize)> = None;
+	let rv = iterate_ents(fs, stream, start, |idx, ent| {
+		match ent[0]
+		{
+		on_disk::ENT_END => return Some(cb(idx, None)),
+		on_disk::ENT_FILE => pending = Some( (idx, ent.to_vec(), (ent[1] as usize + 1) * DIRENT_SIZE) ),
+		ty if ty & (on_disk::ENT_INUSE|on_disk::ENT_SECONDARY) == (on_disk::ENT_INUSE|on_disk::ENT_SECONDARY) =>
+			if let Some(p) = pending.as_mut() {
+				p.1.extend_from_slice(ent);
+			},
+		_ => pending = None,
+		}
+		if pending.as_ref().map(|p| p.1.len() >= p.2) == Some(true) {
+			let (idx, raw, _) = pending.take().unwrap();
+			match EntrySet::parse(raw)
+			{
+			Some(set) => if let Some(v) = cb(idx, Some(&set)) {
+				return Some(Some(v));
+				},
+			None => log_notice!("Invalid entry set at {}", idx),
+			}
+		}
+		None
+		})?;
+	Ok(rv.and_then(|v| v))
+}
+
+/// Convert a name to UTF-16, checking that it's a valid exFAT name
+fn encode_name(name: &ByteStr) -> node::Result<Vec<u16>> {
+	let rv: Vec<u16> = ::utf16::wtf8_to_utf16(name.as_bytes()).collect();
+	if rv.is_empty() || rv.len() > on_disk::MAX_NAME_LEN {
+		return Err(::vfs::Error::InvalidParameter);
+	}
+	if rv.iter().any(|&c| c < 0x20 || (c < 0x80 && b"\"*/:<>?\\|".contains(&(c as u8)))) {
+		return Err(::vfs::Error::InvalidParameter);
+	}
+	Ok(rv)
+}
+
+pub struct DirNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	id: node::InodeId,
+	info: Arc<NodeInfo>,
+	/// First cluster (directories are never empty, so this doesn't change)
+	first_cluster: u32,
+}
+impl_fmt! {
+	Debug(self, f) for DirNode {
+		write!(f, "{{id={:#x} cluster={:#x}}}", self.id, self.first_cluster)
+	}
+}
+
+impl DirNode {
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, id: node::InodeId, info: Arc<NodeInfo>) -> Box<DirNode> {
+		let first_cluster = info.stream.read().chain.first;
+		Box::new(DirNode {
+			fs,
+			id,
+			info,
+			first_cluster,
+			})
+	}
+}
+impl ::core::ops::Drop for DirNode {
+	fn drop(&mut self) {
+		self.fs.release_node_info(self.id, &self.info);
+	}
+}
+impl node::NodeBase for DirNode {
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+}
+
+impl DirNode
+{
+	fn iterate_sets<T>(&self, start: u32, cb: impl FnMut(u32, Option<&EntrySet>)->Option<T>) -> node::Result<Option<T>> {
+		let stream = *self.info.stream.read();
+		iterate_sets(&self.fs, &stream, start, cb)
+	}
+	/// Locate an entry set by name
+	fn find(&self, name: &[u16]) -> node::Result<Option<(u32, EntrySet)>> {
+		let hash = self.fs.upcase.name_hash(name);
+		self.iterate_sets(0, |idx, set| {
+			let set = set?;
+			if set.stream.name_hash == hash && self.fs.upcase.names_equal(&set.name, name) {
+				Some( (idx, set.clone()) )
+			}
+			else {
+				None
+			}
+			})
+	}
+
+	/// Locate `count` consecutive free entries (extending the directory if required)
+	fn find_free(&self, count: u32) -> node::Result<u32> {
+		// Entries of removed (but still open) nodes can't be re-used, as the inode number would be duplicated
+		let reserved = self.fs.unlinked_entries(self.first_cluster);
+		loop
+		{
+			let stream = *self.info.stream.read();
+			let mut run_start = 0;
+			let mut run_len = 0;
+			let mut seen_end = false;
+			let rv = iterate_ents(&self.fs, &stream, 0, |idx, ent| {
+				seen_end |= ent[0] == on_disk::ENT_END;
+				if seen_end || (ent[0] & on_disk::ENT_INUSE == 0 && !reserved.contains(&idx)) {
+					if run_len == 0 {
+						run_start = idx;
+					}
+					run_len += 1;
+					if run_len == count {
+						return Some(run_start);
+					}
+				}
+				else {
+					run_len = 0;
+				}
+				None
+				})?;
+			if let Some(rv) = rv {
+				return Ok(rv);
+			}
+			self.extend()?;
+		}
+	}
+
+	/// Add a (zeroed) cluster to the end of the directory
+	fn extend(&self) -> node::Result<()> {
+		let mut stream = self.info.stream.write();
+		if stream.data_length + self.fs.cluster_size as u64 > MAX_DIR_SIZE {
+			return Err(::vfs::Error::OutOfSpace);
+		}
+		let n_clusters = self.fs.clusters_for(stream.data_length);
+		let last = self.fs.get_chain_cluster(stream.chain, n_clusters - 1)?;
+		let mut chain = stream.chain;
+		let new_cluster = self.fs.append_cluster(&mut chain, n_clusters, Some(last))?;
+		log_debug!("DirNode::extend: Added cluster {:#x} to {:#x} ({:?})", new_cluster, self.first_cluster, chain);
+		::kernel::futures::block_on(self.fs.write_clusters(new_cluster, &vec![0; self.fs.cluster_size]))?;
+
+		stream.chain = chain;
+		stream.data_length += self.fs.cluster_size as u64;
+		stream.valid_data_length = stream.data_length;
+		self.fs.register_dir(chain);
+		update_stream(&self.fs, self.id, &self.info, &stream)
+	}
+}
+
+impl node::Dir for DirNode {
+	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
+		log_trace!("DirNode::lookup({:?})", name);
+		let name = encode_name(name).map_err(|_| ::vfs::Error::NotFound)?;
+		let dir_info = self.fs.get_dir_info(self.first_cluster);
+		let _lh_dir = dir_info.lock.read();
+		match self.find(&name)?
+		{
+		Some((idx, _)) => Ok( crate::make_inode(self.first_cluster, idx) ),
+		None => Err(::vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		log_trace!("DirNode::read(ofs={})", ofs);
+		let dir_info = self.fs.get_dir_info(self.first_cluster);
+		let _lh_dir = dir_info.lock.read();
+
+		let rv = self.iterate_sets(ofs as u32, |idx, set| {
+			match set
+			{
+			// On next call, we want to hit this entry (so we can return count=0)
+			None => Some(idx),
+			Some(set) => {
+				if !callback(crate::make_inode(self.first_cluster, idx), &mut set.name().wtf8()) {
+					Some(idx + set.ent_count())
+				}
+				else {
+					None
+				}
+				},
+			}
+			})?;
+		Ok(match rv
+			{
+			Some(v) => v as usize,
+			None => (self.info.stream.read().data_length / DIRENT_SIZE as u64) as usize,
+			})
+	}
+	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
+		log_trace!("DirNode::create('{:?}', {:?})", name, nodetype);
+		if self.fs.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let attributes = match nodetype
+			{
+			node::NodeType::File => on_disk::ATTR_ARCHIVE,
+			node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
+			node::NodeType::Symlink(_) => return Err(::vfs::Error::Unknown("exFAT doesn't support symbolic links")),
+			};
+		let name = encode_name(name)?;
+
+		let dir_info = self.fs.get_dir_info(self.first_cluster);
+		let _lh_dir = dir_info.lock.write();
+		if self.info.unlinked.load(Ordering::Relaxed) {
+			return Err(::vfs::Error::NotFound);
+		}
+		if self.find(&name)?.is_some() {
+			return Err(::vfs::Error::AlreadyExists);
+		}
+
+		let n_name_ents = (name.len() + on_disk::NAME_CHARS_PER_ENT - 1) / on_disk::NAME_CHARS_PER_ENT;
+		let count = 2 + n_name_ents as u32;
+		let index = self.find_free(count)?;
+
+		// Directories always have a cluster allocated
+		let stream = if attributes & on_disk::ATTR_DIRECTORY != 0 {
+				let mut chain = Chain { first: 0, no_fat_chain: false };
+				let new_cluster = self.fs.append_cluster(&mut chain, 0, None)?;
+				if let Err(e) = ::kernel::futures::block_on(self.fs.write_clusters(new_cluster, &vec![0; self.fs.cluster_size])) {
+					self.fs.truncate_chain(&mut chain, 1, 0)?;
+					return Err(e.into());
+				}
+				self.fs.register_dir(chain);
+				StreamInfo { chain, data_length: self.fs.cluster_size as u64, valid_data_length: self.fs.cluster_size as u64 }
+			}
+			else {
+				StreamInfo { chain: Chain { first: 0, no_fat_chain: false }, data_length: 0, valid_data_length: 0 }
+			};
+		log_debug!("DirNode::create: {} entries at {}, {:?}", count, index, stream);
+
+		let mut raw = vec![0; count as usize * DIRENT_SIZE];
+		on_disk::FileEnt {
+			secondary_count: (count - 1) as u8,
+			set_checksum: 0,
+			attributes,
+			create_time: on_disk::DEFAULT_TIMESTAMP,
+			modified_time: on_disk::DEFAULT_TIMESTAMP,
+			accessed_time: on_disk::DEFAULT_TIMESTAMP,
+			}.write(&mut raw[..DIRENT_SIZE]);
+		let mut stream_ent = on_disk::StreamEnt {
+			flags: 0,
+			name_length: name.len() as u8,
+			name_hash: self.fs.upcase.name_hash(&name),
+			valid_data_length: 0,
+			first_cluster: 0,
+			data_length: 0,
+			};
+		stream.to_ent(&mut stream_ent);
+		stream_ent.write(&mut raw[DIRENT_SIZE..][..DIRENT_SIZE]);
+		for (i, dst) in raw[2*DIRENT_SIZE..].chunks_mut(DIRENT_SIZE).enumerate() {
+			let mut ent = on_disk::NameEnt { chars: [0; on_disk::NAME_CHARS_PER_ENT] };
+			for (d,s) in ent.chars.iter_mut().zip( name[i * on_disk::NAME_CHARS_PER_ENT..].iter() ) {
+				*d = *s;
+			}
+			ent.write(dst);
+		}
+		on_disk::update_set_checksum(&mut raw);
+
+		let chain = *self.info.stream.read();
+		write_entries(&self.fs, chain.chain, index, &raw)?;
+		Ok( crate::make_inode(self.first_cluster, index) )
+	}
+	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
+		log_trace!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+		// An exFAT directory entry set holds the node's allocation, so there can't be more than one
+		Err(::vfs::Error::Unknown("exFAT doesn't support hard links"))
+	}
+	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
+		log_trace!("DirNode::unlink('{:?}')", name);
+		if self.fs.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let name = encode_name(name).map_err(|_| ::vfs::Error::NotFound)?;
+
+		let dir_info = self.fs.get_dir_info(self.first_cluster);
+		let _lh_dir = dir_info.lock.write();
+		let Some((index, set)) = self.find(&name)? else {
+			return Err(::vfs::Error::NotFound);
+			};
+		let child_stream = StreamInfo::from_ent(&self.fs, &set.stream)?;
+		if set.file.is_dir() {
+			let child_info = self.fs.get_dir_info(child_stream.chain.first);
+			let _lh_child = child_info.lock.read();
+			let is_empty = iterate_sets(&self.fs, &child_stream, 0, |_, set| set.map(|_| ()))?.is_none();
+			if !is_empty {
+				return Err(::vfs::Error::DirectoryNotEmpty);
+			}
+		}
+
+		// Clear the in-use bit on each entry
+		let mut raw = set.raw;
+		for ent in raw.chunks_mut(DIRENT_SIZE) {
+			ent[0] &= !on_disk::ENT_INUSE;
+		}
+		let id = crate::make_inode(self.first_cluster, index);
+		let chain = self.info.stream.read().chain;
+		// Hold the node list lock, so the node can't be opened while it's being removed
+		let lh_nodes = self.fs.nodes.lock();
+		if let Some(info) = lh_nodes.get(&id) {
+			// Still open, release the clusters once closed
+			let _lh_stream = info.stream.write();
+			write_entries(&self.fs, chain, index, &raw)?;
+			info.unlinked.store(true, Ordering::Relaxed);
+			log_debug!("DirNode::unlink: {:#x} still open", id);
+		}
+		else {
+			write_entries(&self.fs, chain, index, &raw)?;
+			drop(lh_nodes);
+			let mut child_chain = child_stream.chain;
+			self.fs.truncate_chain(&mut child_chain, self.fs.clusters_for(child_stream.data_length), 0)?;
+			if set.file.is_dir() {
+				self.fs.dirs.write().remove(&child_stream.chain.first);
+			}
+		}
+		Ok( () )
+	}
+}
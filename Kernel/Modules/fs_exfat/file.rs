@@ -0,0 +1,270 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/file.rs
+//! File IO
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::mem::Arc;
+use ::vfs::node;
+use crate::{FilesystemInner,NodeInfo,StreamInfo};
+use crate::ERROR_SHORTCHAIN;
+
+pub struct FileNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	id: node::InodeId,
+	info: Arc<NodeInfo>,
+}
+
+impl FileNode
+{
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, id: node::InodeId, info: Arc<NodeInfo>) -> Box<FileNode> {
+		Box::new(FileNode {
+			fs,
+			id,
+			info,
+			})
+	}
+}
+impl ::core::ops::Drop for FileNode {
+	fn drop(&mut self) {
+		self.fs.release_node_info(self.id, &self.info);
+	}
+}
+impl node::NodeBase for FileNode {
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+}
+impl node::File for FileNode {
+	fn size(&self) -> u64 {
+		self.info.stream.read().data_length
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		if self.fs.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let mut stream = self.info.stream.write();
+		if newsize < stream.data_length {
+			// Update size, and then deallocate clusters
+			// - Size first, so a failure when releasing just leaks clusters
+			let n_clusters = self.fs.clusters_for(stream.data_length);
+			let keep_clusters = self.fs.clusters_for(newsize);
+			stream.data_length = newsize;
+			stream.valid_data_length = u64::min(stream.valid_data_length, newsize);
+			let mut chain = stream.chain;
+			if keep_clusters == 0 {
+				stream.chain.first = 0;
+			}
+			super::dir::update_stream(&self.fs, self.id, &self.info, &stream)?;
+			log_debug!("truncate({:#x}): Keeping {} of {} clusters", newsize, keep_clusters, n_clusters);
+			self.fs.truncate_chain(&mut chain, n_clusters, keep_clusters)?;
+			Ok( newsize )
+		}
+		else if newsize > stream.data_length {
+			// Allocate new clusters, and zero-fill (updating the size as we go)
+			let ofs = stream.data_length;
+			self.write_inner(&mut stream, ofs, WriteData::Zero(newsize - ofs))?;
+			Ok( stream.data_length )
+		}
+		else {
+			Ok( newsize )
+		}
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		if self.fs.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let mut stream = self.info.stream.write();
+		if ofs >= stream.data_length {
+			return Ok( () );
+		}
+		// Clearing doesn't change the file size, so clamp to the end of the file
+		let size = u64::min(size, stream.data_length - ofs);
+		self.write_inner(&mut stream, ofs, WriteData::Zero(size))?;
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let stream = self.info.stream.read();
+		// Sanity check and bound parameters
+		if ofs > stream.data_length {
+			// out of range
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		if ofs == stream.data_length {
+			return Ok(0);
+		}
+		let maxread = u64::min(stream.data_length - ofs, buf.len() as u64) as usize;
+		let buf = &mut buf[..maxread];
+		log_trace!("read(@{:#x} len={:?})", ofs, buf.len());
+
+		// Data past the valid length hasn't been written, and reads as zero
+		let valid_len = u64::min(stream.valid_data_length.saturating_sub(ofs), buf.len() as u64) as usize;
+		for b in buf[valid_len..].iter_mut() {
+			*b = 0;
+		}
+		if valid_len == 0 {
+			return Ok( buf.len() );
+		}
+
+		let cluster_size = self.fs.cluster_size;
+		let mut cluster = self.fs.get_chain_cluster(stream.chain, ofs / cluster_size as u64)?;
+		let mut cluster_ofs = (ofs % cluster_size as u64) as usize;
+		let mut pos = 0;
+		while pos < valid_len
+		{
+			let dst = &mut buf[pos..valid_len];
+			let seg_len = if cluster_ofs == 0 && dst.len() >= cluster_size {
+					// Contiguous files can be read in one go
+					let count = if stream.chain.no_fat_chain { dst.len() / cluster_size } else { 1 };
+					let bytes = count * cluster_size;
+					log_trace!("read(): Read cluster extent {:#x} + {}", cluster, count);
+					::kernel::futures::block_on(self.fs.read_clusters(cluster, &mut dst[..bytes]))?;
+					cluster += count as u32 - 1;
+					bytes
+				}
+				else {
+					let seg_len = usize::min(cluster_size - cluster_ofs, dst.len());
+					log_trace!("read(): Read partial {:#x} ofs={} len={}", cluster, cluster_ofs, seg_len);
+					::kernel::futures::block_on(self.fs.with_cluster(cluster, |c| {
+						dst[..seg_len].copy_from_slice( &c[cluster_ofs..][..seg_len] );
+						}))?;
+					seg_len
+				};
+			pos += seg_len;
+			cluster_ofs = 0;
+			if pos < valid_len {
+				cluster = self.fs.get_next_cluster(stream.chain, cluster)?.ok_or(ERROR_SHORTCHAIN)?;
+			}
+		}
+		log_trace!("read(): Complete {}", buf.len());
+		Ok( buf.len() )
+	}
+	/// Write data to the file, can only grow the file if ofs==size
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		if self.fs.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		let mut stream = self.info.stream.write();
+		self.write_inner(&mut stream, ofs, WriteData::Data(buf))
+	}
+}
+
+/// Source of data for `FileNode::write_inner`
+#[derive(Copy,Clone)]
+enum WriteData<'a>
+{
+	Data(&'a [u8]),
+	/// Zero-fill the given number of bytes
+	Zero(u64),
+}
+impl<'a> WriteData<'a>
+{
+	fn len(&self) -> u64 {
+		match *self
+		{
+		WriteData::Data(d) => d.len() as u64,
+		WriteData::Zero(l) => l,
+		}
+	}
+}
+
+impl FileNode
+{
+	/// Write (or zero-fill) a range of the file, extending the file if the range passes the current end
+	///
+	/// `stream` is the locked allocation, which is updated (both in memory and on disk) if it changes.
+	fn write_inner(&self, stream: &mut StreamInfo, ofs: u64, data: WriteData) -> node::Result<usize> {
+		if ofs > stream.data_length {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		// Space between the valid length and the write has to be zeroed first
+		if ofs > stream.valid_data_length {
+			let valid = stream.valid_data_length;
+			self.write_inner(stream, valid, WriteData::Zero(ofs - valid))?;
+		}
+		let len = data.len();
+		if len == 0 {
+			return Ok(0);
+		}
+		log_trace!("write_inner(@{:#x} len={:#x}, {:?})", ofs, len, stream);
+		let cluster_size = self.fs.cluster_size;
+		let zero_cluster = match data
+			{
+			WriteData::Zero(_) => vec![0; cluster_size],
+			WriteData::Data(_) => Vec::new(),
+			};
+		let orig_stream = *stream;
+
+		// Seek to correct position in the cluster chain
+		let mut n_alloc = self.fs.clusters_for(stream.data_length);
+		let mut cluster_idx = ofs / cluster_size as u64;
+		let mut prev = if cluster_idx == 0 { None } else { Some(self.fs.get_chain_cluster(stream.chain, cluster_idx - 1)?) };
+
+		let mut cluster_ofs = (ofs % cluster_size as u64) as usize;
+		let mut pos = 0;
+		let mut rv = Ok( () );
+		while pos < len
+		{
+			let cluster = if cluster_idx < n_alloc {
+					match prev
+					{
+					None => stream.chain.first,
+					Some(p) => self.fs.get_next_cluster(stream.chain, p)?.ok_or(ERROR_SHORTCHAIN)?,
+					}
+				}
+				else {
+					// Need to allocate a new one!
+					match self.fs.append_cluster(&mut stream.chain, n_alloc, prev)
+					{
+					Ok(c) => { n_alloc += 1; c },
+					Err(e) => { rv = Err(e); break },
+					}
+				};
+			let seg_len = u64::min((cluster_size - cluster_ofs) as u64, len - pos) as usize;
+			let src = match data
+				{
+				WriteData::Data(d) => &d[pos as usize..][..seg_len],
+				WriteData::Zero(_) => &zero_cluster[..seg_len],
+				};
+			let res = if seg_len == cluster_size {
+					::kernel::futures::block_on(self.fs.write_clusters(cluster, src))
+				}
+				else {
+					::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
+						c[cluster_ofs..][..seg_len].copy_from_slice(src);
+						}))
+				};
+			if let Err(e) = res {
+				rv = Err(e.into());
+				break;
+			}
+			prev = Some(cluster);
+			cluster_idx += 1;
+			cluster_ofs = 0;
+			pos += seg_len as u64;
+			stream.data_length = u64::max(stream.data_length, ofs + pos);
+			stream.valid_data_length = u64::max(stream.valid_data_length, ofs + pos);
+		}
+		// Clusters that were allocated but not written to (due to an error) must still be covered by the data length
+		if self.fs.clusters_for(stream.data_length) < n_alloc {
+			stream.data_length = (n_alloc - 1) * cluster_size as u64 + 1;
+		}
+
+		// Update the directory entry
+		if stream.data_length != orig_stream.data_length || stream.valid_data_length != orig_stream.valid_data_length || stream.chain != orig_stream.chain {
+			super::dir::update_stream(&self.fs, self.id, &self.info, stream)?;
+		}
+		match rv
+		{
+		Ok(()) => Ok(pos as usize),
+		// Return a short write
+		Err(_) if pos > 0 => Ok(pos as usize),
+		Err(e) => Err(e),
+		}
+	}
+}
@@ -0,0 +1,491 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/lib.rs
+//! exFAT Filesystem driver
+//!
+//! Inode numbers are the location of the node's primary directory entry (the first cluster of the containing
+//! directory, and the entry index within that directory). The root directory has no entry, and is inode zero.
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use] extern crate kernel;
+use kernel::prelude::*;
+
+use kernel::metadevs::storage::{self,VolumeHandle,SizePrinter};
+use kernel::lib::mem::aref::ArefInner;
+use kernel::lib::mem::Arc;
+use kernel::lib::collections::VecMap;
+use kernel::sync::{Mutex,RwLock};
+use core::sync::atomic::{AtomicBool,Ordering};
+use ::vfs::{mount, node};
+
+extern crate utf16;
+extern crate block_cache;
+
+module_define!{FS_EXFAT, [VFS], init}
+
+/// On-disk structures
+mod on_disk;
+/// Up-case table (case-insensitive comparisons)
+mod upcase;
+/// Cluster allocation (bitmap and FAT)
+mod alloc;
+/// Directory IO
+mod dir;
+/// File IO
+mod file;
+
+use alloc::Chain;
+
+const ERROR_SHORTCHAIN: ::vfs::Error = ::vfs::Error::Unknown("Cluster chain terminated early");
+
+/// Number of bits used for the entry index in an inode number (directories are limited to 256MiB)
+const INODE_INDEX_BITS: u32 = 24;
+
+/// Driver structure
+struct Driver;
+
+struct Filesystem
+{
+	inner: ArefInner<FilesystemInner>
+}
+impl ::core::ops::Deref for Filesystem {
+	type Target = FilesystemInner;
+	fn deref(&self) -> &FilesystemInner { &self.inner }
+}
+
+pub struct FilesystemInner
+{
+	vh: ::block_cache::CachedVolume,
+	is_readonly: bool,
+
+	// NOTE: All sector counts/indexes are in units of volume blocks (which can be smaller than the exFAT sector size)
+	blocks_per_cluster: usize,
+	cluster_size: usize,
+	/// Total number of clusters in the cluster heap (numbered from 2)
+	cluster_count: u32,
+	/// First sector of the active FAT
+	fat_start: u64,
+	/// First sector of the cluster heap
+	heap_start: u64,
+
+	upcase: upcase::UpcaseTable,
+	/// Clusters holding the allocation bitmap (in order)
+	bitmap_clusters: Vec<u32>,
+	/// Lock held while allocating/releasing clusters, protects the next-fit allocation hint
+	alloc_lock: Mutex<u32>,
+
+	/// Directories that have been seen (keyed by first cluster), used to locate entries from an inode number
+	dirs: RwLock<VecMap<u32, Arc<DirInfo>>>,
+	/// Shared state for nodes that currently have handles
+	nodes: Mutex<VecMap<node::InodeId, Arc<NodeInfo>>>,
+}
+
+/// Information about a directory's cluster chain
+#[derive(Default)]
+pub struct DirInfo
+{
+	/// The directory is a contiguous allocation (the FAT isn't used)
+	no_fat_chain: AtomicBool,
+	/// Lock on the directory's contents
+	lock: RwLock<()>,
+}
+
+/// Current data allocation of a node
+#[derive(Copy,Clone,Debug)]
+pub struct StreamInfo
+{
+	chain: Chain,
+	data_length: u64,
+	valid_data_length: u64,
+}
+
+/// State shared between all handles to a node
+pub struct NodeInfo
+{
+	is_dir: bool,
+	stream: RwLock<StreamInfo>,
+	/// The directory entry was removed while the node was open, release the clusters on close
+	unlinked: AtomicBool,
+}
+
+static S_DRIVER: Driver = Driver;
+
+fn init()
+{
+	let h = mount::DriverRegistration::new("exfat", &S_DRIVER);
+	// TODO: Remember the registration for unloading
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, vol: &VolumeHandle) -> ::vfs::Result<usize> {
+		let bs = {
+			let mut bs = vec![0u8; ::core::cmp::max(512, vol.block_size())];
+			::kernel::futures::block_on( vol.read_blocks(0, &mut bs) )?;
+			on_disk::BootSect::read(&bs[..512])
+			};
+		if bs.is_exfat() {
+			Ok(1)
+		}
+		else {
+			Ok(0)
+		}
+	}
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+		let vol = ::block_cache::CachedVolume::new(vol);
+
+		let bs = {
+			let mut buf = [0; 512];
+			::kernel::futures::block_on(vol.read_inner(0, 0, &mut buf))?;
+			on_disk::BootSect::read(&buf)
+			};
+		log_debug!("BootSect = {:?}", bs);
+		if !bs.is_exfat() {
+			return Err(::vfs::Error::TypeMismatch);
+		}
+		if !(9 ..= 12).contains(&bs.bytes_per_sector_shift) || bs.bytes_per_sector_shift + bs.sectors_per_cluster_shift > 25 {
+			log_warning!("Invalid exFAT geometry: sector shift {}, cluster shift {}", bs.bytes_per_sector_shift, bs.sectors_per_cluster_shift);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let bps = 1usize << bs.bytes_per_sector_shift;
+		if bps < vol.block_size() {
+			log_warning!("exFAT sector size {} unsupported on volume with {} byte blocks", bps, vol.block_size());
+			return Err(::vfs::Error::Unknown("exFAT: Unsupported sector size"));
+		}
+		if bs.revision >> 8 != 1 {
+			log_warning!("Unsupported exFAT revision {}.{:02}", bs.revision >> 8, bs.revision & 0xFF);
+			return Err(::vfs::Error::Unknown("exFAT: Unsupported revision"));
+		}
+		if bs.number_of_fats == 0 || bs.number_of_fats > 2 {
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+
+		// Check the boot region checksum (stored repeated in sector 11)
+		{
+			let mut region = vec![0; 12 * bps];
+			::kernel::futures::block_on(vol.read_blocks(0, &mut region))?;
+			let exp = u32::from_le_bytes([region[11*bps], region[11*bps+1], region[11*bps+2], region[11*bps+3]]);
+			let checksum = on_disk::boot_checksum(&region[..11*bps]);
+			if checksum != exp {
+				log_warning!("exFAT boot region checksum mismatch: {:#x} != exp {:#x}", checksum, exp);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+		}
+
+		// TexFAT (two FATs) isn't supported for writing, and an unclean volume shouldn't be modified until checked
+		let is_readonly = if bs.number_of_fats != 1 {
+				log_notice!("exFAT volume has {} FATs, mounting read-only", bs.number_of_fats);
+				true
+			}
+			else if bs.volume_flags & on_disk::VOLFLAG_DIRTY != 0 {
+				log_notice!("exFAT volume is marked dirty, mounting read-only");
+				true
+			}
+			else {
+				false
+			};
+		let active_fat = if bs.volume_flags & on_disk::VOLFLAG_ACTIVE_FAT != 0 && bs.number_of_fats == 2 { 1 } else { 0 };
+
+		// Volume blocks per exFAT sector
+		let vbps = (bps / vol.block_size()) as u64;
+		log_debug!("{} clusters of {} bytes, Size {}", bs.cluster_count, bps << bs.sectors_per_cluster_shift,
+			SizePrinter(bs.volume_length << bs.bytes_per_sector_shift));
+		let root_cluster = bs.root_dir_cluster;
+		let mut fs = FilesystemInner {
+			is_readonly,
+			blocks_per_cluster: (vbps << bs.sectors_per_cluster_shift) as usize,
+			cluster_size: bps << bs.sectors_per_cluster_shift,
+			cluster_count: bs.cluster_count,
+			fat_start: (bs.fat_offset as u64 + active_fat * bs.fat_length as u64) * vbps,
+			heap_start: bs.cluster_heap_offset as u64 * vbps,
+			upcase: upcase::UpcaseTable::ascii(),
+			bitmap_clusters: Vec::new(),
+			alloc_lock: Mutex::new(2),
+			dirs: Default::default(),
+			nodes: Default::default(),
+			vh: vol,
+			};
+		if !fs.is_valid_cluster(root_cluster) {
+			log_error!("Invalid exFAT bootsector: bad root cluster {:#x}", root_cluster);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+
+		// Locate the allocation bitmap and up-case table (critical entries in the root directory)
+		let root_chain = Chain { first: root_cluster, no_fat_chain: false };
+		let root_len = fs.chain_length(root_chain)?;
+		let mut bitmap = None;
+		let mut upcase = None;
+		'outer: for i in 0 .. root_len
+		{
+			let cluster = fs.get_chain_cluster(root_chain, i as u64)?;
+			let ents = ::kernel::futures::block_on(fs.with_cluster(cluster, |data| {
+				data.chunks(on_disk::DIRENT_SIZE)
+					.map(|e| (e[0], on_disk::SystemEnt::read(e)))
+					.take_while(|e| e.0 != on_disk::ENT_END)
+					.filter(|e| e.0 == on_disk::ENT_BITMAP || e.0 == on_disk::ENT_UPCASE)
+					.collect::<Vec<_>>()
+				}))?;
+			for (ty, ent) in ents
+			{
+				match ty
+				{
+				// With two FATs, there's two bitmaps (bit 0 of the flags indicates which)
+				on_disk::ENT_BITMAP if (ent.flags & 1) as u64 == active_fat => bitmap = Some(ent),
+				on_disk::ENT_UPCASE => upcase = Some(ent),
+				_ => {},
+				}
+			}
+			if bitmap.is_some() && upcase.is_some() {
+				break 'outer;
+			}
+		}
+
+		let Some(bitmap) = bitmap else {
+			log_error!("exFAT root directory has no allocation bitmap");
+			return Err(::vfs::Error::InconsistentFilesystem);
+			};
+		if bitmap.data_length * 8 < fs.cluster_count as u64 {
+			log_error!("exFAT allocation bitmap too small ({} bytes for {} clusters)", bitmap.data_length, fs.cluster_count);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let bitmap_chain = Chain { first: bitmap.first_cluster, no_fat_chain: false };
+		fs.bitmap_clusters = (0 .. fs.clusters_for(bitmap.data_length))
+			.map(|i| fs.get_chain_cluster(bitmap_chain, i))
+			.collect::<Result<_,_>>()?;
+
+		match upcase
+		{
+		Some(ent) if ent.data_length <= 0x1_0000 * 2 => {
+			let mut data = vec![0; fs.clusters_for(ent.data_length) as usize * fs.cluster_size];
+			let upcase_chain = Chain { first: ent.first_cluster, no_fat_chain: false };
+			for (i,dst) in data.chunks_mut(fs.cluster_size).enumerate() {
+				let cluster = fs.get_chain_cluster(upcase_chain, i as u64)?;
+				::kernel::futures::block_on(fs.read_clusters(cluster, dst))?;
+			}
+			match upcase::UpcaseTable::load(&data[..ent.data_length as usize], ent.checksum)
+			{
+			Some(t) => fs.upcase = t,
+			None => {
+				log_warning!("Bad up-case table, using ASCII only and mounting read-only");
+				fs.is_readonly = true;
+				},
+			}
+			},
+		_ => {
+			log_warning!("No (or oversized) up-case table, using ASCII only and mounting read-only");
+			fs.is_readonly = true;
+			},
+		}
+
+		fs.dirs.get_mut().insert(root_cluster, Default::default());
+		fs.nodes.get_mut().insert(0, Arc::new(NodeInfo {
+			is_dir: true,
+			stream: RwLock::new(StreamInfo {
+				chain: root_chain,
+				data_length: root_len as u64 * fs.cluster_size as u64,
+				valid_data_length: root_len as u64 * fs.cluster_size as u64,
+				}),
+			unlinked: AtomicBool::new(false),
+			}));
+
+		Ok(Box::new(Filesystem {
+			// SAFE: Saving to a Box, so won't move
+			inner: unsafe { ArefInner::new(fs) },
+			}))
+	}
+}
+
+impl mount::Filesystem for Filesystem
+{
+	fn root_inode(&self) -> node::InodeId {
+		0
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		if id == 0 {
+			let info = self.nodes.lock().get(&0).cloned()?;
+			return Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), 0, info)));
+		}
+		let (dir_cluster, index) = split_inode(id);
+		let Some(dir_info) = self.dirs.read().get(&dir_cluster).cloned() else {
+			log_error!("get_node_by_inode({:#x}): Directory {:#x} hasn't been seen", id, dir_cluster);
+			return None;
+			};
+		let info = {
+			// Read the entry with the node list locked, so it can't be removed until the node is registered
+			let mut lh_nodes = self.nodes.lock();
+			if let Some(v) = lh_nodes.get(&id) {
+				v.clone()
+			}
+			else {
+				let chain = Chain { first: dir_cluster, no_fat_chain: dir_info.no_fat_chain.load(Ordering::Relaxed) };
+				let set = match dir::read_entry_set(self, chain, index)
+					{
+					Ok(Some(v)) => v,
+					Ok(None) => {
+						log_notice!("get_node_by_inode({:#x}): No valid entry", id);
+						return None;
+						},
+					Err(e) => {
+						log_error!("get_node_by_inode({:#x}): Error reading entry - {:?}", id, e);
+						return None;
+						},
+					};
+				let v = match NodeInfo::from_set(self, &set)
+					{
+					Ok(v) => Arc::new(v),
+					Err(e) => {
+						log_error!("get_node_by_inode({:#x}): Bad entry - {:?}", id, e);
+						return None;
+						},
+					};
+				lh_nodes.insert(id, v.clone());
+				v
+			}
+			};
+		Some(if info.is_dir {
+			self.register_dir(info.stream.read().chain);
+			node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), id, info))
+		}
+		else {
+			node::Node::File(file::FileNode::new_boxed(self.inner.borrow(), id, info))
+		})
+	}
+}
+
+/// Construct an inode number from the location of a primary directory entry
+fn make_inode(dir_cluster: u32, index: u32) -> node::InodeId {
+	assert!(index < 1 << INODE_INDEX_BITS);
+	(dir_cluster as u64) << INODE_INDEX_BITS | index as u64
+}
+/// Split an inode number into the directory's first cluster and the entry index
+fn split_inode(id: node::InodeId) -> (u32, u32) {
+	((id >> INODE_INDEX_BITS) as u32, (id & ((1 << INODE_INDEX_BITS) - 1)) as u32)
+}
+
+impl NodeInfo
+{
+	fn from_set(fs: &FilesystemInner, set: &dir::EntrySet) -> ::vfs::Result<NodeInfo> {
+		Ok(NodeInfo {
+			is_dir: set.file.is_dir(),
+			stream: RwLock::new(StreamInfo::from_ent(fs, &set.stream)?),
+			unlinked: AtomicBool::new(false),
+		})
+	}
+}
+impl StreamInfo
+{
+	/// Parse a stream extension entry, checking that the allocation is within the cluster heap
+	fn from_ent(fs: &FilesystemInner, ent: &on_disk::StreamEnt) -> ::vfs::Result<StreamInfo> {
+		let rv = StreamInfo {
+			chain: Chain {
+				first: if ent.flags & on_disk::STREAM_ALLOC_POSSIBLE != 0 { ent.first_cluster } else { 0 },
+				no_fat_chain: ent.flags & on_disk::STREAM_NO_FAT_CHAIN != 0,
+				},
+			data_length: ent.data_length,
+			// Clamp to the allocation, just in case
+			valid_data_length: u64::min(ent.valid_data_length, ent.data_length),
+			};
+		// A contiguous chain covers the entire data length, a FAT chain is checked as it's followed
+		let n_clusters = if rv.chain.no_fat_chain { fs.clusters_for(rv.data_length) } else { 1 };
+		if rv.chain.first != 0 && !fs.is_valid_run(rv.chain.first, n_clusters) {
+			log_error!("Stream allocation out of range ({:#x}+{}, {} clusters)", rv.chain.first, n_clusters, fs.cluster_count);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		Ok(rv)
+	}
+	/// Write the allocation into a stream extension entry
+	fn to_ent(&self, ent: &mut on_disk::StreamEnt) {
+		ent.flags = on_disk::STREAM_ALLOC_POSSIBLE | if self.chain.first != 0 && self.chain.no_fat_chain { on_disk::STREAM_NO_FAT_CHAIN } else { 0 };
+		ent.first_cluster = self.chain.first;
+		ent.data_length = self.data_length;
+		ent.valid_data_length = self.valid_data_length;
+	}
+}
+
+impl FilesystemInner
+{
+	/// Release a node handle's reference to the shared state
+	fn release_node_info(&self, id: node::InodeId, info: &Arc<NodeInfo>) {
+		// If the count is 2 (`info` and the `nodes` map), then remove it from the map
+		let mut lh = self.nodes.lock();
+		if id != 0 && Arc::strong_count(info) == 2 {
+			lh.remove(&id);
+			drop(lh);
+			// If the node was deleted while open, then release the data now
+			if info.unlinked.load(Ordering::Relaxed) {
+				let mut stream = info.stream.write();
+				log_debug!("release_node_info: Releasing unlinked node {:#x} ({:?})", id, stream.chain);
+				if info.is_dir {
+					self.dirs.write().remove(&stream.chain.first);
+				}
+				let n_clusters = self.clusters_for(stream.data_length);
+				if let Err(e) = self.truncate_chain(&mut stream.chain, n_clusters, 0) {
+					log_error!("release_node_info: Error releasing clusters of {:#x} - {:?}", id, e);
+				}
+			}
+		}
+	}
+	/// Returns the indexes of unlinked (but still open) entries in a directory, these can't be re-used until closed
+	fn unlinked_entries(&self, dir_cluster: u32) -> Vec<u32> {
+		self.nodes.lock().iter()
+			.filter(|(&id,info)| id != 0 && split_inode(id).0 == dir_cluster && info.unlinked.load(Ordering::Relaxed))
+			.map(|(&id,_)| split_inode(id).1)
+			.collect()
+	}
+
+	/// Record the chain type of a directory (so its entries can be located by inode number)
+	fn register_dir(&self, chain: Chain) -> Arc<DirInfo> {
+		let mut lh = self.dirs.write();
+		let rv = lh.entry(chain.first).or_default();
+		rv.no_fat_chain.store(chain.no_fat_chain, Ordering::Relaxed);
+		rv.clone()
+	}
+	fn get_dir_info(&self, first_cluster: u32) -> Arc<DirInfo> {
+		self.dirs.write().entry(first_cluster).or_default().clone()
+	}
+
+	/// Number of clusters needed to hold `bytes`
+	fn clusters_for(&self, bytes: u64) -> u64 {
+		(bytes + self.cluster_size as u64 - 1) / self.cluster_size as u64
+	}
+	fn is_valid_cluster(&self, cluster: u32) -> bool {
+		2 <= cluster && cluster - 2 < self.cluster_count
+	}
+	/// Check that `count` clusters starting at `first` are all within the cluster heap
+	fn is_valid_run(&self, first: u32, count: u64) -> bool {
+		self.is_valid_cluster(first) && (first - 2) as u64 + count <= self.cluster_count as u64
+	}
+	fn get_sector_for_cluster(&self, cluster: u32) -> u64 {
+		assert!(self.is_valid_cluster(cluster), "Cluster {:#x} out of range", cluster);
+		self.heap_start + (cluster as u64 - 2) * self.blocks_per_cluster as u64
+	}
+	/// Sector and offset of a byte within a cluster
+	fn get_cluster_addr(&self, cluster: u32, ofs: usize) -> (u64, usize) {
+		let bs = self.vh.block_size();
+		(self.get_sector_for_cluster(cluster) + (ofs / bs) as u64, ofs % bs)
+	}
+
+	/// Read clusters from disk (uncached, for file data)
+	async fn read_clusters(&self, cluster: u32, dst: &mut [u8]) -> Result<(), storage::IoError> {
+		log_trace!("read_clusters({:#x}, {})", cluster, dst.len() / self.cluster_size);
+		assert_eq!(dst.len() % self.cluster_size, 0);
+		self.vh.read_blocks(self.get_sector_for_cluster(cluster), dst).await
+	}
+	/// Write clusters to disk (uncached, for file data)
+	async fn write_clusters(&self, cluster: u32, src: &[u8]) -> Result<(), storage::IoError> {
+		log_trace!("write_clusters({:#x}, {})", cluster, src.len() / self.cluster_size);
+		assert_eq!(src.len() % self.cluster_size, 0);
+		self.vh.write_blocks(self.get_sector_for_cluster(cluster), src).await
+	}
+	/// Cached cluster access
+	async fn with_cluster<T>(&self, cluster: u32, callback: impl FnOnce(&[u8])->T) -> Result<T, storage::IoError> {
+		let blocks = self.vh.get_blocks(self.get_sector_for_cluster(cluster), self.blocks_per_cluster).await?;
+		Ok( blocks.with_data(callback) )
+	}
+	async fn edit_cluster(&self, cluster: u32, callback: impl FnOnce(&mut [u8])) -> Result<(), storage::IoError> {
+		self.vh.edit(self.get_sector_for_cluster(cluster), self.blocks_per_cluster, callback).await
+	}
+}
@@ -0,0 +1,261 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/on_disk.rs
+//! On-Disk structures and flags
+#[allow(unused_imports)]
+use kernel::prelude::*;
+
+pub const FS_NAME: &[u8; 8] = b"EXFAT   ";
+
+/// Directory entry size (all entries are the same size)
+pub const DIRENT_SIZE: usize = 32;
+
+// Directory entry types (bit 7 is the "in use" flag)
+pub const ENT_END: u8 = 0x00;
+pub const ENT_INUSE: u8 = 0x80;
+pub const ENT_BITMAP: u8 = 0x81;
+pub const ENT_UPCASE: u8 = 0x82;
+pub const ENT_VOLUME_LABEL: u8 = 0x83;
+pub const ENT_FILE: u8 = 0x85;
+pub const ENT_STREAM: u8 = 0xC0;
+pub const ENT_NAME: u8 = 0xC1;
+/// Bit 6 of the type: Secondary entry (belongs to a primary's entry set)
+pub const ENT_SECONDARY: u8 = 0x40;
+
+pub const ATTR_READONLY : u16 = 0x01;
+pub const ATTR_HIDDEN   : u16 = 0x02;
+pub const ATTR_SYSTEM   : u16 = 0x04;
+pub const ATTR_DIRECTORY: u16 = 0x10;
+pub const ATTR_ARCHIVE  : u16 = 0x20;
+
+/// Stream extension flag: The allocation is valid (otherwise first cluster and length are zero)
+pub const STREAM_ALLOC_POSSIBLE: u8 = 0x01;
+/// Stream extension flag: The allocation is contiguous, and the FAT is not used
+pub const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+/// Characters per name entry
+pub const NAME_CHARS_PER_ENT: usize = 15;
+/// Maximum name length (UTF-16 code units)
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Timestamp used for new entries (1980-01-01 00:00:00, the epoch)
+pub const DEFAULT_TIMESTAMP: u32 = ((1 << 5) | 1) << 16;
+
+pub const VOLFLAG_ACTIVE_FAT: u16 = 0x1;
+pub const VOLFLAG_DIRTY: u16 = 0x2;
+
+fn rd_u16(s: &[u8], ofs: usize) -> u16 {
+	u16::from_le_bytes([s[ofs], s[ofs+1]])
+}
+fn rd_u32(s: &[u8], ofs: usize) -> u32 {
+	u32::from_le_bytes([s[ofs], s[ofs+1], s[ofs+2], s[ofs+3]])
+}
+fn rd_u64(s: &[u8], ofs: usize) -> u64 {
+	rd_u32(s, ofs) as u64 | (rd_u32(s, ofs+4) as u64) << 32
+}
+fn wr_u16(s: &mut [u8], ofs: usize, v: u16) {
+	s[ofs..][..2].copy_from_slice(&v.to_le_bytes());
+}
+fn wr_u32(s: &mut [u8], ofs: usize, v: u32) {
+	s[ofs..][..4].copy_from_slice(&v.to_le_bytes());
+}
+fn wr_u64(s: &mut [u8], ofs: usize, v: u64) {
+	s[ofs..][..8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Main boot sector
+#[derive(Debug)]
+pub struct BootSect
+{
+	pub fs_name: [u8; 8],
+	pub partition_offset: u64,
+	pub volume_length: u64,
+	/// Sector offset of the first FAT
+	pub fat_offset: u32,
+	/// Length of each FAT (in sectors)
+	pub fat_length: u32,
+	pub cluster_heap_offset: u32,
+	pub cluster_count: u32,
+	pub root_dir_cluster: u32,
+	pub serial_number: u32,
+	pub revision: u16,
+	pub volume_flags: u16,
+	pub bytes_per_sector_shift: u8,
+	pub sectors_per_cluster_shift: u8,
+	pub number_of_fats: u8,
+	pub boot_signature: u16,
+}
+impl BootSect
+{
+	pub fn read(src: &[u8]) -> BootSect {
+		assert!(src.len() >= 512);
+		BootSect {
+			fs_name: { let mut v = [0; 8]; v.copy_from_slice(&src[3..][..8]); v },
+			partition_offset: rd_u64(src, 64),
+			volume_length: rd_u64(src, 72),
+			fat_offset: rd_u32(src, 80),
+			fat_length: rd_u32(src, 84),
+			cluster_heap_offset: rd_u32(src, 88),
+			cluster_count: rd_u32(src, 92),
+			root_dir_cluster: rd_u32(src, 96),
+			serial_number: rd_u32(src, 100),
+			revision: rd_u16(src, 104),
+			volume_flags: rd_u16(src, 106),
+			bytes_per_sector_shift: src[108],
+			sectors_per_cluster_shift: src[109],
+			number_of_fats: src[110],
+			boot_signature: rd_u16(src, 510),
+		}
+	}
+	/// Quick check that this is an exFAT boot sector
+	pub fn is_exfat(&self) -> bool {
+		&self.fs_name == FS_NAME && self.boot_signature == 0xAA55
+	}
+}
+
+/// File directory entry (primary entry for files and directories)
+#[derive(Debug,Clone)]
+pub struct FileEnt
+{
+	pub secondary_count: u8,
+	pub set_checksum: u16,
+	pub attributes: u16,
+	pub create_time: u32,
+	pub modified_time: u32,
+	pub accessed_time: u32,
+}
+impl FileEnt
+{
+	pub fn read(s: &[u8]) -> FileEnt {
+		FileEnt {
+			secondary_count: s[1],
+			set_checksum: rd_u16(s, 2),
+			attributes: rd_u16(s, 4),
+			create_time: rd_u32(s, 8),
+			modified_time: rd_u32(s, 12),
+			accessed_time: rd_u32(s, 16),
+		}
+	}
+	pub fn write(&self, s: &mut [u8]) {
+		for b in s[..DIRENT_SIZE].iter_mut() { *b = 0; }
+		s[0] = ENT_FILE;
+		s[1] = self.secondary_count;
+		wr_u16(s, 2, self.set_checksum);
+		wr_u16(s, 4, self.attributes);
+		wr_u32(s, 8, self.create_time);
+		wr_u32(s, 12, self.modified_time);
+		wr_u32(s, 16, self.accessed_time);
+	}
+	pub fn is_dir(&self) -> bool {
+		self.attributes & ATTR_DIRECTORY != 0
+	}
+}
+
+/// Stream extension directory entry (the data allocation for a file)
+#[derive(Debug,Clone)]
+pub struct StreamEnt
+{
+	pub flags: u8,
+	pub name_length: u8,
+	pub name_hash: u16,
+	pub valid_data_length: u64,
+	pub first_cluster: u32,
+	pub data_length: u64,
+}
+impl StreamEnt
+{
+	pub fn read(s: &[u8]) -> StreamEnt {
+		StreamEnt {
+			flags: s[1],
+			name_length: s[3],
+			name_hash: rd_u16(s, 4),
+			valid_data_length: rd_u64(s, 8),
+			first_cluster: rd_u32(s, 20),
+			data_length: rd_u64(s, 24),
+		}
+	}
+	pub fn write(&self, s: &mut [u8]) {
+		for b in s[..DIRENT_SIZE].iter_mut() { *b = 0; }
+		s[0] = ENT_STREAM;
+		s[1] = self.flags;
+		s[3] = self.name_length;
+		wr_u16(s, 4, self.name_hash);
+		wr_u64(s, 8, self.valid_data_length);
+		wr_u32(s, 20, self.first_cluster);
+		wr_u64(s, 24, self.data_length);
+	}
+}
+
+/// File name directory entry
+pub struct NameEnt
+{
+	pub chars: [u16; NAME_CHARS_PER_ENT],
+}
+impl NameEnt
+{
+	pub fn read(s: &[u8]) -> NameEnt {
+		let mut chars = [0; NAME_CHARS_PER_ENT];
+		for (i,c) in chars.iter_mut().enumerate() {
+			*c = rd_u16(s, 2 + i*2);
+		}
+		NameEnt { chars }
+	}
+	pub fn write(&self, s: &mut [u8]) {
+		for b in s[..DIRENT_SIZE].iter_mut() { *b = 0; }
+		s[0] = ENT_NAME;
+		for (i,c) in self.chars.iter().enumerate() {
+			wr_u16(s, 2 + i*2, *c);
+		}
+	}
+}
+
+/// Allocation bitmap / Up-case table entries (both just point at a cluster range)
+pub struct SystemEnt
+{
+	pub flags: u8,
+	/// Up-case table checksum (unused for the bitmap)
+	pub checksum: u32,
+	pub first_cluster: u32,
+	pub data_length: u64,
+}
+impl SystemEnt
+{
+	pub fn read(s: &[u8]) -> SystemEnt {
+		SystemEnt {
+			flags: s[1],
+			checksum: rd_u32(s, 4),
+			first_cluster: rd_u32(s, 20),
+			data_length: rd_u64(s, 24),
+		}
+	}
+}
+
+/// Checksum over an entry set (skipping the checksum field in the first entry)
+pub fn entry_set_checksum(ents: &[u8]) -> u16 {
+	let mut rv = 0u16;
+	for (i,&b) in ents.iter().enumerate() {
+		if i == 2 || i == 3 {
+			continue ;
+		}
+		rv = rv.rotate_right(1).wrapping_add(b as u16);
+	}
+	rv
+}
+/// Update the checksum stored in an entry set
+pub fn update_set_checksum(ents: &mut [u8]) {
+	let c = entry_set_checksum(ents);
+	wr_u16(ents, 2, c);
+}
+
+/// Boot region checksum (over the first 11 sectors, ignoring the volatile fields)
+pub fn boot_checksum(sectors: &[u8]) -> u32 {
+	let mut rv = 0u32;
+	for (i,&b) in sectors.iter().enumerate() {
+		if i == 106 || i == 107 || i == 112 {
+			continue ;
+		}
+		rv = rv.rotate_right(1).wrapping_add(b as u32);
+	}
+	rv
+}
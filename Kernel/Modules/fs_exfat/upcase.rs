@@ -0,0 +1,77 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/upcase.rs
+//! Up-case table (used for case-insensitive name comparisons and name hashes)
+use kernel::prelude::*;
+
+/// Expanded up-case table (index is the UTF-16 code unit)
+pub struct UpcaseTable
+{
+	table: Vec<u16>,
+}
+impl UpcaseTable
+{
+	/// Load a (possibly compressed) table from its on-disk data
+	pub fn load(data: &[u8], exp_checksum: u32) -> Option<UpcaseTable>
+	{
+		let checksum = data.iter().fold(0u32, |rv, &b| rv.rotate_right(1).wrapping_add(b as u32));
+		if checksum != exp_checksum {
+			log_warning!("Up-case table checksum mismatch: {:#x} != exp {:#x}", checksum, exp_checksum);
+			return None;
+		}
+		let mut table = Vec::with_capacity(0x1_0000);
+		let mut it = data.chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]]));
+		while let Some(v) = it.next()
+		{
+			if table.len() >= 0x1_0000 {
+				break;
+			}
+			if v == 0xFFFF {
+				// Compressed: the next value is a count of identity-mapped characters
+				let count = it.next().unwrap_or(0) as usize;
+				for _ in 0 .. count {
+					if table.len() >= 0x1_0000 {
+						break;
+					}
+					let c = table.len() as u16;
+					table.push(c);
+				}
+			}
+			else {
+				table.push(v);
+			}
+		}
+		log_debug!("Up-case table: {} bytes on disk, {} entries", data.len(), table.len());
+		Some(UpcaseTable { table })
+	}
+	/// Fallback table (ASCII only), used if the on-disk table is unusable
+	pub fn ascii() -> UpcaseTable
+	{
+		UpcaseTable {
+			table: (0 .. 0x80u16).map(|c| if b'a' as u16 <= c && c <= b'z' as u16 { c - 0x20 } else { c }).collect(),
+		}
+	}
+
+	pub fn upcase(&self, c: u16) -> u16 {
+		match self.table.get(c as usize)
+		{
+		Some(&v) => v,
+		None => c,
+		}
+	}
+	/// Compare two names (case-insensitive)
+	pub fn names_equal(&self, a: &[u16], b: &[u16]) -> bool {
+		a.len() == b.len() && a.iter().zip(b.iter()).all(|(&a,&b)| self.upcase(a) == self.upcase(b))
+	}
+	/// Calculate the name hash stored in the stream extension entry
+	pub fn name_hash(&self, name: &[u16]) -> u16 {
+		let mut rv = 0u16;
+		for &c in name {
+			let c = self.upcase(c);
+			rv = rv.rotate_right(1).wrapping_add(c as u8 as u16);
+			rv = rv.rotate_right(1).wrapping_add((c >> 8) as u16);
+		}
+		rv
+	}
+}
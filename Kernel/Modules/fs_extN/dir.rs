@@ -21,189 +21,151 @@ impl Dir
 	}
 
 
-	/// Returns (block_index, offset)
-	fn find_name(&self, name: &ByteStr) -> ::vfs::node::Result<(usize, usize, ::vfs::node::InodeId)>
+	pub fn inode(&self) -> &::inodes::Inode {
+		&self.inode
+	}
+
+	/// Locate a name in the directory
+	fn find_name(&self, name: &ByteStr) -> ::vfs::node::Result<EntPos>
 	{
 		log_debug!("find_name({:?})", name);
 		let inode = self.inode.lock_read();
-		// TODO: Use the B+ trees if present
 
-		// Linear search
-		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		// Use the hashed index if present
+		if inode.i_flags() & ::ondisk::EXT4_INDEX_FL != 0
 		{
-			log_trace!("find_name: Block {} (vol_blk={})", blk_index, vol_blk);
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+			if let Some(leaves) = try!(::htree::find_leaves(&inode, name.as_bytes()))
 			{
-				if ent.d_rec_len == 0 {
-					log_error!("find_name: Found d_rec_len=0");
-					return Err( ::vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 {
-					log_debug!("find_name: Found d_inode=0");
-					return Err( :: vfs::Error::NotFound );
-				}
-				else if &ent.d_name == name.as_bytes()
+				for blk_index in leaves
 				{
-					return Ok( (blk_index, offset, ent.d_inode as ::vfs::node::InodeId) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
+					log_trace!("find_name: Index leaf {}", blk_index);
+					let vol_blk = try!(inode.get_block_addr(blk_index));
+					if let Some(rv) = try!(self.find_in_block(vol_blk, name)) {
+						return Ok(rv);
+					}
 				}
+				return Err( ::vfs::Error::NotFound );
+			}
+		}
+
+		// Linear search
+		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		{
+			log_trace!("find_name: Block {} (vol_blk={})", blk_index, vol_blk);
+			if let Some(rv) = try!(self.find_in_block(vol_blk, name)) {
+				return Ok(rv);
 			}
 		}
 		Err( ::vfs::Error::NotFound )
 	}
-
-	/// Defragment a directory block, and return the offset and length of the final free entry
-	fn defragment_block(fs: &crate::instance::InstanceInner, block: u32) -> ::vfs::node::Result<(usize, u16)> {
-		fs.edit_block(block, |blk_data: &mut [u8]| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-				};
-			let mut write_u32s = 0;
-			let mut read_u32s = 0;
-			while read_u32s < blk_data.len() {
-				if read_u32s + 1 == blk_data.len() {
-					return Err(::vfs::Error::InconsistentFilesystem);
-				}
-				let rec_len = (u32::from_le(blk_data[read_u32s+1]) & 0xFFFF) as u16;
-				let rec_len_u32s = ((rec_len + 4-1) / 4) as usize;
-				if read_u32s + rec_len_u32s > blk_data.len() {
-					return Err(::vfs::Error::InconsistentFilesystem);
-				}
-				if blk_data[read_u32s] == 0 {
-					// Entry is free, don't update the write position
-				}
-				else {
-					if read_u32s == write_u32s {
-						// Positions are equal, no change
-					}
-					else {
-						// Copy entry into the new location
-						blk_data.copy_within(read_u32s .. read_u32s + rec_len_u32s, write_u32s);
-					}
-					write_u32s += rec_len_u32s;
-				}
-				read_u32s += rec_len_u32s;
+	fn find_in_block(&self, vol_blk: u32, name: &ByteStr) -> ::vfs::node::Result<Option<EntPos>>
+	{
+		let blk_data = try!(self.inode.fs.get_block(vol_blk));
+		
+		let mut offset = 0;
+		let mut prev_ofs = None;
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.d_rec_len == 0 {
+				log_error!("find_name: Found d_rec_len=0");
+				return Err( ::vfs::Error::InconsistentFilesystem );
 			}
-			let tail_len = read_u32s - write_u32s;
-			if tail_len > 0 {
-				blk_data[write_u32s + 0] = 0;
-				blk_data[write_u32s + 1] = (tail_len as u32 * 4).to_le();
+			else if ent.d_inode != 0 && &ent.d_name == name.as_bytes()
+			{
+				return Ok(Some(EntPos { vol_blk, ofs: offset, prev_ofs, inode: ent.d_inode }));
 			}
-			Ok( (write_u32s * 4, (tail_len * 4) as u16) )
-			})
+			prev_ofs = Some(offset);
+			offset += ent.u32_len() * 4;
+		}
+		Ok(None)
 	}
 
-	/// Locate a entry in the directory, also checking for duplicates
-	fn find_free(&self, name: &ByteStr) -> ::vfs::node::Result<(u32, usize)>
+	/// Add an entry to the directory, checking for duplicates
+	///
+	/// Uses the first entry with enough spare space, or adds a new block to the directory.
+	fn add_dir_ent(&self, name: &ByteStr, inode: u32, d_type: u8) -> Result<(), ::vfs::Error>
 	{
-		let inode = self.inode.lock_read();
-		// Linear search
-		// TODO: ext3 and later use B+ trees (so can exit the search early, as the lookup can be faster)
-		// - These are a ReadOnly feature, so we shouldn't be here if those are present
+		if name.len() == 0 || name.len() > 255 {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		let _lh_write = self.inode.lock_dir();
+		let fs = &self.inode.fs;
+		let needed = dirent_size(name.len());
 
-		let mut rv: Option< ((u32, usize), u16) > = None;
-		let mut total_free = 0;
-		for (blk_index, vol_blk) in inode.blocks().enumerate()
+		// 1. Find a suitable slot (and check for an existing entry with this name)
+		let mut slot = None;
 		{
-			let mut block_free = 0;
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+			let inode = self.inode.lock_read();
+			for vol_blk in inode.blocks()
 			{
-				if ent.d_rec_len == 0 {
-					return Err( ::vfs::Error::InconsistentFilesystem );
-				}
-				
-				if ent.d_inode == 0 {
-					if &ent.d_name == name.as_bytes() {
+				let blk_data = try!(fs.get_block(vol_blk));
+				let mut offset = 0;
+				for ent in DirEnts(&blk_data)
+				{
+					if ent.d_rec_len == 0 {
+						return Err( ::vfs::Error::InconsistentFilesystem );
+					}
+					if ent.d_inode != 0 && &ent.d_name == name.as_bytes() {
 						return Err( ::vfs::Error::AlreadyExists );
 					}
-				}
-				else {
-					if (ent.d_rec_len - 8) >= name.len() as u16 {
-						// Free entry with sufficient space!
-						rv = Some(match rv
-							{
-							Some( (v, len) ) if len < ent.d_rec_len => (v, len),
-							_ => ( (blk_index as u32, offset), ent.d_rec_len ),
-							});
+					let used = if ent.d_inode == 0 { 0 } else { dirent_size(ent.d_name.len()) };
+					if slot.is_none() && ent.d_rec_len as usize >= used + needed {
+						slot = Some( (vol_blk, offset) );
 					}
-					block_free += ent.u32_len() * 4;
-				}
-				offset += ent.u32_len() * 4;
-			}
-
-			// If there wasn't a good space found yet, but this block has enough space on its own - then defragment and return
-			if rv.is_none() && block_free - 8 >= name.len() {
-				// Defragment the block, there's enough space for this name but not in a single contiguous chunk.
-				drop(blk_data);
-				let (offset,rec_len) = Self::defragment_block(&self.inode.fs, vol_blk)?;
-				if rec_len - 8 >= name.len() as u16 {
-					rv = Some(( (blk_index as u32, offset), rec_len));
-				}
-				else {
-					// BUG? It was calculated that there was enough space, but defragmentation didn't provide enough
+					offset += ent.u32_len() * 4;
 				}
 			}
-
-			total_free += block_free - 8;	// Calculate total free space, accounting for the required dirent header
-		}
-		if let Some( (rv, _) ) = rv {
-			return Ok(rv);
 		}
 
-		if total_free >= name.len() {
-			// There is _maybe_ enough space for this name, but not in a contiguous block
-			// - "maybe" because entries cannot span block boundaries, so there might be a few small free slots at the
-			//   end of blocks.
-			// Defragment blocks in overlapping pairs until there's enough free space
+		// The index isn't maintained, so stop using it (the index blocks are valid empty directory blocks)
+		if self.inode.lock_read().i_flags() & ::ondisk::EXT4_INDEX_FL != 0 {
+			log_debug!("add_dir_ent: Clearing directory index");
+			self.inode.lock_write().clear_i_flags(::ondisk::EXT4_INDEX_FL);
 		}
 
-		todo!("Dir::find_free - expand directory");
-	}
-
-	/// Add an entry to the directory, checking for duplicates
-	fn add_dir_ent(&self, name: &ByteStr, inode: u32/*, d_type: u8*/) -> Result<(), ::vfs::Error>
-	{
-		if !(name.len() <= 255) {
-			return Err(::vfs::Error::InvalidParameter);
-		}
-		let _lh_write = self.inode.lock_dir();
-
-		// 1. Find a suitable slot
-		let (blk, ofs) = try!(self.find_free(name));
-		log_debug!("add_dir_ent: Slot found: blk {blk} ofs {ofs}");
-		// 2. Fill said slot
-		let vol_blk = try!( self.inode.lock_read().blocks_from(blk as u32).next_or_err() );
-		self.inode.fs.edit_block(vol_blk, |blk_data| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-				};
-			match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+		// 2. If there was no space, add a new block
+		let (vol_blk, ofs) = match slot
 			{
-			None => return Err(::vfs::Error::InconsistentFilesystem),
-			Some(ent) => {
-				ent.d_name_len = name.len() as u8;
-				ent.d_inode = inode as u32;
-				//ent.d_type = d_type;
+			Some(v) => v,
+			None => {
+				let mut inode = self.inode.lock_write();
+				let blk_idx = inode.max_blocks();
+				inode.ensure_blocks_allocated(blk_idx, 1)?;
+				inode.set_i_size( (blk_idx as u64 + 1) * fs.fs_block_size as u64 )?;
+				let vol_blk = inode.get_block_addr(blk_idx)?;
+				log_debug!("add_dir_ent: Expanded directory with block {} (B{})", blk_idx, vol_blk);
+				// The new block is a single empty entry
+				fs.edit_block(vol_blk, |blk_data| {
+					blk_data.fill(0);
+					write_dirent_header(::as_u32_slice(blk_data), 0, 0, fs.fs_block_size as u16, 0, 0);
+					Ok( () )
+					})?;
+				(vol_blk, 0)
 				},
-			}
+			};
+		log_debug!("add_dir_ent: Slot found: B{} ofs {}", vol_blk, ofs);
+
+		// 3. Fill said slot (splitting the existing entry if it's in use)
+		fs.edit_block(vol_blk, |blk_data| {
+			let blk_data = ::as_u32_slice(blk_data);
+			let (cur_inode, rec_len, name_len) = match ::ondisk::DirEnt::new(&blk_data[ofs/4 ..])
+				{
+				Some(ent) => (ent.d_inode, ent.d_rec_len, ent.d_name.len()),
+				None => return Err(::vfs::Error::InconsistentFilesystem),
+				};
+			let (ofs, rec_len) = if cur_inode == 0 {
+					(ofs, rec_len)
+				}
+				else {
+					let used = dirent_size(name_len);
+					let ent = ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap();
+					ent.d_rec_len = used as u16;
+					(ofs + used, rec_len - used as u16)
+				};
+			write_dirent_header(blk_data, ofs, inode, rec_len, name.len() as u8, d_type);
 			// Re-get the entry using the new name length, and store the new name in it
 			let ent = ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap();
 			ent.d_name.clone_from_slice( name.as_ref() );
-			// If the entry has plenty of free space (record length is more than 16 bytes longer than needed), then split it
-			// - A dirent has 8 bytes of overhead (u32 inode, u16 len, u8 namelen, u8 type)
-			// TODO: ^
 			Ok( () )
 			})
 	}
@@ -217,6 +179,9 @@ impl ::vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> ::vfs::node::Result<::vfs::node::Metadata> {
+		Ok( self.inode.lock_read().metadata() )
+	}
 }
 impl ::vfs::node::Dir for Dir
 {
@@ -226,8 +191,8 @@ impl ::vfs::node::Dir for Dir
 			Err(::vfs::Error::NotFound)
 		}
 		else {
-			let (_, _, rv) = try!(self.find_name(name));
-			Ok( rv )
+			let pos = try!(self.find_name(name));
+			Ok( pos.inode as ::vfs::node::InodeId )
 		}
 	}
 	fn read(&self, start_ofs: usize, callback: &mut ::vfs::node::ReadDirCallback) -> ::vfs::Result<usize>
@@ -303,24 +268,41 @@ impl ::vfs::node::Dir for Dir
 	fn create(&self, name: &ByteStr, nodetype: ::vfs::node::NodeType) -> ::vfs::node::Result<::vfs::node::InodeId> {
 		if self.inode.fs.is_readonly()
 		{
-			Err( ::vfs::Error::ReadOnlyFilesystem )
+			return Err( ::vfs::Error::ReadOnlyFilesystem );
 		}
-		else
-		{
-			let ino_id = try!( self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype) );
-
-			match self.add_dir_ent(name, ino_id)
+		let fs = &self.inode.fs;
+		let parent_id = self.inode.get_id() as u32;
+		let (i_mode, i_links_count, d_type) = match nodetype
 			{
-			Ok(()) => {
-				log_debug!("create: {:?} = Inode{}", name, ino_id);
-				Ok(ino_id as ::vfs::node::InodeId)
-				},
-			Err(e) => {
-				// Call with_inode to force the inode to be deallocated
-				let _ = self.inode.fs.with_inode(ino_id, |_| Ok(()));
-				Err(e)
-				},
+			::vfs::node::NodeType::File => (::ondisk::S_IFREG | 0o644, 1, ::ondisk::FT_REG_FILE),
+			// Directories are referenced by the parent's entry and their own `.`
+			::vfs::node::NodeType::Dir => (::ondisk::S_IFDIR | 0o755, 2, ::ondisk::FT_DIR),
+			::vfs::node::NodeType::Symlink(_) => return Err( ::vfs::Error::Unknown("TODO: extN symbolic links") ),
+			};
+		let is_dir = d_type == ::ondisk::FT_DIR;
+
+		let ino_id = try!( fs.allocate_inode(parent_id, i_mode, i_links_count) );
+		let rv = if is_dir {
+				fs.with_inode(ino_id, |ino| init_dir(ino, parent_id))
 			}
+			else {
+				Ok( () )
+			};
+		match rv.and_then(|_| self.add_dir_ent(name, ino_id, d_type))
+		{
+		Ok(()) => {
+			log_debug!("create: {:?} = Inode{}", name, ino_id);
+			if is_dir {
+				// The new directory's `..` references this directory
+				self.inode.inc_link_count();
+			}
+			Ok(ino_id as ::vfs::node::InodeId)
+			},
+		Err(e) => {
+			// Clear the link count, so the inode is released when the temporary handle is dropped
+			let _ = fs.with_inode(ino_id, |ino| { ino.lock_write().set_i_links_count(0); Ok(()) });
+			Err(e)
+			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn (::vfs::node::NodeBase)) -> ::vfs::node::Result<()> {
@@ -328,7 +310,7 @@ impl ::vfs::node::Dir for Dir
 		{
 			Err( ::vfs::Error::ReadOnlyFilesystem )
 		}
-		else if name == ""
+		else if name == "" || name == "." || name == ".."
 		{
 			Err(::vfs::Error::InvalidParameter)
 		}
@@ -338,56 +320,85 @@ impl ::vfs::node::Dir for Dir
 		}
 		else
 		{
-			// TODO: How can I be sure that the passed inode number is valid? (or that it stays valid)
-			let inode = node.get_id();
-			try!(self.add_dir_ent(name, inode as u32));
-			// 3. Update inode's link count
-			try!(self.inode.fs.with_inode(inode as u32, |ino| {
-				ino.inc_link_count();
-				Ok( () )
-				}));
-			
-			todo!("link(name={:?}, inode={:?})", name, inode);
+			let target = if let Some(f) = node.get_any().downcast_ref::<::file::File>() {
+					f.inode()
+				}
+				else if node.get_any().downcast_ref::<Dir>().is_some() {
+					log_notice!("link({:?}): Hard links to directories are not supported", name);
+					return Err(::vfs::Error::InvalidParameter);
+				}
+				else {
+					return Err(::vfs::Error::TypeMismatch);
+				};
+			if !::core::ptr::eq(&*target.fs, &*self.inode.fs) {
+				log_notice!("link({:?}): Linking across filesystems", name);
+				return Err(::vfs::Error::InvalidParameter);
+			}
+
+			// Increment the link count first, so the inode can't be released if the original name is removed
+			target.inc_link_count();
+			match self.add_dir_ent(name, target.get_id() as u32, ::ondisk::FT_REG_FILE)
+			{
+			Ok(()) => Ok( () ),
+			Err(e) => {
+				target.dec_link_count();
+				Err(e)
+				},
+			}
 		}
 	}
 	fn unlink(&self, name: &ByteStr) -> ::vfs::node::Result<()> {
 		if self.inode.fs.is_readonly()
 		{
-			Err( ::vfs::Error::ReadOnlyFilesystem )
+			return Err( ::vfs::Error::ReadOnlyFilesystem );
 		}
-		else if name == ""
+		if name == "" || name == "." || name == ".."
 		{
-			Err( ::vfs::Error::InvalidParameter )
+			return Err( ::vfs::Error::InvalidParameter );
 		}
-		else
-		{
-			let (blk, ofs, _) = try!(self.find_name(name));
+		let fs = &self.inode.fs;
+		let _lh = self.inode.lock_dir();
+		let pos = try!(self.find_name(name));
+		log_debug!("unlink({:?}): I{} at B{}+{}", name, pos.inode, pos.vol_blk, pos.ofs);
 
-			let inode = self.inode.lock_read();
-			let vol_blk = try!( inode.blocks_from(blk as u32).next_or_err() );
+		// Hold the target open while the entry is removed, it's released once the last handle is dropped
+		fs.with_inode(pos.inode, |child| {
+			let is_dir = child.lock_read().i_mode_fmt() == ::ondisk::S_IFDIR;
+			if is_dir && !try!(dir_is_empty(child)) {
+				return Err(::vfs::Error::DirectoryNotEmpty);
+			}
 
-			self.inode.fs.edit_block(vol_blk, |blk_data| {
-				// SAFE: Alignment checked, range valid
-				let blk_data: &mut [u32] = unsafe {
-					assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-					::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
+			// Remove the entry (merging into the previous entry if there is one)
+			fs.edit_block(pos.vol_blk, |blk_data| {
+				let blk_data = ::as_u32_slice(blk_data);
+				let rec_len = match ::ondisk::DirEnt::new(&blk_data[pos.ofs/4 ..])
+					{
+					Some(ent) => ent.d_rec_len,
+					None => return Err(::vfs::Error::InconsistentFilesystem),
 					};
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+				match pos.prev_ofs
 				{
-				None => return Err(::vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					// Clear name length
-					ent.d_name_len = 0;
-					// Decrement inode's reference count
-					try!(self.inode.fs.with_inode(ent.d_inode, |ino| {
-						ino.dec_link_count();
-						Ok( () )
-						}));
-					Ok( () )
+				Some(prev_ofs) => match ::ondisk::DirEnt::new_mut(&mut blk_data[prev_ofs/4 ..])
+					{
+					Some(prev) => prev.d_rec_len += rec_len,
+					None => return Err(::vfs::Error::InconsistentFilesystem),
 					},
+				None => blk_data[pos.ofs/4] = 0,
 				}
-				})
-		}
+				Ok( () )
+				})?;
+
+			// Update link counts
+			if is_dir {
+				// Removes both the entry and the directory's own `.`, and this directory loses the `..` reference
+				child.lock_write().set_i_links_count(0);
+				self.inode.dec_link_count();
+			}
+			else {
+				child.dec_link_count();
+			}
+			Ok( () )
+			})
 	}
 }
 
@@ -423,3 +434,61 @@ impl<'a> Iterator for DirEnts<'a>
 	}
 }
 
+/// Location of an entry within a directory
+struct EntPos
+{
+	vol_blk: u32,
+	/// Byte offset of the entry in the block
+	ofs: usize,
+	/// Offset of the previous entry in the same block
+	prev_ofs: Option<usize>,
+	inode: u32,
+}
+
+/// Minimum record length for an entry with the given name length
+fn dirent_size(name_len: usize) -> usize {
+	(::ondisk::DIRENT_MIN_SIZE + name_len + 4-1) & !(4-1)
+}
+/// Write the fixed portion of a directory entry (the name is written separately)
+fn write_dirent_header(blk_data: &mut [u32], ofs: usize, inode: u32, rec_len: u16, name_len: u8, d_type: u8) {
+	blk_data[ofs/4 + 0] = inode.to_le();
+	blk_data[ofs/4 + 1] = (rec_len as u32 | (name_len as u32) << 16 | (d_type as u32) << 24).to_le();
+}
+
+/// Populate the first block of a new directory with the `.` and `..` entries
+fn init_dir(inode: &::inodes::Inode, parent: u32) -> ::vfs::node::Result<()> {
+	let fs = &inode.fs;
+	let mut lh = inode.lock_write();
+	lh.ensure_blocks_allocated(0, 1)?;
+	lh.set_i_size(fs.fs_block_size as u64)?;
+	let vol_blk = lh.get_block_addr(0)?;
+	fs.edit_block(vol_blk, |blk_data| {
+		blk_data.fill(0);
+		let blk_data = ::as_u32_slice(blk_data);
+		let dot_len = dirent_size(1);
+		write_dirent_header(blk_data, 0, inode.get_id() as u32, dot_len as u16, 1, ::ondisk::FT_DIR);
+		::ondisk::DirEnt::new_mut(&mut blk_data[0..]).unwrap().d_name.clone_from_slice(b".");
+		write_dirent_header(blk_data, dot_len, parent, (fs.fs_block_size - dot_len) as u16, 2, ::ondisk::FT_DIR);
+		::ondisk::DirEnt::new_mut(&mut blk_data[dot_len/4..]).unwrap().d_name.clone_from_slice(b"..");
+		Ok( () )
+		})
+}
+
+/// Returns `true` if the directory contains only `.` and `..`
+fn dir_is_empty(inode: &::inodes::Inode) -> ::vfs::node::Result<bool> {
+	let lh = inode.lock_read();
+	for vol_blk in lh.blocks()
+	{
+		let blk_data = try!(inode.fs.get_block(vol_blk));
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.d_rec_len == 0 {
+				return Err( ::vfs::Error::InconsistentFilesystem );
+			}
+			if ent.d_inode != 0 && &ent.d_name != b"." && &ent.d_name != b".." {
+				return Ok(false);
+			}
+		}
+	}
+	Ok(true)
+}
@@ -0,0 +1,360 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/extents.rs
+//! ext4 extent trees (`EXT4_EXTENTS_FL`)
+//!
+//! Lookups walk the tree directly. Modifications decode every extent, edit the list, then re-build the tree
+//! (re-using the existing tree blocks where possible).
+use kernel::prelude::*;
+use instance::InstanceInner;
+use ondisk::{ExtentHeader,ExtentIdx,ExtentLeaf};
+
+/// Number of entries that fit in `i_block` (after the header)
+const ROOT_MAX_ENTRIES: usize = 4;
+/// Size of the header and each entry, in 32-bit words
+const HDR_WORDS: usize = 3;
+const ENT_WORDS: usize = 3;
+
+/// A decoded leaf extent
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct Extent
+{
+	/// First logical block
+	pub lblk: u32,
+	pub len: u32,
+	/// First volume block
+	pub pblk: u32,
+	/// Blocks are allocated but not written (read as zero)
+	pub uninit: bool,
+}
+impl Extent
+{
+	fn end(&self) -> u32 {
+		self.lblk + self.len
+	}
+}
+
+/// Initialise an empty tree (for a new inode)
+pub fn init_root(inode: &mut ::ondisk::Inode) {
+	inode.i_flags |= ::ondisk::EXT4_EXTENTS_FL;
+	let root = &mut inode.i_block[..];
+	for v in root.iter_mut() {
+		*v = 0;
+	}
+	ExtentHeader {
+		eh_magic: ::ondisk::EXTENT_MAGIC,
+		eh_entries: 0,
+		eh_max: ROOT_MAX_ENTRIES as u16,
+		eh_depth: 0,
+		eh_generation: 0,
+		}.to_words(root);
+}
+
+fn get_header(node: &[u32]) -> ::vfs::node::Result<ExtentHeader> {
+	let hdr = ExtentHeader::from_words(node);
+	if hdr.eh_magic != ::ondisk::EXTENT_MAGIC {
+		log_error!("Extent node has bad magic {:#x}", hdr.eh_magic);
+		return Err(::vfs::Error::InconsistentFilesystem);
+	}
+	if HDR_WORDS + hdr.eh_entries as usize * ENT_WORDS > node.len() {
+		log_error!("Extent node has too many entries ({})", hdr.eh_entries);
+		return Err(::vfs::Error::InconsistentFilesystem);
+	}
+	Ok(hdr)
+}
+fn entry(node: &[u32], idx: usize) -> &[u32] {
+	&node[HDR_WORDS + idx * ENT_WORDS..][..ENT_WORDS]
+}
+/// Combine the halves of a 48-bit block number
+fn block_num(lo: u32, hi: u16) -> ::vfs::node::Result<u32> {
+	if hi != 0 {
+		log_error!("TODO: extN block numbers above 2^32 ({:#x}:{:08x})", hi, lo);
+		return Err(::vfs::Error::Unknown("extN: Block number out of range"));
+	}
+	Ok(lo)
+}
+fn decode_leaf(w: &[u32]) -> ::vfs::node::Result<Extent> {
+	let e = ExtentLeaf::from_words(w);
+	let (len, uninit) = if e.ee_len as u32 > ::ondisk::EXTENT_INIT_MAX_LEN {
+			(e.ee_len as u32 - ::ondisk::EXTENT_INIT_MAX_LEN, true)
+		}
+		else {
+			(e.ee_len as u32, false)
+		};
+	Ok(Extent {
+		lblk: e.ee_block,
+		len: len,
+		pblk: block_num(e.ee_start_lo, e.ee_start_hi)?,
+		uninit: uninit,
+		})
+}
+
+/// Locate the extent containing `block_idx`
+///
+/// Returns the volume block and number of contiguous blocks (up to `max_blocks`). A volume block of zero
+/// indicates a hole (or uninitialised blocks), which should read as zeroes.
+pub fn get_extent(fs: &InstanceInner, inode: &::ondisk::Inode, block_idx: u32, max_blocks: u32) -> ::vfs::node::Result<(u32, u32)>
+{
+	let mut node_data: Vec<u32>;
+	let mut node = &inode.i_block[..];
+	// End of the range covered by the current node (from the parent's next index entry)
+	let mut limit = u32::MAX;
+	loop
+	{
+		let hdr = get_header(node)?;
+		let n_ents = hdr.eh_entries as usize;
+		if hdr.eh_depth == 0
+		{
+			for i in 0 .. n_ents
+			{
+				let e = decode_leaf(entry(node, i))?;
+				if block_idx < e.lblk {
+					// Hole before this extent
+					limit = e.lblk;
+					break;
+				}
+				if block_idx < e.end() {
+					let count = u32::min(e.end() - block_idx, max_blocks);
+					if e.uninit {
+						return Ok( (0, count) );
+					}
+					return Ok( (e.pblk + (block_idx - e.lblk), count) );
+				}
+			}
+			return Ok( (0, u32::min(limit - block_idx, max_blocks)) );
+		}
+
+		// Find the last index entry starting at or before the block
+		let mut sel = None;
+		for i in 0 .. n_ents
+		{
+			let ei = ExtentIdx::from_words(entry(node, i));
+			if ei.ei_block > block_idx {
+				limit = u32::min(limit, ei.ei_block);
+				break;
+			}
+			sel = Some(ei);
+		}
+		let ei = match sel
+			{
+			Some(v) => v,
+			// Before the first child, so is a hole
+			None => return Ok( (0, u32::min(limit - block_idx, max_blocks)) ),
+			};
+		node_data = fs.get_block( block_num(ei.ei_leaf_lo, ei.ei_leaf_hi)? )?.to_vec();
+		node = &node_data;
+	}
+}
+
+/// Decode every extent in the tree (in order), along with the blocks used by the tree itself
+fn collect(fs: &InstanceInner, node: &[u32], extents: &mut Vec<Extent>, tree_blocks: &mut Vec<u32>) -> ::vfs::node::Result<()>
+{
+	let hdr = get_header(node)?;
+	for i in 0 .. hdr.eh_entries as usize
+	{
+		if hdr.eh_depth == 0 {
+			extents.push( decode_leaf(entry(node, i))? );
+		}
+		else {
+			let ei = ExtentIdx::from_words(entry(node, i));
+			let blk = block_num(ei.ei_leaf_lo, ei.ei_leaf_hi)?;
+			tree_blocks.push(blk);
+			let child = fs.get_block(blk)?.to_vec();
+			collect(fs, &child, extents, tree_blocks)?;
+		}
+	}
+	Ok( () )
+}
+
+/// Ensure that blocks are allocated for the range `first .. first+count`
+///
+/// Uninitialised extents in the range are zeroed and marked as initialised.
+pub fn ensure_allocated(fs: &InstanceInner, inode_num: u32, inode: &mut ::ondisk::Inode, first: u32, count: u32) -> ::vfs::node::Result<()>
+{
+	let mut extents = Vec::new();
+	let mut tree_blocks = Vec::new();
+	collect(fs, &inode.i_block, &mut extents, &mut tree_blocks)?;
+	let mut n_alloc = 0;
+	let orig_extents = extents.clone();
+
+	let end = first.saturating_add(count);
+	let mut pos = first;
+	// Allocation hint: the block before the first one requested
+	let mut prev_block = extents.iter()
+		.filter(|e| e.lblk < first)
+		.last()
+		.map(|e| e.pblk + u32::min(e.len, first - e.lblk) - 1)
+		.unwrap_or(0);
+	let rv = (|| -> ::vfs::node::Result<()> {
+		while pos < end
+		{
+			match extents.iter().position(|e| e.lblk <= pos && pos < e.end())
+			{
+			Some(i) => {
+				let e = &mut extents[i];
+				if e.uninit {
+					let zero_buf = vec![0u8; fs.fs_block_size];
+					for b in e.pblk .. e.pblk + e.len {
+						fs.write_blocks(b, &zero_buf)?;
+					}
+					e.uninit = false;
+				}
+				prev_block = e.pblk + e.len - 1;
+				pos = e.end();
+				},
+			None => {
+				let hole_end = extents.iter().map(|e| e.lblk).filter(|&l| l > pos).min().unwrap_or(u32::MAX);
+				let hole_end = u32::min(hole_end, end);
+				while pos < hole_end
+				{
+					let b = fs.allocate_data_block(inode_num, prev_block)?;
+					n_alloc += 1;
+					insert_block(&mut extents, pos, b);
+					prev_block = b;
+					pos += 1;
+				}
+				},
+			}
+		}
+		Ok( () )
+		})();
+
+	// Save even on failure, so the allocated blocks are referenced
+	if extents != orig_extents {
+		let (tree_alloc, tree_freed) = rebuild(fs, inode_num, &mut inode.i_block, &extents, tree_blocks)?;
+		inode.add_i_blocks(fs, n_alloc + tree_alloc);
+		inode.sub_i_blocks(fs, tree_freed);
+	}
+	rv
+}
+
+/// Add a single block mapping, extending the previous extent if possible
+fn insert_block(extents: &mut Vec<Extent>, lblk: u32, pblk: u32)
+{
+	let idx = extents.iter().position(|e| e.lblk > lblk).unwrap_or(extents.len());
+	if idx > 0 {
+		let p = &mut extents[idx-1];
+		if !p.uninit && p.end() == lblk && p.pblk + p.len == pblk && p.len < ::ondisk::EXTENT_INIT_MAX_LEN {
+			p.len += 1;
+			return ;
+		}
+	}
+	extents.insert(idx, Extent { lblk: lblk, len: 1, pblk: pblk, uninit: false });
+}
+
+/// Release all blocks from `first` onwards
+pub fn free_from(fs: &InstanceInner, inode_num: u32, inode: &mut ::ondisk::Inode, first: u32) -> ::vfs::node::Result<()>
+{
+	let mut extents = Vec::new();
+	let mut tree_blocks = Vec::new();
+	collect(fs, &inode.i_block, &mut extents, &mut tree_blocks)?;
+	let mut n_freed = 0;
+	let orig_extents = extents.clone();
+
+	let rv = (|| -> ::vfs::node::Result<()> {
+		while let Some(e) = extents.last_mut()
+		{
+			if e.end() <= first {
+				break;
+			}
+			let keep = first.saturating_sub(e.lblk);
+			while e.len > keep {
+				fs.free_data_block(e.pblk + e.len - 1)?;
+				n_freed += 1;
+				e.len -= 1;
+			}
+			if e.len == 0 {
+				extents.pop();
+			}
+		}
+		Ok( () )
+		})();
+
+	if extents != orig_extents {
+		let (tree_alloc, tree_freed) = rebuild(fs, inode_num, &mut inode.i_block, &extents, tree_blocks)?;
+		inode.add_i_blocks(fs, tree_alloc);
+		inode.sub_i_blocks(fs, n_freed + tree_freed);
+	}
+	rv
+}
+
+/// Re-create the tree from a list of extents
+///
+/// Existing tree blocks are re-used first, any surplus is freed. Returns the number of tree blocks allocated and freed.
+fn rebuild(fs: &InstanceInner, inode_num: u32, root: &mut [u32], extents: &[Extent], mut free_blocks: Vec<u32>) -> ::vfs::node::Result<(u32, u32)>
+{
+	let mut n_alloc = 0;
+	let per_block = (fs.fs_block_size / 4 - HDR_WORDS) / ENT_WORDS;
+	let n_old = free_blocks.len() as u32;
+	let mut n_used = 0;
+	log_debug!("extents::rebuild(I{}): {} extents, {} tree blocks", inode_num, extents.len(), n_old);
+
+	// Encoded entries for the current level, along with the first logical block of each
+	let mut level: Vec<(u32, [u32; ENT_WORDS])> = extents.iter().map(|e| {
+		let mut w = [0; ENT_WORDS];
+		ExtentLeaf {
+			ee_block: e.lblk,
+			ee_len: (e.len + if e.uninit { ::ondisk::EXTENT_INIT_MAX_LEN } else { 0 }) as u16,
+			ee_start_hi: 0,
+			ee_start_lo: e.pblk,
+			}.to_words(&mut w);
+		(e.lblk, w)
+		}).collect();
+	let mut depth = 0;
+	while level.len() > ROOT_MAX_ENTRIES
+	{
+		let mut next_level = Vec::with_capacity( (level.len() + per_block - 1) / per_block );
+		for chunk in level.chunks(per_block)
+		{
+			let blk = match free_blocks.pop()
+				{
+				Some(b) => b,
+				None => {
+					let hint = extents.first().map(|e| e.pblk).unwrap_or(0);
+					let b = fs.allocate_data_block(inode_num, hint)?;
+					n_alloc += 1;
+					b
+					},
+				};
+			n_used += 1;
+			fs.edit_block(blk, |blk_data| {
+				blk_data.fill(0);
+				let blk_data = ::as_u32_slice(blk_data);
+				write_node(blk_data, per_block, depth, chunk);
+				Ok( () )
+				})?;
+			let mut w = [0; ENT_WORDS];
+			ExtentIdx { ei_block: chunk[0].0, ei_leaf_lo: blk, ei_leaf_hi: 0, ei_unused: 0 }.to_words(&mut w);
+			next_level.push( (chunk[0].0, w) );
+		}
+		level = next_level;
+		depth += 1;
+	}
+	write_node(root, ROOT_MAX_ENTRIES, depth, &level);
+
+	// Release surplus tree blocks
+	let n_freed = free_blocks.len() as u32;
+	for b in free_blocks {
+		fs.free_data_block(b)?;
+	}
+	log_trace!("extents::rebuild: depth={}, {} tree blocks ({} before)", depth, n_used, n_old);
+	Ok( (n_alloc, n_freed) )
+}
+fn write_node(node: &mut [u32], max: usize, depth: u16, entries: &[(u32, [u32; ENT_WORDS])])
+{
+	let generation = if node.len() > 2 { node[2] } else { 0 };
+	for v in node[HDR_WORDS..][..max * ENT_WORDS].iter_mut() {
+		*v = 0;
+	}
+	ExtentHeader {
+		eh_magic: ::ondisk::EXTENT_MAGIC,
+		eh_entries: entries.len() as u16,
+		eh_max: max as u16,
+		eh_depth: depth,
+		eh_generation: generation,
+		}.to_words(node);
+	for (i, (_, w)) in entries.iter().enumerate() {
+		node[HDR_WORDS + i * ENT_WORDS..][..ENT_WORDS].copy_from_slice(w);
+	}
+}
@@ -3,6 +3,8 @@
 //
 // Modules/fs_extN/file.rs
 //! Regular file
+use kernel::prelude::*;
+
 pub struct File
 {
 	inode: ::inodes::Inode,
@@ -17,6 +19,9 @@ impl File
 			inode: inode,
 			}
 	}
+	pub fn inode(&self) -> &::inodes::Inode {
+		&self.inode
+	}
 }
 
 impl vfs::node::NodeBase for File
@@ -27,6 +32,9 @@ impl vfs::node::NodeBase for File
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+		Ok( self.inode.lock_read().metadata() )
+	}
 }
 impl vfs::node::File for File
 {
@@ -54,6 +62,10 @@ impl vfs::node::File for File
 		iter_blocks_range(&inode, ofs, buf.len(), &mut |block_range, data_range| {
 			match block_range
 			{
+			// Sparse region
+			BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {
+				buf[data_range].fill(0);
+				},
 			BlockRef::Sub(blkid, sub_range) => {
 				let blk_data = try!(self.inode.fs.get_block_uncached(blkid));
 				buf[data_range].copy_from_slice(&blk_data[sub_range]);
@@ -67,28 +79,28 @@ impl vfs::node::File for File
 	}
 
 	fn truncate(&self, new_size: u64) -> vfs::node::Result<u64> {
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
 		let mut inode = self.inode.lock_write();
 		let old_size = inode.i_size();
-		if new_size == 0
-		{
-			todo!("truncate - 0");
-		}
-		else if new_size == old_size
+		if new_size == old_size
 		{
 			Ok( new_size )
 		}
 		else if new_size < old_size
 		{
-			todo!("truncate - shrink");
+			// Update the size first, then release the blocks past the new end
+			inode.set_i_size(new_size)?;
+			let keep_blocks = ::kernel::lib::num::div_up(new_size, self.inode.fs.fs_block_size as u64);
+			inode.free_blocks_from(keep_blocks as u32)?;
+			Ok( new_size )
 		}
 		else {
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
+			ensure_blocks_present(&self.inode.fs, &mut inode, old_size, new_size)?;
 			inode.set_i_size(new_size)?;
-			// TODO: Risky cast? If truncating to a very large size
-			iter_blocks_range(&inode, old_size, (new_size - old_size) as usize, &mut |_block_range, _data_range| {
-				// TODO: Zero the blocks?
-				Ok( () )
-				})?;
+			// Zero the newly exposed region (including the tail of the previous last block)
+			zero_inner(&inode, old_size, new_size - old_size)?;
 			Ok( new_size )
 		}
 	}
@@ -103,31 +115,32 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
-			// 1. Leading partial
-			// 2. Inner
-			// 3. Trailing partial
-			todo!("clear");
+			zero_inner(&inode, ofs, size)
 		}
 	}
 	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
 		let inode = self.inode.lock_read();
 		let size = inode.i_size();
-		if self.inode.fs.is_readonly()
+		if ofs > size
 		{
-			Err( vfs::Error::ReadOnlyFilesystem )
+			Err( vfs::Error::InvalidParameter )
 		}
-		else if ofs == size
+		else if ofs + buf.len() as u64 > size
 		{
+			// Extending the file, upgrade to a write lock
 			drop(inode);
 			let mut inode = self.inode.lock_write();
-			if ofs == inode.i_size() {
-			}
-			else {
+			if inode.i_size() != size {
 				// Race! Should this be possible? (shouldn't the vfs layer protect that?)
+				log_notice!("write: File size changed while upgrading lock ({:#x} != {:#x})", inode.i_size(), size);
 			}
 			let new_size = ofs + buf.len() as u64;
 			// Ensure that there are blocks allocated
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
+			// - If this fails part-way, then the allocated blocks are kept (and released on truncate/delete)
+			ensure_blocks_present(&self.inode.fs, &mut inode, ofs, new_size)?;
 			// Extend the size
 			inode.set_i_size(new_size)?;
 			// Write data
@@ -135,8 +148,12 @@ impl vfs::node::File for File
 			inode.set_i_size(ofs + rv as u64)?;
 			Ok(rv)
 		}
-		else if ofs > size || buf.len() as u64 > size || ofs + buf.len() as u64 > size {
-			Err( vfs::Error::InvalidParameter )
+		else if has_holes(&inode, ofs, buf.len())? {
+			// Sparse (or uninitialised) region, allocate blocks before writing
+			drop(inode);
+			let mut inode = self.inode.lock_write();
+			ensure_blocks_present(&self.inode.fs, &mut inode, ofs, ofs + buf.len() as u64)?;
+			write_inner(&inode, ofs, buf)
 		}
 		else {
 			// NOTE: In this section, we're free to read-modify-write blocks without fear, as the VFS itself handles
@@ -146,10 +163,46 @@ impl vfs::node::File for File
 	}
 }
 
+/// Zero a range of the file (which must have blocks allocated)
+fn zero_inner(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len: u64) -> vfs::Result<()> {
+	// Work in chunks, to bound the size of the zero buffer
+	const CHUNK_SIZE: u64 = 0x10000;
+	let fs_block_size = inode.fs().fs_block_size;
+	let zero_buf = vec![0u8; usize::max(CHUNK_SIZE as usize, fs_block_size)];
+	let mut pos = 0;
+	while pos < len
+	{
+		let chunk_len = u64::min(len - pos, zero_buf.len() as u64) as usize;
+		iter_blocks_range(inode, ofs + pos, chunk_len, &mut |block_range, data_range| {
+			match block_range
+			{
+			// Already reads as zero
+			BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {},
+			BlockRef::Sub(blkid, sub_range) => {
+				inode.fs().edit_block(blkid, |data| {
+					data[sub_range].fill(0);
+					Ok( () )
+					})?;
+				},
+			BlockRef::Range(blkid, _count) => {
+				inode.fs().write_blocks(blkid, &zero_buf[..data_range.len()])?;
+				},
+			}
+			Ok( () )
+			})?;
+		pos += chunk_len as u64;
+	}
+	Ok( () )
+}
+
 fn write_inner(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
 	iter_blocks_range(inode, ofs, buf.len(), &mut |block_range, data_range| {
 		match block_range
 		{
+		BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {
+			log_error!("write_inner: Writing to a sparse region");
+			return Err(vfs::Error::InconsistentFilesystem);
+			},
 		BlockRef::Sub(blkid, sub_range) => {
 			inode.fs().edit_block(blkid, |data| {
 				data[sub_range].copy_from_slice(&buf[data_range]);
@@ -200,15 +253,38 @@ fn iter_blocks_range(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len:
 	{
 		let b = blocks.next_or_err()?;
 		log_trace!("iter_blocks_range: Suffix B{} 0+{}", b, trailing_bytes);
-		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+len )?;
+		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+trailing_bytes )?;
 		written += trailing_bytes;
 	}
 	Ok( written )
 }
 
-fn ensure_blocks_present(fs: &super::instance::InstanceInner, lh: &mut super::inodes::InodeHandleWrite, size: u64) -> vfs::Result<()> {
-	let nblocks = ::kernel::lib::num::div_up(size, fs.fs_block_size as u64);
+/// Ensure that blocks are allocated for the byte range `start .. end`
+fn ensure_blocks_present(fs: &super::instance::InstanceInner, lh: &mut super::inodes::InodeHandleWrite, start: u64, end: u64) -> vfs::Result<()> {
+	let first_block = start / fs.fs_block_size as u64;
+	let end_block = ::kernel::lib::num::div_up(end, fs.fs_block_size as u64);
+	if end_block > first_block {
+		lh.ensure_blocks_allocated(first_block as u32, (end_block - first_block) as u32)
+	}
+	else {
+		Ok( () )
+	}
+}
 
-	lh.ensure_blocks_allocated(0, nblocks as u32)
+/// Check if a byte range contains any sparse (unallocated) blocks
+fn has_holes(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len: usize) -> vfs::Result<bool> {
+	let fs_block_size = inode.fs().fs_block_size as u64;
+	let first_block = (ofs / fs_block_size) as u32;
+	let end_block = ::kernel::lib::num::div_up(ofs + len as u64, fs_block_size) as u32;
+	let mut blk = first_block;
+	while blk < end_block
+	{
+		let (addr, count) = inode.get_extent_from_block(blk, end_block - blk)?;
+		if addr == 0 {
+			return Ok(true);
+		}
+		blk += count;
+	}
+	Ok(false)
 }
 
@@ -0,0 +1,245 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/htree.rs
+//! Hashed directory indexes (`EXT4_INDEX_FL`)
+//!
+//! The index blocks are formatted to look like empty directory entries, so the directory can still be read
+//! linearly. This driver only uses the index for lookups, and clears `EXT4_INDEX_FL` when adding entries.
+use kernel::prelude::*;
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+
+/// Hash value marking the end of the directory (can't be returned by the hash function)
+const HTREE_EOF_32BIT: u32 = 0x7FFF_FFFF;
+
+/// Calculate the (major) hash of a directory entry name
+pub fn dirhash(name: &[u8], version: u8, unsigned: bool, seed: &[u32; 4]) -> Option<u32>
+{
+	// A zero seed uses the MD4 initial state
+	let mut buf = if seed.iter().any(|&v| v != 0) { *seed } else { [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476] };
+	let hash = match version
+		{
+		DX_HASH_LEGACY => legacy_hash(name, unsigned),
+		DX_HASH_HALF_MD4 => {
+			let mut input = [0; 8];
+			let mut p = name;
+			while !p.is_empty() {
+				str2hashbuf(p, &mut input, unsigned);
+				half_md4_transform(&mut buf, &input);
+				p = &p[usize::min(p.len(), 32)..];
+			}
+			buf[1]
+			},
+		DX_HASH_TEA => {
+			let mut input = [0; 4];
+			let mut p = name;
+			while !p.is_empty() {
+				str2hashbuf(p, &mut input, unsigned);
+				tea_transform(&mut buf, &input);
+				p = &p[usize::min(p.len(), 16)..];
+			}
+			buf[0]
+			},
+		_ => return None,
+		};
+	let hash = hash & !1;
+	Some(if hash == HTREE_EOF_32BIT << 1 { (HTREE_EOF_32BIT - 1) << 1 } else { hash })
+}
+
+fn char_val(b: u8, unsigned: bool) -> u32 {
+	if unsigned { b as u32 } else { b as i8 as i32 as u32 }
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32
+{
+	let (mut hash0, mut hash1) = (0x12a3fe2d_u32, 0x37abe8f9_u32);
+	for &b in name
+	{
+		let mut hash = hash1.wrapping_add( hash0 ^ char_val(b, unsigned).wrapping_mul(7152373) );
+		if hash & 0x8000_0000 != 0 {
+			hash = hash.wrapping_sub(0x7FFF_FFFF);
+		}
+		hash1 = hash0;
+		hash0 = hash;
+	}
+	hash0 << 1
+}
+
+/// Pack (up to) the first `4*out.len()` bytes of the name into words, padding with the length
+fn str2hashbuf(msg: &[u8], out: &mut [u32], unsigned: bool)
+{
+	let len = msg.len() as u32;
+	let pad = len | (len << 8);
+	let pad = pad | (pad << 16);
+
+	let mut val = pad;
+	let mut idx = 0;
+	for (i, &b) in msg.iter().take(out.len() * 4).enumerate()
+	{
+		val = char_val(b, unsigned).wrapping_add(val << 8);
+		if i % 4 == 3 {
+			out[idx] = val;
+			idx += 1;
+			val = pad;
+		}
+	}
+	if idx < out.len() {
+		out[idx] = val;
+		idx += 1;
+	}
+	for v in &mut out[idx..] {
+		*v = pad;
+	}
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8])
+{
+	fn f(x: u32, y: u32, z: u32) -> u32 { z ^ (x & (y ^ z)) }
+	fn g(x: u32, y: u32, z: u32) -> u32 { (x & y).wrapping_add((x ^ y) & z) }
+	fn h(x: u32, y: u32, z: u32) -> u32 { x ^ y ^ z }
+	const K1: u32 = 0;
+	const K2: u32 = 0o13240474631;
+	const K3: u32 = 0o15666365641;
+	macro_rules! round {
+		($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+			$a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s);
+		};
+	}
+	let [mut a, mut b, mut c, mut d] = *buf;
+
+	round!(f, a, b, c, d, input[0].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[1].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[2].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[3].wrapping_add(K1), 19);
+	round!(f, a, b, c, d, input[4].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[5].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[6].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[7].wrapping_add(K1), 19);
+
+	round!(g, a, b, c, d, input[1].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[3].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[5].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+	round!(g, a, b, c, d, input[0].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[2].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[4].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+	round!(h, a, b, c, d, input[3].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[7].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+	round!(h, a, b, c, d, input[1].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[5].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+	buf[0] = buf[0].wrapping_add(a);
+	buf[1] = buf[1].wrapping_add(b);
+	buf[2] = buf[2].wrapping_add(c);
+	buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4])
+{
+	const DELTA: u32 = 0x9E3779B9;
+	let mut sum = 0u32;
+	let (mut b0, mut b1) = (buf[0], buf[1]);
+	let [a, b, c, d] = *input;
+	for _ in 0 .. 16
+	{
+		sum = sum.wrapping_add(DELTA);
+		b0 = b0.wrapping_add( (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b) );
+		b1 = b1.wrapping_add( (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d) );
+	}
+	buf[0] = buf[0].wrapping_add(b0);
+	buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// Index root information (following the `.` and `..` entries in the first block)
+struct RootInfo
+{
+	hash_version: u8,
+	indirect_levels: u8,
+	/// Word offset of the count/limit header
+	entries_ofs: usize,
+}
+fn parse_root(blk: &[u32]) -> Option<RootInfo>
+{
+	// `.` is 12 bytes, `..` is at byte 12 and its name occupies up to byte 24
+	let reserved_zero = blk[24/4];
+	let info = blk[28/4];
+	let hash_version = info as u8;
+	let info_length = (info >> 8) as u8;
+	let indirect_levels = (info >> 16) as u8;
+	if reserved_zero != 0 || info_length != 8 || indirect_levels > 2 {
+		log_notice!("htree: Unexpected root info {:#x} {:#x}", reserved_zero, info);
+		return None;
+	}
+	Some(RootInfo {
+		hash_version: hash_version,
+		indirect_levels: indirect_levels,
+		entries_ofs: (24 + info_length as usize) / 4,
+		})
+}
+
+/// Locate the leaf blocks (logical block indexes within the directory) that may contain `name`
+///
+/// Returns `None` if the index can't be used (unknown hash or malformed), in which case the caller should fall
+/// back to a linear search. The first returned block is the main candidate, following blocks are hash collisions.
+pub fn find_leaves(inode: &::inodes::InodeHandleRead, name: &[u8]) -> ::vfs::node::Result<Option<Vec<u32>>>
+{
+	use inodes::InodeHandleTrait;
+	let fs = inode.fs();
+	let root_blk = fs.get_block( inode.get_block_addr(0)? )?;
+	let info = match parse_root(&root_blk)
+		{
+		Some(v) => v,
+		None => return Ok(None),
+		};
+	let (seed, unsigned) = fs.dir_hash_params();
+	let hash = match dirhash(name, info.hash_version, unsigned, &seed)
+		{
+		Some(v) => v,
+		None => {
+			log_notice!("htree: Unknown hash version {}", info.hash_version);
+			return Ok(None);
+			},
+		};
+	log_trace!("htree::find_leaves({:?}): hash={:#x}", ::kernel::lib::byte_str::ByteStr::new(name), hash);
+
+	// Entries are (hash, block) pairs, with the first hash replaced by (limit, count)
+	let mut entries: Vec<u32> = root_blk[info.entries_ofs..].to_vec();
+	drop(root_blk);
+	let mut level = 0;
+	loop
+	{
+		let count = (entries[0] >> 16) as usize;
+		if count == 0 || count * 2 > entries.len() {
+			log_notice!("htree: Bad entry count {}", count);
+			return Ok(None);
+		}
+		// Find the last entry with a hash at or below the target
+		let idx = (1 .. count).take_while(|&i| entries[i*2] <= hash).last().unwrap_or(0);
+		let block = entries[idx*2 + 1] & 0x0FFF_FFFF;
+		if level == info.indirect_levels
+		{
+			let mut rv = vec![block];
+			// Following leaves that continue the same hash (low bit set) could also contain the name
+			for i in idx+1 .. count {
+				if entries[i*2] & !1 != hash || entries[i*2] & 1 == 0 {
+					break;
+				}
+				rv.push(entries[i*2 + 1] & 0x0FFF_FFFF);
+			}
+			return Ok(Some(rv));
+		}
+		// Interior node: a fake (empty) directory entry, followed by the entries
+		let node = fs.get_block( inode.get_block_addr(block)? )?;
+		entries = node[8/4..].to_vec();
+		level += 1;
+	}
+}
@@ -1,10 +1,15 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
 //
-//
-//
-//! 
+// Modules/fs_extN/inodes.rs
+//! Inode handling (block maps and link counts)
+use kernel::prelude::*;
 use instance::{InstancePtr,InstanceInner};
 use core::sync::atomic::{AtomicBool,Ordering};
 
+/// Maximum link count (above which directories use a link count of 1)
+const LINK_MAX: u16 = 65000;
+
 pub struct Inode
 {
 	pub fs: InstancePtr,
@@ -35,6 +40,17 @@ impl Drop for Inode
 {
 	fn drop(&mut self)
 	{
+		// Last reference to an inode with no links, release it
+		let is_orphan = {
+			let od = self.on_disk.read();
+			od.i_links_count == 0 && od.i_mode != 0
+			};
+		if is_orphan {
+			if let Err(e) = self.release() {
+				log_error!("Inode::drop - Error releasing I{}: {:?}", self.inode_idx, e);
+			}
+			return ;
+		}
 		if self.is_dirty.load(Ordering::Relaxed) {
 			log_warning!("Inode::drop - Dirty node being dropped, writing back and ignoring errors");
 			let _ = self.flush();
@@ -54,11 +70,46 @@ impl Inode
 		Ok( () )
 	}
 
+	/// Decrement the link count (the inode is released once the count reaches zero and the last handle is dropped)
 	pub fn dec_link_count(&self) {
-		todo!("Inode::dec_link_count");
+		let mut lh = self.lock_write();
+		if lh.lock.i_links_count == 0 {
+			log_error!("Inode::dec_link_count - I{} already has zero links", self.inode_idx);
+		}
+		else if lh.i_mode_fmt() == ::ondisk::S_IFDIR && lh.lock.i_links_count == 1 {
+			// FEAT_RO_COMPAT_DIR_NLINK: Directory has too many sub-directories to count, leave as-is
+		}
+		else {
+			lh.lock.i_links_count -= 1;
+		}
 	}
 	pub fn inc_link_count(&self) {
-		todo!("Inode::inc_link_count");
+		let mut lh = self.lock_write();
+		if lh.i_mode_fmt() == ::ondisk::S_IFDIR && (lh.lock.i_links_count == 1 || lh.lock.i_links_count + 1 >= LINK_MAX) {
+			// FEAT_RO_COMPAT_DIR_NLINK: Too many sub-directories, so the count is no longer maintained
+			lh.lock.i_links_count = 1;
+		}
+		else {
+			lh.lock.i_links_count += 1;
+		}
+	}
+
+	/// Free all data blocks and the inode itself
+	fn release(&self) -> vfs::Result<()>
+	{
+		log_debug!("Inode::release(I{})", self.inode_idx);
+		let is_dir = {
+			let mut lh = self.lock_write();
+			// Fast symlinks store the target in `i_block`, so have no blocks to free
+			let is_fast_symlink = lh.i_mode_fmt() == ::ondisk::S_IFLNK && lh.lock.i_blocks == 0;
+			if !is_fast_symlink {
+				lh.free_blocks_from(0)?;
+			}
+			lh.lock.i_size = 0;
+			lh.lock.i_dir_acl = 0;
+			lh.i_mode_fmt() == ::ondisk::S_IFDIR
+			};
+		self.fs.free_inode(self.inode_idx, is_dir)
 	}
 
 	/// Obtain the inode ID
@@ -93,6 +144,17 @@ pub struct InodeHandleWrite<'a> {
 	parent: &'a Inode,
 	lock: ::kernel::sync::rwlock::Write<'a, ::ondisk::Inode>,
 }
+impl<'a> Drop for InodeHandleWrite<'a> {
+	fn drop(&mut self) {
+		// Write back while the lock is still held, so the on-disk copy is never stale
+		self.parent.is_dirty.store(true, Ordering::Relaxed);
+		match self.parent.fs.write_inode(self.parent.inode_idx, &self.lock)
+		{
+		Ok(()) => self.parent.is_dirty.store(false, Ordering::Relaxed),
+		Err(e) => log_error!("InodeHandleWrite::drop - Error writing back I{}: {:?}", self.parent.inode_idx, e),
+		}
+	}
+}
 macro_rules! common_methods {
 	($($(#[$attr:meta])* pub fn $name:ident(&$self:ident$(, $a:ident : $t:ty)*) -> $rv:ty $b:block)+) => {
 		pub trait InodeHandleTrait<'a> {
@@ -145,6 +207,12 @@ common_methods! {
 	pub fn i_size(&self) -> u64 {
 		self.lock.i_size(&self.parent.fs)
 	}
+	pub fn i_links_count(&self) -> u16 {
+		self.lock.i_links_count
+	}
+	pub fn i_flags(&self) -> u32 {
+		self.lock.i_flags
+	}
 	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)> {
 		self.lock.get_extent_from_block(&self.parent.fs, block_idx, max_blocks)
 	}
@@ -169,6 +237,10 @@ common_methods! {
 			inner_idx: start,
 			}
 	}
+	/// Get the VFS metadata for this inode
+	pub fn metadata(&self) -> vfs::node::Metadata {
+		self.lock.metadata(&self.parent.fs)
+	}
 }
 impl<'a> InodeHandleWrite<'a> {
 	pub fn set_i_size(&mut self, new_size: u64) -> vfs::node::Result<()> {
@@ -177,6 +249,16 @@ impl<'a> InodeHandleWrite<'a> {
 	pub fn ensure_blocks_allocated(&mut self, block_idx: u32, num_blocks: u32) -> vfs::node::Result<()> {
 		self.lock.ensure_blocks_allocated(&self.parent.fs, self.parent.inode_idx, block_idx, num_blocks)
 	}
+	/// Release all blocks starting at `block_idx` (including indirect blocks that are no longer needed)
+	pub fn free_blocks_from(&mut self, block_idx: u32) -> vfs::node::Result<()> {
+		self.lock.free_blocks_from(&self.parent.fs, self.parent.inode_idx, block_idx)
+	}
+	pub fn set_i_links_count(&mut self, count: u16) {
+		self.lock.i_links_count = count;
+	}
+	pub fn clear_i_flags(&mut self, flags: u32) {
+		self.lock.i_flags &= !flags;
+	}
 }
 
 
@@ -194,6 +276,52 @@ impl crate::ondisk::Inode
 	fn i_mode_fmt(&self) -> u16 {
 		self.i_mode & ::ondisk::S_IFMT
 	}
+	fn uses_extents(&self) -> bool {
+		self.i_flags & ::ondisk::EXT4_EXTENTS_FL != 0
+	}
+	/// Number of `i_blocks` units in a filesystem block
+	fn i_blocks_per_block(&self, fs: &InstanceInner) -> u32 {
+		if self.i_flags & ::ondisk::EXT4_HUGE_FILE_FL != 0 {
+			1
+		}
+		else {
+			(fs.fs_block_size / 512) as u32
+		}
+	}
+	/// Number of bytes allocated to this inode (from `i_blocks`)
+	fn allocated_size(&self, fs: &InstanceInner) -> u64 {
+		let mut count = self.i_blocks as u64;
+		if fs.has_feature_ro_compat(crate::ondisk::FEAT_RO_COMPAT_HUGE_FILE) {
+			// Linux: `l_i_blocks_hi`
+			count |= ((self._osd2[0] & 0xFFFF) as u64) << 32;
+		}
+		let unit = if self.i_flags & ::ondisk::EXT4_HUGE_FILE_FL != 0 { fs.fs_block_size as u64 } else { 512 };
+		count * unit
+	}
+	fn metadata(&self, fs: &InstanceInner) -> vfs::node::Metadata {
+		// Linux: `l_i_uid_high` and `l_i_gid_high`
+		let uid_hi = self._osd2[1] & 0xFFFF;
+		let gid_hi = self._osd2[1] >> 16;
+		vfs::node::Metadata {
+			allocated_size: self.allocated_size(fs),
+			link_count: self.i_links_count as u32,
+			owner: self.i_uid as u32 | uid_hi << 16,
+			group: self.i_gid as u32 | gid_hi << 16,
+			mode: self.i_mode & 0o7777,
+			access_time: Some(self.i_atime as i32 as i64),
+			modify_time: Some(self.i_mtime as i32 as i64),
+			change_time: Some(self.i_ctime as i32 as i64),
+			..Default::default()
+			}
+	}
+	/// Account for newly allocated filesystem blocks
+	pub fn add_i_blocks(&mut self, fs: &InstanceInner, count: u32) {
+		self.i_blocks += count * self.i_blocks_per_block(fs);
+	}
+	/// Account for released filesystem blocks
+	pub fn sub_i_blocks(&mut self, fs: &InstanceInner, count: u32) {
+		self.i_blocks -= count * self.i_blocks_per_block(fs);
+	}
 	fn i_size(&self, fs: &InstanceInner) -> u64 {
 		self.i_size as u64 | (if fs.has_feature_ro_compat(crate::ondisk::FEAT_RO_COMPAT_LARGE_FILE) { (self.i_dir_acl as u64) << 32 } else { 0 })
 	}
@@ -254,8 +382,14 @@ impl crate::ondisk::Inode
 			panic!("");
 		}
 	}
+	/// Get the volume block for `block_idx`, and the number of following contiguous blocks (up to `max_blocks`)
+	///
+	/// A zero volume block indicates a hole (sparse file), which reads as zeroes.
 	fn get_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
 	{
+		if self.uses_extents() {
+			return ::extents::get_extent(fs, self, block_idx, max_blocks);
+		}
 		match Self::get_block_addr_extent(fs, block_idx, max_blocks)
 		{
 		(BlockAddrs::Direct { direct_idx: idx }, max_blocks) => {
@@ -311,6 +445,9 @@ impl crate::ondisk::Inode
 
 	pub fn get_block_addr(&self, fs: &InstanceInner, block_idx: u32) -> vfs::node::Result<u32>
 	{
+		if self.uses_extents() {
+			return Ok( ::extents::get_extent(fs, self, block_idx, 1)?.0 );
+		}
 		match Self::get_block_addr_extent(fs, block_idx, 1).0
 		{
 		BlockAddrs::Direct { direct_idx: idx } => {
@@ -336,30 +473,189 @@ impl crate::ondisk::Inode
 	}
 
 	fn ensure_blocks_allocated(&mut self, fs: &InstanceInner, inode_num: u32, mut block_idx: u32, mut count: u32) -> vfs::node::Result<()> {
-		let mut prev_block = 0;	// track the previous block to allow efficient allocation
-		if block_idx > 0 {
-			// TODO: Populate the previous block? What if the file is sparse?
+		if self.uses_extents() {
+			return ::extents::ensure_allocated(fs, inode_num, self, block_idx, count);
 		}
-		while count > 0
-		{
-			let (addrs, span_count) = Self::get_block_addr_extent(fs, block_idx, count);
-			block_idx += span_count;
-			count -= span_count;
-			match addrs
+		// Track the previous block to allow efficient (contiguous) allocation
+		let prev_block = if block_idx > 0 { self.get_block_addr(fs, block_idx - 1).unwrap_or(0) } else { 0 };
+		let mut alloc = BlockAllocator { fs, inode_num, prev_block, count: 0 };
+		let rv = (|| -> vfs::node::Result<()> {
+			while count > 0
 			{
-			BlockAddrs::Direct { direct_idx } => {
-				for s in &mut self.i_block[direct_idx..][.. span_count as usize] {
-					if *s == 0 {
-						*s = fs.allocate_data_block(inode_num, prev_block)?;
+				let (addrs, span_count) = Self::get_block_addr_extent(fs, block_idx, count);
+				block_idx += span_count;
+				count -= span_count;
+				match addrs
+				{
+				BlockAddrs::Direct { direct_idx } => {
+					for s in &mut self.i_block[direct_idx..][.. span_count as usize] {
+						if *s == 0 {
+							*s = alloc.alloc()?;
+						}
+						alloc.prev_block = *s;
+					}
+					},
+				BlockAddrs::Single { idx } => {
+					let si = alloc.get_or_alloc_root(&mut self.i_block[SI_BLOCK])?;
+					alloc.fill_entries(si, idx, span_count as usize)?;
+					},
+				BlockAddrs::Double { blk, idx } => {
+					let di = alloc.get_or_alloc_root(&mut self.i_block[DI_BLOCK])?;
+					let di = alloc.get_or_alloc_entry(di, blk)?;
+					alloc.fill_entries(di, idx, span_count as usize)?;
+					},
+				BlockAddrs::Triple { blk_o, blk_i, idx } => {
+					let ti = alloc.get_or_alloc_root(&mut self.i_block[TI_BLOCK])?;
+					let ti = alloc.get_or_alloc_entry(ti, blk_o)?;
+					let ti = alloc.get_or_alloc_entry(ti, blk_i)?;
+					alloc.fill_entries(ti, idx, span_count as usize)?;
+					},
+				}
+			}
+			Ok( () )
+			})();
+		// Account for everything allocated (even on failure, those blocks are now referenced)
+		self.add_i_blocks(fs, alloc.count);
+		rv
+	}
+
+	/// Release all blocks from `block_idx` onwards
+	fn free_blocks_from(&mut self, fs: &InstanceInner, inode_num: u32, block_idx: u32) -> vfs::node::Result<()> {
+		if self.uses_extents() {
+			return ::extents::free_from(fs, inode_num, self, block_idx);
+		}
+		let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+		let keep = block_idx as u64;
+		let mut freed = 0;
+		let rv = (|| -> vfs::node::Result<()> {
+			for s in self.i_block[..SI_BLOCK].iter_mut().skip(block_idx as usize) {
+				if *s != 0 {
+					fs.free_data_block(*s)?;
+					*s = 0;
+					freed += 1;
+				}
+			}
+			let mut base = SI_BLOCK as u64;
+			let mut span = u32_per_fs_block;
+			for (level, slot) in [(1, SI_BLOCK), (2, DI_BLOCK), (3, TI_BLOCK)] {
+				if self.i_block[slot] != 0 && keep < base + span {
+					if free_indirect(fs, self.i_block[slot], level, keep.saturating_sub(base), &mut freed)? {
+						self.i_block[slot] = 0;
 					}
-					prev_block = *s;
 				}
-				},
-			// TODO: Indirect requires editing the result of `get_block`
-			_ => todo!("ensure_blocks_allocated - indirect"), 
+				base += span;
+				span *= u32_per_fs_block;
+			}
+			Ok( () )
+			})();
+		self.sub_i_blocks(fs, freed);
+		rv
+	}
+}
+
+/// Free the entries of an indirect block from `keep` (relative to the block's first data block) onwards
+///
+/// Returns `true` if the indirect block itself was freed
+fn free_indirect(fs: &InstanceInner, block: u32, level: u32, keep: u64, freed: &mut u32) -> vfs::node::Result<bool> {
+	let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+	let per_entry = u32_per_fs_block.pow(level - 1);
+	let entries: Vec<u32> = fs.get_block(block)?.to_vec();
+	let mut new_entries = entries.clone();
+	let rv = (|| -> vfs::node::Result<()> {
+		for (i, e) in new_entries.iter_mut().enumerate() {
+			let ent_base = i as u64 * per_entry;
+			if *e == 0 || ent_base + per_entry <= keep {
+				continue ;
+			}
+			if level == 1 {
+				fs.free_data_block(*e)?;
+				*freed += 1;
+				*e = 0;
+			}
+			else if free_indirect(fs, *e, level - 1, keep.saturating_sub(ent_base), freed)? {
+				*e = 0;
 			}
 		}
 		Ok( () )
+		})();
+	if rv.is_ok() && keep == 0 {
+		fs.free_data_block(block)?;
+		*freed += 1;
+		return Ok(true);
+	}
+	if new_entries != entries {
+		write_indirect_entries(fs, block, 0, &new_entries)?;
+	}
+	rv.map(|_| false)
+}
+fn write_indirect_entries(fs: &InstanceInner, block: u32, first: usize, values: &[u32]) -> vfs::node::Result<()> {
+	fs.edit_block(block, |blk_data| {
+		for (d, v) in blk_data[first * 4..].chunks_mut(4).zip(values.iter()) {
+			d.copy_from_slice(&v.to_le_bytes());
+		}
+		Ok( () )
+		})
+}
+
+/// Helper for `ensure_blocks_allocated`, tracks the allocation hint and the number of blocks allocated
+struct BlockAllocator<'a>
+{
+	fs: &'a InstanceInner,
+	inode_num: u32,
+	prev_block: u32,
+	count: u32,
+}
+impl<'a> BlockAllocator<'a>
+{
+	fn alloc(&mut self) -> vfs::node::Result<u32> {
+		let rv = self.fs.allocate_data_block(self.inode_num, self.prev_block)?;
+		self.count += 1;
+		Ok(rv)
+	}
+	/// Allocate a zeroed block (for an indirect block)
+	fn alloc_zeroed(&mut self) -> vfs::node::Result<u32> {
+		let rv = self.alloc()?;
+		self.fs.edit_block(rv, |blk_data| { blk_data.fill(0); Ok( () ) })?;
+		Ok(rv)
+	}
+	fn get_or_alloc_root(&mut self, slot: &mut u32) -> vfs::node::Result<u32> {
+		if *slot == 0 {
+			*slot = self.alloc_zeroed()?;
+		}
+		Ok(*slot)
+	}
+	/// Get an entry from an indirect block, allocating a new (zeroed) block if it's empty
+	fn get_or_alloc_entry(&mut self, block: u32, idx: usize) -> vfs::node::Result<u32> {
+		let cur = self.fs.get_block(block)?[idx];
+		if cur != 0 {
+			Ok(cur)
+		}
+		else {
+			let rv = self.alloc_zeroed()?;
+			write_indirect_entries(self.fs, block, idx, &[rv])?;
+			Ok(rv)
+		}
+	}
+	/// Populate `count` data block entries in an indirect block
+	fn fill_entries(&mut self, block: u32, first: usize, count: usize) -> vfs::node::Result<()> {
+		let cur: Vec<u32> = self.fs.get_block(block)?[first..][..count].to_vec();
+		let mut new = cur.clone();
+		let mut rv = Ok( () );
+		for v in &mut new {
+			if *v == 0 {
+				match self.alloc()
+				{
+				Ok(b) => *v = b,
+				Err(e) => { rv = Err(e); break; },
+				}
+			}
+			self.prev_block = *v;
+		}
+		// Write even on failure, so the successfully allocated blocks aren't leaked
+		if new != cur {
+			write_indirect_entries(self.fs, block, first, &new)?;
+		}
+		rv
 	}
 }
 
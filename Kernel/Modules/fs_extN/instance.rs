@@ -7,19 +7,24 @@ use kernel::prelude::*;
 use vfs::node;
 use kernel::metadevs::storage::VolumeHandle;
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use core::sync::atomic::{AtomicBool,Ordering};
 
 pub struct Instance(ArefInner<InstanceInner>);
 pub type InstancePtr = ArefBorrow<InstanceInner>;
 
 pub struct InstanceInner
 {
-	is_readonly: bool,
+	is_readonly: AtomicBool,
 	pub vol: ::block_cache::CachedVolume,
 	superblock: ::kernel::sync::RwLock<crate::ondisk::Superblock>,
 	pub fs_block_size: usize,
 
 	mount_handle: ::vfs::mount::SelfHandle,
 	group_descriptors: ::kernel::sync::RwLock< Vec<::ondisk::GroupDesc> >,
+	/// On-disk size of each group descriptor (larger with FEAT_INCOMPAT_64BIT)
+	group_desc_size: usize,
+	/// Lock held while allocating/freeing blocks and inodes (keeps the bitmaps and counts consistent)
+	alloc_lock: ::kernel::sync::Mutex<()>,
 }
 
 #[allow(dead_code)]
@@ -73,14 +78,11 @@ impl Instance
 		let superblock_idx = (1024 / vol_bs) as u64;
 		let superblock_ofs = (1024 % vol_bs) as usize;
 
-		let (superblock, first_block) = {
+		let superblock = {
 			let mut first_block: Vec<u8> = vec![0; ::core::cmp::max(1024, vol_bs)];
 			::kernel::futures::block_on(vol.read_blocks(superblock_idx, &mut first_block[..]))?;
 			assert!(superblock_ofs % 4 == 0);
-			(
-				::ondisk::Superblock::from_slice(&first_block[superblock_ofs ..][..1024]),
-				first_block,
-				)
+			::ondisk::Superblock::from_slice(&first_block[superblock_ofs ..][..1024])
 			};
 
 		if superblock.data.s_magic != 0xEF53 {
@@ -105,103 +107,52 @@ impl Instance
 			log_warning!("ExtN TODO: Handle filesystem block size smaller than disk block size?");
 			return Err(::vfs::Error::InconsistentFilesystem);
 		}
-		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
-
-		// Read group descriptor table
-		// - This resides in the first FS block after the superblock
-		let group_descs = {
+		if superblock.has_feature_incompat(::ondisk::FEAT_INCOMPAT_64BIT) && superblock.ext.s_blocks_count_hi != 0 {
+			// TODO: Support block numbers above 2^32 (the block cache supports it, but this driver uses u32 everywhere)
+			return Err(::vfs::Error::Unknown("extN: Volumes larger than 2^32 blocks are not supported"));
+		}
+		let group_desc_size = superblock.s_group_desc_size();
+		{
 			const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
-			if GROUP_DESC_SIZE != superblock.s_group_desc_size() {
+			const GROUP_DESC_HI_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDescHi>();
+			if group_desc_size != GROUP_DESC_SIZE && group_desc_size < GROUP_DESC_SIZE + GROUP_DESC_HI_SIZE {
+				log_warning!("Unexpected group descriptor size {}", group_desc_size);
 				return Err(::vfs::Error::Unknown("Superblock size mismatch vs expected"));
 			}
-
-			let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
-			// Group descriptors are in the first filesystem block after the superblock
-			// - So either immediately right after the superblock, or the second block (whichever is larger)
-			let byte_offset = usize::max(2*1024, fs_block_size);
-			log_trace!("Group Descs: {} groups @ byte {}, {} per volume block (vol_bs={})",
-				num_groups, byte_offset, groups_per_vol_block, vol_bs);
-
-			let mut gds: Vec<::ondisk::GroupDesc> = (0..num_groups).map(|_| Default::default()).collect();
-
-			// The superblock is 1024 bytes at offset 1024
-			// - If the volume block size is 2K or larger, then there are some group descriptors in the first block
-			let (n_skip, mut vol_block) = if vol_bs > byte_offset {
-					// Volume block size is larger than the offset
-					// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
-					let n_shared = (vol_bs - byte_offset) / GROUP_DESC_SIZE;
-
-					let mut src = &first_block[byte_offset..];
-					let count = ::core::cmp::min(n_shared, gds.len());
-					assert_eq!(src.len(), count * GROUP_DESC_SIZE);
-					for s in &mut gds[..count] {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					(count, 1)
-				}
-				else {
-					// Volume BS <= superblock
-					// - Offset of byte 2048 in the disk
-					(0, (byte_offset / vol_bs) as u64)
-				};
-
-			// Determine how many descriptors are in the subsequent volume blocks
-			let rem_count = gds.len() - n_skip;
-			let tail_count = rem_count % groups_per_vol_block;
-			let body_count = rem_count - tail_count;
-			log_trace!("vol_block={} n_skip={} => rem_count={} (tail_count={}, body_count={})",
-				vol_block, n_skip, rem_count,  tail_count, body_count);
-
-			let mut buf: Vec<u8> = vec![0; vol_bs];
-			if body_count > 0
-			{
-				for gds in gds[n_skip..][..body_count].chunks_mut(groups_per_vol_block) {
-					::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-					let mut src = &buf[..];
-					for s in gds {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					vol_block += 1;
-				}
-			}
-
-			if tail_count > 0
-			{
-				let ofs = n_skip + body_count;
-				// Read a single volume block into a buffer, then populate from that
-				::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-				let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
-				let mut src = &buf[..n_bytes];
-				for s in &mut gds[ofs..] {
-					*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-					log_debug!("GROUP DESC: {:?}", s);
-				}
+		}
+		let journal_inum = if superblock.data.s_rev_level > 0 && superblock.ext.s_feature_compat & ::ondisk::FEAT_COMPAT_HAS_JOURNAL != 0 {
+				superblock.ext.s_journal_inum
 			}
-
-			gds
+			else {
+				0
 			};
-
-
-		for (i, gd) in group_descs.iter().enumerate()
-		{
-			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
-		}
+		let needs_recovery = superblock.has_feature_incompat(::ondisk::FEAT_INCOMPAT_RECOVER);
 
 		let inner = InstanceInner {
-			is_readonly: is_readonly,
+			is_readonly: AtomicBool::new(is_readonly),
 			fs_block_size: fs_block_size,
 			superblock: ::kernel::sync::RwLock::new(superblock),
-			group_descriptors: ::kernel::sync::RwLock::new(group_descs),
+			group_desc_size: group_desc_size,
+			group_descriptors: ::kernel::sync::RwLock::new(Vec::new()),
 			mount_handle: mount_handle,
+			alloc_lock: Default::default(),
 			vol: ::block_cache::CachedVolume::new(vol),
 			};
+		inner.load_group_descriptors()?;
 
 		// SAFE: Boxed instantly
-		unsafe {
-			Ok(Box::new(Instance(ArefInner::new( inner ))))
+		let rv = unsafe { Box::new(Instance(ArefInner::new( inner ))) };
+
+		// Replay the journal before anything else looks at the metadata
+		if journal_inum != 0 {
+			InstanceInner::check_journal(&rv.0.borrow(), journal_inum, needs_recovery)?;
+		}
+		else if needs_recovery {
+			log_warning!("Volume `{}` needs journal recovery, but has no internal journal - mounting read-only", rv.0.vol.name());
+			rv.0.is_readonly.store(true, Ordering::Relaxed);
 		}
+
+		Ok(rv)
 	}
 }
 
@@ -241,23 +192,111 @@ impl InstanceInner
 {
 	pub fn is_readonly(&self) -> bool
 	{
-		self.is_readonly
+		self.is_readonly.load(Ordering::Relaxed)
+	}
+
+	/// Read the group descriptor table (after the superblock has been loaded)
+	fn load_group_descriptors(&self) -> ::vfs::Result<()>
+	{
+		const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
+		const GROUP_DESC_HI_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDescHi>();
+		let vol_bs = self.vol.block_size();
+		let num_groups = self.num_groups();
+
+		// Group descriptors are in the first filesystem block after the superblock
+		// - So either immediately right after the superblock, or the second block (whichever is larger)
+		let byte_offset = usize::max(2*1024, self.fs_block_size);
+		let n_bytes = num_groups as usize * self.group_desc_size;
+		log_trace!("Group Descs: {} groups @ byte {}, {} bytes each (vol_bs={})",
+			num_groups, byte_offset, self.group_desc_size, vol_bs);
+
+		// Read all of the volume blocks covering the table
+		let first_vol_block = byte_offset / vol_bs;
+		let n_vol_blocks = ::kernel::lib::num::div_up(byte_offset + n_bytes, vol_bs) - first_vol_block;
+		let mut buf: Vec<u8> = vec![0; n_vol_blocks * vol_bs];
+		::kernel::futures::block_on(self.vol.read_blocks(first_vol_block as u64, &mut buf))?;
+		let table = &buf[byte_offset % vol_bs..][..n_bytes];
+
+		let mut gds: Vec<::ondisk::GroupDesc> = Vec::with_capacity(num_groups as usize);
+		for (i, ent) in table.chunks(self.group_desc_size).enumerate()
+		{
+			let gd = ::ondisk::GroupDesc::from_slice(&ent[..GROUP_DESC_SIZE]);
+			if self.group_desc_size > GROUP_DESC_SIZE {
+				// FEAT_INCOMPAT_64BIT: Metadata must be in the first 2^32 blocks
+				let hi = ::ondisk::GroupDescHi::from_slice(&ent[GROUP_DESC_SIZE..][..GROUP_DESC_HI_SIZE]);
+				if hi.bg_block_bitmap_hi != 0 || hi.bg_inode_bitmap_hi != 0 || hi.bg_inode_table_hi != 0 {
+					log_warning!("Group #{} has metadata above 2^32 blocks", i);
+					return Err(::vfs::Error::Unknown("extN: Block number out of range"));
+				}
+			}
+			log_debug!("{}: Group #{}: {:?}", self.vol.name(), i, gd);
+			gds.push(gd);
+		}
+
+		*self.group_descriptors.write() = gds;
+		Ok( () )
+	}
+
+	/// Check the journal (if present), replaying it if the filesystem wasn't cleanly unmounted
+	fn check_journal(this: &InstancePtr, journal_inum: u32, needs_recovery: bool) -> ::vfs::Result<()>
+	{
+		let fs = &**this;
+		match ::journal::check_and_replay(this, journal_inum, needs_recovery)
+		{
+		Ok(::journal::JournalState::Clean) => {},
+		Ok(::journal::JournalState::Replayed) => {
+			// The replay could have changed the superblock and group descriptors
+			let vol_bs = fs.vol.block_size();
+			let mut buf: Vec<u8> = vec![0; usize::max(1024, vol_bs)];
+			::kernel::futures::block_on(fs.vol.read_blocks((1024 / vol_bs) as u64, &mut buf))?;
+			*fs.superblock.write() = ::ondisk::Superblock::from_slice(&buf[1024 % vol_bs..][..1024]);
+			fs.load_group_descriptors()?;
+			},
+		Ok(::journal::JournalState::Unsupported) if !needs_recovery => {
+			log_notice!("Volume `{}` has an unsupported journal, but it's clean", fs.vol.name());
+			},
+		Ok(::journal::JournalState::Unsupported) => {
+			log_warning!("Volume `{}` needs journal recovery, but the journal can't be replayed - mounting read-only", fs.vol.name());
+			fs.is_readonly.store(true, Ordering::Relaxed);
+			return Ok( () );
+			},
+		Err(e) => {
+			log_warning!("Volume `{}` journal replay failed ({:?}) - mounting read-only", fs.vol.name(), e);
+			fs.is_readonly.store(true, Ordering::Relaxed);
+			return Ok( () );
+			},
+		}
+		if needs_recovery {
+			fs.edit_superblock(|sb| sb.ext.s_feature_incompat &= !::ondisk::FEAT_INCOMPAT_RECOVER)?;
+		}
+		Ok( () )
 	}
 }
 
 /// Structure representing a view into a BlockCache entry
-pub struct Block<'a>(::block_cache::BlockHandleRead<'a>, u16,u16);
+pub struct Block<'a>(BlockInner<'a>);
+enum BlockInner<'a>
+{
+	Cached(::block_cache::BlockHandleRead<'a>, u16,u16),
+	/// Blocks larger than a page span multiple cache entries, so are copied out
+	Owned(Box<[u32]>),
+}
 impl<'a> ::core::ops::Deref for Block<'a>
 {
 	type Target = [u32];
 	fn deref(&self) -> &[u32] {
-		let &Block(ref handle, ofs, size) = self;
-		// SAFE: Alignment should be good (but is checked anyway)
-		unsafe {
-			assert!(ofs as usize + size as usize <= handle.data().len());
-			assert!(ofs % 4 == 0);
-			assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
-			::core::slice::from_raw_parts(&handle.data()[ofs as usize] as *const u8 as *const u32, (size / 4) as usize)
+		match self.0
+		{
+		BlockInner::Cached(ref handle, ofs, size) => {
+			// SAFE: Alignment should be good (but is checked anyway)
+			unsafe {
+				assert!(ofs as usize + size as usize <= handle.data().len());
+				assert!(ofs % 4 == 0);
+				assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
+				::core::slice::from_raw_parts(&handle.data()[ofs as usize] as *const u8 as *const u32, (size / 4) as usize)
+			}
+			},
+		BlockInner::Owned(ref data) => data,
 		}
 	}
 }
@@ -267,17 +306,22 @@ impl InstanceInner
 	/// Obtain a block (using the block cache)
 	pub fn get_block(&self, block: u32) -> ::vfs::node::Result<Block<'_>>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
 		log_trace!("get_block({})", block);
 		let sector = block as u64 * self.vol_blocks_per_fs_block();
 
+		if self.fs_block_size > ::kernel::PAGE_SIZE {
+			// The block spans multiple cache entries, so copy it into a contiguous buffer
+			let h = ::kernel::futures::block_on(self.vol.get_blocks(sector, self.vol_blocks_per_fs_block() as usize))?;
+			let data: Vec<u32> = h.chunks()
+				.flat_map(|c| c.chunks_exact(4))
+				.map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]]))
+				.collect();
+			return Ok( Block(BlockInner::Owned(data.into_boxed_slice())) );
+		}
+
 		let ch = ::kernel::futures::block_on(self.vol.get_block(sector))?;
 		let ofs = (sector - ch.index()) as usize * self.vol.block_size();
-		Ok( Block(ch, ofs as u16, self.fs_block_size as u16) )
+		Ok( Block(BlockInner::Cached(ch, ofs as u16, self.fs_block_size as u16)) )
 	}
 
 	/// Edit a block in the cache using the provided closure
@@ -285,12 +329,8 @@ impl InstanceInner
 	where
 		F: FnOnce(&mut [u8]) -> ::vfs::node::Result<R>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
-		log_trace!("get_block({})", block);
+		log_trace!("edit_block({})", block);
+		// NOTE: If the block is larger than a page, the cache collects it into a temporary buffer
 		let sector = block as u64 * self.vol_blocks_per_fs_block();
 
 		::kernel::futures::block_on(self.vol.edit(sector, self.vol_blocks_per_fs_block() as usize, |data| {
@@ -322,147 +362,159 @@ impl InstanceInner
 	/// Read a sequence of blocks into a user-provided buffer
 	pub fn read_blocks(&self, first_block: u32, data: &mut [u8]) -> ::vfs::node::Result<()>
 	{
-		::kernel::futures::block_on( self.vol.read_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		::kernel::futures::block_on( self.vol.read_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
 		Ok( () )
 	}
 
 	/// Write a sequence of blocks from a user-provided buffer
 	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> ::vfs::node::Result<()>
 	{
-		// TODO: Requires maybe interfacing with the cache used by get_block?
-		::kernel::futures::block_on( self.vol.write_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		// NOTE: Goes via the cache layer, so any cached copy (e.g. if this was previously a metadata block) is updated
+		::kernel::futures::block_on( self.vol.write_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
 		Ok( () )
 	}
 }
 
+/// Block allocation
 impl InstanceInner
 {
+	/// Returns (group, index within group) for a block number
 	fn get_block_grp_id(&self, block_idx: u32) -> (u32, u32) {
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
-		(block_idx / s_blocks_per_group, block_idx % s_blocks_per_group)
+		let sb = self.superblock.read();
+		let rel = block_idx - sb.data.s_first_data_block;
+		(rel / sb.data.s_blocks_per_group, rel % sb.data.s_blocks_per_group)
+	}
+	/// Number of blocks in a group (the last group may be short)
+	fn get_group_block_count(&self, group: u32) -> u32 {
+		let sb = self.superblock.read();
+		let base = group * sb.data.s_blocks_per_group;
+		u32::min(sb.data.s_blocks_per_group, sb.data.s_blocks_count - sb.data.s_first_data_block - base)
+	}
+	fn num_groups(&self) -> u32 {
+		self.group_descriptors.read().len() as u32
 	}
+
 	/// Allocate a new data block
+	///
+	/// Prefers the block after `prev_block` (if non-zero), then the inode's group, then any other group.
 	pub fn allocate_data_block(&self, inode_num: u32, prev_block: u32) -> ::vfs::node::Result<u32> {
 		log_debug!("allocate_data_block(inode_num=I{}, prev_block=B{})", inode_num, prev_block);
-		let has_blocks = self.edit_superblock(|sb| {
-			if sb.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_64BIT) {
-				if sb.data.s_free_blocks_count == 0 && sb.ext.s_free_blocks_count_hi == 0 {
-					false
-				}
-				else {
-					let (new, overflow) = sb.data.s_free_blocks_count.overflowing_sub(1);
-					if overflow {
-						sb.ext.s_free_blocks_count_hi -= 1;
-					}
-					sb.data.s_free_blocks_count = new;
-					true
-				}
-			}
-			else {
-				if sb.data.s_free_blocks_count == 0 {
-					false
-				}
-				else {
-					sb.data.s_free_blocks_count -= 1;
-					true
-				}
-			}
-			})?;
-		if !has_blocks {
+		let _lh = self.alloc_lock.lock();
+		if self.superblock.read().data.s_free_blocks_count == 0 {
 			return Err(::vfs::Error::OutOfSpace);
 		}
-		if prev_block != 0 {
-			let (block_bg, _) = self.get_block_grp_id(prev_block);
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
-			// 1. Check within the same BG (telling it the previous block, so it can pick one near that)
-			if let Some(rv) = self.allocate_block_in_group(block_bg, prev_block)? {
-				return Ok(rv);
+
+		let inode_bg = self.get_inode_grp_id(inode_num).0;
+		let (first_bg, hint) = if prev_block != 0 {
+				let (bg, idx) = self.get_block_grp_id(prev_block);
+				(bg, idx + 1)
 			}
-			// 2. If the inode BG is different, then check in the inode's BG
-			if block_bg != inode_bg {
-				if let Some(rv) = self.allocate_block_in_group(inode_bg, 0)? {
-					return Ok(rv);
-				}
+			else {
+				(inode_bg, 0)
+			};
+		let num_groups = self.num_groups();
+		let order = ::core::iter::once(first_bg)
+			.chain( Some(inode_bg).filter(|&g| g != first_bg) )
+			.chain( (1 .. num_groups).map(|i| (first_bg + i) % num_groups).filter(|&g| g != inode_bg) )
+			;
+		for group in order
+		{
+			let (bmp_block, free) = {
+				let lh = self.group_descriptors.read();
+				let gd = &lh[group as usize];
+				(gd.bg_block_bitmap, gd.bg_free_blocks_count)
+				};
+			if free == 0 {
+				continue ;
 			}
-		}
-		else {
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
-			if let Some(rv) = self.allocate_block_in_group(inode_bg, 0)? {
+			let hint = if group == first_bg { hint } else { 0 };
+			match self.bitmap_alloc(bmp_block, self.get_group_block_count(group), hint)?
+			{
+			Some(bit) => {
+				self.edit_block_group_header(group, |bg| bg.bg_free_blocks_count -= 1)?;
+				self.edit_superblock(|sb| sb.data.s_free_blocks_count -= 1)?;
+				let rv = {
+					let sb = self.superblock.read();
+					sb.data.s_first_data_block + group * sb.data.s_blocks_per_group + bit
+					};
+				log_debug!("allocate_data_block: Allocate B{} (group {})", rv, group);
 				return Ok(rv);
+				},
+			None => {
+				log_error!("allocate_data_block: Group {} said that there were {} free blocks, but bitmap was full", group, free);
+				},
 			}
 		}
-		// Fallback: Otherwise, first available within the inodes BG
-		// Or, from a random block group
-		todo!("allocate_data_block(inode={}, prev_block={})", inode_num, prev_block);
-	}
-
-	fn allocate_block_in_group(&self, group: u32, prev_block: u32) -> ::vfs::node::Result<Option<u32>> {
-		let first_bmp_block = self.group_descriptors.read()[group as usize].bg_block_bitmap;
-		// Prefer allocating within a few blocks of the previous (ideally right after) - if non zero
-		if prev_block != 0 {
-			let next_block = prev_block + 1;
-			let (block_bg, block_subidx) = self.get_block_grp_id(next_block);
-			if block_bg == group {
-				// Edit the bitmap, check if this bit is clear
-				let bmp_mask = 1 << (block_subidx % 8);
-				let bmp_byte = block_subidx / 8;
-				let bmp_block = bmp_byte / self.fs_block_size as u32;
-				let bmp_byte = bmp_byte % self.fs_block_size as u32;
-				// If it is, then set it and decrement the (non-zero) free block count
-				if self.edit_block(first_bmp_block + bmp_block, |blk_data| {
-					if blk_data[bmp_byte as usize] & bmp_mask == 0 {
-						blk_data[bmp_byte as usize] |= bmp_mask;
-						Ok(true)
-					}
-					else {
-						Ok(false)
-					}
-					})? {
-					if !self.edit_block_group_header(block_bg, |bg| if bg.bg_free_blocks_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })? {
-						return Err(::vfs::Error::InconsistentFilesystem);
-					}
-					log_debug!("allocate_block_in_group(): return next B{}", next_block);
-					return Ok(Some(next_block));
-				}
-			}
-		}
+		log_error!("allocate_data_block: Superblock said there were free blocks, but no group had any");
+		Err(::vfs::Error::OutOfSpace)
+	}
 
-		// Decrement the block count, and then find an entry
-		if !self.edit_block_group_header(group, |bg| if bg.bg_free_inodes_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })?
-		{
-			return Ok(None);
+	/// Release a data block back to the free pool
+	pub fn free_data_block(&self, block: u32) -> ::vfs::node::Result<()> {
+		log_debug!("free_data_block(B{})", block);
+		let _lh = self.alloc_lock.lock();
+		let (group, idx) = self.get_block_grp_id(block);
+		let bmp_block = self.group_descriptors.read()[group as usize].bg_block_bitmap;
+		if !self.bitmap_free(bmp_block, idx)? {
+			log_error!("free_data_block: B{} was already free", block);
+			return Err(::vfs::Error::InconsistentFilesystem);
 		}
+		self.edit_block_group_header(group, |bg| bg.bg_free_blocks_count += 1)?;
+		self.edit_superblock(|sb| sb.data.s_free_blocks_count += 1)?;
+		Ok( () )
+	}
 
-		// Iterate the bitmap
-		let blocks_per_bmpblock = self.fs_block_size * 8;
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
-		for base in (0 .. s_blocks_per_group).step_by(blocks_per_bmpblock) {
-			// Number of inodes in this bitmap block (might be fewer, if the group size is small)
-			let n_blocks = (s_blocks_per_group - base).min(blocks_per_bmpblock as u32);
-			let n_bytes = ::kernel::lib::num::div_up(n_blocks, 8) as usize;
-			let rv = self.edit_block(first_bmp_block + base / blocks_per_bmpblock as u32, |blk_data| {
-				Ok(match blk_data[..n_bytes].iter().position(|&v| v != !0)
+	/// Locate and set a clear bit in a bitmap, starting at `hint` (and wrapping around)
+	fn bitmap_alloc(&self, first_bmp_block: u32, n_bits: u32, hint: u32) -> ::vfs::node::Result<Option<u32>> {
+		let hint = if hint < n_bits { hint } else { 0 };
+		if let Some(rv) = self.bitmap_alloc_range(first_bmp_block, hint, n_bits)? {
+			return Ok(Some(rv));
+		}
+		self.bitmap_alloc_range(first_bmp_block, 0, hint)
+	}
+	fn bitmap_alloc_range(&self, first_bmp_block: u32, start: u32, end: u32) -> ::vfs::node::Result<Option<u32>> {
+		let bits_per_block = self.fs_block_size as u32 * 8;
+		let mut base = start - start % bits_per_block;
+		while base < end
+		{
+			let first = u32::max(start, base) - base;
+			let last = u32::min(end - base, bits_per_block);
+			let rv = self.edit_block(first_bmp_block + base / bits_per_block, |blk_data| {
+				let mut bit = first;
+				while bit < last
 				{
-				None => None,
-				Some(p) => {
-					let bit = blk_data[p].trailing_ones();
-					blk_data[p] |= 1 << bit;
-					Some(p as u32 * 8 + bit)
-					},
-				})
-				})?;
-			if let Some(rel_block_id) = rv {
-				// Check for the edge case where the returned ID is outside the expected bounds
-				if rel_block_id >= n_blocks {
-					break
+					let byte = &mut blk_data[(bit / 8) as usize];
+					if *byte == 0xFF {
+						bit = (bit | 7) + 1;
+					}
+					else if *byte & (1 << (bit % 8)) == 0 {
+						*byte |= 1 << (bit % 8);
+						return Ok(Some(base + bit));
+					}
+					else {
+						bit += 1;
+					}
 				}
-				let rv = group * s_blocks_per_group + base + rel_block_id;
-				log_debug!("allocate_block_in_bg({}) Allocate B{}", group, rv);
-				return Ok(Some(rv));
+				Ok(None)
+				})?;
+			if rv.is_some() {
+				return Ok(rv);
 			}
+			base += bits_per_block;
 		}
-		log_error!("allocate_block_in_group: Descriptor said that there were free blocks, but bitmap was full.");
-		Err(::vfs::Error::InconsistentFilesystem)
+		Ok(None)
+	}
+	/// Clear a bit in a bitmap, returning `false` if it was already clear
+	fn bitmap_free(&self, first_bmp_block: u32, bit: u32) -> ::vfs::node::Result<bool> {
+		let bits_per_block = self.fs_block_size as u32 * 8;
+		let (blk, bit) = (bit / bits_per_block, bit % bits_per_block);
+		self.edit_block(first_bmp_block + blk, |blk_data| {
+			let byte = &mut blk_data[(bit / 8) as usize];
+			let mask = 1 << (bit % 8);
+			let was_set = *byte & mask != 0;
+			*byte &= !mask;
+			Ok(was_set)
+			})
 	}
 }
 
@@ -474,22 +526,34 @@ impl InstanceInner
 		if self.vol.block_size() > 1024 {
 			::kernel::futures::block_on(self.vol.edit(0, 1, |data| {
 				let data = &mut data[1024..][..1024];
-				lh.write_to_slice(data);
+				Self::write_superblock_to(&mut lh, data);
 				}))?;
 		}
 		else {
 			::kernel::futures::block_on(self.vol.edit(1024 / self.vol.block_size() as u64, 1024 / self.vol.block_size(), |data| {
-				lh.write_to_slice(data);
+				Self::write_superblock_to(&mut lh, &mut data[..1024]);
 				}))?;
 		}
 		Ok(rv)
 	}
+	/// Serialise the superblock, updating the checksum if FEAT_RO_COMPAT_METADATA_CSUM is set
+	fn write_superblock_to(sb: &mut crate::ondisk::Superblock, data: &mut [u8]) {
+		const CSUM_OFS: usize = 1024 - 4;
+		sb.write_to_slice(data);
+		if sb.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_METADATA_CSUM) {
+			sb.s_checksum = ::kernel::lib::crc::CASTAGNOLI.update(!0, &data[..CSUM_OFS]);
+			data[CSUM_OFS..][..4].copy_from_slice(&sb.s_checksum.to_le_bytes());
+		}
+	}
 	fn edit_block_group_header<R>(&self, idx: u32, cb: impl FnOnce(&mut crate::ondisk::GroupDesc)->R) -> ::vfs::node::Result<R> {
+		const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
 		let mut lh = self.group_descriptors.write();
 		let rv = cb(&mut lh[idx as usize]);
-		let ofs = 1024 + idx as usize * ::core::mem::size_of::<::ondisk::GroupDesc>();
+		// The descriptor table is in the filesystem block after the superblock
+		// - Only the low half is written, the upper halves of the counts are always zero
+		let ofs = usize::max(2*1024, self.fs_block_size) + idx as usize * self.group_desc_size;
 		::kernel::futures::block_on(self.vol.edit( (ofs / self.vol.block_size()) as u64, 1, |data| {
-			let buf = &mut data[ofs % self.vol.block_size()..][..::core::mem::size_of::<::ondisk::GroupDesc>()];
+			let buf = &mut data[ofs % self.vol.block_size()..][..GROUP_DESC_SIZE];
 			lh[idx as usize].write_to_slice(buf);
 			}))?;
 		Ok(rv)
@@ -529,102 +593,118 @@ impl InstanceInner
 		// - This prevents us from having to maintain our own node cache
 
 		let node = try!(self.mount_handle.get_node(inode_num as ::vfs::node::InodeId));
-		match node.get_node_any().downcast_ref()
-		{
-		Some(our_in) => fcn(our_in),
-		None => Err(::vfs::Error::Unknown("BUG: Node wasn't an extN inode")),
+		let any = node.get_node_any();
+		if let Some(f) = any.downcast_ref::<::file::File>() {
+			fcn(f.inode())
+		}
+		else if let Some(d) = any.downcast_ref::<::dir::Dir>() {
+			fcn(d.inode())
+		}
+		else {
+			Err(::vfs::Error::Unknown("BUG: Node wasn't an extN inode"))
 		}
 	}
 
 	/// Allocate a new inode number, possibly in the same block group as `parent_inode_num`.
-	pub fn allocate_inode(&self, parent_inode_num: u32, nodetype: ::vfs::node::NodeType) -> ::vfs::node::Result< u32 >
+	///
+	/// The new inode is written to disk with the requested type and link count (but no data blocks)
+	pub fn allocate_inode(&self, parent_inode_num: u32, i_mode: u16, i_links_count: u16) -> ::vfs::node::Result< u32 >
 	{
-		// TODO: Update the superblock
-		let has_inodes = self.edit_superblock(|sb| {
-			if sb.data.s_free_inodes_count == 0 {
-				false
-			}
-			else {
-				sb.data.s_free_inodes_count -= 1;
-				true
-			}
-			})?;
-		if !has_inodes {
-			return Err(::vfs::Error::OutOfSpace);
-		}
-
 		assert!(parent_inode_num != 0);	// Has to be a parent - root exists
-		let (grp, _idx) = self.get_inode_grp_id(parent_inode_num);
+		let is_dir = i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFDIR;
+		let rv = {
+			let _lh = self.alloc_lock.lock();
+			if self.superblock.read().data.s_free_inodes_count == 0 {
+				return Err(::vfs::Error::OutOfSpace);
+			}
 
-		let rv = if let Some(rv) = self.allocate_inode_in_bg(grp, nodetype)? {
-				rv
+			// Search the parent's group first, then all others
+			let (parent_grp, _idx) = self.get_inode_grp_id(parent_inode_num);
+			let num_groups = self.num_groups();
+			let mut rv = None;
+			for grp in (0 .. num_groups).map(|i| (parent_grp + i) % num_groups)
+			{
+				if let Some(v) = self.allocate_inode_in_bg(grp, is_dir)? {
+					rv = Some(v);
+					break;
+				}
+			}
+			match rv
+			{
+			Some(v) => v,
+			None => {
+				log_error!("allocate_inode: Superblock said there were free inodes, but no group had any");
+				return Err(::vfs::Error::OutOfSpace);
+				},
 			}
-			else {
-				todo!("InstanceInner::allocate_inode - Search for any BG");
 			};
 
-		self.write_inode(rv, &crate::ondisk::Inode {
-			i_mode: match nodetype
-				{
-				::vfs::node::NodeType::File => ::ondisk::S_IFREG,
-				_ => todo!(""),
-				},
+		let mut inode = crate::ondisk::Inode {
+			i_mode: i_mode,
+			i_links_count: i_links_count,
 			..Default::default()
-			})?;
+			};
+		// Files and directories use extents when supported (symlinks may be stored inline)
+		let fmt = i_mode & ::ondisk::S_IFMT;
+		if self.has_feature_incompat(::ondisk::FEAT_INCOMPAT_EXTENTS) && (fmt == ::ondisk::S_IFREG || fmt == ::ondisk::S_IFDIR) {
+			::extents::init_root(&mut inode);
+		}
+		self.init_inode(rv, &inode)?;
 
 		Ok(rv)
 	}
-	fn allocate_inode_in_bg(&self, grp: u32, _nodetype: ::vfs::node::NodeType) -> ::vfs::node::Result< Option<u32> > {
-		// NOTE: Check with read-only first, and only read-modify-write if the read-only check passed
-		if self.group_descriptors.read()[grp as usize].bg_free_inodes_count == 0 {
-			return Ok(None);
+	/// Release an inode (must have already had its data freed)
+	pub fn free_inode(&self, inode_num: u32, is_dir: bool) -> ::vfs::node::Result<()>
+	{
+		log_debug!("free_inode(I{}, is_dir={})", inode_num, is_dir);
+		// Clear the on-disk inode (a zero mode marks it as unused to fsck)
+		self.init_inode(inode_num, &Default::default())?;
+
+		let _lh = self.alloc_lock.lock();
+		let (grp, idx) = self.get_inode_grp_id(inode_num);
+		let bmp_block = self.group_descriptors.read()[grp as usize].bg_inode_bitmap;
+		if !self.bitmap_free(bmp_block, idx)? {
+			log_error!("free_inode: I{} was already free", inode_num);
+			return Err(::vfs::Error::InconsistentFilesystem);
 		}
-		let Some(first_bmp_block) = self.edit_block_group_header(grp, |gd|
+		self.edit_block_group_header(grp, |gd| {
+			gd.bg_free_inodes_count += 1;
+			if is_dir {
+				gd.bg_used_dirs_count -= 1;
+			}
+			})?;
+		self.edit_superblock(|sb| sb.data.s_free_inodes_count += 1)?;
+		Ok( () )
+	}
+	/// Allocate an inode within a specific group (caller holds `alloc_lock`)
+	fn allocate_inode_in_bg(&self, grp: u32, is_dir: bool) -> ::vfs::node::Result< Option<u32> > {
+		let first_bmp_block = {
+			let lh = self.group_descriptors.read();
+			let gd = &lh[grp as usize];
 			if gd.bg_free_inodes_count == 0 {
-				// This should only be hit if there is a race
-				None
+				return Ok(None);
 			}
-			else {
-				gd.bg_free_inodes_count -= 1;
-				Some(gd.bg_inode_bitmap)
-			})? else {
-			return Ok(None);
-		};
+			gd.bg_inode_bitmap
+			};
 
-		// Start the bitmap check
-		let inodes_per_block = self.fs_block_size * 8;
 		let s_inodes_per_group = self.superblock.read().s_inodes_per_group();
-		for base in (0 .. s_inodes_per_group).step_by(inodes_per_block) {
-			// Number of inodes in this bitmap block (might be fewer, if the group size is small)
-			let n_inodes = (s_inodes_per_group - base).min(inodes_per_block as u32);
-			let n_bytes = ::kernel::lib::num::div_up(n_inodes, 8) as usize;
-			let rv = self.edit_block(first_bmp_block + base / inodes_per_block as u32, |blk_data| {
-				Ok(match blk_data[..n_bytes].iter().position(|&v| v != !0)
-				{
-				None => None,
-				Some(p) => {
-					let bit = blk_data[p].trailing_ones();
-					blk_data[p] |= 1 << bit;
-					Some(p as u32 * 8 + bit)
-					},
-				})
-				})?;
-			if let Some(rel_inode_id) = rv {
-				// Check for the edge case where the returned ID is outside the expected bounds (could happen if s_inodes_per_group is
-				// not a multiple of 32)
-				if rel_inode_id >= n_inodes {
-					break
-				}
-				let rv = 1 + grp * s_inodes_per_group + base + rel_inode_id;
-				log_debug!("allocate_inode_in_bg({}) Allocate I{}", grp, rv);
-				assert!(rv > 2);
-				return Ok(Some(rv));
+		let Some(rel_inode_id) = self.bitmap_alloc(first_bmp_block, s_inodes_per_group, 0)? else {
+			log_error!("allocate_inode_in_bg: Descriptor said that there were free inodes, but bitmap was full.");
+			return Ok(None);
+		};
+		self.edit_block_group_header(grp, |gd| {
+			gd.bg_free_inodes_count -= 1;
+			if is_dir {
+				gd.bg_used_dirs_count += 1;
 			}
-		}
-		log_error!("allocate_inode_in_bg: Descriptor said that there were free inodes, but bitmap was full.");
-		Err(::vfs::Error::InconsistentFilesystem)
-	}
+			})?;
+		self.edit_superblock(|sb| sb.data.s_free_inodes_count -= 1)?;
 
+		let rv = 1 + grp * s_inodes_per_group + rel_inode_id;
+		log_debug!("allocate_inode_in_bg({}) Allocate I{}", grp, rv);
+		assert!(rv > 2);
+		Ok(Some(rv))
+	}
 	/// Read an inode descriptor from the disk
 	pub fn read_inode(&self, inode_num: u32) -> ::vfs::Result< ::ondisk::Inode >
 	{
@@ -646,6 +726,29 @@ impl InstanceInner
 		log_trace!("- rv={:?}", rv);
 		Ok( rv )
 	}
+	/// Write a new inode to the disk, clearing the extra fields of large inodes
+	fn init_inode(&self, inode_num: u32, inode_data: &::ondisk::Inode) -> ::vfs::Result< () >
+	{
+		const BASE_SIZE: usize = ::core::mem::size_of::<::ondisk::Inode>();
+		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
+
+		let s_inode_size = self.superblock.read().s_inode_size();
+		::kernel::futures::block_on(self.vol.edit(vol_block, 1, |data| {
+			let data = &mut data[blk_ofs..][..s_inode_size];
+			data.fill(0);
+			{
+				let mut slice = &mut data[..];
+				let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut slice);
+			}
+			// Set `i_extra_isize` for used inodes (so the extra timestamp fields are valid)
+			if s_inode_size > BASE_SIZE && inode_data.i_mode != 0 {
+				let extra_isize = usize::min(::ondisk::INODE_EXTRA_SIZE, s_inode_size - BASE_SIZE) as u16;
+				data[BASE_SIZE..][..2].copy_from_slice(&extra_isize.to_le_bytes());
+			}
+			}))?;
+
+		Ok( () )
+	}
 	/// Write an inode descriptor back to the disk
 	pub fn write_inode(&self, inode_num: u32, inode_data: &::ondisk::Inode) -> ::vfs::Result< () >
 	{
@@ -668,13 +771,18 @@ impl InstanceInner
 		(self.fs_block_size / self.vol.block_size()) as u64
 	}
 
-	#[allow(dead_code)]
 	pub fn has_feature_incompat(&self, feat: u32) -> bool {
 		self.superblock.read().has_feature_incompat(feat)
 	}
 	pub fn has_feature_ro_compat(&self, feat: u32) -> bool {
 		self.superblock.read().has_feature_ro_compat(feat)
 	}
+
+	/// Directory index hash parameters: (seed, use unsigned chars)
+	pub fn dir_hash_params(&self) -> ([u32; 4], bool) {
+		let sb = self.superblock.read();
+		(sb.ext.s_hash_seed, sb.ext.s_flags & ::ondisk::EXT2_FLAGS_UNSIGNED_HASH != 0)
+	}
 }
 
 
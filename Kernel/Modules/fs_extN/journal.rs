@@ -0,0 +1,382 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/journal.rs
+//! ext3/ext4 journal (jbd2) replay
+//!
+//! Only recovery is supported, this driver doesn't write transactions to the journal.
+use kernel::prelude::*;
+use kernel::lib::crc::CASTAGNOLI;
+use instance::InstancePtr;
+
+const JBD2_MAGIC: u32 = 0xC03B3998;
+
+const BT_DESCRIPTOR: u32 = 1;
+const BT_COMMIT: u32 = 2;
+const BT_SUPERBLOCK_V1: u32 = 3;
+const BT_SUPERBLOCK_V2: u32 = 4;
+const BT_REVOKE: u32 = 5;
+
+const FEAT_INCOMPAT_REVOKE: u32 = 0x1;
+const FEAT_INCOMPAT_64BIT: u32 = 0x2;
+const FEAT_INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+const FEAT_INCOMPAT_CSUM_V2: u32 = 0x8;
+const FEAT_INCOMPAT_CSUM_V3: u32 = 0x10;
+/// Incompatible journal features that are understood (e.g. FEAT_INCOMPAT_FAST_COMMIT isn't)
+const SUPPORTED_INCOMPAT: u32 = FEAT_INCOMPAT_REVOKE | FEAT_INCOMPAT_64BIT | FEAT_INCOMPAT_ASYNC_COMMIT | FEAT_INCOMPAT_CSUM_V2 | FEAT_INCOMPAT_CSUM_V3;
+
+// Descriptor block tag flags
+const TAG_FLAG_ESCAPE: u32 = 0x1;
+const TAG_FLAG_SAME_UUID: u32 = 0x2;
+const TAG_FLAG_LAST_TAG: u32 = 0x8;
+
+/// Size of the block header (magic, type, sequence)
+const HEADER_SIZE: usize = 12;
+/// Offset of `s_checksum` in the journal superblock
+const SB_CHECKSUM_OFS: usize = 0xFC;
+/// Size of the journal superblock structure (used for the checksum)
+const SB_SIZE: usize = 1024;
+
+fn be32(d: &[u8], ofs: usize) -> u32 {
+	u32::from_be_bytes([d[ofs], d[ofs+1], d[ofs+2], d[ofs+3]])
+}
+fn be16(d: &[u8], ofs: usize) -> u16 {
+	u16::from_be_bytes([d[ofs], d[ofs+1]])
+}
+
+/// Result of opening the journal
+pub enum JournalState
+{
+	/// No recovery was needed
+	Clean,
+	/// Transactions were replayed (on-disk metadata has changed)
+	Replayed,
+	/// The journal can't be handled, the filesystem should only be mounted read-only
+	Unsupported,
+}
+
+/// Journal superblock
+struct Superblock
+{
+	blocksize: u32,
+	maxlen: u32,
+	first: u32,
+	sequence: u32,
+	start: u32,
+	feature_incompat: u32,
+	uuid: [u8; 16],
+}
+
+/// An open (external inode) journal
+struct Journal<'a>
+{
+	fs: &'a InstancePtr,
+	inode: ::inodes::Inode,
+	sb: Superblock,
+	/// Checksum seed (CRC32C of the journal UUID), for FEAT_INCOMPAT_CSUM_V2/V3
+	csum_seed: Option<u32>,
+}
+
+/// Check the journal, replaying any committed transactions
+///
+/// `needs_recovery` is from FEAT_INCOMPAT_RECOVER in the filesystem superblock
+pub fn check_and_replay(fs: &InstancePtr, journal_inum: u32, needs_recovery: bool) -> ::vfs::Result<JournalState>
+{
+	let inode = ::inodes::Inode::from_id(fs.clone(), journal_inum)?;
+	let mut j = Journal {
+		fs: fs,
+		inode: inode,
+		sb: Superblock { blocksize: 0, maxlen: 0, first: 0, sequence: 0, start: 0, feature_incompat: 0, uuid: [0; 16] },
+		csum_seed: None,
+		};
+	let sb_data = j.read_block(0)?;
+	if be32(&sb_data, 0) != JBD2_MAGIC {
+		log_warning!("Journal superblock has bad magic {:#x}", be32(&sb_data, 0));
+		return Ok(JournalState::Unsupported);
+	}
+	let version = be32(&sb_data, 4);
+	j.sb = Superblock {
+		blocksize: be32(&sb_data, 0x0C),
+		maxlen: be32(&sb_data, 0x10),
+		first: be32(&sb_data, 0x14),
+		sequence: be32(&sb_data, 0x18),
+		start: be32(&sb_data, 0x1C),
+		feature_incompat: if version == BT_SUPERBLOCK_V2 { be32(&sb_data, 0x28) } else { 0 },
+		uuid: { let mut v = [0; 16]; v.copy_from_slice(&sb_data[0x30..][..16]); v },
+		};
+	log_debug!("Journal: v{} bs={} maxlen={} first={} seq={} start={} incompat={:#x}",
+		if version == BT_SUPERBLOCK_V1 { 1 } else { 2 },
+		j.sb.blocksize, j.sb.maxlen, j.sb.first, j.sb.sequence, j.sb.start, j.sb.feature_incompat);
+	if version != BT_SUPERBLOCK_V1 && version != BT_SUPERBLOCK_V2 {
+		log_warning!("Journal superblock has unknown type {}", version);
+		return Ok(JournalState::Unsupported);
+	}
+	if j.sb.feature_incompat & !SUPPORTED_INCOMPAT != 0 {
+		log_warning!("Journal uses unsupported features {:#x}", j.sb.feature_incompat & !SUPPORTED_INCOMPAT);
+		return Ok(JournalState::Unsupported);
+	}
+	if j.sb.blocksize as usize != fs.fs_block_size || j.sb.first == 0 || j.sb.first >= j.sb.maxlen {
+		log_warning!("Journal geometry invalid (bs={}, first={}, maxlen={})", j.sb.blocksize, j.sb.first, j.sb.maxlen);
+		return Ok(JournalState::Unsupported);
+	}
+	if j.has_csum() {
+		j.csum_seed = Some( CASTAGNOLI.update(!0, &j.sb.uuid) );
+	}
+
+	if j.sb.start == 0 {
+		if needs_recovery {
+			log_notice!("Journal is empty, but the filesystem was flagged as needing recovery");
+		}
+		return Ok(JournalState::Clean);
+	}
+
+	// Pass 1: Locate the end of the log, and collect revoke records
+	let mut revoked = ::kernel::lib::collections::VecMap::new();
+	let end_seq = j.scan(&mut revoked)?;
+	log_log!("Journal: Replaying transactions {} to {}", j.sb.sequence, end_seq);
+	// Pass 2: Write the logged blocks to their final locations
+	let n_blocks = j.replay(end_seq, &revoked)?;
+	log_log!("Journal: Replayed {} blocks", n_blocks);
+
+	// Mark the journal as empty
+	// - Skip a sequence number, so stale blocks from the last transaction aren't mistaken for a new one
+	j.sb.sequence = end_seq.wrapping_add(1);
+	j.sb.start = 0;
+	j.write_superblock(sb_data)?;
+	Ok(JournalState::Replayed)
+}
+
+impl<'a> Journal<'a>
+{
+	fn has_csum(&self) -> bool {
+		self.sb.feature_incompat & (FEAT_INCOMPAT_CSUM_V2 | FEAT_INCOMPAT_CSUM_V3) != 0
+	}
+	fn has_64bit(&self) -> bool {
+		self.sb.feature_incompat & FEAT_INCOMPAT_64BIT != 0
+	}
+
+	fn read_block(&self, idx: u32) -> ::vfs::Result<Box<[u8]>> {
+		let blk = self.inode.lock_read().get_block_addr(idx)?;
+		if blk == 0 {
+			log_error!("Journal block {} isn't allocated", idx);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		self.fs.get_block_uncached(blk)
+	}
+	/// Advance a log position, wrapping at the end of the journal
+	fn next_pos(&self, pos: u32) -> u32 {
+		if pos + 1 >= self.sb.maxlen { self.sb.first } else { pos + 1 }
+	}
+
+	/// Size of a descriptor block tag (excluding the optional UUID)
+	fn tag_size(&self) -> usize {
+		if self.sb.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0 {
+			16
+		}
+		else {
+			let base = if self.sb.feature_incompat & FEAT_INCOMPAT_CSUM_V2 != 0 { 12 + 2 } else { 12 };
+			if self.has_64bit() { base } else { base - 4 }
+		}
+	}
+	/// Check the checksum of a block with a checksum field (which is zeroed for the calculation)
+	fn check_block_csum(&self, data: &[u8], csum_ofs: usize) -> bool {
+		match self.csum_seed
+		{
+		None => true,
+		Some(seed) => {
+			let expected = be32(data, csum_ofs);
+			let crc = CASTAGNOLI.update(seed, &data[..csum_ofs]);
+			let crc = CASTAGNOLI.update(crc, &[0; 4]);
+			let crc = CASTAGNOLI.update(crc, &data[csum_ofs+4..]);
+			crc == expected
+			},
+		}
+	}
+	/// Read the header of a log block, returning the block type (or `None` if it's not the expected sequence number)
+	fn read_header(&self, data: &[u8], seq: u32) -> Option<u32> {
+		if be32(data, 0) != JBD2_MAGIC || be32(data, 8) != seq {
+			None
+		}
+		else {
+			Some(be32(data, 4))
+		}
+	}
+
+	/// Parse the tags in a descriptor block, returning (target block, flags, checksum) for each data block
+	fn parse_tags(&self, data: &[u8]) -> Vec<(u64, u32, u32)> {
+		let tag_size = self.tag_size();
+		let end = if self.has_csum() { data.len() - 4 } else { data.len() };
+		let is_v3 = self.sb.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0;
+		let mut rv = Vec::new();
+		let mut ofs = HEADER_SIZE;
+		while ofs + tag_size <= end
+		{
+			let t = &data[ofs..][..tag_size];
+			let lo = be32(t, 0) as u64;
+			let (flags, csum, hi) = if is_v3 {
+					(be32(t, 4), be32(t, 12), if self.has_64bit() { be32(t, 8) } else { 0 })
+				}
+				else {
+					(be16(t, 6) as u32, be16(t, 4) as u32, if self.has_64bit() { be32(t, 8) } else { 0 })
+				};
+			rv.push( (lo | (hi as u64) << 32, flags, csum) );
+			ofs += tag_size;
+			if flags & TAG_FLAG_SAME_UUID == 0 {
+				ofs += 16;
+			}
+			if flags & TAG_FLAG_LAST_TAG != 0 {
+				break;
+			}
+		}
+		rv
+	}
+
+	/// Scan the log, returning the sequence number after the last committed transaction
+	fn scan(&self, revoked: &mut ::kernel::lib::collections::VecMap<u64, u32>) -> ::vfs::Result<u32>
+	{
+		let mut pos = self.sb.start;
+		let mut seq = self.sb.sequence;
+		// Revoke records are only used if their transaction was committed
+		let mut pending_revokes: Vec<u64> = Vec::new();
+		loop
+		{
+			let data = self.read_block(pos)?;
+			let Some(ty) = self.read_header(&data, seq) else {
+				break;
+			};
+			match ty
+			{
+			BT_DESCRIPTOR => {
+				if self.has_csum() && !self.check_block_csum(&data, data.len() - 4) {
+					log_notice!("Journal: Descriptor block checksum mismatch (seq {})", seq);
+					break;
+				}
+				for _ in self.parse_tags(&data) {
+					pos = self.next_pos(pos);
+				}
+				},
+			BT_COMMIT => {
+				// The first checksum slot is at offset 16 (after the type/size bytes)
+				if self.has_csum() && !self.check_block_csum(&data, 16) {
+					log_notice!("Journal: Commit block checksum mismatch (seq {})", seq);
+					break;
+				}
+				for b in pending_revokes.drain(..) {
+					let ent = revoked.entry(b).or_insert(seq);
+					if *ent < seq {
+						*ent = seq;
+					}
+				}
+				seq = seq.wrapping_add(1);
+				},
+			BT_REVOKE => {
+				if self.has_csum() && !self.check_block_csum(&data, data.len() - 4) {
+					log_notice!("Journal: Revoke block checksum mismatch (seq {})", seq);
+					break;
+				}
+				let count = usize::min(be32(&data, HEADER_SIZE) as usize, data.len());
+				let rec_size = if self.has_64bit() { 8 } else { 4 };
+				let mut ofs = HEADER_SIZE + 4;
+				while ofs + rec_size <= count {
+					let b = if rec_size == 8 {
+							(be32(&data, ofs) as u64) << 32 | be32(&data, ofs+4) as u64
+						}
+						else {
+							be32(&data, ofs) as u64
+						};
+					pending_revokes.push(b);
+					ofs += rec_size;
+				}
+				},
+			_ => {
+				log_notice!("Journal: Unexpected block type {} at {}", ty, pos);
+				break;
+				},
+			}
+			pos = self.next_pos(pos);
+		}
+		Ok(seq)
+	}
+
+	/// Replay all transactions before `end_seq`, returning the number of blocks written
+	fn replay(&self, end_seq: u32, revoked: &::kernel::lib::collections::VecMap<u64, u32>) -> ::vfs::Result<usize>
+	{
+		let mut pos = self.sb.start;
+		let mut seq = self.sb.sequence;
+		let mut count = 0;
+		while seq != end_seq
+		{
+			let data = self.read_block(pos)?;
+			let Some(ty) = self.read_header(&data, seq) else {
+				log_error!("Journal: Log changed between passes?");
+				return Err(::vfs::Error::InconsistentFilesystem);
+			};
+			match ty
+			{
+			BT_DESCRIPTOR => {
+				for (target, flags, csum) in self.parse_tags(&data)
+				{
+					pos = self.next_pos(pos);
+					let mut blk_data = self.read_block(pos)?;
+					if let Some(&rev_seq) = revoked.get(&target) {
+						if rev_seq >= seq {
+							log_trace!("Journal: Block {} revoked (seq {} <= {})", target, seq, rev_seq);
+							continue ;
+						}
+					}
+					if !self.check_data_csum(seq, &blk_data, csum) {
+						log_warning!("Journal: Data block for {} failed checksum (seq {}), skipping", target, seq);
+						continue ;
+					}
+					if flags & TAG_FLAG_ESCAPE != 0 {
+						blk_data[..4].copy_from_slice(&JBD2_MAGIC.to_be_bytes());
+					}
+					if target >= 1 << 32 {
+						log_error!("Journal: Target block {:#x} is out of range", target);
+						return Err(::vfs::Error::InconsistentFilesystem);
+					}
+					log_trace!("Journal: Write block {} (seq {})", target, seq);
+					self.fs.write_blocks(target as u32, &blk_data)?;
+					count += 1;
+				}
+				},
+			BT_COMMIT => {
+				seq = seq.wrapping_add(1);
+				},
+			_ => {},
+			}
+			pos = self.next_pos(pos);
+		}
+		Ok(count)
+	}
+	fn check_data_csum(&self, seq: u32, data: &[u8], csum: u32) -> bool {
+		match self.csum_seed
+		{
+		None => true,
+		Some(seed) => {
+			let crc = CASTAGNOLI.update(seed, &seq.to_be_bytes());
+			let crc = CASTAGNOLI.update(crc, data);
+			if self.sb.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0 {
+				crc == csum
+			}
+			else {
+				crc & 0xFFFF == csum
+			}
+			},
+		}
+	}
+
+	/// Write back the superblock (with updated `s_sequence` and `s_start`)
+	fn write_superblock(&self, mut data: Box<[u8]>) -> ::vfs::Result<()>
+	{
+		data[0x18..][..4].copy_from_slice(&self.sb.sequence.to_be_bytes());
+		data[0x1C..][..4].copy_from_slice(&self.sb.start.to_be_bytes());
+		if self.has_csum() {
+			data[SB_CHECKSUM_OFS..][..4].copy_from_slice(&[0; 4]);
+			let crc = CASTAGNOLI.update(!0, &data[..SB_SIZE]);
+			data[SB_CHECKSUM_OFS..][..4].copy_from_slice(&crc.to_be_bytes());
+		}
+		let blk = self.inode.lock_read().get_block_addr(0)?;
+		self.fs.write_blocks(blk, &data)
+	}
+}
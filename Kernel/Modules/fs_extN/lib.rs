@@ -20,6 +20,9 @@ module_define!{FS_EXTN, [VFS], init}
 
 mod ondisk;
 mod inodes;
+mod extents;
+mod htree;
+mod journal;
 
 mod dir;
 mod file;
@@ -34,17 +37,36 @@ fn init()
 const SUPPORTED_OPT_FEATURES: u32 = 0
 	| ::ondisk::FEAT_COMPAT_EXT_ATTR	// Extended attributes
 	| ::ondisk::FEAT_COMPAT_RESIZE_INODE	// Extra space was allocated for resizing the filesystem
+	| ::ondisk::FEAT_COMPAT_DIR_INDEX	// Hashed directory indexes (cleared from a directory when it's modified)
+	| ::ondisk::FEAT_COMPAT_HAS_JOURNAL	// Journal (replayed on mount, not used for writes)
 	;
 /// Read-only features: Missing features stop write support
 const SUPPORTED_RDO_FEATURES: u32 = 0
 	| ::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER	// Enables storing SB backups at group 0, 3^n, 5^n, and 7^n
 	| ::ondisk::FEAT_RO_COMPAT_LARGE_FILE	// 64-bit file sizes (in a separate inode field)
+	| ::ondisk::FEAT_RO_COMPAT_HUGE_FILE	// `i_blocks` can be in filesystem block units (EXT4_HUGE_FILE_FL)
+	| ::ondisk::FEAT_RO_COMPAT_DIR_NLINK	// Directory link counts of 1 mean "too many to count"
+	| ::ondisk::FEAT_RO_COMPAT_EXTRA_ISIZE	// Reserved space in large inodes (cleared for new inodes)
 	;
 /// Required Features: Missing features prevent mounting
 const SUPPORTED_REQ_FEATURES: u32 = 0
 	| ::ondisk::FEAT_INCOMPAT_FILETYPE	// DirEnt.d_name_len restricted to 1 byte and extra byte used for file type
+	| ::ondisk::FEAT_INCOMPAT_EXTENTS	// Files can use extent trees instead of block maps
+	| ::ondisk::FEAT_INCOMPAT_64BIT	// Larger group descriptors (block numbers must still fit in 32 bits)
+	| ::ondisk::FEAT_INCOMPAT_FLEX_BG	// Group metadata can be located in other groups
+	| ::ondisk::FEAT_INCOMPAT_CSUM_SEED	// Checksum seed in the superblock (checksums are not checked)
+	| ::ondisk::FEAT_INCOMPAT_RECOVER	// Journal needs replaying (done on mount)
 	;
 
+/// View a block buffer as 32-bit words
+fn as_u32_slice(blk_data: &mut [u8]) -> &mut [u32] {
+	// SAFE: Alignment checked, range valid
+	unsafe {
+		assert!(&blk_data[0] as *const _ as usize % 4 == 0);
+		::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
+	}
+}
+
 static S_DRIVER: Driver = Driver;
 struct Driver;
 
@@ -79,7 +101,7 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
 		Ok( try!(instance::Instance::new_boxed(vol, mounthandle)) )
 	}
 }
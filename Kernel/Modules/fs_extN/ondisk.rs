@@ -52,12 +52,12 @@ impl Superblock
 	}
 
 	pub fn s_group_desc_size(&self) -> usize {
-		//if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
-		//	self.ext.s_group_desc_size as usize
-		//}
-		//else {
+		if self.has_feature_incompat(FEAT_INCOMPAT_64BIT) {
+			self.ext.s_desc_size as usize
+		}
+		else {
 			32
-		//}
+		}
 	}
 }
 def_from_slice!{ Superblock }
@@ -325,7 +325,16 @@ pub const S_IROTH: u16 =  0o004;	// Global Read
 pub const S_IWOTH: u16 =  0o002;	// Global Write
 pub const S_IXOTH: u16 =  0o001;	// Global Execute
 
-pub const EXT4_INDEX_FL: u16 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_INDEX_FL: u32 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_HUGE_FILE_FL: u32 = 0x40000;	// i_flags: `i_blocks` is in units of filesystem blocks
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;	// i_flags: `i_block` contains an extent tree
+
+/// Size of the fields in `InodeExtra` (used as `i_extra_isize` for new inodes)
+pub const INODE_EXTRA_SIZE: usize = 32;
+
+// Superblock.s_flags
+pub const EXT2_FLAGS_SIGNED_HASH: u32 = 0x1;
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;
 
 #[repr(C)]
 #[derive(Default,::kernel_derives::EncodedLE)]
@@ -341,6 +350,24 @@ pub struct GroupDesc
 	pub bg_reserved: [u32; 3],	// Reserved
 }
 def_from_slice!{ GroupDesc }
+/// Upper halves of the group descriptor fields (only present with FEAT_INCOMPAT_64BIT)
+#[repr(C)]
+#[derive(Default,::kernel_derives::EncodedLE)]
+pub struct GroupDescHi
+{
+	pub bg_block_bitmap_hi: u32,
+	pub bg_inode_bitmap_hi: u32,
+	pub bg_inode_table_hi: u32,
+	pub bg_free_blocks_count_hi: u16,
+	pub bg_free_inodes_count_hi: u16,
+	pub bg_used_dirs_count_hi: u16,
+	pub bg_itable_unused_hi: u16,
+	pub bg_exclude_bitmap_hi: u32,
+	pub bg_block_bitmap_csum_hi: u16,
+	pub bg_inode_bitmap_csum_hi: u16,
+	pub bg_reserved: u32,
+}
+def_from_slice!{ GroupDescHi }
 impl_fmt! {
 	Debug(self, f) for GroupDesc {
 		write!(f, "GroupDesc {{ addrs: (block_bm: {}, inode_bm: {}, inodes: {}), counts: (free_blk: {}, free_inodes: {}, used_dirs: {}) }}",
@@ -370,6 +397,16 @@ pub struct DirEnt
 }
 pub const DIRENT_MIN_SIZE: usize = 8;
 
+// DirEnt.d_type values (with FEAT_INCOMPAT_FILETYPE)
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
 impl DirEnt
 {
 	pub fn new_raw(buf: *mut [u32], name_len: usize) -> *mut DirEnt
@@ -427,3 +464,92 @@ impl_fmt! {
 			)
 	}
 }
+
+
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+/// Extents longer than this are uninitialised (allocated, but read as zero)
+pub const EXTENT_INIT_MAX_LEN: u32 = 1 << 15;
+
+/// Header of an extent tree node (in `i_block`, or at the start of a tree block)
+///
+/// Followed by `eh_entries` 12-byte entries - `ExtentIdx` if `eh_depth` is non-zero, `ExtentLeaf` otherwise
+#[derive(Debug)]
+pub struct ExtentHeader
+{
+	pub eh_magic: u16,
+	pub eh_entries: u16,
+	pub eh_max: u16,
+	pub eh_depth: u16,
+	pub eh_generation: u32,
+}
+/// Extent tree leaf entry
+#[derive(Debug)]
+pub struct ExtentLeaf
+{
+	/// First logical block
+	pub ee_block: u32,
+	/// Number of blocks (with the uninitialised flag)
+	pub ee_len: u16,
+	pub ee_start_hi: u16,
+	pub ee_start_lo: u32,
+}
+/// Extent tree index entry
+#[derive(Debug)]
+pub struct ExtentIdx
+{
+	/// First logical block covered by the child node
+	pub ei_block: u32,
+	pub ei_leaf_lo: u32,
+	pub ei_leaf_hi: u16,
+	pub ei_unused: u16,
+}
+// NOTE: These are always accessed as 32-bit words (within `i_block` or a cached block)
+impl ExtentHeader
+{
+	pub fn from_words(w: &[u32]) -> ExtentHeader {
+		ExtentHeader {
+			eh_magic: w[0] as u16,
+			eh_entries: (w[0] >> 16) as u16,
+			eh_max: w[1] as u16,
+			eh_depth: (w[1] >> 16) as u16,
+			eh_generation: w[2],
+			}
+	}
+	pub fn to_words(&self, w: &mut [u32]) {
+		w[0] = self.eh_magic as u32 | (self.eh_entries as u32) << 16;
+		w[1] = self.eh_max as u32 | (self.eh_depth as u32) << 16;
+		w[2] = self.eh_generation;
+	}
+}
+impl ExtentLeaf
+{
+	pub fn from_words(w: &[u32]) -> ExtentLeaf {
+		ExtentLeaf {
+			ee_block: w[0],
+			ee_len: w[1] as u16,
+			ee_start_hi: (w[1] >> 16) as u16,
+			ee_start_lo: w[2],
+			}
+	}
+	pub fn to_words(&self, w: &mut [u32]) {
+		w[0] = self.ee_block;
+		w[1] = self.ee_len as u32 | (self.ee_start_hi as u32) << 16;
+		w[2] = self.ee_start_lo;
+	}
+}
+impl ExtentIdx
+{
+	pub fn from_words(w: &[u32]) -> ExtentIdx {
+		ExtentIdx {
+			ei_block: w[0],
+			ei_leaf_lo: w[1],
+			ei_leaf_hi: w[2] as u16,
+			ei_unused: (w[2] >> 16) as u16,
+			}
+	}
+	pub fn to_words(&self, w: &mut [u32]) {
+		w[0] = self.ei_block;
+		w[1] = self.ei_leaf_lo;
+		w[2] = self.ei_leaf_hi as u32 | (self.ei_unused as u32) << 16;
+	}
+}
@@ -38,11 +38,45 @@ impl DirNode {
 
 impl node::NodeBase for DirNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("DirNode::get_id")
+		if self.start_cluster == self.fs.root_first_cluster {
+			return super::InodeRef::root(self.start_cluster).to_id();
+		}
+		// The parent directory is found using the `..` entry
+		match ::kernel::futures::block_on(self.fs.with_cluster(self.start_cluster, |c| on_disk::DirEnt::read(&mut &c[32..64])))
+		{
+		Ok(ent) if &ent.name == b"..         " => {
+			let parent = (ent.cluster as u32) | (ent.cluster_hi as u32) << 16;
+			let parent = if parent == 0 { self.fs.root_first_cluster } else { ClusterNum::new(parent).unwrap_or(self.fs.root_first_cluster) };
+			super::InodeRef::new(self.start_cluster, parent).to_id()
+			},
+		Ok(ent) => {
+			log_error!("DirNode::get_id: {} has no `..` entry ({:?})", self.start_cluster, ent);
+			super::InodeRef::new(self.start_cluster, self.fs.root_first_cluster).to_id()
+			},
+		Err(e) => {
+			log_error!("DirNode::get_id: IO error reading {} - {:?}", self.start_cluster, e);
+			super::InodeRef::new(self.start_cluster, self.fs.root_first_cluster).to_id()
+			},
+		}
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let mut rv = if self.start_cluster == self.fs.root_first_cluster {
+				// The root has no directory entry
+				node::Metadata { link_count: 1, mode: 0o777, ..Default::default() }
+			}
+			else {
+				// Same as `get_id`, find the parent using the `..` entry
+				let ent = ::kernel::futures::block_on(self.fs.with_cluster(self.start_cluster, |c| on_disk::DirEnt::read(&mut &c[32..64])))?;
+				let parent = (ent.cluster as u32) | (ent.cluster_hi as u32) << 16;
+				let parent = if parent == 0 { self.fs.root_first_cluster } else { ClusterNum::new(parent).unwrap_or(self.fs.root_first_cluster) };
+				DirNode::new(self.fs.reborrow(), parent).get_child_metadata(self.start_cluster)?
+			};
+		rv.allocated_size = (self.clusters().count() * self.fs.cluster_size) as u64;
+		Ok(rv)
+	}
 }
 
 #[derive(Debug)]
@@ -50,14 +84,23 @@ pub struct OpenFileInfo
 {
 	dir_cluster: ClusterNum,
 	reference_count: u32,
+	/// The last directory entry was removed while open, release the clusters on close
+	unlinked: bool,
 }
 impl OpenFileInfo {
 	pub fn new(dir_cluster: ClusterNum) -> OpenFileInfo {
 		OpenFileInfo {
 			dir_cluster,
 			reference_count: 0,
+			unlinked: false,
 		}
 	}
+	pub fn dir_cluster(&self) -> ClusterNum {
+		self.dir_cluster
+	}
+	pub fn is_unlinked(&self) -> bool {
+		self.unlinked
+	}
 	pub fn add_ref(&mut self) {
 		self.reference_count += 1;
 	}
@@ -80,7 +123,15 @@ impl super::FilesystemInner {
 	pub fn close_file(&self, file_cluster: ClusterNum) {
 		let mut lh_files = self.open_files.write();
 		if lh_files.get_mut(&file_cluster).expect("close_file but not open?").sub_ref() {
-			lh_files.remove(&file_cluster);
+			let info = lh_files.remove(&file_cluster).unwrap();
+			drop(lh_files);
+			// If the file was deleted while open, then release the data now
+			if info.unlinked {
+				log_debug!("close_file: Releasing unlinked file {}", file_cluster);
+				if let Err(e) = self.free_chain(file_cluster) {
+					log_error!("close_file: Error releasing clusters of unlinked file {} - {:?}", file_cluster, e);
+				}
+			}
 		}
 	}
 }
@@ -125,6 +176,10 @@ pub fn update_file_size(fs: &FilesystemInner, file_cluster: ClusterNum, new_size
 	// Lock the file list and get the current file
 	let lh_files = fs.open_files.read();
 	let file_info = lh_files.get(&file_cluster).ok_or(::vfs::Error::Unknown("FAT: update_file_size called with file not recorded open"))?;
+	if file_info.unlinked {
+		// No directory entry to update
+		return Ok( () );
+	}
 	// Get/create the current directory info (shared ownership)
 	let dir_info = fs.get_dir_info(file_info.dir_cluster);
 	let _lh_dir = dir_info.info.lock.read();	// Entry count isn't changing, so can be a read lock
@@ -230,6 +285,22 @@ impl DirNode {
 		})
 	}
 	
+	/// Get the metadata for the child whose data starts at `ent_cluster`
+	pub fn get_child_metadata(&self, ent_cluster: ClusterNum) -> node::Result<node::Metadata>
+	{
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.read();
+		let e = self.find_ent_by_cluster(ent_cluster)?.ok_or(::vfs::Error::NotFound)?;
+		let mut rv = node::Metadata {
+			allocated_size: ::kernel::lib::num::round_up(e.size as u64, self.fs.cluster_size as u64),
+			link_count: 1,
+			mode: if e.attributes & on_disk::ATTR_READONLY != 0 { 0o555 } else { 0o777 },
+			..Default::default()
+			};
+		e.times.fill_metadata(&mut rv);
+		Ok(rv)
+	}
+	
 	fn find_ent_by_cluster(&self, ent_cluster: ClusterNum) -> Result<Option<DirEntShort>, super::storage::IoError> {
 		log_trace!("find_ent_by_cluster(self={:?}, ent_cluster={})", self, ent_cluster);
 		self.iterate_ents(0, |_i, ent| {
@@ -328,6 +399,14 @@ impl DirEnt {
 				cluster: ClusterNum::new( (ent.cluster as u32) | (ent.cluster_hi as u32) << 16 ).unwrap_or( ClusterNum::new(0xFF_FFFF).unwrap() ),
 				size: ent.size,
 				attributes: ent.attribs,
+				times: DosTimes {
+					creation_ds: ent.creation_ds,
+					creation_time: ent.creation_time,
+					creation_date: ent.creation_date,
+					accessed_date: ent.accessed_date,
+					modified_time: ent.modified_time,
+					modified_date: ent.modified_date,
+					},
 				})
 		}
 	}
@@ -345,12 +424,12 @@ impl DirEnt {
 				size: v.size,
 				cluster: v.cluster.get() as u16,
 				cluster_hi: (v.cluster.get() >> 16) as u16,
-				creation_ds: 0,
-				creation_date: 0,
-				creation_time: 0,
-				accessed_date: 0,
-				modified_date: 0,
-				modified_time: 0,
+				creation_ds: v.times.creation_ds,
+				creation_date: v.times.creation_date,
+				creation_time: v.times.creation_time,
+				accessed_date: v.times.accessed_date,
+				modified_date: v.times.modified_date,
+				modified_time: v.times.modified_time,
 				}.write(&mut dst);
 			},
 		DirEnt::Long(e) => {
@@ -376,9 +455,35 @@ struct DirEntShort {
 	cluster: ClusterNum,
 	size: u32,
 	attributes: u8,
-	//creation_time: ::kernel::time::Timestamp,
-	//modified_time: ::kernel::time::Timestamp,
-	//accessed_time: ::kernel::time::Timestamp,
+	times: DosTimes,
+}
+/// Raw timestamps from a short entry (kept so they're preserved when the entry is re-written)
+#[derive(Default,Copy,Clone)]
+struct DosTimes {
+	creation_ds: u8,
+	creation_time: u16,
+	creation_date: u16,
+	accessed_date: u16,
+	modified_time: u16,
+	modified_date: u16,
+}
+impl DosTimes {
+	/// Populate the timestamps in VFS metadata (FAT stores local time, which is treated as UTC)
+	fn fill_metadata(&self, meta: &mut node::Metadata) {
+		meta.create_time = dos_timestamp(self.creation_date, self.creation_time).map(|v| v + self.creation_ds as i64 / 100);
+		meta.modify_time = dos_timestamp(self.modified_date, self.modified_time);
+		meta.access_time = dos_timestamp(self.accessed_date, 0);
+	}
+}
+/// Decode a DOS date/time pair (a zero date means that the field isn't set)
+fn dos_timestamp(date: u16, time: u16) -> Option<node::Timestamp> {
+	if date == 0 {
+		return None;
+	}
+	Some(node::timestamp_from_date(
+		1980 + (date >> 9) as i64, (date >> 5 & 0xF) as u8, (date & 0x1F) as u8,
+		(time >> 11) as u8, (time >> 5 & 0x3F) as u8, (time & 0x1F) as u8 * 2,
+		))
 }
 impl_fmt! {
 	Debug(self,f) for DirEntShort {
@@ -577,271 +682,547 @@ impl node::Dir for DirNode {
 		}
 	}
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
-		// File cluster for the dir's data
-		let Some(new_cluster) = self.fs.alloc_cluster_unchained(self.start_cluster)? else {
-			return Err(::vfs::Error::OutOfSpace);
+		log_trace!("DirNode::create('{:?}', {:?})", name, nodetype);
+		let attributes = match nodetype
+			{
+			node::NodeType::File => on_disk::ATTR_ARCHIVE,
+			node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
+			node::NodeType::Symlink(_) => return Err(::vfs::Error::Unknown("FAT doesn't support symbolic links")),
 			};
-		log_debug!("DirNode::create('{:?}', {:?}): new_cluster={}", name, nodetype, new_cluster);
-		fn is_valid_short_char(b: u8) -> bool {
-			b.is_ascii_uppercase() || b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_'
-		}
-		/// Check if the passed string is a valid short file name, and return the encoded version if it is
-		fn is_valid_short_name(name: &ByteStr) -> Option<[u8; 8+1+3]> {
-			let mut rv = [0; 8+1+3];
-			let mut dotpos = None;
-			let mut has_upper = false;
-			let mut has_lower = false;
-			for (i,&b) in name.as_bytes().iter().enumerate() {
-				if b == b'.' && i > 0 {	// leading dot isn't valid
-					dotpos = Some(i);
-					break;
-				}
-				else if i == 8 {
-					return None;
-				}
-				else if !is_valid_short_char(b) {
-					return None;
-				}
-				else {
-					rv[i] = b;
-					has_lower |= b.is_ascii_lowercase();
-					has_upper |= b.is_ascii_uppercase();
+
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
+		let new_cluster = self.add_entry(name, || {
+			// File cluster for the node's data
+			let Some(new_cluster) = self.fs.alloc_cluster_unchained(self.start_cluster)? else {
+				return Err(::vfs::Error::OutOfSpace);
+				};
+			log_debug!("DirNode::create('{:?}', {:?}): new_cluster={}", name, nodetype, new_cluster);
+			if attributes & on_disk::ATTR_DIRECTORY != 0 {
+				// New directories need to be zeroed, and have the `.` and `..` entries
+				if let Err(e) = self.init_new_dir(new_cluster) {
+					self.fs.free_chain(new_cluster)?;
+					return Err(e);
 				}
 			}
-			if has_upper && has_lower {
-				return None;
-			}
-			if let Some(dotpos) = dotpos {
-				let mut has_upper = false;
-				let mut has_lower = false;
-				rv[dotpos] = b'.';
-				for (i,&b) in name.as_bytes()[dotpos+1..].iter().enumerate() {
-					if i == 3 {
-						return None;
-					}
-					else if !is_valid_short_char(b) {
-						return None;
-					}
-					else {
-						rv[dotpos+1+i] = b;
-						has_lower |= b.is_ascii_lowercase();
-						has_upper |= b.is_ascii_uppercase();
-					}
-				}
-				if has_upper && has_lower {
-					return None;
-				}
+			Ok( (new_cluster, attributes, 0) )
+			})?;
+		Ok( super::InodeRef::new(new_cluster, self.start_cluster).to_id() )
+	}
+	/// Create a new name for an existing node
+	///
+	/// FAT has no concept of hard links, so this is only intended to be used for moving a node (i.e. followed by an
+	/// `unlink` of the original name). The extra reference is tracked in memory until then.
+	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
+		log_trace!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+		let (fs, cluster, size, attributes) = if let Some(f) = node.get_any().downcast_ref::<FileNode>() {
+				let (fs, cluster, size) = f.get_ref();
+				(fs, cluster, size, on_disk::ATTR_ARCHIVE)
 			}
-			Some(rv)
-		}
-		fn make_short_name(name: &ByteStr, index: u16) -> [u8; 8+1+3] {
-			let mut rv = [0; 8+1+3];
-			let mut i = 0;
-			// Strip leading invalid characters
-			let mut iter = name.as_bytes().iter().copied().peekable();
-			if iter.peek() == Some(&b'.') {
-				iter.next();
-			}
-			let mut has_ext = false;
-			while let Some(v) = iter.next() {
-				if i > 0 && v == b'.' {
-					has_ext = true;
-					break;
-				}
-				if i == 8 {
-					rv[6] = b'~';
-					rv[7] = b'1';
-					// Advance until the extension
-					while let Some(v) = iter.next() {
-						if v == b'.' {
-							has_ext = true;
-							break;
-						}
-					}
-					break;
-				}
-				if is_valid_short_char(v.to_ascii_uppercase()) {
-					rv[i] = v.to_ascii_uppercase();
-					i += 1;
-				}
+			else if let Some(d) = node.get_any().downcast_ref::<DirNode>() {
+				(&*d.fs, d.start_cluster, 0, on_disk::ATTR_DIRECTORY)
 			}
-			if i == 0 {
-				rv[i] = b'_';
-				i += 1;
-			}
-			if has_ext && iter.peek().is_some() {
-				rv[i] = b'.';
-				i += 1;
-				while let Some(v) = iter.next() {
-					if is_valid_short_char(v.to_ascii_uppercase()) {
-						rv[i] = v.to_ascii_uppercase();
-						i += 1;
-					}
+			else {
+				return Err(::vfs::Error::TypeMismatch);
+			};
+		if !::core::ptr::eq(fs, &*self.fs) {
+			log_notice!("DirNode::link: Linking across filesystems");
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		if cluster == self.fs.root_first_cluster {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+
+		let mut lh_files = self.fs.open_files.write();
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
+		self.add_entry(name, || Ok( (cluster, attributes, size) ))?;
+		*self.fs.extra_links.lock().entry(cluster).or_insert(0) += 1;
+
+		if attributes & on_disk::ATTR_DIRECTORY != 0 {
+			// Update the `..` entry of the directory to point to this one
+			let parent = self.parent_ref_cluster();
+			::kernel::futures::block_on(self.fs.edit_cluster(cluster, |data| {
+				let mut ent = on_disk::DirEnt::read(&mut &data[32..64]);
+				ent.cluster = parent as u16;
+				ent.cluster_hi = (parent >> 16) as u16;
+				ent.write(&mut &mut data[32..64]);
+				}))?;
+		}
+		else if let Some(info) = lh_files.get_mut(&cluster) {
+			// Size updates now go to the new entry
+			info.dir_cluster = self.start_cluster;
+		}
+		Ok( () )
+	}
+	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
+		log_trace!("DirNode::unlink('{:?}')", name);
+		if name == "." || name == ".." {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		// Lock the file list, then the directory
+		let mut lh_files = self.fs.open_files.write();
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
+
+		// Locate the entry, and any LFN entries before it
+		let mut lfn = LFN::new();
+		let mut lfn_pos: Vec<EntPos> = Vec::new();
+		let (ent_positions, ent) = match self.iterate_ents_pos(|pos, ent| {
+			match ent {
+			DirEnt::End => {},
+			DirEnt::Short(e) => {
+				if e.name().as_bytes().eq_ignore_ascii_case(name.as_bytes()) || (lfn.is_valid() && lfn.name() == name) {
+					let mut positions = ::core::mem::replace(&mut lfn_pos, Vec::new());
+					positions.push(pos);
+					return Some( (positions, e) );
 				}
-				if rv[i-1] == b'.' {
-					rv[i] = b'_';
+				lfn.clear();
+				lfn_pos.clear();
+				},
+			DirEnt::Long(e) => {
+				if e.id & 0x40 != 0 {
+					lfn_pos.clear();
 				}
+				lfn.add(&e);
+				lfn_pos.push(pos);
+				},
+			DirEnt::Empty
+			|DirEnt::Invalid(_) => {
+				lfn.clear();
+				lfn_pos.clear();
+				},
 			}
+			None
+			})?
+			{
+			Some(v) => v,
+			None => return Err(::vfs::Error::NotFound),
+			};
+		log_debug!("DirNode::unlink: Found {:?} at {:?}", ent, ent_positions);
 
-			if index > 0 {
-				todo!("Mangle short name for de-duplication");
+		if ent.attributes & on_disk::ATTR_DIRECTORY != 0 {
+			if ent.cluster == self.fs.root_first_cluster {
+				return Err(::vfs::Error::InvalidParameter);
+			}
+			// A directory being moved (linked elsewhere) keeps its contents
+			let is_moving = self.fs.extra_links.lock().get(&ent.cluster).is_some();
+			if !is_moving && !DirNode::new(self.fs.reborrow(), ent.cluster).is_empty()? {
+				return Err(::vfs::Error::DirectoryNotEmpty);
 			}
+		}
 
-			rv
+		// Mark all of the entries as deleted
+		for pos in ent_positions {
+			::kernel::futures::block_on(self.fs.edit_cluster(pos.cluster, |data| {
+				data[pos.idx * 32] = 0xE5;
+				}))?;
 		}
-		let short_name;
-		// Add entries to the end of the dir
-		// - Determine if this file can be encoded as a short filename, and if not - how many entries it will need
-		let num_entries = if let Some(sn) = is_valid_short_name(name) {
-			short_name = sn;
-			1
-		} else {
-			short_name = make_short_name(name, 0);
-			1 + (::utf16::wtf8_to_utf16(name.as_bytes()).count() + 1 + 13-1) / 13
-		};
 
-		let dir_info = self.fs.get_dir_info(self.start_cluster);
-		let _lh_dir = dir_info.info.lock.write();
-		// - Lock the directory, then start seeking clusters looking for a sequence of slots large enough
-		let mut end_entry = None;
-		let (found_slot, short_collision, total_free_slots) = {
-			let mut found_slot = None;
-			let mut short_collision = false;
-			let mut n_free_total = 0;
-			let mut n_free_run = 0;	// Number of free entries in a row currently seen
-			let mut lfn = LFN::new();
-			let mut idx = 0;	// Index of the directory entry
-			// TODO: `iterate_ent` doesn't return the cluster - so reimplemented here
-			for c in self.clusters()
-			{
-				if let Some(rv) = ::kernel::futures::block_on(self.fs.with_cluster(c, |cluster| {
-					for (i,ent) in DirEnts::new(&cluster).enumerate()
-					{
-						match ent {
-						DirEnt::End => {
-							end_entry = Some( (c,i) );
-							break ;
-							},
-						DirEnt::Short(ref e) => {
-							if e.name() == name || lfn.name() == name {
-								return Some(Err(::vfs::Error::AlreadyExists));
-							}
-							if e.name().as_bytes() == &short_name {
-								short_collision = true;
-							}
-							lfn.clear();
-							},
-						DirEnt::Long(ref e) => lfn.add(e),
-						DirEnt::Empty => {
-							lfn.clear();
-							},
-						DirEnt::Invalid(_) => lfn.clear(),
-						}
-						if let DirEnt::Empty = ent {
-							n_free_run += 1;
-							n_free_total += 1;
-							if found_slot.is_none() && n_free_run == num_entries {
-								found_slot = Some(idx);
-							}
-						}
-						else {
-							n_free_run = 0;
-						}
-						idx += 1;
-					}
-					None
-					}))? {
-					return rv;
-				}
-				if end_entry.is_some() {
-					break;
+		// Release the data (if this was the last reference to it)
+		{
+			let mut lh_links = self.fs.extra_links.lock();
+			if let Some(count) = lh_links.get_mut(&ent.cluster) {
+				*count -= 1;
+				if *count == 0 {
+					lh_links.remove(&ent.cluster);
 				}
+				log_debug!("DirNode::unlink: {} still has other links", ent.cluster);
+				return Ok( () );
 			}
-			(found_slot, short_collision, n_free_total)
-		};
+		}
+		if let Some(info) = lh_files.get_mut(&ent.cluster) {
+			// Still open, defer until the last handle is closed
+			info.unlinked = true;
+			return Ok( () );
+		}
+		drop(lh_files);
+		self.fs.free_chain(ent.cluster)?;
+		Ok( () )
+	}
+}
+
+/// Location of a directory entry
+#[derive(Copy,Clone,Debug)]
+struct EntPos
+{
+	cluster: ClusterNum,
+	/// Index of the entry within the cluster
+	idx: usize,
+}
+
+impl DirNode {
+	/// Returns `true` if this is the fixed-size root directory (FAT12/FAT16)
+	fn is_fixed_root(&self) -> bool {
+		!is!(self.fs.ty, super::Size::Fat32) && self.start_cluster == self.fs.root_first_cluster
+	}
+	/// Cluster number to use in a child's `..` entry (zero for the root)
+	fn parent_ref_cluster(&self) -> u32 {
+		if self.start_cluster == self.fs.root_first_cluster { 0 } else { self.start_cluster.get() }
+	}
+	fn ents_per_cluster(&self) -> usize {
+		self.fs.cluster_size / 32
+	}
 
-		// If there was a short name collision (... which can only be flagged if LFN is used, otherwise it's an error)
-		// then re-iterate and create a list of short names
-		if short_collision
+	/// Iterate directory entries (along with their location), stopping after the end marker
+	fn iterate_ents_pos<T>(&self, mut cb: impl FnMut(EntPos, DirEnt)->Option<T>) -> Result<Option<T>, super::storage::IoError> {
+		// The fixed root directory may not be a multiple of the cluster size
+		let mut rem_ents = if self.is_fixed_root() {
+				self.fs.root_sector_count as usize * self.fs.vh.block_size() / 32
+			}
+			else {
+				usize::MAX
+			};
+		for c in self.clusters()
 		{
-			let mut names = vec![];
-			self.iterate_ents(0, |_i, ent| {
-				if let DirEnt::Short(e) = ent {
-					// TODO: Only push if it prefix matches the preferred name (until the `~`)
-					names.push( e.name );
+			if let Some(rv) = ::kernel::futures::block_on(self.fs.with_cluster(c, |cluster| {
+				for (i,ent) in DirEnts::new(&cluster).enumerate() {
+					if rem_ents == 0 {
+						return Some(None);
+					}
+					rem_ents -= 1;
+					let is_end = matches!(ent, DirEnt::End);
+					if let Some(rv) = cb(EntPos { cluster: c, idx: i }, ent) {
+						return Some(Some(rv));
+					}
+					if is_end {
+						return Some(None);
+					}
 				}
-				None::<()>
+				None
+			}))? {
+				return Ok(rv);
+			}
+		}
+		Ok(None)
+	}
+
+	/// Returns `true` if the directory only contains `.` and `..`
+	fn is_empty(&self) -> Result<bool, super::storage::IoError> {
+		let found = self.iterate_ents_pos(|_pos, ent| {
+			match ent
+			{
+			DirEnt::Short(e) if e.name() != "." && e.name() != ".." => Some(()),
+			_ => None,
+			}
 			})?;
-			todo!("DirNode::create(): Handle short name collision");
+		Ok(found.is_none())
+	}
+
+	/// Zero a newly allocated directory cluster, and populate the `.` and `..` entries
+	fn init_new_dir(&self, new_cluster: ClusterNum) -> node::Result<()> {
+		let mut data = vec![0u8; self.fs.cluster_size];
+		for (i,(name,cluster)) in [ (b".          ", new_cluster.get()), (b"..         ", self.parent_ref_cluster()) ].iter().enumerate() {
+			on_disk::DirEnt {
+				name: **name,
+				attribs: on_disk::ATTR_DIRECTORY,
+				lcase: 0,
+				size: 0,
+				cluster: *cluster as u16,
+				cluster_hi: (*cluster >> 16) as u16,
+				creation_ds: 0,
+				creation_date: 0,
+				creation_time: 0,
+				accessed_date: 0,
+				modified_date: 0,
+				modified_time: 0,
+				}.write(&mut &mut data[i*32..][..32]);
 		}
+		::kernel::futures::block_on(self.fs.write_clusters(new_cluster, &data))?;
+		Ok( () )
+	}
 
-		let mut ents_it = CreateDirents::new(nodetype, new_cluster, short_name, if num_entries == 1 { None } else {Some(name) })
-			.chain(::core::iter::once(DirEnt::End))
-			.peekable()
-			;
+	/// Allocate (and zero) a new cluster on the end of the directory
+	fn extend(&self, last_cluster: ClusterNum) -> node::Result<ClusterNum> {
+		if self.is_fixed_root() {
+			log_notice!("DirNode::extend: Fixed-size root directory is full");
+			return Err(::vfs::Error::OutOfSpace);
+		}
+		let Some(new_cluster) = self.fs.alloc_cluster_chained(last_cluster)? else {
+			return Err(::vfs::Error::OutOfSpace);
+			};
+		log_debug!("DirNode::extend: {} - Added {} after {}", self.start_cluster, new_cluster, last_cluster);
+		::kernel::futures::block_on(self.fs.write_clusters(new_cluster, &vec![0u8; self.fs.cluster_size]))?;
+		Ok(new_cluster)
+	}
 
-		if let Some(pos) = found_slot {
-			// Can freely insert
-			todo!("DirNode::create('{:?}', {:?}): new_cluster={}, num_entries={} - found_slot={:?}", name, nodetype, new_cluster, num_entries, pos);
+	/// Add a set of entries (LFN and short) for `name` to the directory
+	///
+	/// `get_target` is called once the name has been checked and slots located, and returns the cluster, attributes
+	/// and size for the new entry. The caller must hold the directory's write lock.
+	fn add_entry(&self, name: &ByteStr, get_target: impl FnOnce()->node::Result<(ClusterNum, u8, u32)>) -> node::Result<ClusterNum> {
+		if name.len() == 0 || name == "." || name == ".." || name.as_bytes().iter().any(|&b| b == b'/' || b == b'\\' || b < 0x20) {
+			return Err(::vfs::Error::InvalidParameter);
 		}
-		else {
-			// Extend the directory
-			// - Should this also defragment?
-			if total_free_slots >= num_entries {
-				// There were enough entries - need to defragment the directory
-				todo!("DirNode::create(): Defragment directory ({} total free, needed {} contig)", total_free_slots, num_entries);
+		// - Determine if this file can be encoded as a short filename, and if not - how many entries it will need
+		let short_name = is_valid_short_name(name);
+		let num_entries = if short_name.is_some() {
+				1
 			}
 			else {
-				// Don't bother defragmenting, just extend the length
-				// - Maybe the existing last cluster can be extended...
-
-				// NOTE: No need to update the size, as the size of a directory is always zero.
-
-				let mut clusters_it = self.clusters();
-				let mut cluster = self.start_cluster;
-				let mut idx = self.fs.cluster_size / 32;	// Default to past the end
-				while let Some(c) = clusters_it.next() {
-					cluster = c;
-					if let Some((end_cluster, end_idx)) = end_entry {
-						if c == end_cluster {
-							idx = end_idx;
+				let lfn_len = ::utf16::wtf8_to_utf16(name.as_bytes()).count();
+				if lfn_len > 255 {
+					return Err(::vfs::Error::InvalidParameter);
+				}
+				1 + (lfn_len + 13-1) / 13
+			};
+
+		// - Start seeking clusters looking for a sequence of slots large enough
+		let mut end_pos = None;
+		let mut last_cluster = self.start_cluster;
+		let mut short_names: Vec<[u8; 11]> = Vec::new();
+		let mut found_slot: Option<Vec<EntPos>> = None;
+		let mut cur_run: Vec<EntPos> = Vec::new();
+		let mut lfn = LFN::new();
+		if let Some(e) = self.iterate_ents_pos(|pos, ent| {
+			last_cluster = pos.cluster;
+			match ent {
+			DirEnt::End => {
+				end_pos = Some(pos);
+				return None;
+				},
+			DirEnt::Short(ref e) => {
+				if e.name().as_bytes().eq_ignore_ascii_case(name.as_bytes()) || (lfn.is_valid() && lfn.name() == name) {
+					return Some(::vfs::Error::AlreadyExists);
+				}
+				short_names.push(e.get_encoded_name().1);
+				lfn.clear();
+				},
+			DirEnt::Long(ref e) => lfn.add(e),
+			DirEnt::Empty => {
+				lfn.clear();
+				},
+			DirEnt::Invalid(_) => lfn.clear(),
+			}
+			if let DirEnt::Empty = ent {
+				cur_run.push(pos);
+				if found_slot.is_none() && cur_run.len() == num_entries {
+					found_slot = Some(cur_run.clone());
+				}
+			}
+			else {
+				cur_run.clear();
+			}
+			None
+			})?
+		{
+			return Err(e);
+		}
+
+		// Pick a short name that doesn't collide with an existing one
+		let short_name = match short_name
+			{
+			Some(sn) => {
+				if short_names.iter().any(|v| *v == encode_short_name(&sn)) {
+					return Err(::vfs::Error::AlreadyExists);
+				}
+				sn
+				},
+			None => {
+				let (sn, lossy) = make_short_name(name, 0);
+				let mut rv = None;
+				if !lossy && !short_names.iter().any(|v| *v == encode_short_name(&sn)) {
+					rv = Some(sn);
+				}
+				else {
+					for index in 1 ..= 999_999 {
+						let sn = make_short_name(name, index).0;
+						if !short_names.iter().any(|v| *v == encode_short_name(&sn)) {
+							rv = Some(sn);
 							break;
 						}
 					}
 				}
+				rv.ok_or(::vfs::Error::Unknown("FAT: Unable to generate a unique short name"))?
+				},
+			};
+		log_debug!("add_entry: {:?} short={:?} num_entries={}", name, ByteStr::new(short_name.split(|&b| b == 0).next().unwrap()), num_entries);
 
-				while ents_it.peek().is_some()
+		// Determine where the entries will go
+		// - If there's no large enough gap, then append to the end of the directory (instead of compacting)
+		let (positions, terminator) = match found_slot
+			{
+			Some(v) => (v, None),
+			None => {
+				let ents_per_cluster = self.ents_per_cluster();
+				let mut positions = Vec::with_capacity(num_entries);
+				let mut next = end_pos;
+				while positions.len() < num_entries
 				{
-					assert!(idx <= self.fs.cluster_size / 32);
-					if idx == self.fs.cluster_size / 32 {
-						if let Some(next_cluster) = clusters_it.next() {
-							cluster = next_cluster;
+					let pos = match next
+						{
+						Some(v) => v,
+						None => {
+							let c = self.extend(last_cluster)?;
+							last_cluster = c;
+							EntPos { cluster: c, idx: 0 }
+							},
+						};
+					positions.push(pos);
+					next = if pos.idx + 1 < ents_per_cluster {
+							Some(EntPos { cluster: pos.cluster, idx: pos.idx + 1 })
 						}
 						else {
-							todo!("Allocate a new cluster?");
-						}
-						idx = 0;
-					}
-					::kernel::futures::block_on(self.fs.edit_cluster(cluster, |data| {
-						while idx < self.fs.cluster_size / 32 {
-							let Some(ent) = ents_it.next() else { break; };
-							log_debug!("WRITE: {} @ {} {:?}", cluster, idx*32, ent);
-							ent.to_raw(&mut data[idx*32..][..32]);
-							log_debug!("- {:x?}", &data[idx*32..][..32]);
-							idx += 1;
-						}
-						}))?;
+							// The next cluster (if any) after the end marker is already all end markers
+							match self.clusters().skip_while(|&c| c != pos.cluster).nth(1)
+							{
+							Some(c) => Some(EntPos { cluster: c, idx: 0 }),
+							None => None,
+							}
+						};
 				}
+				// Only bother writing a terminator if it's in the same cluster (other clusters are zeroed)
+				let terminator = match next
+					{
+					Some(p) if p.cluster == positions.last().unwrap().cluster => Some(p),
+					_ => None,
+					};
+				(positions, terminator)
+				},
+			};
+
+		// Obtain the target cluster (allocating for new nodes), and write the entries
+		let (target_cluster, attributes, size) = get_target()?;
+		let ents = CreateDirents::new(target_cluster, attributes, size, short_name, if num_entries == 1 { None } else { Some(name) });
+		for (pos, ent) in Iterator::zip(positions.into_iter(), ents) {
+			::kernel::futures::block_on(self.fs.edit_cluster(pos.cluster, |data| {
+				log_debug!("WRITE: {:?} {:?}", pos, ent);
+				ent.to_raw(&mut data[pos.idx*32..][..32]);
+				}))?;
+		}
+		if let Some(pos) = terminator {
+			::kernel::futures::block_on(self.fs.edit_cluster(pos.cluster, |data| {
+				DirEnt::End.to_raw(&mut data[pos.idx*32..][..32]);
+				}))?;
+		}
+		Ok(target_cluster)
+	}
+}
+
+fn is_valid_short_char(b: u8) -> bool {
+	b.is_ascii_uppercase() || b.is_ascii_lowercase() || b.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&b)
+}
+/// Check if the passed string is a valid short file name, and return the encoded version if it is
+fn is_valid_short_name(name: &ByteStr) -> Option<[u8; 8+1+3]> {
+	let mut rv = [0; 8+1+3];
+	let mut dotpos = None;
+	let mut has_upper = false;
+	let mut has_lower = false;
+	for (i,&b) in name.as_bytes().iter().enumerate() {
+		if b == b'.' && i > 0 {	// leading dot isn't valid
+			dotpos = Some(i);
+			break;
+		}
+		else if i == 8 {
+			return None;
+		}
+		else if !is_valid_short_char(b) {
+			return None;
+		}
+		else {
+			rv[i] = b;
+			has_lower |= b.is_ascii_lowercase();
+			has_upper |= b.is_ascii_uppercase();
+		}
+	}
+	if has_upper && has_lower {
+		return None;
+	}
+	if let Some(dotpos) = dotpos {
+		let mut has_upper = false;
+		let mut has_lower = false;
+		let ext = &name.as_bytes()[dotpos+1..];
+		if ext.len() == 0 {
+			return None;
+		}
+		rv[dotpos] = b'.';
+		for (i,&b) in ext.iter().enumerate() {
+			if i == 3 {
+				return None;
+			}
+			else if !is_valid_short_char(b) {
+				return None;
+			}
+			else {
+				rv[dotpos+1+i] = b;
+				has_lower |= b.is_ascii_lowercase();
+				has_upper |= b.is_ascii_uppercase();
 			}
 		}
-		Ok( super::InodeRef::new(new_cluster, self.start_cluster).to_id() )
+		if has_upper && has_lower {
+			return None;
+		}
 	}
-	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
-		todo!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+	Some(rv)
+}
+/// Generate a short name for a long name, with an optional `~N` numeric tail
+///
+/// Returns the name, and `true` if the conversion lost information (so a numeric tail should be used)
+fn make_short_name(name: &ByteStr, index: u32) -> ([u8; 8+1+3], bool) {
+	let bytes = name.as_bytes();
+	// Leading dots and spaces are ignored
+	let trimmed = {
+		let skip = bytes.iter().take_while(|&&b| b == b'.' || b == b' ').count();
+		&bytes[skip..]
+		};
+	let mut lossy = trimmed.len() != bytes.len();
+	let (base, ext) = match trimmed.iter().rposition(|&b| b == b'.')
+		{
+		Some(p) => (&trimmed[..p], &trimmed[p+1..]),
+		None => (trimmed, &[][..]),
+		};
+	let mut convert = |src: &[u8], max: usize| -> Vec<u8> {
+		let mut rv = Vec::new();
+		for &b in src {
+			if b == b' ' || b == b'.' {
+				lossy = true;
+				continue;
+			}
+			let b = b.to_ascii_uppercase();
+			let b = if is_valid_short_char(b) { b } else { lossy = true; b'_' };
+			if rv.len() == max {
+				lossy = true;
+				break;
+			}
+			rv.push(b);
+		}
+		rv
+		};
+	let mut base = convert(base, 8);
+	let ext = convert(ext, 3);
+	if base.is_empty() {
+		base.push(b'_');
 	}
-	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
-		todo!("DirNode::unlink('{:?}')", name);
+
+	let mut rv = [0; 8+1+3];
+	let mut i = 0;
+	if index > 0 {
+		let tail = format!("~{}", index);
+		let base_len = usize::min(base.len(), 8 - tail.len());
+		for &b in base[..base_len].iter().chain(tail.as_bytes().iter()) {
+			rv[i] = b;
+			i += 1;
+		}
 	}
+	else {
+		for &b in &base {
+			rv[i] = b;
+			i += 1;
+		}
+	}
+	if ext.len() > 0 {
+		rv[i] = b'.';
+		i += 1;
+		for &b in &ext {
+			rv[i] = b;
+			i += 1;
+		}
+	}
+	(rv, lossy)
+}
+/// Get the on-disk (space padded, upper case) version of a short name
+fn encode_short_name(name: &[u8; 8+1+3]) -> [u8; 11] {
+	DirEntShort { name: *name, cluster: ClusterNum::new(2).unwrap(), size: 0, attributes: 0, times: Default::default() }.get_encoded_name().1
 }
 
 
@@ -853,17 +1234,13 @@ struct CreateDirents {
 	long_name: ::core::iter::Rev<::kernel::lib::vec::IntoIter<DirEntLong>>,
 }
 impl CreateDirents {
-	fn new(node_type: node::NodeType, target_cluster: ClusterNum, name: [u8; 8+1+3], long_name: Option<&'_ ByteStr>) -> Self {
+	fn new(target_cluster: ClusterNum, attributes: u8, size: u32, name: [u8; 8+1+3], long_name: Option<&'_ ByteStr>) -> Self {
 		let short_ent = DirEntShort {
 			name,
 			cluster: target_cluster,
-			size: 0,
-			attributes: match node_type
-				{
-				node::NodeType::File => on_disk::ATTR_ARCHIVE,
-				node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
-				node::NodeType::Symlink(_) => todo!("Symlink"),
-				},
+			size,
+			attributes,
+			times: Default::default(),
 			};
 		let short_name_checksum = short_ent.get_encoded_name().1.iter().copied().fold(0, |sum, b| {
 			u8::wrapping_add((sum >> 1) + (sum << 7), b)
@@ -2,13 +2,23 @@
 //!
 use kernel::metadevs::storage;
 use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
+use core::sync::atomic::Ordering;
 use super::Size;
 use super::ClusterNum;
 
-// End-of-chain marker values
+// End-of-chain marker values (written)
 const FAT12_EOC: u16 = 0x0FFF;
 const FAT16_EOC: u16 = 0xFFFF;
-const FAT32_EOC: u32 = 0x00FFFFFF;	// FAT32 is actually FAT24...
+const FAT32_EOC: u32 = 0x0FFFFFFF;
+// Bad cluster markers (values above these are also end-of-chain)
+const FAT12_BAD: u16 = 0x0FF7;
+const FAT16_BAD: u16 = 0xFFF7;
+const FAT32_BAD: u32 = 0x0FFFFFF7;
+/// FAT32 entries are only 28 bits, the top four are reserved and must be preserved
+const FAT32_MASK: u32 = 0x0FFFFFFF;
+
+/// Value used for unknown FSInfo fields
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
 
 /// FAT management methods
 impl super::FilesystemInner
@@ -22,6 +32,10 @@ impl super::FilesystemInner
 			log_error!("Unallocted (zero) FAT entry in a chain");
 			Err(::vfs::Error::InconsistentFilesystem)
 			},
+		FatEntry::Bad => {
+			log_error!("Bad cluster marker in a chain ({})", cluster);
+			Err(::vfs::Error::InconsistentFilesystem)
+			},
 		FatEntry::EndOfChain => Ok(None),
 		FatEntry::Chain(val) => Ok(Some( ClusterNum::new(val).map_err(|()| ::vfs::Error::InconsistentFilesystem)? )),
 		}
@@ -29,7 +43,8 @@ impl super::FilesystemInner
 
 	/// Allocate a new cluster, and append it to the FAT chain
 	pub fn alloc_cluster_chained(&self, prev_cluster: ClusterNum) -> Result< Option<ClusterNum>, storage::IoError > {
-		let Some(cluster_idx) = self.alloc_cluster_unchained(prev_cluster)? else { return Ok(None); };
+		let _lh = self.fat_lock.lock();
+		let Some(cluster_idx) = self.alloc_cluster_inner(prev_cluster)? else { return Ok(None); };
 		// Update the previous cluster's chain from EOC to this
 		self.set_fat_entry(prev_cluster, FatEntry::EndOfChain, FatEntry::Chain(cluster_idx.get()))?;
 		Ok( Some(cluster_idx) )
@@ -37,101 +52,233 @@ impl super::FilesystemInner
 
 	/// Allocate a new cluster as the start of a new chain (use the previous cluster to maybe reduce fragmentation)
 	pub fn alloc_cluster_unchained(&self, prev_cluster: ClusterNum) -> Result< Option<ClusterNum>, storage::IoError > {
-		let prev_cluster = if prev_cluster.get() == super::FATL_ROOT_CLUSTER { 2 } else { prev_cluster.get() };
-		// Search for an unallocated cluster in the FAT, starting from `prev_cluster`
-		// - May need to use a pre-allocated bitmap to speed up allocation?
-		// - Or just iterate the FAT
-		let cps = match self.ty
-			{
-			Size::Fat12 => (self.vh.block_size() / 3 * 2) as u32,	// 2 per 3 bytes
-			Size::Fat16 => (self.vh.block_size() / 2) as u32,
-			Size::Fat32 => (self.vh.block_size() / 4) as u32,
-			};
-		let aligned = if (prev_cluster + 1) % cps != 0 {
-				let idx_in_sector = (prev_cluster + 1) % cps;
-				let base = prev_cluster + 1 - idx_in_sector;
-				if let Some(rv) = self.find_and_alloc_cluster_in_sector(base, idx_in_sector, cps)? {
-					assert_eq!(self.get_fat_entry(rv).unwrap(), FatEntry::EndOfChain);
-					return Ok(Some(rv));
-				}
-				base + cps
-			} else {
-				prev_cluster + 1
-			};
-		// Iterate until the end of the list
-		for base in (aligned .. self.cluster_count as u32).step_by(cps as usize)
+		let _lh = self.fat_lock.lock();
+		self.alloc_cluster_inner(prev_cluster)
+	}
+
+	/// Deallocate a cluster (at the end of a chain)
+	pub fn release_cluster(&self, cluster: ClusterNum, prev: Option<ClusterNum>) -> Result<(), storage::IoError> {
+		let _lh = self.fat_lock.lock();
+		if let Some(prev) = prev {
+			// Set the entry to EOC, must have been `cluster_idx`
+			self.set_fat_entry(prev, FatEntry::Chain(cluster.get()), FatEntry::EndOfChain)?;
+		}
+		// Set this cluster to 0 (must have been EOC)
+		self.set_fat_entry(cluster, FatEntry::EndOfChain, FatEntry::Unallocated)?;
+		self.fsinfo_update(1, None)?;
+		Ok( () )
+	}
+
+	/// Release an entire cluster chain (starting at `first`)
+	pub fn free_chain(&self, first: ClusterNum) -> Result<(), ::vfs::Error> {
+		let _lh = self.fat_lock.lock();
+		self.free_chain_inner(first)
+	}
+
+	/// Terminate a chain after `last`, freeing any following clusters
+	pub fn truncate_chain(&self, last: ClusterNum) -> Result<(), ::vfs::Error> {
+		let _lh = self.fat_lock.lock();
+		match self.get_fat_entry(last)?
 		{
-			if let Some(rv) = self.find_and_alloc_cluster_in_sector(base, 0, cps)? {
-				assert_eq!(self.get_fat_entry(rv).unwrap(), FatEntry::EndOfChain);
-				return Ok(Some(rv));
-			}
+		FatEntry::EndOfChain => Ok( () ),
+		FatEntry::Chain(next) => {
+			self.set_fat_entry(last, FatEntry::Chain(next), FatEntry::EndOfChain)?;
+			self.free_chain_inner( ClusterNum::new(next).map_err(|()| ::vfs::Error::InconsistentFilesystem)? )
+			},
+		e => {
+			log_error!("truncate_chain({}): Cluster isn't in a chain ({:?})", last, e);
+			Err(::vfs::Error::InconsistentFilesystem)
+			},
 		}
+	}
+}
+
+/// Internal allocation methods (`fat_lock` must be held)
+impl super::FilesystemInner
+{
+	/// Past-the-end cluster number
+	fn max_cluster(&self) -> u32 {
+		// NOTE: `ClusterNum` is limited to 24 bits (due to the inode packing)
+		u32::min(self.cluster_count as u32 + 2, 0x100_0000)
+	}
 
-		// Loop back around until the starting point.
-		if aligned != 0
+	fn alloc_cluster_inner(&self, prev_cluster: ClusterNum) -> Result< Option<ClusterNum>, storage::IoError > {
+		// Start searching just after the previous cluster (to reduce fragmentation), or from the FSInfo hint
+		let start = if prev_cluster.get() >= self.max_cluster() {
+				self.fsinfo.as_ref()
+					.map(|i| i.next_free.load(Ordering::Relaxed))
+					.filter(|&v| 2 <= v && v < self.max_cluster())
+					.unwrap_or(2)
+			}
+			else {
+				prev_cluster.get() + 1
+			};
+		let Some(rv) = self.find_free_cluster(start)? else { return Ok(None); };
+		let rv = ClusterNum::new(rv).map_err(|()| storage::IoError::Unknown("FAT: Allocated invalid cluster"))?;
+		self.set_fat_entry(rv, FatEntry::Unallocated, FatEntry::EndOfChain)?;
+		self.fsinfo_update(-1, Some(rv.get()))?;
+		Ok(Some(rv))
+	}
+
+	fn free_chain_inner(&self, first: ClusterNum) -> Result<(), ::vfs::Error> {
+		let mut cluster = first;
+		let mut count: i32 = 0;
+		loop
 		{
-			for base in (0 .. aligned - cps).step_by(cps as usize)
+			let ent = self.get_fat_entry(cluster)?;
+			match ent
 			{
-				if let Some(rv) = self.find_and_alloc_cluster_in_sector(base, 0, cps)? {
-					assert_eq!(self.get_fat_entry(rv).unwrap(), FatEntry::EndOfChain);
-					return Ok(Some(rv));
-				}
+			FatEntry::Chain(_) | FatEntry::EndOfChain => {},
+			_ => {
+				log_error!("free_chain({}): {} is not allocated ({:?})", first, cluster, ent);
+				self.fsinfo_update(count, None)?;
+				return Err(::vfs::Error::InconsistentFilesystem);
+				},
+			}
+			self.set_fat_entry(cluster, ent, FatEntry::Unallocated)?;
+			count += 1;
+			match ent
+			{
+			FatEntry::Chain(next) => cluster = ClusterNum::new(next).map_err(|()| ::vfs::Error::InconsistentFilesystem)?,
+			_ => break,
 			}
 		}
-		if aligned != prev_cluster + 1
+		log_debug!("free_chain({}): Released {} clusters", first, count);
+		self.fsinfo_update(count, None)?;
+		Ok( () )
+	}
+
+	/// Locate an unallocated cluster, starting at `start` and wrapping around
+	fn find_free_cluster(&self, start: u32) -> Result<Option<u32>, storage::IoError> {
+		let end = self.max_cluster();
+		if let Some(rv) = self.find_free_cluster_in(start, end)? {
+			return Ok(Some(rv));
+		}
+		self.find_free_cluster_in(2, start)
+	}
+	fn find_free_cluster_in(&self, start: u32, end: u32) -> Result<Option<u32>, storage::IoError> {
+		match self.ty
 		{
-			assert!(aligned > 0);
-			let base = aligned - cps;
-			if let Some(rv) = self.find_and_alloc_cluster_in_sector(aligned - cps, 0, prev_cluster + 1 - base)? {
-				assert_eq!(self.get_fat_entry(rv).unwrap(), FatEntry::EndOfChain);
-				return Ok(Some(rv));
+		// FAT12 entries straddle sectors, and the tables are small - so just check each entry
+		Size::Fat12 => {
+			for c in start .. end {
+				if let FatEntry::Unallocated = FatEntry::from_fat12(self.read_fat12_raw(c)?) {
+					return Ok(Some(c));
+				}
+			}
+			},
+		// Otherwise, scan a cache block at a time
+		Size::Fat16|Size::Fat32 => {
+			let ent_size = if is!(self.ty, Size::Fat16) { 2 } else { 4 };
+			let mut c = start;
+			while c < end
+			{
+				let (sector, ofs) = self.get_fat_addr(c);
+				// Scan to the end of the cache entry (or the end of the block, if blocks are larger than a page)
+				let bpp = self.vh.blocks_per_page();
+				let blk = ::kernel::futures::block_on(self.vh.get_blocks(sector, (bpp - sector % bpp) as usize))?;
+				let (found, count) = blk.with_data(|data| {
+					let data = &data[ofs..];
+					let count = u32::min( (data.len() / ent_size) as u32, end - c );
+					for (i,mut e) in data.chunks(ent_size).take(count as usize).enumerate()
+					{
+						let ent = match self.ty
+							{
+							Size::Fat16 => FatEntry::from_fat16(e.read_u16::<LittleEndian>().unwrap()),
+							_ => FatEntry::from_fat32(e.read_u32::<LittleEndian>().unwrap()),
+							};
+						if let FatEntry::Unallocated = ent {
+							return (Some(c + i as u32), count);
+						}
+					}
+					(None, count)
+					});
+				if found.is_some() {
+					return Ok(found);
+				}
+				c += count;
 			}
+			},
 		}
-
 		Ok(None)
 	}
-	/// Deallocate a cluster (at the end of a chain)
-	pub fn release_cluster(&self, cluster: ClusterNum, prev: Option<ClusterNum>) -> Result<(), storage::IoError> {
-		if let Some(prev) = prev {
-			// Set the entry to EOC, must have been `cluster_idx`
-			self.set_fat_entry(prev, FatEntry::Chain(cluster.get()), FatEntry::EndOfChain)?;
+
+	/// Update the FSInfo free count (and next free hint) if present
+	fn fsinfo_update(&self, delta: i32, next_free: Option<u32>) -> Result<(), storage::IoError> {
+		let Some(ref info) = self.fsinfo else { return Ok(()) };
+		let free_count = info.free_count.load(Ordering::Relaxed);
+		let free_count = if free_count == FSINFO_UNKNOWN { free_count } else { (free_count as i32 + delta) as u32 };
+		info.free_count.store(free_count, Ordering::Relaxed);
+		if let Some(v) = next_free {
+			info.next_free.store(v, Ordering::Relaxed);
 		}
-		// Set this cluster to 0 (must have been EOC)
-		self.set_fat_entry(cluster, FatEntry::EndOfChain, FatEntry::Unallocated)?;
-		Ok( () )
+		let next_free = info.next_free.load(Ordering::Relaxed);
+		::kernel::futures::block_on(self.vh.edit(info.sector, 1, |data| {
+			write_u32_le(&mut data[488..][..4], free_count);
+			write_u32_le(&mut data[492..][..4], next_free);
+			}))
 	}
+}
 
-	// TODO: A deallocation that chains?
+/// Cached FSInfo sector state (FAT32 only)
+pub struct FsInfo
+{
+	sector: u64,
+	free_count: ::core::sync::atomic::AtomicU32,
+	next_free: ::core::sync::atomic::AtomicU32,
+}
+impl FsInfo
+{
+	/// Read and validate the FSInfo sector
+	pub fn load(vh: &::block_cache::CachedVolume, sector: u64, cluster_count: usize) -> Result<Option<FsInfo>, storage::IoError>
+	{
+		let mut buf = [0; 512];
+		::kernel::futures::block_on(vh.read_inner(sector, 0, &mut buf))?;
+		let info = super::on_disk::FsInfo::read(&buf);
+		if info.lead_sig != super::on_disk::FSINFO_LEAD_SIG || info.struct_sig != super::on_disk::FSINFO_STRUCT_SIG {
+			log_notice!("FSInfo at sector {} has bad signatures ({:#x},{:#x})", sector, info.lead_sig, info.struct_sig);
+			return Ok(None);
+		}
+		// Sanity check the values, invalidating them if they're not possible
+		let free_count = if info.free_count as usize > cluster_count { FSINFO_UNKNOWN } else { info.free_count };
+		log_debug!("FSInfo: free_count={:#x} next_free={:#x}", free_count, info.next_free);
+		Ok(Some(FsInfo {
+			sector: sector,
+			free_count: ::core::sync::atomic::AtomicU32::new(free_count),
+			next_free: ::core::sync::atomic::AtomicU32::new(info.next_free),
+			}))
+	}
 }
 
 #[derive(Copy,Clone,Debug, PartialEq)]
 enum FatEntry {
 	Unallocated,
 	EndOfChain,
+	Bad,
 	Chain(u32),
 }
 impl FatEntry {
-	fn from_fat12_outer(val: u32, is_second: bool) -> Self {
-		Self::from_fat12(if is_second { (val >> 12) as u16 } else { (val & 0xFFF) as u16 })
-	}
 	fn from_fat12(val: u16) -> Self {
 		match val {
 		0 => FatEntry::Unallocated,
-		FAT12_EOC => FatEntry::EndOfChain,
+		FAT12_BAD => FatEntry::Bad,
+		val if val > FAT12_BAD => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val as u32),
 		}
 	}
 	fn from_fat16(val: u16) -> Self {
 		match val {
 		0 => FatEntry::Unallocated,
-		FAT16_EOC => FatEntry::EndOfChain,
+		FAT16_BAD => FatEntry::Bad,
+		val if val > FAT16_BAD => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val as u32),
 		}
 	}
 	fn from_fat32(val: u32) -> Self {
-		match val {
+		match val & FAT32_MASK {
 		0 => FatEntry::Unallocated,
-		FAT32_EOC => FatEntry::EndOfChain,
+		FAT32_BAD => FatEntry::Bad,
+		val if val > FAT32_BAD => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val),
 		}
 	}
@@ -140,21 +287,15 @@ impl FatEntry {
 		match self {
 		FatEntry::Unallocated => 0,
 		FatEntry::EndOfChain => FAT12_EOC,
+		FatEntry::Bad => FAT12_BAD,
 		FatEntry::Chain(val) => val as u16,
 		}
 	}
-	fn to_fat12_outer(self, prev: u32, is_second: bool) -> u32 {
-		if is_second {
-			(prev & 0xFFF) | (self.to_fat12() as u32) << 12
-		}
-		else {
-			(prev & 0xFFF_000) | (self.to_fat12() as u32) << 0
-		}
-	}
 	fn to_fat16(self) -> u16 {
 		match self {
 		FatEntry::Unallocated => 0,
 		FatEntry::EndOfChain => FAT16_EOC,
+		FatEntry::Bad => FAT16_BAD,
 		FatEntry::Chain(val) => val as u16,
 		}
 	}
@@ -162,6 +303,7 @@ impl FatEntry {
 		match self {
 		FatEntry::Unallocated => 0,
 		FatEntry::EndOfChain => FAT32_EOC,
+		FatEntry::Bad => FAT32_BAD,
 		FatEntry::Chain(val) => val,
 		}
 	}
@@ -169,169 +311,120 @@ impl FatEntry {
 
 impl super::FilesystemInner
 {
-	fn get_fat_addr(&self, cluster: u32) -> (u64, usize, usize, u32) {
-		// - Determine what sector contains the requested FAT entry
-		let bs = self.vh.block_size();
-		let cps;
-		let (ofs, ent_len) = match self.ty
+	/// Get the sector and byte offset of a FAT entry (in the first/active FAT)
+	fn get_fat_addr(&self, cluster: u32) -> (u64, usize) {
+		let bs = self.vh.block_size() as u64;
+		let byte_ofs = match self.ty
 			{
-			Size::Fat12 => {
-				cps = bs as u32 / 3 * 2;	// 2 per 3 bytes
-				( (cluster % cps) / 2 * 3, 3 )
-				},
-			Size::Fat16 => {
-				cps = bs as u32 / 2;
-				( (cluster % cps) * 2, 2, )
-				},
-			Size::Fat32 => {
-				cps = bs as u32 / 4;
-				( (cluster % cps) * 4, 4, )
-				},
+			Size::Fat12 => cluster as u64 * 3 / 2,	// 1.5 bytes per entry
+			Size::Fat16 => cluster as u64 * 2,
+			Size::Fat32 => cluster as u64 * 4,
 			};
-		let sector_idx = self.first_fat_sector as u64 + (cluster / cps) as u64;
-		//log_trace!("get_fat_addr({}): S {} ofs={} ent_len={} cps={}", cluster, sector_idx, ofs, ent_len, cps);
-		(sector_idx, ofs as usize, ent_len, cps)
+		let sector_idx = self.first_fat_sector as u64 + byte_ofs / bs;
+		//log_trace!("get_fat_addr({}): S {} ofs={}", cluster, sector_idx, byte_ofs % bs);
+		(sector_idx, (byte_ofs % bs) as usize)
+	}
+
+	/// Read the raw 12-bit value for a FAT12 entry (which may straddle two sectors)
+	fn read_fat12_raw(&self, cluster: u32) -> Result<u16, storage::IoError> {
+		let (sector_idx, ofs) = self.get_fat_addr(cluster);
+		let mut buf = [0; 2];
+		if ofs + 1 < self.vh.block_size() {
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+		}
+		else {
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..1]) )?;
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx+1, 0, &mut buf[1..]) )?;
+		}
+		let val = u16::from_le_bytes(buf);
+		Ok(if cluster % 2 == 1 { val >> 4 } else { val & 0xFFF })
+	}
+
+	/// Write a byte range into the FAT, updating all mirrors
+	fn write_fat_bytes(&self, sector_idx: u64, ofs: usize, data: &[u8]) -> Result<(), storage::IoError> {
+		for i in 0 .. self.fat_mirror_count {
+			let sector = sector_idx + (i * self.fat_size) as u64;
+			::kernel::futures::block_on(self.vh.edit(sector, 1, |buf| buf[ofs..][..data.len()].copy_from_slice(data)))?;
+		}
+		Ok( () )
 	}
 
 	/// Read a FAT entry
 	fn get_fat_entry(&self, cluster: ClusterNum) -> Result<FatEntry, storage::IoError> {
-		let (sector_idx, ofs, ent_len, _cps) = self.get_fat_addr(cluster.get());
+		let (sector_idx, ofs) = self.get_fat_addr(cluster.get());
 
-		// - Read entry from the FAT
-		let mut buf = [0; 4];
-		::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..ent_len]) )?;
-		let mut buf = &buf[..ent_len];
-
-		// - Extract the entry
 		Ok(match self.ty
 		{
-		// FAT12 has special handling because it packs 2 entries into 24 bytes
-		Size::Fat12 => FatEntry::from_fat12_outer(buf.read_uint::<LittleEndian>(3).unwrap() as u32, cluster.get() % 2 == 1),
-		Size::Fat16 => FatEntry::from_fat16(buf.read_u16::<LittleEndian>().unwrap()),
-		Size::Fat32 => FatEntry::from_fat32(buf.read_u32::<LittleEndian>().unwrap()),
+		// FAT12 has special handling because it packs 2 entries into 24 bits
+		Size::Fat12 => FatEntry::from_fat12(self.read_fat12_raw(cluster.get())?),
+		Size::Fat16 => {
+			let mut buf = [0; 2];
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+			FatEntry::from_fat16(u16::from_le_bytes(buf))
+			},
+		Size::Fat32 => {
+			let mut buf = [0; 4];
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+			FatEntry::from_fat32(u32::from_le_bytes(buf))
+			},
 		})
 	}
 	/// Update a FAT entry, checking the previous value
+	///
+	/// NOTE: The caller must hold `fat_lock`
 	fn set_fat_entry(&self, cluster: ClusterNum, exp_prev: FatEntry, new: FatEntry) -> Result< (), storage::IoError > {
-		let (sector_idx, ofs, ent_len, _cps) = self.get_fat_addr(cluster.get());
+		let (sector_idx, ofs) = self.get_fat_addr(cluster.get());
 
-		// Use `block_cache`'s read/write locks
-		let changed = ::kernel::futures::block_on(self.vh.edit(sector_idx, 1, |buf| {
-			let buf = &mut buf[ofs..][..ent_len];
-			match self.ty
-			{
-			// FAT12 has special handling because it packs 2 entries into 24 bytes
-			Size::Fat12 => {
-				let val = (&buf[..]).read_uint::<LittleEndian>(3).unwrap() as u32;
-				let newval = if cluster.get() % 2 == 0 {
-						let cur = (val & 0xFFF) as u16;
-						if cur != exp_prev.to_fat12() {
-							log_error!("FAT Check failure: {} expected {:?} got {:?}",
-								cluster, exp_prev, FatEntry::from_fat12(cur));
-							return false;
-						}
-						(val & 0xFFF_000) | (new.to_fat12() as u32) << 0
-					}
-					else {
-						let cur = (val >> 12) as u16;
-						if cur != exp_prev.to_fat12() {
-							log_error!("FAT Check failure: {} expected {:?} got {:?}",
-								cluster, exp_prev, FatEntry::from_fat12(cur));
-							return false;
-						}
-						(val & 0x000_FFF) | (new.to_fat12() as u32) << 12
-					};
-				write_u24_le(buf, newval);
-				},
-			// Simple read+check and write for FAT16/FAT32
-			Size::Fat16 => {
-				let val = (&buf[..]).read_u16::<LittleEndian>().unwrap();
-				if val != exp_prev.to_fat16() {
-					log_error!("FAT Check failure: {} expected {:?} got {:?}",
-						cluster, exp_prev, FatEntry::from_fat16(val));
-					return false;
-				}
-				write_u16_le(buf, new.to_fat16());
-				},
-			Size::Fat32 => {
-				let val = (&buf[..]).read_u32::<LittleEndian>().unwrap();
-				if val != exp_prev.to_fat32() {
-					log_error!("FAT Check failure: {} expected {:?} got {:?}",
-						cluster, exp_prev, FatEntry::from_fat32(val));
-					return false;
-				}
-				write_u32_le(buf, new.to_fat32());
-				},
-			}
-			true
-			}))?;
-
-		if !changed {
-			Err(storage::IoError::Unknown("FAT: Internal assertion failure"))
-		}
-		else {
-			Ok( () )
+		let cur = self.get_fat_entry(cluster)?;
+		if cur != exp_prev {
+			log_error!("FAT Check failure: {} expected {:?} got {:?}", cluster, exp_prev, cur);
+			return Err(storage::IoError::Unknown("FAT: Internal assertion failure"));
 		}
-	}
 
-	/// Find an unallocated cluster in this FAT sector, and mark it as allocated (using EOC)
-	/// * `base`: The index of the first cluster in this FAT sector
-	/// * `start`: Sector-internal index of the first cluster to consider
-	/// * `end`: Sector-internal index of the past-end cluster to consider
-	fn find_and_alloc_cluster_in_sector(&self, base: u32, start: u32, end: u32) -> Result<Option<ClusterNum>, storage::IoError>
-	{
-		assert!(base < self.cluster_count as u32, "find_and_alloc_cluster_in_sector: base {} >= count {}", base, self.cluster_count);
-		let (sector_idx, _ofs, _ent_size, cps) = self.get_fat_addr(base);
-		assert!(base % cps == 0);
-		// Clamp the end to CPS (should be already), and to the last cluster in the volume (may not be)
-		let end = end.min(cps).min(self.cluster_count as u32 - base);
-		::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |data| {
-			match self.ty
-			{
-			Size::Fat12 => {
-				for sub_idx in start .. end {
-					let buf = &mut data[sub_idx as usize / 2 * 3..][..3];
-					let val = {&buf[..]}.read_uint::<LittleEndian>(3).unwrap() as u32;
-					if let FatEntry::Unallocated = FatEntry::from_fat12_outer(val, sub_idx % 2 == 1) {
-						write_u24_le(buf, FatEntry::EndOfChain.to_fat12_outer(val, sub_idx % 2 == 1));
-						return Some(ClusterNum::new(base + sub_idx).unwrap());
-					}
-				}
-				},
-			Size::Fat16 => {
-				for sub_idx in start .. end {
-					let buf = &mut data[sub_idx as usize * 2..][..2];
-					let val = {&buf[..]}.read_u16::<LittleEndian>().unwrap();
-					if let FatEntry::Unallocated = FatEntry::from_fat16(val) {
-						write_u16_le(buf, FatEntry::EndOfChain.to_fat16());
-						return Some(ClusterNum::new(base + sub_idx).unwrap());
-					}
-				}
-				},
-			Size::Fat32 => {
-				for sub_idx in start .. end {
-					let buf = &mut data[sub_idx as usize * 4..][..4];
-					let val = {&buf[..]}.read_u32::<LittleEndian>().unwrap();
-					if let FatEntry::Unallocated = FatEntry::from_fat32(val) {
-						write_u32_le(buf, FatEntry::EndOfChain.to_fat32());
-						return Some(ClusterNum::new(base + sub_idx).unwrap());
-					}
+		match self.ty
+		{
+		// FAT12 has special handling because it packs 2 entries into 24 bits, and entries can straddle sectors
+		Size::Fat12 => {
+			let mut buf = [0; 2];
+			let straddles = ofs + 1 >= self.vh.block_size();
+			if !straddles {
+				::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+			}
+			else {
+				::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..1]) )?;
+				::kernel::futures::block_on( self.vh.read_inner(sector_idx+1, 0, &mut buf[1..]) )?;
+			}
+			let val = u16::from_le_bytes(buf);
+			let newval = if cluster.get() % 2 == 1 {
+					(val & 0x000F) | new.to_fat12() << 4
 				}
-				},
+				else {
+					(val & 0xF000) | new.to_fat12()
+				};
+			let buf = newval.to_le_bytes();
+			if !straddles {
+				self.write_fat_bytes(sector_idx, ofs, &buf)?;
 			}
-			None
-			}) )
+			else {
+				self.write_fat_bytes(sector_idx, ofs, &buf[..1])?;
+				self.write_fat_bytes(sector_idx+1, 0, &buf[1..])?;
+			}
+			},
+		Size::Fat16 => {
+			self.write_fat_bytes(sector_idx, ofs, &new.to_fat16().to_le_bytes())?;
+			},
+		// FAT32 needs to preserve the reserved top bits
+		Size::Fat32 => {
+			let mut buf = [0; 4];
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+			let val = (u32::from_le_bytes(buf) & !FAT32_MASK) | new.to_fat32();
+			self.write_fat_bytes(sector_idx, ofs, &val.to_le_bytes())?;
+			},
+		}
+		Ok( () )
 	}
 }
 
-fn write_u24_le(dst: &mut [u8], val: u32) {
-	dst[0] = (val >>  0) as u8;
-	dst[1] = (val >>  8) as u8;
-	dst[2] = (val >> 16) as u8;
-}
-fn write_u16_le(dst: &mut [u8], val: u16) {
-	dst.copy_from_slice(&val.to_le_bytes());
-}
 fn write_u32_le(dst: &mut [u8], val: u32) {
 	dst.copy_from_slice(&val.to_le_bytes());
 }
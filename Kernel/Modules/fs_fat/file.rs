@@ -26,19 +26,39 @@ impl FileNode
 			size: ::kernel::sync::RwLock::new(size),
 			})
 	}
+
+	/// Returns the owning filesystem, first cluster, and current size (used by `DirNode::link`)
+	pub(crate) fn get_ref(&self) -> (&FilesystemInner, ClusterNum, u32) {
+		(&self.fs, self.first_cluster, *self.size.read())
+	}
 }
 impl ::core::ops::Drop for FileNode {
 	fn drop(&mut self) {
-		//super::dir::close_file(&self.fs, self.first_cluster);
+		self.fs.close_file(self.first_cluster);
 	}
 }
 impl node::NodeBase for FileNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("FileNode::get_id")
+		let dir_cluster = self.fs.open_files.read().get(&self.first_cluster).expect("FileNode::get_id - Not in open list").dir_cluster();
+		super::InodeRef::new(self.first_cluster, dir_cluster).to_id()
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		// Hold the open file list so the file can't be moved during the lookup
+		let lh_files = self.fs.open_files.read();
+		let info = lh_files.get(&self.first_cluster).expect("FileNode::get_metadata - Not in open list");
+		if info.is_unlinked() {
+			let size = *self.size.read();
+			return Ok(node::Metadata {
+				allocated_size: ::kernel::lib::num::round_up(size as u64, self.fs.cluster_size as u64),
+				mode: 0o777,
+				..Default::default()
+				});
+		}
+		super::dir::DirNode::new(self.fs.reborrow(), info.dir_cluster()).get_child_metadata(self.first_cluster)
+	}
 }
 impl node::File for FileNode {
 	fn size(&self) -> u64 {
@@ -49,19 +69,36 @@ impl node::File for FileNode {
 		let mut size_lh = self.size.write();
 		if newsize < *size_lh {
 			// Update size, and then deallocate clusters
-			let old_size = *size_lh;
+			// - Size first, so a failure when releasing just leaks clusters
 			super::dir::update_file_size(&self.fs, self.first_cluster, newsize)?;
 			*size_lh = newsize;
-			todo!("FileNode::truncate({:#x}): old_size={:#x}", newsize, old_size);
+			// Always keep the first cluster, as it's used as the file's identifier
+			let keep_clusters = ::core::cmp::max(1, ::kernel::lib::num::div_up(newsize as usize, self.fs.cluster_size));
+			let mut clusters = super::ClusterList::chained(&self.fs, self.first_cluster);
+			let last = clusters.nth(keep_clusters - 1).ok_or(ERROR_SHORTCHAIN)?;
+			log_debug!("truncate({:#x}): Keeping {} clusters, ending at {}", newsize, keep_clusters, last);
+			self.fs.truncate_chain(last)?;
+			Ok( newsize as u64 )
+		}
+		else if newsize > *size_lh {
+			// Allocate new clusters, and zero-fill (updating the size as we go)
+			let ofs = *size_lh as u64;
+			self.write_inner(&mut size_lh, ofs, WriteData::Zero((newsize - ofs as u32) as usize))?;
+			Ok( *size_lh as u64 )
 		}
 		else {
-			// Allocate new clusters, then update the size
-			// Update size iteratively, allocating clusters as needed
-			todo!("FileNode::truncate({:#x})", newsize);
+			Ok( newsize as u64 )
 		}
 	}
 	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
-		todo!("FileNode::clear({:#x}+{:#x}", ofs, size);
+		let mut size_lh = self.size.write();
+		if ofs >= *size_lh as u64 {
+			return Ok( () );
+		}
+		// Clearing doesn't change the file size, so clamp to the end of the file
+		let size = u64::min(size, *size_lh as u64 - ofs);
+		self.write_inner(&mut size_lh, ofs, WriteData::Zero(size as usize))?;
+		Ok( () )
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
 		let maxread = {
@@ -144,49 +181,103 @@ impl node::File for FileNode {
 		Ok( read_length )
 	}
 	/// Write data to the file, can only grow the file if ofs==size
-	fn write(&self, ofs: u64, mut buf: &[u8]) -> node::Result<usize> {
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
 		let mut size_lh = self.size.write();
-		if ofs == *size_lh as u64 {
-			let rv = buf.len();
-			// Write data, allocating new clusters if needed
-			let mut clusters = super::ClusterList::chained(&self.fs, self.first_cluster);
-			let mut prev_cluster = self.first_cluster;
-			for _ in 0 .. (ofs/self.fs.cluster_size as u64) {
-				prev_cluster = clusters.next().ok_or(vfs::Error::InconsistentFilesystem)?;
-			}
-			let ofs = (ofs % self.fs.cluster_size as u64) as usize;
-			while buf.len() > 0
+		self.write_inner(&mut size_lh, ofs, WriteData::Data(buf))
+	}
+}
+
+/// Source of data for `FileNode::write_inner`
+#[derive(Copy,Clone)]
+enum WriteData<'a>
+{
+	Data(&'a [u8]),
+	/// Zero-fill the given number of bytes
+	Zero(usize),
+}
+impl<'a> WriteData<'a>
+{
+	fn len(&self) -> usize {
+		match *self
+		{
+		WriteData::Data(d) => d.len(),
+		WriteData::Zero(l) => l,
+		}
+	}
+}
+
+impl FileNode
+{
+	/// Write (or zero-fill) a range of the file, extending the file if the range passes the current end
+	///
+	/// `size` is the locked file size, which is updated (both in memory and on disk) if the file grows.
+	fn write_inner(&self, size: &mut u32, ofs: u64, data: WriteData) -> node::Result<usize> {
+		if ofs > *size as u64 {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		// FAT file sizes are 32-bit
+		let len = u64::min( data.len() as u64, u32::MAX as u64 - ofs ) as usize;
+		if len == 0 {
+			return if data.len() == 0 { Ok(0) } else { Err(::vfs::Error::OutOfSpace) };
+		}
+		log_trace!("write_inner(@{:#x} len={:#x}, size={:#x})", ofs, len, *size);
+		let cluster_size = self.fs.cluster_size;
+		let zero_cluster = match data
 			{
-				let cluster = if let Some(c) = clusters.next() {
-						c
-					} else {
-						// Need to allocate a new one!
-						self.fs.alloc_cluster_chained(prev_cluster)?.ok_or(vfs::Error::OutOfSpace)?
-					};
-				let len = usize::min(self.fs.cluster_size - ofs, buf.len());
-				if len == self.fs.cluster_size {
-					assert!(ofs == 0);
-					::kernel::futures::block_on(self.fs.write_clusters(cluster, &buf[..len]))?;
+			WriteData::Zero(_) => vec![0; cluster_size],
+			WriteData::Data(_) => Vec::new(),
+			};
+		let old_size = *size;
+
+		// Seek to correct position in the cluster chain
+		let mut clusters = super::ClusterList::chained(&self.fs, self.first_cluster);
+		let mut prev_cluster = self.first_cluster;
+		for _ in 0 .. (ofs / cluster_size as u64) {
+			prev_cluster = clusters.next().ok_or(ERROR_SHORTCHAIN)?;
+		}
+
+		let mut cluster_ofs = (ofs % cluster_size as u64) as usize;
+		let mut pos = 0;
+		while pos < len
+		{
+			let cluster = if let Some(c) = clusters.next() {
+					c
 				}
 				else {
-					::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
-						c[ofs..][..len].copy_from_slice(&buf[..len]);
-						}))?;
-				}
-				buf = &buf[len..];
-				*size_lh += len as u32;
+					// Need to allocate a new one!
+					match self.fs.alloc_cluster_chained(prev_cluster)?
+					{
+					Some(c) => c,
+					None if pos > 0 => break,	// Return a short write
+					None => return Err(::vfs::Error::OutOfSpace),
+					}
+				};
+			let seg_len = usize::min(cluster_size - cluster_ofs, len - pos);
+			let src = match data
+				{
+				WriteData::Data(d) => &d[pos..][..seg_len],
+				WriteData::Zero(_) => &zero_cluster[..seg_len],
+				};
+			if seg_len == cluster_size {
+				::kernel::futures::block_on(self.fs.write_clusters(cluster, src))?;
+			}
+			else {
+				::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
+					c[cluster_ofs..][..seg_len].copy_from_slice(src);
+					}))?;
+			}
+			prev_cluster = cluster;
+			cluster_ofs = 0;
+			pos += seg_len;
+			if ofs + pos as u64 > *size as u64 {
+				*size = (ofs + pos as u64) as u32;
 			}
-			// Update the size
-			super::dir::update_file_size(&self.fs, self.first_cluster, *size_lh)?;
-
-			Ok(rv)
 		}
-		else {
-			// Don't want the size to reduce while this is happening...
-			// - But it _could_ increase due to append?
-			// - or just prevent a change
-			todo!("FileNode::write({:#x}, {:p}) - inplace", ofs, ::kernel::lib::SlicePtr(buf));
+
+		// Update the size
+		if *size != old_size {
+			super::dir::update_file_size(&self.fs, self.first_cluster, *size)?;
 		}
+		Ok(pos)
 	}
 }
-
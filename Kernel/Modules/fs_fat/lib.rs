@@ -64,12 +64,22 @@ pub struct FilesystemInner
 	vh: ::block_cache::CachedVolume,
 	ty: Size,
 	
+	// NOTE: All sector counts/indexes are in units of volume blocks (which can be smaller than the FAT sector size)
+	/// Sectors per cluster
 	spc: usize,
 	cluster_size: usize,
 	/// Total number of data clusters
 	cluster_count: usize,
 	first_fat_sector: usize,
 	first_data_sector: usize,
+	/// Size of a single FAT (in sectors)
+	fat_size: usize,
+	/// Number of FAT copies that are kept in sync (1 if FAT32 mirroring is disabled)
+	fat_mirror_count: usize,
+	/// Lock held while the FAT is being modified (check-and-set of entries, and allocation)
+	fat_lock: ::kernel::sync::Mutex<()>,
+	/// FAT32 FSInfo sector (free count and allocation hint)
+	fsinfo: Option<fat::FsInfo>,
 	
 	root_first_cluster: ClusterNum,
 	root_sector_count: u32,
@@ -77,6 +87,8 @@ pub struct FilesystemInner
 	// TODO: Directory handles (with the dir's lock, and the number of open handles/files)
 	dir_info: ::kernel::sync::RwLock<::kernel::lib::collections::VecMap<ClusterNum,Arc<dir::DirInfo>>>,
 	open_files: ::kernel::sync::RwLock<::kernel::lib::collections::VecMap<ClusterNum,dir::OpenFileInfo>>,
+	/// Number of extra directory entries referencing a cluster chain (created by `link`, removed by `unlink`)
+	extra_links: ::kernel::sync::Mutex<::kernel::lib::collections::VecMap<ClusterNum,u32>>,
 }
 
 
@@ -93,9 +105,9 @@ impl mount::Driver for Driver
 {
 	fn detect(&self, vol: &VolumeHandle) -> ::vfs::Result<usize> {
 		let bs = {
-			let mut bs = [0u8; 512];
+			let mut bs = vec![0u8; ::core::cmp::max(512, vol.block_size())];
 			::kernel::futures::block_on( vol.read_blocks(0, &mut bs) )?;
-			on_disk::BootSect::read(&bs)
+			on_disk::BootSect::read(&bs[..512])
 			};
 		
 		let bps = bs.common().bps;
@@ -109,7 +121,7 @@ impl mount::Driver for Driver
 			Ok(1)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
 		let vol = ::block_cache::CachedVolume::new(vol);
 
 		// Read the bootsector
@@ -118,8 +130,10 @@ impl mount::Driver for Driver
 			on_disk::BootSect::read(&mut &blk.data()[..512])
 			};
 		let bs_c = bs.common();
-		if bs_c.bps != 512 {
-			return Err(::vfs::Error::Unknown("TODO: non 512-byte sector FAT"))
+		// FAT sectors can be larger than the volume's blocks (e.g. 4K FAT sectors on a 512 byte device), but not smaller
+		if !(bs_c.bps as usize).is_power_of_two() || (bs_c.bps as usize) < ::core::cmp::max(512, vol.block_size()) {
+			log_warning!("FAT sector size {} unsupported on volume with {} byte blocks", bs_c.bps, vol.block_size());
+			return Err(::vfs::Error::Unknown("FAT: Unsupported sector size"))
 		}
 		if bs_c.fat_count == 0 {
 			return Err(::vfs::Error::Unknown("FAT Count is 0"));
@@ -129,6 +143,8 @@ impl mount::Driver for Driver
 		
 		let bps = bs_c.bps as usize;
 		let spc = bs_c.spc as usize;
+		// Volume blocks per FAT sector
+		let vbps = bps / vol.block_size();
 		
 		let root_dir_sectors = (bs_c.files_in_root as usize*32 + bps - 1) / bps;
 		let fat_size = if bs_c.fat_size_16 > 0 {
@@ -149,7 +165,7 @@ impl mount::Driver for Driver
 		let first_data_sector = bs_c.reserved_sect_count as usize
 			+ fat_size + spare_fat_sectors
 			+ root_dir_sectors;
-		let cluster_count = (total_sectors - first_data_sector - spare_fat_sectors) / spc;
+		let cluster_count = (total_sectors - first_data_sector) / spc;
 		
 		// Determine the FAT type
 		let fat_type = if cluster_count < FAT16_MIN_CLUSTERS {
@@ -163,16 +179,37 @@ impl mount::Driver for Driver
 			};
 		log_debug!("{:?} {} sectors, Size {}", fat_type, total_sectors,
 			SizePrinter((total_sectors*bs_c.bps as usize) as u64));
+
+		// FAT32 can disable mirroring, and select a single active FAT
+		let (first_fat_sector, fat_mirror_count) = match bs.info32()
+			{
+			Some(info) if is!(fat_type, Size::Fat32) && info.ext_flags & 0x80 != 0 => {
+				let active = (info.ext_flags & 0xF) as usize;
+				log_debug!("FAT mirroring disabled, active FAT #{}", active);
+				(bs_c.reserved_sect_count as usize + active * fat_size, 1)
+				},
+			_ => (bs_c.reserved_sect_count as usize, bs_c.fat_count as usize),
+			};
+		let fsinfo = match bs.info32()
+			{
+			Some(info) if is!(fat_type, Size::Fat32) && info.fs_info != 0 && info.fs_info != 0xFFFF =>
+				fat::FsInfo::load(&vol, (info.fs_info as usize * vbps) as u64, cluster_count)?,
+			_ => None,
+			};
 		
 		Ok(Box::new(Filesystem {
 			// SAFE: Saving to a Box, so won't move
 			inner: unsafe { ArefInner::new(FilesystemInner {
 				ty: fat_type,
-				spc: spc,
-				cluster_size: spc * vol.block_size(),
+				spc: spc * vbps,
+				cluster_size: spc * bps,
 				cluster_count: cluster_count,
-				first_fat_sector: bs_c.reserved_sect_count as usize,
-				first_data_sector: first_data_sector,
+				first_fat_sector: first_fat_sector * vbps,
+				first_data_sector: first_data_sector * vbps,
+				fat_size: fat_size * vbps,
+				fat_mirror_count: fat_mirror_count,
+				fat_lock: Default::default(),
+				fsinfo: fsinfo,
 				root_first_cluster: match fat_type {
 					Size::Fat32 => ClusterNum::new(bs.info32().unwrap().root_cluster)
 						.map_err(|()| {
@@ -181,9 +218,10 @@ impl mount::Driver for Driver
 							})?,
 					_ => ClusterNum::new(FATL_ROOT_CLUSTER as u32).unwrap(),
 					},
-				root_sector_count: root_dir_sectors as u32,
+				root_sector_count: (root_dir_sectors * vbps) as u32,
 				dir_info: Default::default(),
 				open_files: Default::default(),
+				extra_links: Default::default(),
 
 				vh: vol,
 				}) },
@@ -232,15 +270,15 @@ impl FilesystemInner
 	}
 
 	/// Cached cluster access
+	/// 
+	/// NOTE: Clusters larger than a page span multiple cache entries, and are copied into a temporary buffer
 	async fn with_cluster<T>(&self, cluster: ClusterNum, callback: impl FnOnce(&[u8])->T) -> Result<T, storage::IoError> {
 		let sector = self.get_sector_for_cluster(cluster);
-		let block = self.vh.get_block(sector).await?;
-		let ofs = sector - block.index();
-		Ok( callback(&block.data()[ofs as usize * self.vh.block_size()..]) )
+		let blocks = self.vh.get_blocks(sector, self.spc).await?;
+		Ok( blocks.with_data(callback) )
 	}
 	async fn edit_cluster(&self, cluster: ClusterNum, callback: impl FnOnce(&mut [u8])) -> Result<(), storage::IoError> {
 		let sector = self.get_sector_for_cluster(cluster);
-		// TODO: What if a cluster is larger than a block?
 		self.vh.edit(sector, /*::block_cache::CacheType::Metadata,*/ self.spc, callback).await
 	}
 }
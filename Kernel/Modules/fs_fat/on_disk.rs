@@ -15,6 +15,9 @@ pub const ATTR_LFN: u8 = ATTR_READONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME
 #[allow(dead_code)]
 pub const ATTR_ARCHIVE  : u8 = 0x20;	// Flag set by user
 
+pub const FSINFO_LEAD_SIG: u32 = 0x41615252;
+pub const FSINFO_STRUCT_SIG: u32 = 0x61417272;
+
 pub const CASE_LOWER_BASE: u8 = 0x08;	// Linux (maybe NT) flag
 pub const CASE_LOWER_EXT : u8 = 0x10;	// Linux (maybe NT) flag
 
@@ -216,6 +219,26 @@ impl BootSect32Info {
 	}
 }
 
+/// FAT32 FSInfo sector
+pub struct FsInfo
+{
+	pub lead_sig: u32,
+	pub struct_sig: u32,
+	pub free_count: u32,
+	pub next_free: u32,
+}
+impl FsInfo {
+	pub fn read(src: &[u8]) -> FsInfo {
+		assert!(src.len() >= 512);
+		FsInfo {
+			lead_sig: read_u32(&mut &src[0..]),
+			struct_sig: read_u32(&mut &src[484..]),
+			free_count: read_u32(&mut &src[488..]),
+			next_free: read_u32(&mut &src[492..]),
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct DirEnt
 {
@@ -93,7 +93,7 @@ impl<'fs> ClusterList<'fs> {
 		{
 		ClusterList::Range(ref mut r) => {
 			let rv = r.start;
-			let count = u32::min( r.start - r.end, max_clusters as u32 );
+			let count = u32::min( r.end - r.start, max_clusters as u32 );
 			if count == 0 {
 				None
 			}
@@ -10,17 +10,23 @@ use vfs::{mount, node};
 use kernel::metadevs::storage::{self,VolumeHandle};
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
 use kernel::lib::byteorder::{ByteOrder,LittleEndian};
-use kernel::lib::byte_str::ByteStr;
+use kernel::lib::byte_str::{ByteStr,ByteString};
 
 #[macro_use]
 extern crate kernel;
 
 extern crate vfs;
 extern crate block_cache;
+extern crate utf16;
 
 module_define!{FS_ISO9660, [VFS], init}
 
-//mod ondisk;
+mod susp;
+
+use susp::RockRidge;
+
+/// Maximum number of volume descriptors to check before giving up
+const MAX_VOLUME_DESCRIPTORS: u32 = 32;
 
 struct Driver;
 static S_DRIVER: Driver = Driver;
@@ -38,7 +44,10 @@ struct InstanceInner
 	root_lba: u32,
 	root_size: u32,
 
+	/// SUSP (Rock Ridge) is in use, and this many bytes are skipped at the start of each system use area
 	susp_len_skip: Option<u8>,
+	/// Names are from a Joliet supplementary volume descriptor (UCS-2 big endian)
+	is_joliet: bool,
 }
 
 fn init()
@@ -64,68 +73,85 @@ impl mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
 		// For this to work properly, the block size must evenly divide 2048
 		if 2048 % vol.block_size() != 0 {
 			return Err( vfs::Error::Unknown("Can't mount ISO9660 with sector size not a factor of 2048"/*, vol.block_size()*/) );
 		}
 		let scale = 2048 / vol.block_size();
 		
-		// Search the start of the disk for the primary volume descriptor
-		// - TODO: Limit the number of sectors searched.
+		// Search the start of the disk for the primary volume descriptor (and a Joliet supplementary descriptor)
 		let mut block = vec![0u8; 2048];
-		for sector in 16 .. 
+		let mut pvd = None;
+		let mut joliet = None;
+		for sector in 16 .. 16 + MAX_VOLUME_DESCRIPTORS
 		{
-			::kernel::futures::block_on(vol.read_blocks((sector*scale) as u64, &mut block))?;
+			::kernel::futures::block_on(vol.read_blocks((sector as usize * scale) as u64, &mut block))?;
 			if &block[1..6] != b"CD001" {
 				return Err( vfs::Error::Unknown("Invalid volume descriptor present") );
 			}
-			else if block[0] == 255 {
-				return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") );
-			}
-			else if block[0] == 0x01 {
-				// Found it!
-				break ;
-			}
-			else {
-				// Try the next one
+			match block[0]
+			{
+			// Terminator
+			255 => break,
+			// Primary volume descriptor
+			0x01 if pvd.is_none() => {
+				//::kernel::logging::hex_dump("ISO966 PVD", &block);
+				pvd = Some( VolumeDescriptor::from_block(&block) );
+				},
+			// Supplementary volume descriptor, Joliet is indicated by the UCS-2 escape sequences
+			0x02 if joliet.is_none() && matches!(&block[88..91], b"%/@" | b"%/C" | b"%/E") => {
+				joliet = Some( VolumeDescriptor::from_block(&block) );
+				},
+			// Try the next one
+			_ => {},
 			}
 		}
-		//::kernel::logging::hex_dump("ISO966 PVD", &block);
-		
-		// Obtain the logical block size (different from medium sector size)
-		let lb_size = LittleEndian::read_u16(&block[128..]);
-		// Extract the root directory entry
-		// - We want the LBA and byte length
-		let root_lba  = LittleEndian::read_u32(&block[156+ 2..]);
-		let root_size = LittleEndian::read_u32(&block[156+10..]);
-		
-		log_debug!("lb_size = {}, root = {:#x} + {:#x} bytes", lb_size, root_lba, root_size);
-	
-	
+		let Some(pvd) = pvd else {
+			return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") );
+			};
+		log_debug!("lb_size = {}, root = {:#x} + {:#x} bytes (Joliet: {:?})", pvd.lb_size, pvd.root_lba, pvd.root_size, joliet);
+		if pvd.lb_size == 0 || pvd.lb_size % vol.block_size() != 0 || pvd.lb_size > ::kernel::PAGE_SIZE {
+			return Err( vfs::Error::Unknown("Unsupported ISO9660 logical block size") );
+		}
+
 		let mut inner = InstanceInner {
 			vh: ::block_cache::CachedVolume::new(vol),
-			lb_size: lb_size as usize,
-			root_lba: root_lba,
-			root_size: root_size,
+			lb_size: pvd.lb_size,
+			root_lba: pvd.root_lba,
+			root_size: pvd.root_size,
 			susp_len_skip: None,
+			is_joliet: false,
 			};
 
 		// Determine if SUSP is in use (used for RockRidge extensions)
 		inner.susp_len_skip = {
-			let mut it = DirSector::new(&inner, ::kernel::futures::block_on(inner.get_sector(root_lba))?, 0 );
+			let mut it = DirSector::new(&inner, ::kernel::futures::block_on(inner.get_sector(pvd.root_lba))?, 0 );
 			let first_ent = match it.next()?
 				{
 				None => return Err(vfs::Error::InconsistentFilesystem),
 				Some(v) => v,
 				};
-			if first_ent.sys_use.len() >= 6 && &first_ent.sys_use[..6] == b"SP\x07\x01\xBE\xEF" {
+			if first_ent.sys_use.len() >= 7 && &first_ent.sys_use[..6] == b"SP\x07\x01\xBE\xEF" {
 				Some(first_ent.sys_use[6])
 			}
 			else {
 				None
 			}
 			};
+
+		// Rock Ridge is preferred (as it carries more information), fall back to Joliet's names if it's not present
+		match (inner.susp_len_skip, joliet)
+		{
+		(Some(_), _) => log_log!("Using Rock Ridge names"),
+		(None, Some(j)) if j.lb_size == pvd.lb_size => {
+			log_log!("Using Joliet names");
+			inner.root_lba = j.root_lba;
+			inner.root_size = j.root_size;
+			inner.is_joliet = true;
+			},
+		(None, _) => log_log!("Using ISO9660 names"),
+		}
 		
 		// SAFE: Stored in a box, and not moved out.
 		Ok( Box::new( Instance(unsafe { ArefInner::new( inner ) }) ) )
@@ -139,10 +165,20 @@ impl mount::Filesystem for Instance
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		if id == 0 {
-			Some(Dir::new_node(self.0.borrow(), self.root_lba, self.root_size) )
+			// Metadata comes from the root's `.` entry
+			let meta = match ::kernel::futures::block_on(self.get_sector(self.root_lba))
+				{
+				Ok(blk) => match DirSector::new(&self.0, blk, 0).next()
+					{
+					Ok(Some(ent)) => ent.metadata(self.lb_size),
+					_ => Default::default(),
+					},
+				Err(_) => Default::default(),
+				};
+			Some(Dir::new_node(self.0.borrow(), id, self.root_lba, self.root_size, meta) )
 		}
 		else {
-			// Look up (or read) parent directory to obtain the info
+			// The inode number is the byte address of the entry within its parent directory
 			let (sector, ofs) = ::kernel::lib::num::div_rem(id as u64, self.lb_size as u64);
 			let blk = match ::kernel::futures::block_on(self.get_sector(sector as u32))
 				{
@@ -156,6 +192,8 @@ impl mount::Filesystem for Instance
 				Ok(None) => return None,
 				Err(_) => return None,
 				};
+			let mode = ent.rr.posix.map(|p| p.mode & susp::S_IFMT);
+			let meta = ent.metadata(self.lb_size);
 			if ent.name.len() == 0 {
 				None
 			}
@@ -164,14 +202,21 @@ impl mount::Filesystem for Instance
 					// Multi-extent file!
 					None
 				}
+				else if mode == Some(susp::S_IFLNK) || ent.symlink.is_some() {
+					Some(Symlink::new_node(id, ent.symlink.unwrap_or(&[]), meta))
+				}
 				else if ent.flags & (1 << 1) != 0 {
-					Some(Dir::new_node(self.0.borrow(), ent.start, ent.size))
+					Some(Dir::new_node(self.0.borrow(), id, ent.start, ent.size, meta))
 				}
 				else if ent.flags & 0x64 != 0 {
 					None
 				}
+				else if mode.unwrap_or(susp::S_IFREG) != susp::S_IFREG {
+					log_notice!("{:?}: Unsupported file type {:#o}", ent, mode.unwrap_or(0));
+					None
+				}
 				else {
-					Some(File::new_node(self.0.borrow(), ent.start, ent.size))
+					Some(File::new_node(self.0.borrow(), id, ent.start, ent.size, meta))
 				}
 			}
 		}
@@ -200,9 +245,12 @@ impl InstanceInner
 	async fn get_sector(&self, sector: u32) -> Result<Sector<'_>, storage::IoError> {
 		assert!(sector > 0);
 		
-		let blk = self.vh.get_block(sector as u64).await?;
-		let ofs = (sector as u64 - blk.index()) as usize * self.vh.block_size();
-		Ok( Sector(blk, ofs as u16, self.vh.block_size() as u16) )
+		// - Logical blocks are no larger than a page, and aligned, so are entirely within one cache entry
+		let hwsects_per_lb = self.lb_size / self.vh.block_size();
+		let hwsector = sector as u64 * hwsects_per_lb as u64;
+		let blk = self.vh.get_block(hwsector).await?;
+		let ofs = (hwsector - blk.index()) as usize * self.vh.block_size();
+		Ok( Sector(blk, ofs as u16, self.lb_size as u16) )
 	}
 }
 
@@ -210,27 +258,34 @@ impl InstanceInner
 struct File
 {
 	fs: ArefBorrow<InstanceInner>,
+	id: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	meta: node::Metadata,
 }
 impl File
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, id: node::InodeId, first_lba: u32, size: u32, meta: node::Metadata) -> node::Node {
 		node::Node::File( Box::new( File {
 			fs: fs,
+			id: id,
 			first_lba: first_lba,
 			size: size,
+			meta: meta,
 			} ) )
 	}
 }
 impl node::NodeBase for File
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("File::get_id")
+		self.id
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
 }
 impl node::File for File
 {
@@ -301,27 +356,34 @@ impl node::File for File
 struct Dir
 {
 	fs: ArefBorrow<InstanceInner>,
+	id: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	meta: node::Metadata,
 }
 impl Dir
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, id: node::InodeId, first_lba: u32, size: u32, meta: node::Metadata) -> node::Node {
 		node::Node::Dir( Box::new( Dir {
 			fs: fs,
+			id: id,
 			first_lba: first_lba,
 			size: size,
+			meta: meta,
 			} ) )
 	}
 }
 impl node::NodeBase for Dir
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("Dir::get_id")
+		self.id
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
 }
 impl node::Dir for Dir
 {
@@ -388,6 +450,64 @@ impl node::Dir for Dir
 	}
 }
 
+// --------------------------------------------------------------------
+/// Rock Ridge symbolic link
+struct Symlink
+{
+	id: node::InodeId,
+	target: ByteString,
+	meta: node::Metadata,
+}
+impl Symlink
+{
+	fn new_node(id: node::InodeId, target: &[u8], meta: node::Metadata) -> node::Node {
+		node::Node::Symlink( Box::new( Symlink {
+			id: id,
+			target: ByteString::from(target),
+			meta: meta,
+			} ) )
+	}
+}
+impl node::NodeBase for Symlink
+{
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
+}
+impl node::Symlink for Symlink
+{
+	fn read(&self) -> ByteString {
+		self.target.clone()
+	}
+}
+
+
+/// Volume descriptor information (common to the primary and supplementary descriptors)
+#[derive(Debug)]
+struct VolumeDescriptor
+{
+	lb_size: usize,
+	root_lba: u32,
+	root_size: u32,
+}
+impl VolumeDescriptor
+{
+	fn from_block(block: &[u8]) -> VolumeDescriptor {
+		// Obtain the logical block size (different from medium sector size)
+		// and extract the root directory entry (we want the LBA and byte length)
+		VolumeDescriptor {
+			lb_size: LittleEndian::read_u16(&block[128..]) as usize,
+			root_lba: LittleEndian::read_u32(&block[156+ 2..]),
+			root_size: LittleEndian::read_u32(&block[156+10..]),
+		}
+	}
+}
 
 #[derive(Default)]
 struct DirEnt<'a>
@@ -400,24 +520,64 @@ struct DirEnt<'a>
 	size: u32,
 	name: &'a [u8],
 	sys_use: &'a [u8],
+	/// Recording date and time (from the directory record)
+	recorded: Option<i64>,
+
+	/// Rock Ridge information
+	rr: RockRidge,
+	/// Symbolic link target (from Rock Ridge)
+	symlink: Option<&'a [u8]>,
 }
 impl<'a> ::core::fmt::Debug for DirEnt<'a> {
 	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
-		write!(f, "DirEnt {{ start: {:#x}, size: {:#x}, name: {:?} }}",
-			self.start, self.size, ByteStr::new(self.name)
-			)
+		write!(f, "DirEnt {{ start: {:#x}, size: {:#x}, name: {:?}", self.start, self.size, ByteStr::new(self.name))?;
+		if let Some(ref p) = self.rr.posix {
+			write!(f, ", mode: {:#o}, uid: {}, gid: {}, nlink: {}", p.mode, p.uid, p.gid, p.n_links)?;
+		}
+		if let Some(t) = self.rr.mtime {
+			write!(f, ", mtime: {}", t)?;
+		}
+		if let (Some(a), Some(c)) = (self.rr.atime, self.rr.ctime) {
+			write!(f, ", atime: {}, ctime: {}", a, c)?;
+		}
+		if let Some(t) = self.symlink {
+			write!(f, ", symlink: {:?}", ByteStr::new(t))?;
+		}
+		write!(f, " }}")
 	}
 }
 
 impl<'a> DirEnt<'a>
 {
+	/// Build the node metadata for this entry (Rock Ridge attributes override the defaults)
+	fn metadata(&self, lb_size: usize) -> node::Metadata {
+		let mut rv = node::Metadata {
+			allocated_size: ::kernel::lib::num::round_up(self.size as u64, lb_size as u64),
+			link_count: 1,
+			mode: 0o555,
+			modify_time: self.rr.mtime.or(self.recorded),
+			access_time: self.rr.atime,
+			change_time: self.rr.ctime,
+			..Default::default()
+			};
+		if let Some(ref p) = self.rr.posix {
+			rv.mode = (p.mode & 0o7777) as u16;
+			rv.owner = p.uid;
+			rv.group = p.gid;
+			rv.link_count = p.n_links;
+		}
+		rv
+	}
 }
 
 struct DirSector<'a> {
 	fs: &'a InstanceInner,
 	data: Sector<'a>,
 	ofs: usize,
-	//name_buf: [u8; 32],
+	/// Decoded name (from Rock Ridge or Joliet)
+	name_buf: Vec<u8>,
+	/// Symbolic link target (from Rock Ridge)
+	link_buf: Vec<u8>,
 }
 
 impl<'a> DirSector<'a>
@@ -428,7 +588,8 @@ impl<'a> DirSector<'a>
 			fs: fs,
 			data: data,
 			ofs: start_ofs,
-			//name_buf: [0; 32],
+			name_buf: Vec::new(),
+			link_buf: Vec::new(),
 		}
 	}
 	pub fn next(&mut self) -> node::Result<Option<DirEnt<'_>>> {
@@ -471,35 +632,43 @@ impl<'a> DirSector<'a>
 				let name_raw = &ent[33..][..name_len];
 				// The name is mangled
 				// - Semicolon then version number, just strip it
-				let mut name = {
+				let name = {
 					let tmp = name_raw.split(|&v| v == b';').next().unwrap();
 					let tmp = tmp.strip_suffix(b".").unwrap_or(tmp);
-					//for (d,s) in Iterator::zip(self.name_buf.iter_mut(), tmp.iter()) {
-					//	*d = s.to_ascii_lowercase();
-					//}
-					//&self.name_buf[..tmp.len()]
 					tmp
 					};
 				log_trace!("DirSector::next: (raw) name={:?} -> {:?}, su.len={}",
 					::kernel::lib::RawString(name_raw), ::kernel::lib::RawString(name),
 					su.len()
 					);
-
-				if let Some(skip) = self.fs.susp_len_skip {
-					let skip = skip as usize;
-					if su.len() < skip {
-						log_warning!("System use area smaller than SUSP skip value");
-						return Err(vfs::Error::InconsistentFilesystem);
-					}
-
-					for ent in SuspIterator(&su[skip..])
-					{
-						// TODO: Need to handle this _FAR_ better
-						if let SuspItem::AlternateName(0, new_name) = ent {
-							name = new_name;
+				// `.` and `..` are single bytes (even with Joliet)
+				let is_special = name_raw == b"\0" || name_raw == b"\x01";
+
+				self.name_buf.clear();
+				self.link_buf.clear();
+				let mut rr = RockRidge::default();
+				let use_name_buf = if let Some(skip) = self.fs.susp_len_skip {
+						let skip = skip as usize;
+						if su.len() < skip {
+							log_warning!("System use area smaller than SUSP skip value");
+							return Err(vfs::Error::InconsistentFilesystem);
 						}
+						rr = RockRidge::parse(self.fs, &su[skip..], &mut self.name_buf, &mut self.link_buf)?;
+						rr.has_name
 					}
-				}
+					else if self.fs.is_joliet && !is_special {
+						// UCS-2 big endian, with the same `;1` suffix
+						let units = name_raw.chunks_exact(2)
+							.map(|v| u16::from_be_bytes([v[0], v[1]]))
+							.take_while(|&c| c != b';' as u16)
+							;
+						self.name_buf.extend( ::utf16::Wtf8::new(::utf16::Chars(units)) );
+						true
+					}
+					else {
+						false
+					};
+				let symlink = if rr.has_symlink { Some(&self.link_buf[..]) } else { None };
 
 				Ok(Some(DirEnt {
 					this_ofs: cur_ofs,
@@ -507,114 +676,13 @@ impl<'a> DirSector<'a>
 					flags: ent[25],
 					start: LittleEndian::read_u32(&ent[2..]),
 					size: LittleEndian::read_u32(&ent[10..]),
-					name: name,
+					name: if use_name_buf { &self.name_buf[..] } else { name },
 					sys_use: su,
+					recorded: susp::parse_time_short(&ent[18..25]),
+					rr: rr,
+					symlink: symlink,
 					}))
 			}
 		}
 	}
 }
-
-struct SuspIterator<'a>(&'a [u8]);
-
-#[derive(Debug)]
-#[allow(dead_code)]
-enum SuspItem<'a>
-{
-	// SUSP Base
-	ContinuationEntry(u32, u32, u32),
-	Pad(&'a [u8]),
-	Identifier,
-	//End,
-	
-	// RockRidge
-	RockRidge(u8),
-	PosixMode {
-		mode: u32,
-		n_links: u32,
-		uid: u32,
-		gid: u32,
-		serial_number: u32,
-		},
-	AlternateName(u8, &'a [u8]),
-	Timestamps {
-		flags: u8,
-		data: &'a [u8],
-		},
-
-	Unknown([u8; 2], u8, &'a[u8]),
-}
-
-impl<'a> Iterator for SuspIterator<'a>
-{
-	type Item = SuspItem<'a>;
-	fn next(&mut self) -> Option<SuspItem<'a>>
-	{
-		if self.0.len() == 0 {
-			None
-		}
-		else if self.0.len() < 4 {
-			log_warning!("SuspIterator: Runt - {} < 4", self.0.len());
-			None
-		}
-		else {
-			let tag = [self.0[0], self.0[1]];
-			let len = self.0[2] as usize;
-			let ver = self.0[3];
-			if len < 4 {
-				log_warning!("SuspIterator: bad entry length {} < 4", len);
-				return None;
-			}
-			if self.0.len() < len {
-				log_warning!("SuspIterator: bad entry length {} > len {}", len, self.0.len());
-				return None;
-			}
-			let data = &self.0[4..len];
-
-			self.0 = &self.0[len..];
-			
-			log_debug!("tag = {}{} - data={} [{:?}]", tag[0] as char, tag[1] as char, len-4, ::kernel::lib::RawString(data));
-			Some(match &tag[..]
-				{
-				b"ST" => return None,	// Terminated
-				b"SP" => SuspItem::Identifier,
-				b"PD" => SuspItem::Pad(data),
-				b"CE" => {
-					if data.len() < 3*8 { return None; }
-					SuspItem::ContinuationEntry(
-						LittleEndian::read_u32(&data[0..]),
-						LittleEndian::read_u32(&data[8..]),
-						LittleEndian::read_u32(&data[16..])
-						)
-					},
-				b"RR" => {
-					if data.len() < 1 { return None; }
-					SuspItem::RockRidge(data[0])
-					},
-				b"PX" => {
-					if data.len() < 4*8 { return None; }
-					SuspItem::PosixMode {
-						mode:    LittleEndian::read_u32(&data[0..]),
-						n_links: LittleEndian::read_u32(&data[8..]),
-						uid:     LittleEndian::read_u32(&data[16..]),
-						gid:     LittleEndian::read_u32(&data[24..]),
-						serial_number: if data.len() >= 32+8 { LittleEndian::read_u32(&data[32..]) } else { 0 },
-						}
-					},
-				b"TF" => {
-					if data.len() < 1 { return None; }
-					SuspItem::Timestamps {
-						flags: data[0],
-						data: &data[1..],
-						}
-					},
-				//b"SL" => SuspItem::Symlink(),
-				b"NM" => {
-					if data.len() < 1 { return None; }
-					SuspItem::AlternateName(data[0], &data[1..])
-					},
-				_ => SuspItem::Unknown(tag, ver, data),
-				})
-		}
-	}
-}
@@ -0,0 +1,306 @@
+// "Tifflin" Kernel - ISO9660 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_iso9660/susp.rs
+//! System Use Sharing Protocol (SUSP) and Rock Ridge extensions
+use kernel::prelude::*;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+use vfs::node;
+
+/// Maximum number of continuation areas followed for a single entry (protects against loops)
+const MAX_CONTINUATIONS: usize = 16;
+
+/// `NM` flag: Entry refers to the current directory
+const NM_CURRENT: u8 = 0x02;
+/// `NM` flag: Entry refers to the parent directory
+const NM_PARENT: u8 = 0x04;
+
+/// `SL` component flag: The component continues in the next component record
+const SL_CONTINUE: u8 = 0x01;
+/// `SL` component flag: Component is `.`
+const SL_CURRENT: u8 = 0x02;
+/// `SL` component flag: Component is `..`
+const SL_PARENT: u8 = 0x04;
+/// `SL` component flag: Component is the root directory
+const SL_ROOT: u8 = 0x08;
+
+/// `TF` flag: Timestamps are in the 17-byte long form
+const TF_LONG_FORM: u8 = 0x80;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// Rock Ridge information for a directory entry
+#[derive(Default)]
+pub struct RockRidge
+{
+	/// An alternate name was present (and has been written to the name buffer)
+	pub has_name: bool,
+	/// A symbolic link target was present (and has been written to the link buffer)
+	pub has_symlink: bool,
+	/// POSIX attributes (from `PX`)
+	pub posix: Option<PosixAttrs>,
+	/// Modification time (seconds since 1970)
+	pub mtime: Option<i64>,
+	/// Access time (seconds since 1970)
+	pub atime: Option<i64>,
+	/// Attribute change time (seconds since 1970)
+	pub ctime: Option<i64>,
+}
+#[derive(Debug,Copy,Clone)]
+pub struct PosixAttrs
+{
+	pub mode: u32,
+	pub n_links: u32,
+	pub uid: u32,
+	pub gid: u32,
+}
+
+impl RockRidge
+{
+	/// Parse the system use area of a directory entry (following continuation areas)
+	///
+	/// The alternate name is appended to `name`, and the symbolic link target to `link`.
+	pub fn parse(fs: &super::InstanceInner, su: &[u8], name: &mut Vec<u8>, link: &mut Vec<u8>) -> node::Result<RockRidge>
+	{
+		let mut rv = RockRidge::default();
+		// Set if the last `SL` component didn't finish
+		let mut sl_continues = false;
+
+		let mut ce_buf = Vec::new();
+		let mut area = su;
+		for _ in 0 .. MAX_CONTINUATIONS
+		{
+			let mut next_area = None;
+			for ent in SuspIterator(area)
+			{
+				match ent
+				{
+				SuspItem::ContinuationEntry(lba, ofs, len) => next_area = Some( (lba, ofs, len) ),
+				SuspItem::AlternateName(flags, data) => {
+					if flags & (NM_CURRENT|NM_PARENT) == 0 {
+						name.extend_from_slice(data);
+						rv.has_name = true;
+					}
+					},
+				SuspItem::PosixMode { mode, n_links, uid, gid, .. } => {
+					rv.posix = Some(PosixAttrs { mode, n_links, uid, gid });
+					},
+				SuspItem::Symlink(_flags, data) => {
+					rv.has_symlink = true;
+					sl_continues = parse_symlink_components(data, link, sl_continues);
+					},
+				SuspItem::Timestamps { flags, data } => rv.parse_timestamps(flags, data),
+				_ => {},
+				}
+			}
+
+			let Some( (lba, ofs, len) ) = next_area else {
+				return Ok(rv);
+				};
+			log_trace!("RockRidge::parse: Continuation {:#x}+{:#x} len={:#x}", lba, ofs, len);
+			ce_buf.clear();
+			ce_buf.resize(ofs as usize + len as usize, 0);
+			let n_sectors = ::kernel::lib::num::div_up(ce_buf.len(), fs.lb_size);
+			ce_buf.resize(n_sectors * fs.lb_size, 0);
+			::kernel::futures::block_on(fs.read_sector(lba, &mut ce_buf))?;
+			area = &ce_buf[ofs as usize ..][.. len as usize];
+		}
+		log_warning!("RockRidge::parse: Too many continuation areas");
+		Err(vfs::Error::InconsistentFilesystem)
+	}
+
+	fn parse_timestamps(&mut self, flags: u8, mut data: &[u8])
+	{
+		let stamp_len = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+		// Order: Creation, Modify, Access, Attributes, Backup, Expiration, Effective
+		for bit in 0 .. 7
+		{
+			if flags & (1 << bit) == 0 {
+				continue ;
+			}
+			let Some(stamp) = ::kernel::lib::split_off_front(&mut data, stamp_len) else {
+				log_warning!("RockRidge: TF entry too short for flags {:#x}", flags);
+				return ;
+				};
+			let time = if stamp_len == 17 { parse_time_long(stamp) } else { parse_time_short(stamp) };
+			match bit
+			{
+			1 => self.mtime = time,
+			2 => self.atime = time,
+			3 => self.ctime = time,
+			_ => {},
+			}
+		}
+	}
+}
+
+/// Append the component records from a `SL` entry to `link`, returns true if the last component continues in the next entry
+fn parse_symlink_components(mut data: &[u8], link: &mut Vec<u8>, mut prev_continues: bool) -> bool
+{
+	while data.len() >= 2
+	{
+		let flags = data[0];
+		let len = data[1] as usize;
+		let Some(content) = data.get(2..).and_then(|v| v.get(..len)) else {
+			log_warning!("RockRidge: SL component overruns entry");
+			break;
+			};
+		data = &data[2 + len..];
+
+		if !prev_continues && link.len() > 0 && link.last() != Some(&b'/') {
+			link.push(b'/');
+		}
+		if flags & SL_ROOT != 0 {
+			link.push(b'/');
+		}
+		else if flags & SL_PARENT != 0 {
+			link.extend_from_slice(b"..");
+		}
+		else if flags & SL_CURRENT != 0 {
+			link.push(b'.');
+		}
+		else {
+			link.extend_from_slice(content);
+		}
+		prev_continues = flags & SL_CONTINUE != 0;
+	}
+	prev_continues
+}
+
+/// Parse a 7-byte timestamp (years since 1900, month, day, hour, minute, second, GMT offset in 15 minute units)
+///
+/// Also used for the recording date in directory records
+pub fn parse_time_short(d: &[u8]) -> Option<i64>
+{
+	if d[1] == 0 {
+		return None;
+	}
+	Some( to_unix_time(1900 + d[0] as i64, d[1], d[2], d[3], d[4], d[5], d[6] as i8) )
+}
+/// Parse a 17-byte timestamp (ASCII "YYYYMMDDHHMMSScc", then a GMT offset in 15 minute units)
+fn parse_time_long(d: &[u8]) -> Option<i64>
+{
+	fn digits(v: &[u8]) -> Option<u32> {
+		v.iter().try_fold(0, |rv, &c| if c.is_ascii_digit() { Some(rv * 10 + (c - b'0') as u32) } else { None })
+	}
+	let year = digits(&d[0..4])?;
+	let month = digits(&d[4..6])?;
+	if year == 0 || month == 0 {
+		return None;
+	}
+	Some( to_unix_time(year as i64, month as u8, digits(&d[6..8])? as u8, digits(&d[8..10])? as u8, digits(&d[10..12])? as u8, digits(&d[12..14])? as u8, d[16] as i8) )
+}
+fn to_unix_time(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8, gmt_ofs: i8) -> i64
+{
+	node::timestamp_from_date(year, month, day, hour, minute, second) - gmt_ofs as i64 * 15 * 60
+}
+
+pub struct SuspIterator<'a>(pub &'a [u8]);
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum SuspItem<'a>
+{
+	// SUSP Base
+	ContinuationEntry(u32, u32, u32),
+	Pad(&'a [u8]),
+	Identifier,
+	//End,
+
+	// RockRidge
+	RockRidge(u8),
+	PosixMode {
+		mode: u32,
+		n_links: u32,
+		uid: u32,
+		gid: u32,
+		serial_number: u32,
+		},
+	AlternateName(u8, &'a [u8]),
+	Symlink(u8, &'a [u8]),
+	Timestamps {
+		flags: u8,
+		data: &'a [u8],
+		},
+
+	Unknown([u8; 2], u8, &'a[u8]),
+}
+
+impl<'a> Iterator for SuspIterator<'a>
+{
+	type Item = SuspItem<'a>;
+	fn next(&mut self) -> Option<SuspItem<'a>>
+	{
+		if self.0.len() == 0 {
+			None
+		}
+		else if self.0.len() < 4 {
+			// Padding at the end of the system use area
+			None
+		}
+		else {
+			let tag = [self.0[0], self.0[1]];
+			let len = self.0[2] as usize;
+			let ver = self.0[3];
+			if len < 4 {
+				log_warning!("SuspIterator: bad entry length {} < 4", len);
+				return None;
+			}
+			if self.0.len() < len {
+				log_warning!("SuspIterator: bad entry length {} > len {}", len, self.0.len());
+				return None;
+			}
+			let data = &self.0[4..len];
+
+			self.0 = &self.0[len..];
+
+			log_trace!("tag = {}{} - data={} [{:?}]", tag[0] as char, tag[1] as char, len-4, ::kernel::lib::RawString(data));
+			Some(match &tag[..]
+				{
+				b"ST" => return None,	// Terminated
+				b"SP" => SuspItem::Identifier,
+				b"PD" => SuspItem::Pad(data),
+				b"CE" => {
+					if data.len() < 3*8 { return None; }
+					SuspItem::ContinuationEntry(
+						LittleEndian::read_u32(&data[0..]),
+						LittleEndian::read_u32(&data[8..]),
+						LittleEndian::read_u32(&data[16..])
+						)
+					},
+				b"RR" => {
+					if data.len() < 1 { return None; }
+					SuspItem::RockRidge(data[0])
+					},
+				b"PX" => {
+					if data.len() < 4*8 { return None; }
+					SuspItem::PosixMode {
+						mode:    LittleEndian::read_u32(&data[0..]),
+						n_links: LittleEndian::read_u32(&data[8..]),
+						uid:     LittleEndian::read_u32(&data[16..]),
+						gid:     LittleEndian::read_u32(&data[24..]),
+						serial_number: if data.len() >= 32+8 { LittleEndian::read_u32(&data[32..]) } else { 0 },
+						}
+					},
+				b"TF" => {
+					if data.len() < 1 { return None; }
+					SuspItem::Timestamps {
+						flags: data[0],
+						data: &data[1..],
+						}
+					},
+				b"SL" => {
+					if data.len() < 1 { return None; }
+					SuspItem::Symlink(data[0], &data[1..])
+					},
+				b"NM" => {
+					if data.len() < 1 { return None; }
+					SuspItem::AlternateName(data[0], &data[1..])
+					},
+				_ => SuspItem::Unknown(tag, ver, data),
+				})
+		}
+	}
+}
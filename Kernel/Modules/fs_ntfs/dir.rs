@@ -50,6 +50,9 @@ impl ::vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		Ok( self.instance.get_metadata(&self.mft_ent, self.i30_allocation.as_ref()) )
+	}
 }
 impl ::vfs::node::Dir for Dir
 {
@@ -30,6 +30,9 @@ impl ::vfs::node::NodeBase for File
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		Ok( self.instance.get_metadata(&self.mft_ent, self.attr_data.as_ref()) )
+	}
 }
 impl ::vfs::node::File for File
 {
@@ -67,8 +67,12 @@ impl Instance
 					log_warning!("$UpCase not large enough - Read {:#x} bytes, expected {:#x}",
 						len, upcase_table.len() * 2);
 				}
-				for e in upcase_table.iter_mut() {
-					*e = e.to_le();
+				for e in upcase_table[..len / 2].iter_mut() {
+					*e = u16::from_le(*e);
+				}
+				// Characters not covered by a short table map to themselves
+				for (i,e) in upcase_table.iter_mut().enumerate().skip(len / 2) {
+					*e = i as u16;
 				}
 				upcase_table
 				};
@@ -84,59 +88,54 @@ impl Instance
 
 	/// Apply `update_sequence` fixups to a loaded metadata block
 	///
-	/// All metadata blocks (e.g. MFT entries, or index blocks) have an "update sequence" that catches sectors (volume
-	/// blocks) that didn't get written to disk correctly. Within the first sector there's a sequence number that's
-	/// incremented on every change to the block and a copy of the original/correct last two bytes of each sector.
+	/// All metadata blocks (e.g. MFT entries, or index blocks) have an "update sequence" that catches 512 byte chunks
+	/// (nominal sectors, independent of the volume's block size) that didn't get written to disk correctly. Within the
+	/// first chunk there's a sequence number that's incremented on every change to the block and a copy of the
+	/// original/correct last two bytes of each chunk.
 	///
-	/// This function takes that update sequence information, checks that the last word of each non-first sector matches
-	/// the expectation and then restores the original value.
+	/// This function takes that update sequence information, checks that the last word of every chunk matches the
+	/// expectation (rejecting the block if any don't - i.e. it was torn) and then restores the original values.
 	///
-	/// `get_usa` is a function that gets an `UpdateSequence` from the passed first sector
+	/// `get_usa` is a function that gets an `UpdateSequence` from the passed first chunk
 	pub fn apply_sequence_fixups(&self, buf: &mut [u8], get_usa: &dyn Fn(&[u8])->Option<&crate::ondisk::UpdateSequence>) -> Result<(),::vfs::Error> {
-		let block_size = self.vol.block_size();
-		if buf.len() >= block_size
-		{
-			assert!(buf.len() % block_size == 0, "apply_sequence_fixups: Passed a buffer not a multiple of volume blocks long"); 
-			let (buf1, buf2) = buf.split_at_mut(block_size);
-			let usa = (get_usa)(buf1).ok_or_else(|| { log_error!("get_usa failed"); ::vfs::Error::InconsistentFilesystem })?;
-			let exp_val = usa.sequence_number();
-			let mut usa_it = usa.array();
-			let s0_last_word = usa_it.next().ok_or(::vfs::Error::InconsistentFilesystem)?;
-			//log_debug!("apply_sequence_fixups: {} sectors, seq={}", 1+buf2.len()/block_size, exp_val);
-
-			fn apply_fixup(sector_idx: usize, sector: &mut [u8], last_word: u16, exp_val: u16) -> Result<(),::vfs::Error> {
-				let block_size = sector.len();
-				let slot = &mut sector[ block_size - 2 ..];
-				let cur_val = u16::from_le_bytes([slot[0], slot[1]]);
-				//log_debug!("apply_sequence_fixups: +{}: 0x{:04x} -> 0x{:04x}", 1+sector_idx, cur_val, last_word);
-				if cur_val != exp_val {
-					log_error!("apply_sequence_fixups: Sequence number mismatch in sector +{}: 0x{:04x} != exp 0x{:04x}",
-						1+sector_idx, cur_val, exp_val
-						);
-					return Err(::vfs::Error::InconsistentFilesystem);
-				}
-				slot.copy_from_slice(&last_word.to_le_bytes());
-				Ok( () )
-			}
-
-			for (rel_sector_idx, (sector, last_word)) in Iterator::zip( buf2.chunks_mut(block_size), usa_it ).enumerate()
-			{
-				apply_fixup(1+rel_sector_idx, sector, last_word, exp_val)?;
-			}
+		const STRIDE: usize = ondisk::UPDATE_SEQUENCE_STRIDE;
+		if buf.len() < STRIDE || buf.len() % STRIDE != 0 {
+			log_error!("apply_sequence_fixups: Buffer length {:#x} isn't a multiple of {:#x}", buf.len(), STRIDE);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let n_chunks = buf.len() / STRIDE;
+		let (buf1, buf2) = buf.split_at_mut(STRIDE);
+		let usa = (get_usa)(buf1).ok_or_else(|| { log_error!("apply_sequence_fixups: `get_usa` failed"); ::vfs::Error::InconsistentFilesystem })?;
+		let exp_val = usa.sequence_number();
+		if usa.array().count() < n_chunks {
+			log_error!("apply_sequence_fixups: Update sequence only covers {} of {} chunks", usa.array().count(), n_chunks);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		//log_debug!("apply_sequence_fixups: {} chunks, seq={}", n_chunks, exp_val);
 
-			apply_fixup(0, &mut buf[..block_size], s0_last_word, exp_val)?;
+		fn get_last_word(chunk: &[u8]) -> u16 {
+			u16::from_le_bytes([chunk[STRIDE-2], chunk[STRIDE-1]])
 		}
-		else
+		// Check every chunk before modifying any, so a torn block is left untouched
+		for (chunk_idx, chunk) in Iterator::chain( ::core::iter::once(&buf1[..]), buf2.chunks(STRIDE) ).enumerate()
 		{
-			// Call the getter, just so the error is triggered if needed
-			match (get_usa)(buf) {
-			Some(_) => {},
-			None => {
-				log_error!("apply_sequence_fixups: `get_usa` failed");
+			let cur_val = get_last_word(chunk);
+			if cur_val != exp_val {
+				log_error!("apply_sequence_fixups: Sequence number mismatch in chunk +{}: 0x{:04x} != exp 0x{:04x}",
+					chunk_idx, cur_val, exp_val
+					);
 				return Err(::vfs::Error::InconsistentFilesystem);
-				}
-			};
+			}
+		}
+
+		let mut usa_it = usa.array();
+		let c0_last_word = usa_it.next().unwrap();
+		for (chunk, last_word) in Iterator::zip( buf2.chunks_mut(STRIDE), usa_it )
+		{
+			chunk[STRIDE-2..].copy_from_slice(&last_word.to_le_bytes());
 		}
+		// The first chunk contains the update sequence, so is fixed last
+		buf1[STRIDE-2..].copy_from_slice(&c0_last_word.to_le_bytes());
 		Ok( () )
 	}
 
@@ -159,8 +158,8 @@ impl Instance
 			::core::cmp::Ord::cmp(&a, &b)
 		}
 	}
+	/// Case-insensitive comparison of two UCS-2 strings (matching the collation used by filename indexes)
 	pub fn compare_ucs2_nocase_iter(&self, a: &mut dyn Iterator<Item=u16>, b: &mut dyn Iterator<Item=u16>) -> ::core::cmp::Ordering {
-		// TODO: Case insensitive UCS-2 comparison! - use the `$UpCase` special file to obtain the sorting rule to use
 		use ::core::cmp::Ordering;
 		loop {
 			match (a.next(), b.next())
@@ -252,7 +251,10 @@ impl Instance
 				// Zero read length means that the read was past the end?
 				return Err(::vfs::Error::NotFound);
 			}
-			assert!(l == buf.len(), "Partial read of MFT entry? ({} != {})", l, buf.len());
+			if l != buf.len() {
+				log_error!("Partial read of MFT entry #{} ({} != {})", entry_idx, l, buf.len());
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
 		}
 		else {
 			if self.mft_record_size > self.vol.block_size() {
@@ -268,6 +270,21 @@ impl Instance
 			}
 		}
 
+		// Check the signature - chkdsk replaces it with `BAAD` if the entry was torn
+		match ondisk::MftEntry::new_borrowed(buf).map(|ent| ent.magic())
+		{
+		Some(ondisk::MftEntry::MAGIC_FILE) => {},
+		Some(ondisk::MftEntry::MAGIC_BAAD) => {
+			log_error!("MFT entry #{} marked as bad (torn write)", entry_idx);
+			return Err(::vfs::Error::InconsistentFilesystem);
+			},
+		Some(m) => {
+			log_error!("MFT entry #{} has an invalid signature {:02x?}", entry_idx, m);
+			return Err(::vfs::Error::InconsistentFilesystem);
+			},
+		None => return Err(::vfs::Error::InconsistentFilesystem),
+		}
+
 		// Apply sequence number fixups
 		//log_debug!("{:?}", ::kernel::logging::HexDump(&*buf));
 		self.apply_sequence_fixups(buf, &|buf1| {
@@ -315,6 +332,47 @@ impl Instance
 		}
 	}
 
+	/// Build VFS metadata from `$STANDARD_INFORMATION` and the allocation of the data attribute
+	pub fn get_metadata(&self, mft_ent: &CachedMft, data_attr: Option<&ondisk::AttrHandle>) -> ::vfs::node::Metadata {
+		/// Convert a NTFS time (100ns units since 1601-01-01) into seconds since 1970
+		fn filetime(v: u64) -> Option<::vfs::node::Timestamp> {
+			if v == 0 {
+				None
+			}
+			else {
+				Some( (v / 10_000_000) as i64 - 11_644_473_600 )
+			}
+		}
+		let si = self.get_attr_inner(mft_ent, ondisk::FileAttr::StandardInformation, "", 0);
+		let mft_ent = mft_ent.inner.read();
+		let mut rv = ::vfs::node::Metadata {
+			link_count: mft_ent.hard_link_count() as u32,
+			mode: 0o555,	// The driver is read-only
+			..Default::default()
+			};
+		if let Some(data) = si.as_ref().and_then(|h| mft_ent.get_attr(h)).and_then(|a| a.inner().as_resident().map(|r| r.data())) {
+			// Layout: Creation, Modification, MFT Change, Access (all u64)
+			if data.len() >= 4*8 {
+				let rd = |o: usize| u64::from_le_bytes(::core::convert::TryInto::try_into(&data[o..][..8]).unwrap());
+				rv.create_time = filetime(rd(0));
+				rv.modify_time = filetime(rd(8));
+				rv.change_time = filetime(rd(16));
+				rv.access_time = filetime(rd(24));
+			}
+			else {
+				log_warning!("$STANDARD_INFORMATION too small ({} bytes)", data.len());
+			}
+		}
+		if let Some(a) = data_attr.and_then(|h| mft_ent.get_attr(h)) {
+			rv.allocated_size = match a.inner()
+				{
+				ondisk::MftAttribData::Resident(_) => 0,	// Stored in the MFT entry
+				ondisk::MftAttribData::Nonresident(r) => r.allocated_size(),
+				};
+		}
+		rv
+	}
+
 	/// Read data out of an attribute (resident or non-resident)
 	pub async fn attr_read(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, ofs: u64, mut dst: &mut [u8]) -> ::vfs::Result<usize> {
 		if dst.len() == 0 {
@@ -353,18 +411,34 @@ impl Instance
 			if space < dst.len() as u64 {
 				dst = &mut dst[..space as usize];
 			}
+			let rv = dst.len();
 
-			if r.starting_vcn() != 0 {
-				todo!("attr_read: TODO - Handle sparse files (starting_vcn = {})", r.starting_vcn());
-				// For this, inject a run filled with zeroes?
+			// Data past the initialised size hasn't been written yet, and reads as zero
+			let init_size = u64::min(r.initialized_size(), r.real_size());
+			let init_len = u64::min(init_size.saturating_sub(ofs), dst.len() as u64) as usize;
+			dst[init_len..].fill(0);
+			let mut dst = &mut dst[..init_len];
+			if dst.len() == 0 {
+				return Ok(rv);
 			}
 
 			let mut cur_vcn = ofs / (self.cluster_size_bytes() as u64);
 			let mut cur_ofs = ofs as usize % self.cluster_size_bytes();
 
+			// Clusters before `starting_vcn` are described by another attribute record (from `$ATTRIBUTE_LIST`), which
+			// isn't supported - so treat them as unpopulated
+			if cur_vcn < r.starting_vcn() {
+				log_warning!("attr_read: Read of VCN {} before first populated VCN {}, returning zeroes", cur_vcn, r.starting_vcn());
+				let len = (r.starting_vcn() - cur_vcn).saturating_mul(self.cluster_size_bytes() as u64) - cur_ofs as u64;
+				let len = u64::min(len, dst.len() as u64) as usize;
+				::kernel::lib::split_off_front_mut(&mut dst, len).unwrap().fill(0);
+				cur_vcn = r.starting_vcn();
+				cur_ofs = 0;
+			}
+
 			let mut runs = CompressionRuns::new(r.data_runs(), 1 << r.compression_unit_size()).peekable();
 			// Seek to the run containing the first cluster
-			let mut runbase_vcn = 0;
+			let mut runbase_vcn = r.starting_vcn();
 			while let Some(r) = runs.peek() {
 				if runbase_vcn + r.cluster_count() > cur_vcn {
 					break;
@@ -372,14 +446,15 @@ impl Instance
 				runbase_vcn += r.cluster_count();
 				runs.next();
 			}
-			let rv = dst.len();
 			// Keep consuming runs until the destination is empty
 			while dst.len() > 0
 			{
 				assert!(cur_vcn >= runbase_vcn, "cur_vcn({}) >= runbase_vcn({})", cur_vcn, runbase_vcn);
 				let Some(cur_run) = runs.next() else {
-					// Filled with zeroes? Or invalid parameter?
-					todo!("Handle reading past the end of the populated runs");
+					// Past the end of the runs in this record (again, only expected with `$ATTRIBUTE_LIST`)
+					log_warning!("attr_read: Read of VCN {} past the populated runs (ending at {}), returning zeroes", cur_vcn, runbase_vcn);
+					dst.fill(0);
+					break;
 					};
 
 				match cur_run
@@ -443,18 +518,15 @@ impl Instance
 						assert!(cur_vcn == irunbase_vcn);
 						cur_ofs = 0;
 					}
-					let n_zeroes = crun_cluster_count - (irunbase_vcn - runbase_vcn);
-					if n_zeroes > 0 {
-						for _ in 0 .. n_zeroes {
-							let len = self.cluster_size_bytes();
-							let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-							buf.fill(0);
-							cur_vcn += 1;
-							irunbase_vcn += 1;
-						}
+					// Any remaining clusters in the compression unit are unpopulated (zeroes)
+					let n_zeroes = crun_cluster_count.saturating_sub(irunbase_vcn - runbase_vcn);
+					if n_zeroes > 0 && dst.len() > 0 {
+						let len = usize::min(dst.len(), n_zeroes as usize * self.cluster_size_bytes() - cur_ofs);
+						::kernel::lib::split_off_front_mut(&mut dst, len).unwrap().fill(0);
+						cur_ofs = 0;
 					}
 					runbase_vcn += crun_cluster_count;
-					assert!(cur_vcn == runbase_vcn, "{cur_vcn} != {runbase_vcn}, len={}", dst.len());
+					cur_vcn = runbase_vcn;
 					},
 				CompressionRun::Compressed(uncompressed_count, compressed_count, iter) => {
 					log_debug!("Compressed +{}", compressed_count);
@@ -106,7 +106,10 @@ impl FileAttr {
 	}
 }
 
-// TODO: Use the update sequence to fix loaded items (contains the actual values of each sector's last two bytes)
+/// Size of the chunks protected by an update sequence (independent of the volume's sector size)
+pub const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+/// Update sequence number and array (contains the actual values of each 512 byte chunk's last two bytes)
 pub struct UpdateSequence([u8]);
 impl UpdateSequence {
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
@@ -150,13 +153,20 @@ impl UpdateSequence {
 
 pub struct MftEntry([u8]);
 delegate!{ MftEntry -> MftEntryHeader =>
+	pub magic: [u8; 4],
 	first_attrib_ofs: u16,
 	flags: u16,
+	pub hard_link_count: u16,
 
 	update_sequence_ofs: u16,
 	update_sequence_size: u16,
 }
 impl MftEntry {
+	/// Magic value for a valid entry (`FILE`)
+	pub const MAGIC_FILE: [u8; 4] = *b"FILE";
+	/// Magic value written by chkdsk over an entry that failed the update sequence check (`BAAD`)
+	pub const MAGIC_BAAD: [u8; 4] = *b"BAAD";
+
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
 		if v.len() < ::core::mem::size_of::<raw::MftEntryHeader>() {
 			log_warning!("MftEntry::new_borrowed: Under-sized {} < {}", v.len(), ::core::mem::size_of::<raw::MftEntryHeader>());
@@ -317,8 +327,8 @@ delegate!{ MftAttrHeader_NonResident =>
 	pub allocated_size: u64,
 	/// Size of the user-facing data (bytes)
 	pub real_size: u64,
-	/// Size of data on-disk (taking into account compression)
-	pub initiated_size: u64,
+	/// Size of the data that has been written (bytes past this read as zero)
+	pub initialized_size: u64,
 }
 impl MftAttrHeader_NonResident {
 	fn size_of() -> usize {
@@ -536,7 +546,6 @@ impl Attrib_IndexBlockHeader {
 	pub fn index_header(&self) -> &Attrib_IndexHeader {
 		Attrib_IndexHeader::from_slice(self.index_header_bytes()).unwrap()
 	}
-	// TODO: the update sequence handles spotting badly-written sectors
 	pub fn update_sequence(&self) -> &UpdateSequence {
 		UpdateSequence::from_subslice(&self.0, self.update_sequence_ofs(), self.update_sequence_size()).unwrap()
 	}
@@ -174,8 +174,8 @@ pub struct MftAttrHeader_NonResident {
 	allocated_size: u64,
 	/// User-facing byte count
 	real_size: u64,
-	/// Size of the data, after compression
-	initiated_size: u64,
+	/// Size of the initialised (written) data, the remainder up to `real_size` reads as zero
+	initialized_size: u64,
 	// name: [u16],
 }
 
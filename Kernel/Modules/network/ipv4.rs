@@ -20,8 +20,15 @@ mod routes;
 pub use self::routes::{SelectedRoute, Route, route_lookup, route_add, route_del, route_enumerate};
 
 mod rx;
+mod fragments;
 pub use self::rx::{handle_rx_ethernet, register_handler};
 
+pub mod icmp;
+pub use self::icmp::UnreachableCode;
+
+/// Identification value for the next fragmented packet
+static NEXT_IDENTIFICATION: ::core::sync::atomic::AtomicU16 = ::core::sync::atomic::AtomicU16::new(1);
+
 /// Active IPv4 interfaces
 static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new());
 
@@ -39,6 +46,11 @@ impl Interface
 	}
 }
 
+pub(crate) fn init()
+{
+	register_handler(icmp::PROTOCOL, icmp::handle_packet).unwrap();
+}
+
 // NOTE: uses mac address to identify interface
 /// Add a new IPv4 interface (address)
 pub fn add_interface(local_mac: [u8; 6], address: Address, mask_bits: u8) -> Result<(),()>
@@ -115,22 +127,50 @@ pub async fn send_packet(source: Address, dest: Address, proto: u8, pkt: crate::
 			},
 		}
 	};
-	// 3. Send
+	// 3. Send (fragmenting if larger than the interface's MTU)
+	let mtu = crate::nic::get_mtu(source_mac).unwrap_or(crate::nic::DEFAULT_MTU);
 	let mut hdr = Ipv4Header {
 		ver_and_len: 0x40 | 20/4,
 		diff_services: 0,
-		total_length: (20 + pkt.total_len()) as u16,
+		total_length: 0,
 		identification: 0,
 		flags: 0,
-		frag_ofs_high: 0,
+		frag_ofs_low: 0,
 		ttl: 255,
 		protocol: proto,
 		hdr_checksum: 0,
 		source: source,
 		destination: dest,
 		};
-	hdr.set_checksum();
-	let hdr_bytes = hdr.encode();
-	crate::nic::send_from(source_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	if 20 + pkt.total_len() <= mtu
+	{
+		hdr.total_length = (20 + pkt.total_len()) as u16;
+		hdr.set_checksum();
+		let hdr_bytes = hdr.encode();
+		crate::nic::send_from(source_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	}
+	else
+	{
+		if 20 + pkt.total_len() > 0xFFFF {
+			log_notice!("Unable to send to {:?}: Packet too large ({} bytes)", dest, pkt.total_len());
+			return Err(());
+		}
+		let data: Vec<u8> = pkt.into_iter().flat_map(|v| v.iter()).copied().collect();
+		hdr.identification = NEXT_IDENTIFICATION.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
+		// All but the last fragment must be a multiple of 8 bytes long
+		let max_frag_len = (mtu - 20) & !7;
+		log_debug!("Fragmenting {} bytes to {:?} into {}-byte fragments (id={:#x})", data.len(), dest, max_frag_len, hdr.identification);
+		for (i,frag) in data.chunks(max_frag_len).enumerate()
+		{
+			let mut hdr = Ipv4Header { total_length: (20 + frag.len()) as u16, ..hdr };
+			hdr.set_fragment_ofs(i * max_frag_len / 8);
+			if (i + 1) * max_frag_len < data.len() {
+				hdr.set_has_more_fragments();
+			}
+			hdr.set_checksum();
+			let hdr_bytes = hdr.encode();
+			crate::nic::send_from(source_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &crate::nic::SparsePacket::new_root(frag)));
+		}
+	}
 	Ok( () )
 }
@@ -0,0 +1,181 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4/fragments.rs
+//! IPv4 fragment reassembly
+use kernel::lib::Vec;
+use kernel::sync::Mutex;
+use crate::nic::PacketReader;
+use super::Address;
+use super::Ipv4Header;
+
+/// Time after the first fragment is seen that a partial datagram is discarded (RFC 791 suggests 15s, 30s is common)
+const REASSEMBLY_TIMEOUT_MS: u64 = 30*1000;
+/// Maximum number of datagrams being reassembled at once (the oldest is discarded when exceeded)
+const MAX_DATAGRAMS: usize = 16;
+/// Largest datagram payload that can be reassembled (limited by the 16-bit total length)
+const MAX_PAYLOAD: usize = 0xFFFF - 20;
+
+static BUFFERS: Mutex<Vec<Datagram>> = Mutex::new(Vec::new());
+
+/// Identifies the fragments of a datagram (RFC 791 - source, destination, identification, protocol)
+#[derive(PartialEq)]
+struct Key
+{
+	source: Address,
+	destination: Address,
+	identification: u16,
+	protocol: u8,
+}
+struct Datagram
+{
+	key: Key,
+	start_time: ::kernel::time::TickCount,
+	data: Vec<u8>,
+	/// Sorted and non-overlapping list of received byte ranges
+	ranges: Vec<(usize,usize)>,
+	/// Payload length, known once the last fragment (the one without "More Fragments") is seen
+	total_len: Option<usize>,
+}
+
+/// Handle a fragment addressed to this host, returning the reassembled payload once all fragments are present
+pub fn push_fragment(hdr: &Ipv4Header, mut reader: PacketReader) -> Option<Vec<u8>>
+{
+	let key = Key { source: hdr.source, destination: hdr.destination, identification: hdr.identification, protocol: hdr.protocol };
+	let ofs = hdr.get_fragment_ofs() * 8;
+	let len = reader.remain();
+	let is_last = !hdr.get_has_more_fragments();
+	// All but the last fragment must be a multiple of 8 bytes
+	if len == 0 || (!is_last && len % 8 != 0) || ofs + len > MAX_PAYLOAD {
+		log_notice!("Malformed fragment from {} (id={:#x}, {}+{})", hdr.source, hdr.identification, ofs, len);
+		return None;
+	}
+
+	let now = ::kernel::time::ticks();
+	let mut lh = BUFFERS.lock();
+	// Discard stale partial datagrams
+	lh.retain(|d| {
+		if now - d.start_time > REASSEMBLY_TIMEOUT_MS {
+			log_debug!("Reassembly of {:#x} from {} timed out", d.key.identification, d.key.source);
+			false
+		}
+		else {
+			true
+		}
+		});
+
+	let idx = match lh.iter().position(|d| d.key == key)
+		{
+		Some(i) => i,
+		None => {
+			if lh.len() >= MAX_DATAGRAMS {
+				log_notice!("Too many datagrams being reassembled, dropping the oldest");
+				lh.remove(0);
+			}
+			lh.push(Datagram { key, start_time: now, data: Vec::new(), ranges: Vec::new(), total_len: None });
+			lh.len() - 1
+			},
+		};
+	let d = &mut lh[idx];
+
+	// Check consistency with the known length
+	let end = ofs + len;
+	let consistent = match d.total_len
+		{
+		Some(total) => end <= total && (!is_last || end == total),
+		None => !is_last || d.ranges.last().map_or(true, |r| r.1 <= end),
+		};
+	if !consistent {
+		log_notice!("Inconsistent fragment from {} (id={:#x}, {}+{}), discarding datagram", hdr.source, hdr.identification, ofs, len);
+		lh.remove(idx);
+		return None;
+	}
+	if is_last {
+		d.total_len = Some(end);
+	}
+
+	// Copy in the data, keeping the first copy of any overlapping bytes
+	if d.data.len() < end {
+		d.data.resize(end, 0);
+	}
+	let mut tmp = [0; 1500];
+	let mut pos = ofs;
+	while pos < end
+	{
+		let n = reader.read(&mut tmp).unwrap_or(0);
+		if n == 0 {
+			break;
+		}
+		for (i,&b) in tmp[..n].iter().enumerate()
+		{
+			let p = pos + i;
+			if !d.ranges.iter().any(|r| r.0 <= p && p < r.1) {
+				d.data[p] = b;
+			}
+		}
+		pos += n;
+	}
+	d.add_range(ofs, end);
+
+	// Complete once a single range covers the whole payload
+	let complete = match d.total_len
+		{
+		Some(total) => d.ranges.len() == 1 && d.ranges[0] == (0, total),
+		None => false,
+		};
+	if complete {
+		Some(lh.remove(idx).data)
+	}
+	else {
+		None
+	}
+}
+
+impl Datagram
+{
+	/// Record that `start..end` has been received, merging adjacent/overlapping ranges
+	fn add_range(&mut self, start: usize, end: usize)
+	{
+		let mut new = (start, end);
+		self.ranges.retain(|r| {
+			if r.1 < new.0 || new.1 < r.0 {
+				true
+			}
+			else {
+				new = (new.0.min(r.0), new.1.max(r.1));
+				false
+			}
+			});
+		let pos = self.ranges.iter().position(|r| r.0 > new.0).unwrap_or(self.ranges.len());
+		self.ranges.insert(pos, new);
+	}
+}
+
+/// Packet handle wrapping a reassembled datagram (so it can be passed to protocol handlers)
+pub struct ReassembledPacket(Vec<u8>);
+impl ReassembledPacket
+{
+	pub fn new_handle<'a>(data: Vec<u8>) -> crate::nic::PacketHandle<'a> {
+		match crate::nic::PacketHandle::new(ReassembledPacket(data))
+		{
+		Ok(v) => v,
+		Err(_) => panic!("ReassembledPacket doesn't fit in a PacketHandle"),
+		}
+	}
+}
+impl crate::nic::RxPacket for ReassembledPacket
+{
+	fn len(&self) -> usize {
+		self.0.len()
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		&self.0
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		self.0.get(range)
+	}
+}
@@ -9,7 +9,7 @@ pub struct Ipv4Header
 	pub total_length: u16,
 	pub identification: u16,
 	pub flags: u8,
-	pub frag_ofs_high: u8,
+	pub frag_ofs_low: u8,
 	pub ttl: u8,
 	pub protocol: u8,
 	pub hdr_checksum: u16,
@@ -26,7 +26,7 @@ impl Ipv4Header
 			(self.total_length >> 8) as u8, self.total_length as u8,
 			(self.identification >> 8) as u8, self.identification as u8,
 			self.flags,
-			self.frag_ofs_high,
+			self.frag_ofs_low,
 			self.ttl,
 			self.protocol,
 			(self.hdr_checksum >> 8) as u8, self.hdr_checksum as u8,
@@ -47,7 +47,7 @@ impl Ipv4Header
 			total_length: reader.read_u16n()?,
 			identification: reader.read_u16n()?,
 			flags: reader.read_u8()?,
-			frag_ofs_high: reader.read_u8()?,	// low bits in the `flags` field
+			frag_ofs_low: reader.read_u8()?,	// high bits in the `flags` field
 			ttl: reader.read_u8()?,
 			protocol: reader.read_u8()?,
 			hdr_checksum: reader.read_u16n()?,
@@ -62,11 +62,18 @@ impl Ipv4Header
 	pub fn get_has_more_fragments(&self) -> bool {
 		self.flags & 1 << 5 != 0
 	}
-	//fn set_has_more_fragments(&mut self) {
-	//	self.flags |= 1 << 5;
-	//}
+	pub fn set_has_more_fragments(&mut self) {
+		self.flags |= 1 << 5;
+	}
 
+	/// Fragment offset (in 8-byte units)
 	pub fn get_fragment_ofs(&self) -> usize {
-		((self.frag_ofs_high as usize) << 5) | (self.flags & 0x1F) as usize
+		// NOTE: The high 5 bits are in the `flags` field
+		(((self.flags & 0x1F) as usize) << 8) | self.frag_ofs_low as usize
+	}
+	pub fn set_fragment_ofs(&mut self, ofs: usize) {
+		assert!(ofs < 1 << 13);
+		self.flags = (self.flags & !0x1F) | (ofs >> 8) as u8;
+		self.frag_ofs_low = ofs as u8;
 	}
-}
\ No newline at end of file
+}
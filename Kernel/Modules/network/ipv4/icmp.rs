@@ -0,0 +1,240 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4/icmp.rs
+//! ICMP (Internet Control Message Protocol) for IPv4
+use kernel::lib::ring_buffer::RingBuf;
+use kernel::lib::Vec;
+use kernel::sync::mutex::LazyMutexDefault;
+use crate::nic::PacketReader;
+
+use super::{Address,Interface};
+use super::Ipv4Header;
+
+pub const PROTOCOL: u8 = 1;
+
+/// Maximum number of error messages sent per second (so a flood of bad packets doesn't become a flood of errors)
+const MAX_ERRORS_PER_SECOND: u32 = 10;
+
+static WORKER_SLEEP: ::kernel::sync::EventChannel = ::kernel::sync::EventChannel::new();
+static PENDING_PACKETS: LazyMutexDefault<PendingPackets> = LazyMutexDefault::new();
+
+struct PendingPackets {
+	_worker: ::kernel::threads::WorkerThread,
+	packets: RingBuf<PendingPacket>,
+	/// Start of the current rate-limiting period, and the number of errors sent in it
+	error_period: (::kernel::time::TickCount, u32),
+}
+impl Default for PendingPackets {
+	fn default() -> Self {
+		Self {
+			packets: RingBuf::new(128),
+			error_period: (0, 0),
+			_worker: ::kernel::threads::WorkerThread::new("ICMPv4", worker),
+		}
+	}
+}
+struct PendingPacket {
+	source: Address,
+	destination: Address,
+	ty: u8,
+	code: u8,
+	/// Data after the type/code/checksum header
+	data: Vec<u8>,
+}
+
+/// Destination unreachable codes used when generating errors
+#[derive(Copy,Clone,Debug)]
+pub enum UnreachableCode {
+	Protocol = 2,
+	Port = 3,
+}
+
+/// Error reported to TCP/UDP by a received ICMP message
+#[derive(Copy,Clone,Debug)]
+pub enum ErrorKind {
+	NetUnreachable,
+	HostUnreachable,
+	ProtocolUnreachable,
+	PortUnreachable,
+	/// Packet was too large and had "Don't Fragment" set
+	FragmentationNeeded,
+	/// TTL reached zero in transit, or reassembly timed out
+	TimeExceeded,
+}
+impl ErrorKind {
+	/// "Hard" errors indicate that the remote will never accept the traffic (RFC 1122 4.2.3.9), so established connections should be aborted
+	pub fn is_hard(&self) -> bool {
+		match self {
+		ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => true,
+		_ => false,
+		}
+	}
+}
+
+/// A worker thread used to handle sending ICMP messages without blocking the RX threads
+fn worker() {
+	loop {
+		WORKER_SLEEP.sleep();
+
+		while let Some(p) = { let mut lh = PENDING_PACKETS.lock(); lh.packets.pop_front() }
+		{
+			let mut hdr = [p.ty, p.code, 0,0];
+			let cksum = super::checksum::from_bytes(hdr.iter().chain(p.data.iter()).copied());
+			hdr[2..][..2].copy_from_slice( &u16::to_be_bytes(cksum) );
+
+			let data = crate::nic::SparsePacket::new_root(&p.data);
+			let pkt = crate::nic::SparsePacket::new_chained(&hdr, &data);
+			let _ = ::kernel::futures::block_on(super::send_packet(p.source, p.destination, PROTOCOL, pkt));
+		}
+	}
+}
+
+/// Handle an incoming ICMP packet
+pub fn handle_packet(interface: &Interface, source: Address, mut reader: PacketReader) -> Result<(),UnreachableCode>
+{
+	if super::checksum::from_reader(reader.clone()) != 0 {
+		log_notice!("ICMP checksum failure from {}", source);
+		return Ok( () );
+	}
+	let (ty, code) = match (reader.read_u8(), reader.read_u8(), reader.read_u16n())
+		{
+		(Ok(ty), Ok(code), Ok(_checksum)) => (ty, code),
+		_ => return Ok( () ),
+		};
+
+	match ty {
+	0 => {	// Echo Reply
+		// TODO: Userland ping support
+	},
+	3 => {	// Destination unreachable
+		let kind = match code {
+			0 => ErrorKind::NetUnreachable,
+			1 => ErrorKind::HostUnreachable,
+			2 => ErrorKind::ProtocolUnreachable,
+			3 => ErrorKind::PortUnreachable,
+			4 => ErrorKind::FragmentationNeeded,
+			// Source route failed, unknown network/host, administratively prohibited, ...
+			_ => ErrorKind::HostUnreachable,
+		};
+		let _unused = reader.read_u32n();
+		report_error(kind, reader);
+	},
+	8 => {	// Echo Request
+		// Reply with the same identifier, sequence number and data
+		let mut data = vec![0; reader.remain()];
+		if data.len() > 0 {
+			let _ = reader.read(&mut data);
+		}
+		enqueue(PendingPacket { source: interface.addr(), destination: source, ty: 0, code: 0, data });
+	},
+	11 => {	// Time Exceeded
+		let _unused = reader.read_u32n();
+		report_error(ErrorKind::TimeExceeded, reader);
+	},
+	_ => {
+		log_debug!("Unhandled ICMP type {} code {} from {}", ty, code, source);
+	},
+	}
+	Ok( () )
+}
+
+/// Pass an error to the protocol that sent the packet quoted in `reader`
+fn report_error(kind: ErrorKind, mut reader: PacketReader)
+{
+	// The error contains the IP header and (at least) the first 8 bytes of the payload of the offending packet
+	let hdr = match Ipv4Header::read(&mut reader)
+		{
+		Ok(v) => v,
+		Err(_) => return,
+		};
+	for _ in 20 .. hdr.get_header_length() {
+		if reader.read_u8().is_err() {
+			return ;
+		}
+	}
+	let (local_port, remote_port) = match (reader.read_u16n(), reader.read_u16n())
+		{
+		(Ok(s), Ok(d)) => (s, d),
+		_ => return,
+		};
+	log_debug!("ICMP {:?} for proto {} {}:{} -> {}:{}", kind, hdr.protocol, hdr.source, local_port, hdr.destination, remote_port);
+	let local = (crate::Address::Ipv4(hdr.source), local_port);
+	let remote = (crate::Address::Ipv4(hdr.destination), remote_port);
+	match hdr.protocol
+	{
+	6 => crate::tcp::handle_icmp_error(local, remote, kind),
+	17 => crate::udp::handle_icmp_error(local, remote, kind),
+	_ => {},
+	}
+}
+
+/// Send a "destination unreachable" error in response to a packet received on `interface`
+pub fn send_unreachable(interface: &Interface, hdr: &Ipv4Header, reader: PacketReader, code: UnreachableCode)
+{
+	// Never send errors in response to broadcasts, or to a source that isn't a single host
+	if hdr.destination != interface.addr() || hdr.source.is_zero() || hdr.source.0 == [0xFF; 4] || hdr.source.0[0] >= 224 {
+		return ;
+	}
+	let mut reader_ic = reader.clone();
+	// Or in response to ICMP errors
+	if hdr.protocol == PROTOCOL {
+		match reader_ic.read_u8()
+		{
+		Ok(0) | Ok(8) | Ok(13) | Ok(15) | Ok(17) => {},	// Queries are fine
+		_ => return,
+		}
+	}
+
+	let mut data = Vec::with_capacity(4 + 20 + 8);
+	data.extend_from_slice(&[0; 4]);	// Unused
+	data.extend_from_slice(&quote_header(hdr, reader.remain()));
+	let mut reader = reader;
+	for _ in 0 .. 8 {
+		match reader.read_u8()
+		{
+		Ok(b) => data.push(b),
+		Err(_) => break,
+		}
+	}
+
+	{
+		let mut lh = PENDING_PACKETS.lock();
+		let now = ::kernel::time::ticks();
+		if now - lh.error_period.0 >= 1000 {
+			lh.error_period = (now, 0);
+		}
+		if lh.error_period.1 >= MAX_ERRORS_PER_SECOND {
+			log_debug!("ICMP error to {} rate limited", hdr.source);
+			return ;
+		}
+		lh.error_period.1 += 1;
+	}
+	log_debug!("Sending ICMP unreachable ({:?}) to {}", code, hdr.source);
+	enqueue(PendingPacket { source: interface.addr(), destination: hdr.source, ty: 3, code: code as u8, data });
+}
+
+/// Re-create the IP header of a received packet, for quoting in an error
+///
+/// Options aren't included, and fragmentation information is cleared (as the packet may have been reassembled)
+fn quote_header(hdr: &Ipv4Header, payload_len: usize) -> [u8; 20]
+{
+	let mut h = Ipv4Header {
+		ver_and_len: 0x40 | 20/4,
+		total_length: (20 + payload_len) as u16,
+		flags: 0,
+		frag_ofs_low: 0,
+		..*hdr
+		};
+	h.set_checksum();
+	h.encode()
+}
+
+fn enqueue(pkt: PendingPacket)
+{
+	match PENDING_PACKETS.lock().packets.push_back(pkt)
+	{
+	Ok(_) => WORKER_SLEEP.post(),
+	Err(_) => log_notice!("ICMP TX queue full, dropping packet"),
+	}
+}
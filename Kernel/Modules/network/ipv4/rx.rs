@@ -6,6 +6,7 @@ use crate::nic::PacketReader;
 use super::{Address,Interface};
 use super::Ipv4Header;
 use super::calculate_checksum;
+use super::icmp::UnreachableCode;
 
 use super::INTERFACES;
 
@@ -13,7 +14,9 @@ use super::INTERFACES;
 static PROTOCOL_HANDLDERS: RwLock<Vec<(u8, ProtoHandler)>> = RwLock::new(Vec::new());
 
 /// Register a protocol handler with this layer
-pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader)) -> Result<(), ()>
+///
+/// The handler can return an error to have an ICMP "destination unreachable" sent back to the source
+pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader)->Result<(),UnreachableCode>) -> Result<(), ()>
 {
 	let mut lh = PROTOCOL_HANDLDERS.write();
 	for &(p, _) in lh.iter()
@@ -76,13 +79,6 @@ pub fn handle_rx_ethernet(phys_interface: &crate::nic::InterfaceData, source_mac
 		}
 	}
 	
-	// Check for IP-level fragmentation
-	if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0 {
-		// TODO: Handle fragmented packets
-		log_error!("TODO: Handle fragmented packets");
-		return Ok( () );
-	}
-	
 	// Sanity check that we have enough bytes for the body.
 	// If there is, then truncate the reader (to provide an exact packet length)
 	let Ok(reader) = reader.take_sub_reader(hdr.total_length as usize - hdr_len) else {
@@ -103,20 +99,17 @@ pub fn handle_rx_ethernet(phys_interface: &crate::nic::InterfaceData, source_mac
 			// TODO: Check if the source address is from the same subnet, and only cache in ARP if it is
 			crate::arp::snoop_v4(source_mac, hdr.source);
 
-			// TODO: Support raw socket Rx
-
-			// Figure out which sub-protocol to send this packet to
-			// - Should there be alternate handlers for 
-			for &(id,ref handler) in PROTOCOL_HANDLDERS.read().iter()
-			{
-				if id == hdr.protocol
-				{
-					handler.dispatch(interface, hdr.source, hdr.destination, reader);
-					return Ok( () );
+			// Check for IP-level fragmentation
+			if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0 {
+				if let Some(data) = super::fragments::push_fragment(&hdr, reader) {
+					log_debug!("Reassembled {} bytes from {} (id={:#x})", data.len(), hdr.source, hdr.identification);
+					let pkt = super::fragments::ReassembledPacket::new_handle(data);
+					dispatch(interface, &hdr, PacketReader::new(&pkt));
 				}
+				return Ok( () );
 			}
-			log_debug!("Unknown protocol {}", hdr.protocol);
-			// No handler, but the interface is known
+
+			dispatch(interface, &hdr, reader);
 			return Ok( () );
 		}
 	}
@@ -130,10 +123,30 @@ pub fn handle_rx_ethernet(phys_interface: &crate::nic::InterfaceData, source_mac
 	Ok( () )
 }
 
+/// Pass a complete packet to the handler for its protocol
+fn dispatch(interface: &Interface, hdr: &Ipv4Header, reader: PacketReader)
+{
+	// TODO: Support raw socket Rx
+
+	// Figure out which sub-protocol to send this packet to
+	// - Should there be alternate handlers for 
+	let rv = match PROTOCOL_HANDLDERS.read().iter().find(|&&(id,_)| id == hdr.protocol)
+		{
+		Some(&(_,ref handler)) => handler.dispatch(interface, hdr.source, hdr.destination, reader.clone()),
+		None => {
+			log_debug!("Unknown protocol {}", hdr.protocol);
+			Err(UnreachableCode::Protocol)
+			},
+		};
+	if let Err(code) = rv {
+		super::icmp::send_unreachable(interface, hdr, reader, code);
+	}
+}
+
 enum ProtoHandler
 {
 	/// Direct in-kernel handling (e.g. TCP)
-	DirectKernel(fn(&Interface, Address, PacketReader)),
+	DirectKernel(fn(&Interface, Address, PacketReader)->Result<(),UnreachableCode>),
 	/// Indirect user handling (pushes onto a buffer for the user to read from)
 	// Ooh, another use for stack_dst, a DST queue!
 	#[allow(dead_code)]
@@ -141,7 +154,7 @@ enum ProtoHandler
 }
 impl ProtoHandler
 {
-	fn dispatch(&self, i: &Interface, src: Address, _dest: Address, r: PacketReader)
+	fn dispatch(&self, i: &Interface, src: Address, _dest: Address, r: PacketReader) -> Result<(),UnreachableCode>
 	{
 		match *self
 		{
@@ -82,7 +82,7 @@ pub async fn send_packet(source: Address, destination: Address, proto: u8, pkt:
 	};
 	// 3. Send
 	let hdr = Ipv6Header {
-		ver_tc_fl: 0x4000_0000,
+		ver_tc_fl: 0x6000_0000,
 		payload_length: pkt.total_len() as u16,
 		hop_limit: 255,
 		next_header: proto,
@@ -91,7 +91,7 @@ pub async fn send_packet(source: Address, destination: Address, proto: u8, pkt:
 		destination,
 		};
 	let hdr_bytes = hdr.encode();
-	crate::nic::send_from(source_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	crate::nic::send_from(source_mac, dest_mac, 0x86DD, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
 	Ok( () )
 }
 
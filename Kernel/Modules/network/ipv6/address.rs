@@ -11,22 +11,24 @@ impl Address {
 	}
 	pub fn mask_net(&self, prefix_bits: u8) -> Address {
 		let mut rv = [0; 8];
-		let prefix_whole = prefix_bits as usize / 16;
-		for i in 0 .. prefix_whole {
-			rv[i] = self.0[i];
+		for i in 0 .. 8 {
+			rv[i] = self.0[i] & Self::prefix_mask(prefix_bits, i);
 		}
-		rv[prefix_whole] = self.0[prefix_whole] & (0xFFFF << (prefix_bits % 16));
 		Address(rv)
 	}
 	pub fn mask_host(&self, prefix_bits: u8) -> Address {
 		let mut rv = [0; 8];
-		let prefix_whole = prefix_bits as usize / 16;
-		rv[prefix_whole] = self.0[prefix_whole] & !(0xFFFF << (prefix_bits % 16));
-		for i in prefix_whole + 1 .. 8 {
-			rv[i] = self.0[i];
+		for i in 0 .. 8 {
+			rv[i] = self.0[i] & !Self::prefix_mask(prefix_bits, i);
 		}
 		Address(rv)
 	}
+	/// Mask for the network part of word `i`, with a prefix of `prefix_bits`
+	fn prefix_mask(prefix_bits: u8, i: usize) -> u16 {
+		assert!(prefix_bits <= 128);
+		let bits = (prefix_bits as usize).saturating_sub(i * 16).min(16);
+		(!0u16).checked_shl(16 - bits as u32).unwrap_or(0)
+	}
 	pub fn words(&self) -> &[u16; 8] {
 		&self.0
 	}
@@ -23,8 +23,11 @@ pub mod arp;
 pub mod ipv4;
 pub mod ipv6;
 
+mod port_pool;
+
 fn init()
 {
+	crate::ipv4::init();
 	crate::tcp::init();
 	crate::udp::init();
 }
@@ -18,6 +18,9 @@ pub use self::registration::{register, Registration};
 
 pub type MacAddr = [u8; 6];
 
+/// Standard ethernet MTU, used by interfaces that don't override [Interface::mtu]
+pub const DEFAULT_MTU: usize = 1500;
+
 #[derive(Debug)]
 pub enum Error
 {
@@ -37,6 +40,11 @@ pub trait Interface: 'static + Send + Sync
 	/// Transmit a raw packet (blocking)
 	fn tx_raw(&self, pkt: SparsePacket);
 
+	/// Largest packet (excluding the ethernet header) that can be sent
+	fn mtu(&self) -> usize {
+		DEFAULT_MTU
+	}
+
 	/// The input buffer can be a mix of `> 'stack` and `< 'stack` buffers. This function should collapse shorter lifetime
 	/// buffers into an internal buffer that lives long enough.
 	//fn tx_async<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, pkt: SparsePacket) -> Result<(), Error>;
@@ -101,6 +109,22 @@ pub fn send_from(local_addr: MacAddr, dest_addr: MacAddr, ether_ty: u16, pkt: Sp
 	}
 }
 
+/// Get the MTU of the interface with the given MAC address
+pub fn get_mtu(local_addr: MacAddr) -> Option<usize>
+{
+	for i in INTERFACES_LIST.lock().iter()
+	{
+		if let Some(v) = i
+		{
+			if v.data.addr == local_addr
+			{
+				return Some(v.data.base_interface.mtu());
+			}
+		}
+	}
+	None
+}
+
 /// Returns the number of allocated interface slots (some of which may be unused)
 pub fn count_interfaces() -> usize
 {
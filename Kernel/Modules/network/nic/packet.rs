@@ -77,7 +77,7 @@ pub struct PacketReader<'a> {
 	len: u16,
 }
 impl<'a> PacketReader<'a> {
-	pub(super) fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
+	pub(crate) fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
 		PacketReader {
 			pkt,
 			ofs: 0,
@@ -0,0 +1,81 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/port_pool.rs
+//! Allocation of ephemeral (dynamic) ports, shared by TCP and UDP
+
+/// Start of the dynamic port range (RFC 6335 6)
+pub const MIN_DYN_PORT: u16 = 0xC000;
+const N_DYN_PORTS: usize = (1<<16) - MIN_DYN_PORT as usize;
+
+/// Bitmap of allocated dynamic ports
+pub struct PortPool {
+	bitmap: [u32; N_DYN_PORTS / 32],
+}
+impl PortPool
+{
+	pub const fn new() -> PortPool
+	{
+		PortPool {
+			bitmap: [0; N_DYN_PORTS / 32],
+			}
+	}
+
+	fn ofs_mask(idx: u16) -> Option<(usize, u32)>
+	{
+		if idx >= MIN_DYN_PORT
+		{
+			let ofs = (idx - MIN_DYN_PORT) as usize / 32;
+			let mask  = 1 << (idx % 32);
+			Some( (ofs, mask) )
+		}
+		else
+		{
+			None
+		}
+	}
+	/// Mark a port as used (ports outside the dynamic range are ignored)
+	pub fn take(&mut self, idx: u16) -> Result<(),()>
+	{
+		let (ofs,mask) = match Self::ofs_mask(idx)
+			{
+			Some(v) => v,
+			None => return Ok(()),
+			};
+		if self.bitmap[ofs] & mask != 0 {
+			Err( () )
+		}
+		else {
+			self.bitmap[ofs] |= mask;
+			Ok( () )
+		}
+	}
+	pub fn release(&mut self, idx: u16)
+	{
+		let (ofs,mask) = match Self::ofs_mask(idx)
+			{
+			Some(v) => v,
+			None => return,
+			};
+		self.bitmap[ofs] &= !mask;
+	}
+	/// Allocate a free port, skipping any for which `in_use` returns true
+	///
+	/// Strategy: Start at a random port and search linearly (RFC 6056 3.3.1), so the port can't be guessed by an
+	/// off-path attacker.
+	pub fn allocate(&mut self, mut in_use: impl FnMut(u16)->bool) -> Option<u16>
+	{
+		let start = (::kernel::entropy::get_u32() as usize % N_DYN_PORTS) as u16;
+		for i in 0 .. N_DYN_PORTS as u16
+		{
+			let idx = MIN_DYN_PORT + (start + i) % N_DYN_PORTS as u16;
+			if in_use(idx) {
+				continue ;
+			}
+			if self.take(idx).is_ok() {
+				return Some(idx);
+			}
+		}
+		None
+	}
+}
@@ -10,13 +10,18 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::nic::SparsePacket;
 use crate::Address;
 use kernel::futures::block_on;
+use crate::port_pool::PortPool;
 
 const IPV4_PROTO_TCP: u8 = 6;
 
 
 mod rx_buffer;
+mod rto;
+mod congestion;
+mod options;
 mod connection;
 use self::connection::Connection;
+use self::options::{Options,OptionsBuf,Negotiated};
 
 fn earliest_timestamp(dst: &mut Option<::kernel::time::TickCount>, src: Option<::kernel::time::TickCount>) {
 	//::kernel::log_trace!("{:?} < {:?}", dst, src);
@@ -34,22 +39,35 @@ fn earliest_timestamp(dst: &mut Option<::kernel::time::TickCount>, src: Option<:
 pub fn init()
 {
 	crate::ipv4::register_handler(IPV4_PROTO_TCP, |int,src_addr, pkt| {
-		rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
+		rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt);
+		Ok( () )
 	}).unwrap();
 	crate::ipv6::register_handler(IPV4_PROTO_TCP, |int,src_addr, pkt| {
 		rx_handler(Address::Ipv6(src_addr), Address::Ipv6(int.addr()), pkt)
 	}).unwrap();
 
-	// TODO: Spawn a worker that waits on all the TX timers and handles sending packets
+	// Worker that waits on all the TX timers (retransmit, delayed ACK, Nagle) and handles sending packets
 	::core::mem::forget(::kernel::threads::WorkerThread::new("TCP Worker", || {
 		// Check/advance all connections, also getting the timeout for the sleep
 		loop
 		{
 			let key = WORKER_CV.get_key();
 			let mut wakeup_time = None;
+			let mut finished = ::kernel::vec::Vec::new();
 			for (quad, conn) in CONNECTIONS.iter()
 			{
-				earliest_timestamp(&mut wakeup_time, conn.lock().run_tasks(quad));
+				let mut conn = conn.lock();
+				earliest_timestamp(&mut wakeup_time, conn.run_tasks(quad));
+				if conn.can_remove() {
+					finished.push(*quad);
+				}
+			}
+			// Remove closed connections (once the user has dropped the handle), releasing the port for reuse
+			for quad in finished
+			{
+				log_debug!("{:?} Removing finished connection", quad);
+				CONNECTIONS.take(&quad);
+				release_port(&quad.local_addr, quad.local_port);
 			}
 			::kernel::log_trace!("wakeup_time = {:?}", wakeup_time);
 			// Wait on a condvar with a timeout (based)
@@ -57,7 +75,7 @@ pub fn init()
 			if let Some(wakeup_time) = wakeup_time {
 				::kernel::futures::block_on(::kernel::futures::join_one(
 					WORKER_CV.wait(key),
-					::kernel::futures::msleep( (wakeup_time - ::kernel::time::ticks()) as usize )
+					::kernel::futures::msleep( wakeup_time.saturating_sub(::kernel::time::ticks()) as usize )
 					));
 			}
 			else {
@@ -85,15 +103,54 @@ fn get_outbound_ip_for(addr: &Address) -> Option<Address>
 	}
 }
 /// Allocate a port for the given local address
-fn allocate_port(_addr: &Address) -> Option<u16>
+fn allocate_port(addr: &Address) -> Option<u16>
 {
 	// TODO: Could store bitmap against the interface (having a separate bitmap for each interface)
-	S_PORTS.lock().allocate()
+	// - Skip ports that have a listening server, so a client connection never shares a port with a server
+	S_PORTS.lock().allocate(|p| SERVERS.get(&ListenPair::any(p)).is_some() || SERVERS.get(&ListenPair::fixed(*addr, p)).is_some())
 }
 fn release_port(_addr: &Address, idx: u16)
 {
 	S_PORTS.lock().release(idx)
 }
+/// Generate the initial sequence number for a connection (RFC 6528)
+///
+/// `ISN = M + F(quad, secret)`, where `M` is a clock ticking every 4us and `F` is a keyed hash of the addresses and ports.
+/// The clock keeps sequence numbers increasing for successive connections on the same quad, while the hash stops an
+/// attacker predicting the ISN of connections they can't see.
+fn initial_sequence(quad: &Quad) -> u32
+{
+	static S_ISN_KEY: Mutex<Option<[u64; 2]>> = Mutex::new(None);
+	let key = *S_ISN_KEY.lock().get_or_insert_with(|| [::kernel::entropy::get_u64(), ::kernel::entropy::get_u64()]);
+	fn addr_words(a: &Address) -> [u64; 2] {
+		match a
+		{
+		Address::Ipv4(a) => [u32::from_be_bytes(a.0) as u64, 0],
+		Address::Ipv6(a) => {
+			let v = u128::from_be_bytes(a.to_bytes());
+			[(v >> 64) as u64, v as u64]
+			},
+		}
+	}
+	let l = addr_words(&quad.local_addr);
+	let r = addr_words(&quad.remote_addr);
+	let ports = (quad.local_port as u64) << 16 | quad.remote_port as u64;
+	let f = ::kernel::entropy::keyed_hash(&key, &[l[0], l[1], r[0], r[1], ports]) as u32;
+	let m = (::kernel::time::ticks() * 250) as u32;
+	m.wrapping_add(f)
+}
+/// Determine the MSS to advertise on a connection, from the MTU of the interface used to reach the remote
+fn get_local_mss(quad: &Quad) -> u16
+{
+	let (mac, ip_header_len) = match (quad.local_addr, quad.remote_addr)
+		{
+		(Address::Ipv4(l), Address::Ipv4(r)) => (crate::ipv4::route_lookup(l, r).map(|r| r.source_mac), 20),
+		(Address::Ipv6(l), Address::Ipv6(r)) => (crate::ipv6::route_lookup(l, r).map(|r| r.source_mac), 40),
+		_ => (None, 40),
+		};
+	let mtu = mac.and_then(crate::nic::get_mtu).unwrap_or(crate::nic::DEFAULT_MTU);
+	(mtu - ip_header_len - 5*4).min(0xFFFF) as u16
+}
 
 fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::PacketReader)
 {
@@ -129,13 +186,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 	}
 
 	// Options
-	while pkt.remain() > pre_header_reader.remain() - hdr_len
-	{
-		match pkt.read_u8().unwrap()
-		{
-		_ => {},
-		}
-	}
+	let options = Options::read(&mut pkt, hdr_len.saturating_sub(5*4));
 	
 	let get_server = ||->Option<_> {
 		Option::or( SERVERS.get( &ListenPair::fixed(dest_addr, hdr.dest_port) ), SERVERS.get( &ListenPair::any(hdr.dest_port) ) )
@@ -145,7 +196,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 	// Search for active connections with this quad
 	if let Some(c) = CONNECTIONS.get(&quad)
 	{
-		c.lock().handle(&quad, &hdr, pkt);
+		c.lock().handle(&quad, &hdr, &options, pkt);
 	}
 	// Search for proto-connections
 	// - Proto-connections are lighter weight than full-blown connections, reducing the impact of a SYN flood
@@ -157,7 +208,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 			if hdr.sequence_number == c.seen_seq && hdr.acknowledgement_number == c.sent_seq.wrapping_add(1)
 			{
 				// Make the full connection struct
-				match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr)))
+				match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr, &options, c.options)))
 				{
 				Ok(()) => {
 					log_debug!("Final ACK of a handshake: {:?}", quad);
@@ -180,9 +231,9 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 			}
 		}
 		else {
-			// No proto connection - RST?
+			// No proto connection
 			log_debug!("Unexpected ACK: {:?}", quad);
-			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_ACK|FLAG_RST, 0, &[], &[]));
+			send_reset(&quad, &hdr, pkt.remain());
 		}
 	}
 	// If none found, look for servers on the destination (if SYN)
@@ -194,28 +245,82 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 			if s.accept_space.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| if v == 0 { None } else { Some(v - 1) }).is_err() { 
 				log_debug!("Start of incoming handshake: {:?} - Dropped, queue full", quad);
 				// Reject if no space
-				// - Send a RST
 				// TODO: Queue a packet instead of blocking here
-				block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number.wrapping_add(1), FLAG_RST, 0, &[], &[]));
+				send_reset(&quad, &hdr, pkt.remain());
 			}
 			else {
 				log_debug!("Start of incoming handshake: {:?}", quad);
-				// - Add the quad as a proto-connection and send the SYN-ACK
-				let pc = ProtoConnection::new(hdr.sequence_number.wrapping_add(1));
-				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq, FLAG_SYN|FLAG_ACK, hdr.window_size, &[], &[]));
+				// - Add the quad as a proto-connection and send the SYN-ACK (replying to the offered options)
+				let local_mss = get_local_mss(&quad);
+				let pc = ProtoConnection::new(initial_sequence(&quad), hdr.sequence_number.wrapping_add(1), Negotiated::from_syn(local_mss, &options));
+				let syn_options = OptionsBuf::syn(local_mss, Some(&options), options::timestamp_now());
+				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq, FLAG_SYN|FLAG_ACK, connection::DEF_RX_WINDOW_SIZE as u16, syn_options.as_bytes(), &[], &[]));
 				let _ = PROTO_CONNECTIONS.replace(quad, pc);	// Insert without replacing
 			}
 		}
 		else
 		{
-			// Send a RST
 			log_debug!("SYN to closed port: {:?}", quad);
-			// All RSTs ACK the contents of the recieved packet
-			let ack = hdr.sequence_number.wrapping_add( pre_header_reader.remain().max(1) as u32 );
-			block_on(quad.send_packet(hdr.acknowledgement_number, ack, FLAG_RST|FLAG_ACK, 0, &[], &[]));
+			send_reset(&quad, &hdr, pkt.remain());
 		}
 	}
-	// Otherwise, drop
+	// A RST for an unknown connection is dropped (replying could cause a loop)
+	else if hdr.flags & FLAG_RST != 0
+	{
+		// - Unless it's for a proto-connection, which it aborts (RFC 9293 3.10.7.3 - SYN-RECEIVED returns to LISTEN)
+		if let Some(c) = PROTO_CONNECTIONS.take(&quad)
+		{
+			if hdr.sequence_number != c.seen_seq {
+				let _ = PROTO_CONNECTIONS.insert(quad, c);
+			}
+			else if let Some(s) = get_server() {
+				log_debug!("Handshake reset: {:?}", quad);
+				s.accept_space.fetch_add(1, Ordering::SeqCst);
+			}
+		}
+	}
+	// Anything else is for a closed (or never opened) connection
+	else
+	{
+		log_debug!("Segment for unknown connection: {:?} {:#x}", quad, hdr.flags);
+		send_reset(&quad, &hdr, pkt.remain());
+	}
+}
+
+/// Send a RST in response to a segment for a nonexistent connection (RFC 9293 3.10.7.1)
+///
+/// The RST uses the ACK number of the segment (so the peer accepts it), and ACKs the segment's contents.
+fn send_reset(quad: &Quad, hdr: &PktHeader, data_len: usize)
+{
+	let seq = if hdr.flags & FLAG_ACK != 0 { hdr.acknowledgement_number } else { 0 };
+	// SYN and FIN each take up a sequence number
+	let seg_len = data_len
+		+ if hdr.flags & FLAG_SYN != 0 { 1 } else { 0 }
+		+ if hdr.flags & FLAG_FIN != 0 { 1 } else { 0 };
+	let ack = hdr.sequence_number.wrapping_add(seg_len as u32);
+	block_on(quad.send_packet(seq, ack, FLAG_RST|FLAG_ACK, 0, &[], &[], &[]));
+}
+
+/// Handle an ICMP error received for a packet sent from `local` to `remote`
+pub(crate) fn handle_icmp_error(local: (Address, u16), remote: (Address, u16), kind: crate::ipv4::icmp::ErrorKind)
+{
+	use crate::ipv4::icmp::ErrorKind;
+	let quad = Quad::new(local.0, local.1, remote.0, remote.1);
+	let err = match kind
+		{
+		ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => ConnError::RemoteRefused,
+		ErrorKind::NetUnreachable | ErrorKind::HostUnreachable | ErrorKind::TimeExceeded => ConnError::NoRoute,
+		// TODO: Path MTU discovery
+		ErrorKind::FragmentationNeeded => return,
+		};
+	if let Some(conn) = CONNECTIONS.get(&quad)
+	{
+		conn.lock().handle_icmp_error(&quad, err, kind.is_hard());
+	}
+	else
+	{
+		log_debug!("ICMP error for unknown connection {:?}", quad);
+	}
 }
 
 fn calculate_checksum(src_addr: Address, dest_addr: Address, hdr: &PktHeader, tail_len: usize, tail_sum: u16) -> u16
@@ -299,12 +404,11 @@ impl Quad
 			local_addr, local_port, remote_addr, remote_port
 			}
 	}
-	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, data1: &[u8], data2: &[u8])
+	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, options_bytes: &[u8], data1: &[u8], data2: &[u8])
 	{
 		// Make a header
-		// TODO: Any options required?
-		let options_bytes = &[];
 		let opts_len_rounded = ((options_bytes.len() + 3) / 4) * 4;
+		let opt_pad = &[0; 3][.. opts_len_rounded - options_bytes.len()];
 		let hdr = {
 			let mut hdr = PktHeader {
 				source_port: self.local_port,
@@ -320,10 +424,10 @@ impl Quad
 			hdr.checksum = calculate_checksum(
 				self.local_addr, self.remote_addr,
 				&hdr,
-				data1.len()+data2.len(),
+				opts_len_rounded+data1.len()+data2.len(),
 				super::ipv4::checksum::from_bytes(
-					// TODO: options (padded to multiple of 4 bytes)
-					Iterator::chain(data1.iter().copied(), data2.iter().copied() )
+					// Options (padded to multiple of 4 bytes), then the data
+					options_bytes.iter().chain(opt_pad.iter()).chain(data1.iter()).chain(data2.iter()).copied()
 				)
 				);
 			hdr.as_bytes()
@@ -334,7 +438,7 @@ impl Quad
 		let data_pkt = SparsePacket::new_root(data2);
 		let data_pkt = SparsePacket::new_chained(data1, &data_pkt);
 		// - Padding required to make the header a multiple of 4 bytes long
-		let opt_pad_pkt = SparsePacket::new_chained(&[0; 3][.. opts_len_rounded - options_bytes.len()], &data_pkt);
+		let opt_pad_pkt = SparsePacket::new_chained(opt_pad, &data_pkt);
 		let opt_pkt = SparsePacket::new_chained(options_bytes, &opt_pad_pkt);
 		let hdr_pkt = SparsePacket::new_chained(&hdr, &opt_pkt);
 
@@ -440,14 +544,17 @@ struct ProtoConnection
 {
 	seen_seq: u32,
 	sent_seq: u32,
+	/// Options negotiated by the SYN and SYN-ACK
+	options: Negotiated,
 }
 impl ProtoConnection
 {
-	fn new(seen_seq: u32) -> ProtoConnection
+	fn new(sent_seq: u32, seen_seq: u32, options: Negotiated) -> ProtoConnection
 	{
 		ProtoConnection {
 			seen_seq: seen_seq,
-			sent_seq: 1,	// TODO: Random
+			sent_seq: sent_seq,
+			options,
 			}
 	}
 }
@@ -496,7 +603,7 @@ impl ServerHandle
 /// Can be directly constructed (for an outgoing/client connection), or returned from a server
 pub struct ConnectionHandle(Quad);
 
-#[derive(Debug)]
+#[derive(Copy,Clone,Debug,PartialEq)]
 pub enum ConnError
 {
 	NoRoute,
@@ -506,6 +613,10 @@ pub enum ConnError
 	RemoteClosed,
 	RemoteReset,
 	NoPortAvailable,
+	/// No data is available to receive yet (the connection is still open)
+	NoData,
+	/// The connection hasn't been established yet
+	NotConnected,
 }
 
 impl ConnectionHandle
@@ -529,7 +640,7 @@ impl ConnectionHandle
 		let quad = Quad::new(local_addr, local_port,  addr, port, );
 		log_trace!("ConnectionHandle::connect: quad={:?}", quad);
 		// 4. Send the opening SYN (by creating the outbound connection structure)
-		let conn = Connection::new_outbound(&quad, 0x10000u32);
+		let conn = Connection::new_outbound(&quad, initial_sequence(&quad));
 		CONNECTIONS.insert(quad, Mutex::new(conn)).map_err(|_| ()).expect("Our unique port wasn't unique");
 		WORKER_CV.wake_one();
 		Ok( ConnectionHandle(quad) )
@@ -583,94 +694,21 @@ impl ConnectionHandle
 		self.conn().lock().recv_ready()
 	}
 
-	pub fn close(&mut self) -> Result<(), ConnError> {
+	/// Close the sending side of the connection (a FIN is sent once all buffered data has been sent)
+	pub fn close(&self) -> Result<(), ConnError> {
 		self.conn().lock().close(&self.0)
 	}
+	/// Close the receiving side of the connection, further received data is discarded
+	pub fn shutdown_recv(&self) -> Result<(), ConnError> {
+		self.conn().lock().shutdown_recv(&self.0)
+	}
 }
 impl ::core::ops::Drop for ConnectionHandle
 {
 	fn drop(&mut self)
 	{
-		// Mark the connection to close
-	}
-}
-
-
-const MIN_DYN_PORT: u16 = 0xC000;
-const N_DYN_PORTS: usize = (1<<16) - MIN_DYN_PORT as usize;
-struct PortPool {
-	bitmap: [u32; N_DYN_PORTS / 32],
-	//n_free_ports: u16,
-	next_port: u16,
-}
-impl PortPool
-{
-	const fn new() -> PortPool
-	{
-		PortPool {
-			bitmap: [0; N_DYN_PORTS / 32],
-			//n_free_ports: N_DYN_PORTS as u16,
-			next_port: MIN_DYN_PORT,
-			}
-	}
-
-	fn ofs_mask(idx: u16) -> Option<(usize, u32)>
-	{
-		if idx >= MIN_DYN_PORT
-		{
-			let ofs = (idx - MIN_DYN_PORT) as usize / 32;
-			let mask  = 1 << (idx % 32);
-			Some( (ofs, mask) )
-		}
-		else
-		{
-			None
-		}
-	}
-	fn take(&mut self, idx: u16) -> Result<(),()>
-	{
-		let (ofs,mask) = match Self::ofs_mask(idx)
-			{
-			Some(v) => v,
-			None => return Ok(()),
-			};
-		if self.bitmap[ofs] & mask != 0 {
-			Err( () )
-		}
-		else {
-			self.bitmap[ofs] |= mask;
-			Ok( () )
-		}
-	}
-	fn release(&mut self, idx: u16)
-	{
-		let (ofs,mask) = match Self::ofs_mask(idx)
-			{
-			Some(v) => v,
-			None => return,
-			};
-		self.bitmap[ofs] &= !mask;
-	}
-	fn allocate(&mut self) -> Option<u16>
-	{
-		// Strategy: Linear ('cos it's easy)
-		for idx in self.next_port ..= 0xFFFF
-		{
-			match self.take(idx)
-			{
-			Ok(_) => { self.next_port = idx; return Some(idx); },
-			_ => {},
-			}
-		}
-		for idx in MIN_DYN_PORT .. self.next_port
-		{
-			match self.take(idx)
-			{
-			Ok(_) => { self.next_port = idx; return Some(idx); },
-			_ => {},
-			}
-		}
-		None
+		// Close the connection, the worker removes it once the close completes
+		self.conn().lock().release(&self.0);
 	}
 }
 
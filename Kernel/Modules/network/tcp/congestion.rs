@@ -0,0 +1,129 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/tcp/congestion.rs
+//! TCP congestion control: slow start and congestion avoidance (RFC 5681) with NewReno fast recovery (RFC 6582)
+
+/// Number of duplicate ACKs that indicate a lost segment
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+pub struct NewReno
+{
+	mss: u32,
+	/// Congestion window - maximum number of unacknowledged bytes
+	cwnd: u32,
+	/// Slow start threshold
+	ssthresh: u32,
+	/// Number of duplicate ACKs seen in a row
+	dup_acks: u32,
+	/// When in fast recovery, the highest sequence number sent when loss was detected
+	recover: Option<u32>,
+}
+
+/// Action required after an ACK is processed
+#[derive(PartialEq)]
+pub enum AckAction
+{
+	None,
+	/// Retransmit the first unacknowledged segment
+	Retransmit,
+}
+
+impl NewReno
+{
+	pub fn new(mss: u32) -> Self {
+		NewReno {
+			mss,
+			cwnd: Self::initial_window(mss),
+			ssthresh: u32::MAX,
+			dup_acks: 0,
+			recover: None,
+		}
+	}
+	/// Initial window (RFC 5681 3.1)
+	fn initial_window(mss: u32) -> u32 {
+		if mss > 2190 {
+			2 * mss
+		}
+		else if mss > 1095 {
+			3 * mss
+		}
+		else {
+			4 * mss
+		}
+	}
+
+	pub fn cwnd(&self) -> u32 {
+		self.cwnd
+	}
+	pub fn in_recovery(&self) -> bool {
+		self.recover.is_some()
+	}
+
+	/// Handle an ACK that acknowledged `acked` new bytes
+	///
+	/// - `ack` is the acknowledged sequence number
+	/// - `flight_size` is the amount of data outstanding before this ACK
+	pub fn on_new_ack(&mut self, ack: u32, acked: u32, flight_size: u32) -> AckAction {
+		self.dup_acks = 0;
+		match self.recover
+		{
+		Some(recover) if (ack.wrapping_sub(recover) as i32) <= 0 => {
+			// Partial ACK: Another segment was lost, retransmit it and deflate the window (RFC 6582 3.2 step 5)
+			self.cwnd = self.cwnd.saturating_sub(acked) + if acked >= self.mss { self.mss } else { 0 };
+			AckAction::Retransmit
+			},
+		Some(_) => {
+			// Full ACK: Exit fast recovery (RFC 6582 3.2 step 3)
+			self.cwnd = u32::min(self.ssthresh, u32::max(flight_size - acked, self.mss) + self.mss);
+			self.recover = None;
+			AckAction::None
+			},
+		None => {
+			if self.cwnd < self.ssthresh {
+				// Slow start
+				self.cwnd += u32::min(acked, self.mss);
+			}
+			else {
+				// Congestion avoidance: Roughly one MSS per round-trip
+				self.cwnd += u32::max(1, self.mss * self.mss / self.cwnd);
+			}
+			AckAction::None
+			},
+		}
+	}
+
+	/// Handle a duplicate ACK (RFC 5681 3.2)
+	///
+	/// - `high_seq` is the highest sequence number sent
+	pub fn on_dup_ack(&mut self, high_seq: u32, flight_size: u32) -> AckAction {
+		self.dup_acks += 1;
+		if self.recover.is_some() {
+			// Each further duplicate indicates a segment has left the network
+			self.cwnd += self.mss;
+			AckAction::None
+		}
+		else if self.dup_acks == DUP_ACK_THRESHOLD {
+			// Fast retransmit, and enter fast recovery
+			self.ssthresh = self.loss_threshold(flight_size);
+			self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD * self.mss;
+			self.recover = Some(high_seq);
+			AckAction::Retransmit
+		}
+		else {
+			AckAction::None
+		}
+	}
+
+	/// Handle a retransmission timeout (RFC 5681 3.1 - equation 4)
+	pub fn on_timeout(&mut self, flight_size: u32) {
+		self.ssthresh = self.loss_threshold(flight_size);
+		self.cwnd = self.mss;
+		self.dup_acks = 0;
+		self.recover = None;
+	}
+
+	fn loss_threshold(&self, flight_size: u32) -> u32 {
+		u32::max(flight_size / 2, 2 * self.mss)
+	}
+}
@@ -1,20 +1,34 @@
 //! TCP connection logic
 
 use ::kernel::lib::ring_buffer::RingBuf;
+use ::kernel::time::{Timer,TickCount};
 use super::rx_buffer::RxBuffer;
+use super::rto::RttEstimator;
+use super::congestion::{NewReno,AckAction};
+use super::options::{self,Options,OptionsBuf,Negotiated};
 use super::{Quad,WORKER_CV};
 use super::ConnError;
 use super::{FLAG_SYN,FLAG_ACK,FLAG_PSH,FLAG_RST,FLAG_FIN};
 
 const DEF_TX_WINDOW_SIZE: u32 = 0x1000;
-const DEF_RX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
+/// Size of the buffer of data waiting to be sent/ACKed (limits the amount of data in flight)
+const TX_BUFFER_SIZE: usize = 0x10000;	// 64KiB
+pub(super) const DEF_RX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
+/// Initial receive window when window scaling is in use (larger than the 16-bit header field allows)
+const SCALED_RX_WINDOW_SIZE: u32 = 0x20000;	// 128KiB
 const MAX_WINDOW_SIZE: u32 = 0x100000;	// 4MiB
-/// Base timeout between attempting to send a packet and the first retransmit attempt
-const RETRANSMIT_TIMEOUT_MS: usize = 200;
-/// Maximum number of attempt to re-transmit a packet before closing the connection
-const MAX_CONN_ATTEMPTS: u32 = 5;
-/// Maximum segment size (i.e. the largest amount of data in a single IP frame)
-const MSS: usize = 1400;
+/// Maximum number of times the SYN is sent before the connection attempt fails
+const MAX_SYN_ATTEMPTS: u32 = 5;
+/// Maximum number of retransmission timeouts (without an ACK) before the connection is closed
+const MAX_RETRANSMIT_ATTEMPTS: u32 = 10;
+/// Maximum time an ACK can be delayed for (RFC 1122 4.2.3.2 requires less than 500ms)
+const DELAYED_ACK_MS: u64 = 200;
+/// Time to wait for an ACK before sending a partial segment (Nagle's algorithm)
+const NAGLE_TIMEOUT_MS: u64 = 100;
+/// Maximum segment lifetime, TIME-WAIT lasts for twice this
+///
+/// RFC 9293 3.4.2 suggests two minutes, this is the shorter value used by most current stacks
+const MSL_MS: u64 = 30_000;
 
 pub struct Connection
 {
@@ -36,10 +50,35 @@ pub struct Connection
 
 	tx_state: ConnectionTxState,
 	tx_waiters: ::kernel::threads::SleepObjectSet,
+
+	/// Last "soft" ICMP error received (reported instead of a plain timeout)
+	soft_error: Option<ConnError>,
+
+	/// MSS advertised in our SYN (or SYN-ACK)
+	local_mss: u16,
+	/// Parameters negotiated by options in the SYN and SYN-ACK
+	negotiated: Negotiated,
+	/// Start of the most recently received out-of-order segment (reported first in SACK blocks)
+	rx_last_ooo: Option<SeqNum>,
+
+	/// Sequence number of the peer's FIN (known once received, even if data before it is still missing)
+	rx_fin: Option<SeqNum>,
+	/// User has shut down receiving, received data is discarded
+	rx_shutdown: bool,
+	/// Timer for leaving TIME-WAIT (or an abandoned FIN-WAIT-2)
+	close_timer: Timer,
+	/// The user's handle has been dropped, so the connection can be removed once finished
+	handle_released: bool,
 }
 
 #[derive(Copy,Clone,Debug,PartialEq)]
 struct SeqNum(u32);
+impl SeqNum {
+	/// Check if this sequence number is after `other` (handling wrapping)
+	fn is_after(self, other: SeqNum) -> bool {
+		(self.0.wrapping_sub(other.0) as i32) > 0
+	}
+}
 impl ::core::fmt::Display for SeqNum {
 	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		write!(f, "{:#x}", self.0)
@@ -48,13 +87,13 @@ impl ::core::fmt::Display for SeqNum {
 impl ::core::ops::Add for SeqNum {
 	type Output = Self;
 	fn add(self, rhs: Self) -> Self::Output {
-		SeqNum(self.0 + rhs.0)
+		SeqNum(self.0.wrapping_add(rhs.0))
 	}
 }
 impl ::core::ops::Sub for SeqNum {
 	type Output = Self;
 	fn sub(self, rhs: Self) -> Self::Output {
-		SeqNum(self.0 - rhs.0)
+		SeqNum(self.0.wrapping_sub(rhs.0))
 	}
 }
 
@@ -63,6 +102,8 @@ struct ConnectionTxState {
 	buffer: RingBuf<u8>,
 	/// Sequence number of the next byte to be sent
 	next_tx_seq: SeqNum,
+	/// Maximum segment size (i.e. the largest amount of data in a single IP frame)
+	mss: usize,
 	
 	/// Number of bytes that have been sent, but not ACKed
 	/// 
@@ -71,39 +112,73 @@ struct ConnectionTxState {
 
 	/// Last received TX window size
 	max_tx_window_size: u32,
-	/// Current TX window size (can be reduced if packet loss is seen)
-	cur_tx_window_size: u32,
-
-	// TODO: Pending flags?
+	/// Congestion window (limits the amount of unacknowledged data)
+	congestion: NewReno,
+	/// State of the FIN (sent once all buffered data has been sent)
+	fin: TxFin,
 
 	// -- Timers and state for transmit
 	/// Number of re-transmit attempts since the last successful receive
 	retransmit_attempts: u32,
 	/// Timer used to ensure that we get ACKs in a suitable time.
-	retransmit_timer: ::kernel::time::Timer,
+	retransmit_timer: Timer,
+	/// Round-trip time estimate, used to pick the retransmit timeout
+	rtt: RttEstimator,
+	/// Segment being timed for the RTT estimate (the sequence number that ACKs it, and when it was sent)
+	/// 
+	/// Only one segment is timed at a time, and never a retransmitted one (Karn's algorithm)
+	rtt_sample: Option<(SeqNum, TickCount)>,
 	/// Timer to collate multiple sends
-	nagle_timer: ::kernel::time::Timer,
+	nagle_timer: Timer,
 	/// Flag that forces a TX on the next opportunity (e.g. the buffer has a packet worth of data, or a flush was requested)
 	force_tx: bool,
-	/// Send an ACK in the next opportunity
+	/// Received data hasn't been ACKed yet
 	pending_ack: bool,
+	/// Send an ACK in the next opportunity (instead of waiting for the delayed ACK timer)
+	ack_now: bool,
+	/// Timer for delayed ACKs
+	ack_timer: Timer,
+	/// Number of received segments since the last ACK
+	unacked_segments: u32,
 }
 impl ConnectionTxState {
-	fn new(tx_seq: u32, init_window_size: u32) -> Self {
+	fn new(tx_seq: u32, init_window_size: u32, mss: u16) -> Self {
 		ConnectionTxState {
-			buffer: RingBuf::new(DEF_TX_WINDOW_SIZE as usize),
+			buffer: RingBuf::new(TX_BUFFER_SIZE),
 			next_tx_seq: SeqNum(tx_seq),
+			mss: mss as usize,
 
 			sent_bytes: 0,
 			max_tx_window_size: init_window_size,
-			cur_tx_window_size: init_window_size,
+			congestion: NewReno::new(mss as u32),
+			fin: TxFin::None,
 			retransmit_attempts: 0,
-			retransmit_timer: ::kernel::time::Timer::new(),
-			nagle_timer: ::kernel::time::Timer::new(),
+			retransmit_timer: Timer::new(),
+			rtt: RttEstimator::new(),
+			rtt_sample: None,
+			nagle_timer: Timer::new(),
 			force_tx: false,
 			pending_ack: false,
+			ack_now: false,
+			ack_timer: Timer::new(),
+			unacked_segments: 0,
 		}
 	}
+	/// Number of sequence numbers sent but not yet ACKed (data, plus a FIN)
+	fn outstanding(&self) -> usize {
+		self.sent_bytes + if self.fin == TxFin::Sent { 1 } else { 0 }
+	}
+}
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum TxFin
+{
+	/// Connection hasn't been closed locally
+	None,
+	/// Close requested, FIN will be sent after the buffered data
+	Queued,
+	/// FIN sent (it's the last sequence number sent), waiting for an ACK
+	Sent,
+	Acked,
 }
 #[derive(Copy,Clone,Debug,PartialEq)]
 enum ConnectionState
@@ -124,6 +199,8 @@ enum ConnectionState
 	TimeWait,	// Waiting for timeout after local close
 
 	ForceClose,	// RST received, waiting for user close
+	/// (NON-RFC) An ICMP error aborted the connection, waiting for user close
+	Aborted(ConnError),
 	CloseWait,	// FIN received, waiting for user to close (error set, wait for node close)
 	LastAck,	// FIN sent and received, waiting for ACK
 
@@ -132,28 +209,46 @@ enum ConnectionState
 impl Connection
 {
 	/// Create a new connection from the ACK in a SYN-SYN,ACK-ACK
-	pub(super) fn new_inbound(hdr: &super::PktHeader) -> Self
+	pub(super) fn new_inbound(hdr: &super::PktHeader, opts: &Options, mut negotiated: Negotiated) -> Self
 	{
+		if let (Some(_), Some((val, _))) = (negotiated.ts_recent, opts.timestamp) {
+			negotiated.ts_recent = Some(val);
+		}
+		let rx_window_size = Self::initial_rx_window(&negotiated);
 		Connection {
 			state: ConnectionState::Established,
 			conn_waiters: Default::default(),
 			next_rx_seq: SeqNum(hdr.sequence_number),
 			last_rx_ack: SeqNum(hdr.sequence_number),
 			rx_buffer_seq: SeqNum(hdr.sequence_number),
-			rx_buffer: RxBuffer::new(2*DEF_RX_WINDOW_SIZE as usize),
+			rx_buffer: RxBuffer::new(2*rx_window_size as usize),
 			rx_waiters: Default::default(),
 
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
-			rx_window_size: DEF_RX_WINDOW_SIZE,
+			rx_window_size,
 
-			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, hdr.window_size as u32),
+			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, (hdr.window_size as u32) << negotiated.tx_window_shift, negotiated.mss),
 			tx_waiters: Default::default(),
+
+			soft_error: None,
+
+			local_mss: negotiated.mss,	// Not used after the SYN-ACK
+			negotiated,
+			rx_last_ooo: None,
+
+			rx_fin: None,
+			rx_shutdown: false,
+			close_timer: Timer::new(),
+			handle_released: false,
 			}
 	}
 
 	pub(super) fn new_outbound(quad: &Quad, sequence_number: u32) -> Self
 	{
 		log_trace!("Connection::new_outbound({:?}, {:#x})", quad, sequence_number);
+		let local_mss = super::get_local_mss(quad);
+		// Until the SYN-ACK is received, no options are in use
+		let negotiated = Negotiated::from_syn(local_mss, &Options::default());
 		let mut rv = Connection {
 			state: ConnectionState::SynSent,
 			conn_waiters: Default::default(),
@@ -166,18 +261,63 @@ impl Connection
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
 			rx_window_size: DEF_RX_WINDOW_SIZE,
 
-			tx_state: ConnectionTxState::new(sequence_number, DEF_TX_WINDOW_SIZE),
+			tx_state: ConnectionTxState::new(sequence_number, DEF_TX_WINDOW_SIZE, negotiated.mss),
 			tx_waiters: Default::default(),
+
+			soft_error: None,
+
+			local_mss,
+			negotiated,
+			rx_last_ooo: None,
+
+			rx_fin: None,
+			rx_shutdown: false,
+			close_timer: Timer::new(),
+			handle_released: false,
 			};
-		rv.send_empty_packet(quad, FLAG_SYN);
-		// TODO: This should be a little more formalised. A SYN should count as data, and be resent the same way
+		// The SYN takes up a sequence number
 		rv.tx_state.next_tx_seq = rv.tx_state.next_tx_seq + SeqNum(1);
-		rv.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
+		rv.send_syn(quad);
 		rv
 	}
+	/// Send (or re-send) the initial SYN
+	fn send_syn(&mut self, quad: &Quad)
+	{
+		let seq = self.tx_state.next_tx_seq - SeqNum(1);
+		log_debug!("{:?} send_syn({})", quad, seq);
+		// Only time the first attempt (a retransmitted SYN gives an ambiguous RTT)
+		self.tx_state.rtt_sample = if self.tx_state.retransmit_attempts == 0 {
+			Some( (self.tx_state.next_tx_seq, ::kernel::time::ticks()) )
+		} else {
+			None
+		};
+		let options = OptionsBuf::syn(self.local_mss, None, options::timestamp_now());
+		::kernel::futures::block_on(quad.send_packet(seq.0, 0, FLAG_SYN, self.advertised_window(), options.as_bytes(), &[], &[]));
+		self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto());
+	}
+	/// Initial receive window, larger if the window can be scaled
+	fn initial_rx_window(negotiated: &Negotiated) -> u32
+	{
+		if negotiated.rx_window_shift > 0 { SCALED_RX_WINDOW_SIZE } else { DEF_RX_WINDOW_SIZE }
+	}
+	/// Peer's window from a received header (the window in a SYN is never scaled)
+	fn peer_window(&self, hdr: &super::PktHeader) -> u32
+	{
+		if hdr.flags & FLAG_SYN != 0 {
+			hdr.window_size as u32
+		}
+		else {
+			(hdr.window_size as u32) << self.negotiated.tx_window_shift
+		}
+	}
+	/// Window to send in the header
+	fn advertised_window(&self) -> u16
+	{
+		(self.rx_window_size >> self.negotiated.rx_window_shift).min(0xFFFF) as u16
+	}
 
 	/// Handle an inbound packet
-	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, mut pkt: crate::nic::PacketReader)
+	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, opts: &Options, pkt: crate::nic::PacketReader)
 	{
 		match self.state
 		{
@@ -186,6 +326,35 @@ impl Connection
 		_ => {},
 		}
 
+		// Timestamps, and protection against wrapped sequence numbers (RFC 7323 5.3)
+		if let Some(ts_recent) = self.negotiated.ts_recent
+		{
+			match opts.timestamp
+			{
+			None if hdr.flags & FLAG_RST == 0 => {
+				log_debug!("{:?} Dropping segment without a timestamp", quad);
+				return ;
+				},
+			None => {},
+			Some((val, _)) if hdr.flags & FLAG_RST == 0 && (val.wrapping_sub(ts_recent) as i32) < 0 => {
+				log_debug!("{:?} PAWS: Dropping segment with an old timestamp ({} < {})", quad, val, ts_recent);
+				self.send_ack(quad, "PAWS");
+				return ;
+				},
+			Some((val, _)) => {
+				// Only segments covering the last ACK update the timestamp, so delayed ACKs don't inflate the RTT
+				if !SeqNum(hdr.sequence_number).is_after(self.last_rx_ack) {
+					self.negotiated.ts_recent = Some(val);
+				}
+				},
+			}
+		}
+
+		if hdr.flags & FLAG_RST != 0 {
+			self.handle_rst(quad, hdr);
+			return ;
+		}
+
 		// Synchronisation request
 		if hdr.flags & FLAG_SYN != 0 {
 			// TODO: SYN counts as a seqence increment?
@@ -197,53 +366,19 @@ impl Connection
 		}
 		// ACK of sent data
 		if hdr.flags & FLAG_ACK != 0 {
-			// TODO: There are some pseudo-bytes (a SYN flag counts as a transmitted byte)
-			log_trace!("{:?} ACK {} vs {}", quad, SeqNum(hdr.acknowledgement_number), self.tx_state.next_tx_seq);
-			// Determine how many bytes are NOT acked by this packet
-			// - Which can be used to determine how many are ACKed
-			let in_flight = (self.tx_state.next_tx_seq - SeqNum(hdr.acknowledgement_number)).0 as usize;
-			if in_flight > self.tx_state.sent_bytes {
-				// TODO: Error, something funky has happened
-				log_error!("Oops? in_flight={} > sent_bytes={}", in_flight, self.tx_state.sent_bytes);
-			}
-			else {
-				assert!(self.tx_state.sent_bytes <= self.tx_state.buffer.len());
-				let n_bytes = self.tx_state.sent_bytes - in_flight;
-				log_debug!("{:?} ACK {} bytes", quad, n_bytes);
-				for _ in 0 .. n_bytes {
-					self.tx_state.buffer.pop_front();
-					self.tx_state.sent_bytes -= 1;
-				}
-				// If any bytes were acked, then clear retransmit attempt count
-				if n_bytes > 0 {
-					self.tx_state.retransmit_attempts = 0;
-				}
-				// If there are no un-acked bytes, and there's pending bytes. Trigger a re-send
-				if self.tx_state.sent_bytes == 0 && self.tx_state.buffer.len() > 0 {
-					self.tx_state.force_tx = true;
-					WORKER_CV.wake_one();
-				}
-				else {
-					// Since we've seen an ACK, reset the retransmit time
-					if self.tx_state.sent_bytes == 0 {
-						self.tx_state.retransmit_timer.clear();
-					}
-					else {
-						// TODO: Maintain a retransmit timer and double it each time we need to retransmit
-						// TODO: Track estimated latency and set this based on the RTT
-						self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
-					}
-				}
-			}
+			self.handle_ack(quad, hdr, opts, pkt.remain());
 		}
 
 		// Update the window size if it changes
-		if self.tx_state.max_tx_window_size != hdr.window_size as u32 {
-			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, hdr.window_size);
-			self.tx_state.max_tx_window_size = hdr.window_size as u32;
+		let window = self.peer_window(hdr);
+		if self.tx_state.max_tx_window_size != window {
+			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, window);
+			self.tx_state.max_tx_window_size = window;
+			// A larger window may allow more to be sent
+			WORKER_CV.wake_one();
 		}
 		// If there were acked bytes, then signal that TX is now possible again
-		if self.tx_state.buffer.len() < self.rx_window_size as usize {
+		if self.tx_state.buffer.space() > 0 {
 			self.tx_waiters.signal();
 		}
 		
@@ -258,11 +393,24 @@ impl Connection
 				self.rx_buffer_seq = self.next_rx_seq;
 				if hdr.flags & FLAG_ACK != 0 {
 					// Now established
+					// - Apply the options the peer accepted
+					self.negotiated = Negotiated::from_syn(self.local_mss, opts);
+					log_debug!("{:?} Negotiated {:?}", quad, self.negotiated);
+					self.tx_state.mss = self.negotiated.mss as usize;
+					self.tx_state.congestion = NewReno::new(self.negotiated.mss as u32);
+					self.rx_window_size = Self::initial_rx_window(&self.negotiated);
+					self.rx_buffer.resize(2*self.rx_window_size as usize);
 					// - Send ACK back
 					self.send_ack(quad, "SYN-ACK");
 					self.tx_waiters.signal();
 					self.conn_waiters.signal();
 					self.tx_state.retransmit_timer.clear();
+					match self.tx_state.rtt_sample.take()
+					{
+					Some((_, sent)) => self.tx_state.rtt.add_sample( (::kernel::time::ticks() - sent) as u32 ),
+					None => self.tx_state.rtt.syn_retransmitted(),
+					}
+					self.tx_state.retransmit_attempts = 0;
 					ConnectionState::Established
 				}
 				else {
@@ -276,188 +424,421 @@ impl Connection
 			}
 			},
 
-		ConnectionState::Established =>
-			if hdr.flags & FLAG_RST != 0 {
-				// RST received, do an unclean close (reset by peer)
-				// TODO: Signal to user that the connection is closing (error)
-				ConnectionState::ForceClose
-			}
-			else if hdr.flags & FLAG_FIN != 0 {
-				// FIN received, start a clean shutdown
-				self.next_rx_seq = self.next_rx_seq + SeqNum(1);
-				// TODO: Signal to user that the connection is closing (EOF)
-				ConnectionState::CloseWait
+		// Synchronised states that can receive data
+		ConnectionState::Established
+		| ConnectionState::FinWait1
+		| ConnectionState::FinWait2 => {
+			let data_len = pkt.remain();
+			self.receive_data(quad, hdr, pkt);
+			let fin_reached = self.check_fin(quad, hdr, data_len);
+			let fin_acked = self.tx_state.fin == TxFin::Acked;
+			match (self.state, fin_reached, fin_acked)
+			{
+			(ConnectionState::Established, true, _) => ConnectionState::CloseWait,
+			(ConnectionState::FinWait1, true, false) => ConnectionState::Closing,
+			(ConnectionState::FinWait1, true, true) => ConnectionState::TimeWait,
+			(ConnectionState::FinWait1, false, true) => ConnectionState::FinWait2,
+			(ConnectionState::FinWait2, true, _) => ConnectionState::TimeWait,
+			(s, _, _) => s,
 			}
-			else {
-				if pkt.remain() == 0 {
-					// Pure ACK, no change
-					if hdr.flags == FLAG_ACK {
-						log_trace!("{:?} ACK only", quad);
-					}
-					else if self.next_rx_seq != SeqNum(hdr.sequence_number) {
-						log_trace!("{:?} Empty packet, unexpected sequence number {} != {}", quad, SeqNum(hdr.sequence_number), self.next_rx_seq);
-					}
-					else {
-						// Counts as one byte
-						self.next_rx_seq = self.next_rx_seq + SeqNum(1);
-						self.send_ack(quad, "Empty");
-					}
-				}
-				else if (SeqNum(hdr.sequence_number) - self.next_rx_seq + SeqNum(pkt.remain() as u32)).0 > MAX_WINDOW_SIZE {
-					// Completely out of sequence
-				}
-				else {
-					// In sequence.
-					let mut start_ofs = (SeqNum(hdr.sequence_number) - self.next_rx_seq).0 as i32;
-					while start_ofs < 0 {
-						pkt.read_u8().unwrap();
-						start_ofs += 1;
-					}
-					let mut ofs = start_ofs as usize;
-					log_debug!("{:?} RX: buf_ofs={}+{}", quad, self.next_rx_seq - self.rx_buffer_seq, ofs);
-					while let Ok(b) = pkt.read_u8() {
-						//let ofs_0 = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq);
-						//let ofs_0 = if ofs_0 > MAX_CONN_ATTEMPTS
-						match self.rx_buffer.insert( (self.next_rx_seq - self.rx_buffer_seq).0 as usize + ofs, &[b])
-						{
-						Ok(_) => {},
-						Err(e) => {
-							log_error!("{:?} RX buffer push {:?}", quad, e);
-							break;
-							},
-						}
-						ofs += 1;
-					}
-					log_debug!("{:?} RX: start_ofs={}, ofs={}", quad, start_ofs, ofs);
-					// Better idea: Have an ACQ point, and a window point. Buffer is double the window
-					// Once the window point reaches 25% of the window from the ACK point
-					if start_ofs == 0 {
-						self.next_rx_seq = self.next_rx_seq + SeqNum(ofs as u32);
-
-						// Calculate a maximum window size based on how much space is left in the buffer
-						let buffered_len = (self.next_rx_seq - self.rx_buffer_seq).0;	// How much data the user has buffered
-						let cur_max_window = 2*self.rx_window_size_max - buffered_len;	// NOTE: 2* for some flex so the window can stay at max size
-						if cur_max_window < self.rx_window_size {
-							// Reduce the window size and send an ACQ (with the updated size)
-							while cur_max_window < self.rx_window_size {
-								self.rx_window_size /= 2;
-							}
-							self.send_ack(quad, "Constrain window");
-						}
-						else if (self.next_rx_seq - self.last_rx_ack).0 > self.rx_window_size/2 {
-							// Send an ACK now, we've received a burst of data
-							self.send_ack(quad, "Data burst");
-						}
-						else {
-							// TODO: Schedule an ACK in a few hundred milliseconds
-							// - Just set a flag so the next outbound packet ACKs
-							self.tx_state.pending_ack = true;
-						}
-					}
-
-					if self.rx_buffer.valid_len() > 0 {
-						self.rx_waiters.signal();
-					}
-					if hdr.flags & FLAG_PSH != 0 {
-						// TODO: Prod the user that there's new data?
-					}
-				}
-
-				self.state
 			},
-
-		ConnectionState::CloseWait => {
-			// Ignore all packets while waiting for the user to complete teardown
-			self.state
+		// The peer's FIN has been received, so any data or FIN is a retransmission (e.g. our ACK of the FIN was lost)
+		ConnectionState::CloseWait
+		| ConnectionState::Closing
+		| ConnectionState::LastAck
+		| ConnectionState::TimeWait => {
+			if pkt.remain() > 0 || hdr.flags & FLAG_FIN != 0 {
+				self.send_ack(quad, "Retransmission after FIN");
+				if self.state == ConnectionState::TimeWait {
+					// Restart the 2MSL timeout (RFC 9293 3.10.7.4)
+					self.close_timer.reset(2*MSL_MS);
+				}
+			}
+			match self.state
+			{
+			ConnectionState::Closing if self.tx_state.fin == TxFin::Acked => ConnectionState::TimeWait,
+			ConnectionState::LastAck if self.tx_state.fin == TxFin::Acked => ConnectionState::Finished,
+			s => s,
+			}
 			},
 		ConnectionState::Timeout => {
 			log_trace!("{:?} Packet received after timeout declared", quad);
 			self.state
 			},
-		ConnectionState::LastAck =>	// Waiting for ACK in FIN,FIN/ACK,ACK
-			if hdr.flags & FLAG_ACK != 0 {
-				ConnectionState::Finished
+		ConnectionState::ForceClose => self.state,
+		ConnectionState::Aborted(_) => self.state,
+
+		ConnectionState::Finished => return,
+		};
+
+		self.state_update(quad, new_state);
+	}
+
+	/// Receive data from a segment into the RX buffer, and schedule an ACK
+	fn receive_data(&mut self, quad: &Quad, hdr: &super::PktHeader, mut pkt: crate::nic::PacketReader)
+	{
+		if pkt.remain() == 0 {
+			// No data (e.g. a pure ACK, or a FIN)
+			log_trace!("{:?} No data", quad);
+		}
+		else if (SeqNum(hdr.sequence_number) - self.next_rx_seq + SeqNum(pkt.remain() as u32)).0 > MAX_WINDOW_SIZE {
+			// Completely out of sequence, tell the peer what we're expecting
+			self.send_ack(quad, "Out of sequence");
+		}
+		else {
+			// In sequence.
+			let mut start_ofs = (SeqNum(hdr.sequence_number) - self.next_rx_seq).0 as i32;
+			while start_ofs < 0 {
+				pkt.read_u8().unwrap();
+				start_ofs += 1;
+			}
+			let mut ofs = start_ofs as usize;
+			log_debug!("{:?} RX: buf_ofs={}+{}", quad, self.next_rx_seq - self.rx_buffer_seq, ofs);
+			while let Ok(b) = pkt.read_u8() {
+				//let ofs_0 = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq);
+				//let ofs_0 = if ofs_0 > MAX_CONN_ATTEMPTS
+				match self.rx_buffer.insert( (self.next_rx_seq - self.rx_buffer_seq).0 as usize + ofs, &[b])
+				{
+				Ok(_) => {},
+				Err(e) => {
+					log_error!("{:?} RX buffer push {:?}", quad, e);
+					break;
+					},
+				}
+				ofs += 1;
+			}
+			log_debug!("{:?} RX: start_ofs={}, ofs={}", quad, start_ofs, ofs);
+			// Better idea: Have an ACQ point, and a window point. Buffer is double the window
+			// Once the window point reaches 25% of the window from the ACK point
+			if start_ofs == 0 && ofs == 0 {
+				// Only already-received data, the peer has likely missed an ACK
+				self.send_ack(quad, "Duplicate data");
+			}
+			else if start_ofs == 0 {
+				self.next_rx_seq = self.next_rx_seq + SeqNum(ofs as u32);
+				// If this filled a gap, then the out-of-order data after it is now in sequence too
+				let valid_end = self.rx_buffer_seq + SeqNum(self.rx_buffer.valid_len() as u32);
+				let filled_gap = valid_end.is_after(self.next_rx_seq);
+				if filled_gap {
+					self.next_rx_seq = valid_end;
+				}
+				if self.rx_last_ooo.map_or(false, |s| self.next_rx_seq.is_after(s)) {
+					self.rx_last_ooo = None;
+				}
+
+				// Calculate a maximum window size based on how much space is left in the buffer
+				let buffered_len = (self.next_rx_seq - self.rx_buffer_seq).0;	// How much data the user has buffered
+				let cur_max_window = 2*self.rx_window_size_max - buffered_len;	// NOTE: 2* for some flex so the window can stay at max size
+				if cur_max_window < self.rx_window_size {
+					// Reduce the window size and send an ACQ (with the updated size)
+					while cur_max_window < self.rx_window_size {
+						self.rx_window_size /= 2;
+					}
+					self.send_ack(quad, "Constrain window");
+				}
+				else if (self.next_rx_seq - self.last_rx_ack).0 > self.rx_window_size/2 {
+					// Send an ACK now, we've received a burst of data
+					self.send_ack(quad, "Data burst");
+				}
+				else if filled_gap {
+					// Let the peer know the gap is filled as soon as possible (RFC 5681 4.2)
+					self.send_ack(quad, "Filled gap");
+				}
+				else {
+					self.delayed_ack(quad);
+				}
 			}
 			else {
-				self.state
-			},
+				// Out of order, send a duplicate ACK so the peer knows there's a gap (RFC 5681 4.2)
+				self.rx_last_ooo = Some(SeqNum(hdr.sequence_number));
+				self.send_ack(quad, "Out of order");
+			}
 
-		ConnectionState::FinWait1 =>	// FIN sent, waiting for reply (ACK or FIN)
-			if hdr.flags & FLAG_FIN != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				self.send_ack(quad, "FIN-ACK");
-				ConnectionState::Closing
+			if self.rx_buffer.valid_len() > 0 {
+				self.rx_waiters.signal();
 			}
-			else if hdr.flags & FLAG_ACK != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				ConnectionState::FinWait2
+			if hdr.flags & FLAG_PSH != 0 {
+				// TODO: Prod the user that there's new data?
 			}
-			else {
-				self.state
-			},
-		ConnectionState::FinWait2 =>
-			if hdr.flags & FLAG_FIN != 0 {	// Got a FIN after the ACK, close
-				ConnectionState::TimeWait
+		}
+		if self.rx_shutdown {
+			self.discard_rx();
+		}
+	}
+	/// Track the peer's FIN, returning `true` when it is reached (all data before it has been received)
+	fn check_fin(&mut self, quad: &Quad, hdr: &super::PktHeader, data_len: usize) -> bool
+	{
+		if hdr.flags & FLAG_FIN != 0 && self.rx_fin.is_none()
+		{
+			// The FIN follows the data in the segment, and must be within the window to be valid
+			let fin_seq = SeqNum(hdr.sequence_number) + SeqNum(data_len as u32);
+			if (fin_seq - self.next_rx_seq).0 > self.rx_window_size {
+				log_debug!("{:?} Ignoring FIN {} outside window (next {})", quad, fin_seq, self.next_rx_seq);
 			}
 			else {
-				self.state
-			},
+				log_debug!("{:?} FIN at {}", quad, fin_seq);
+				self.rx_fin = Some(fin_seq);
+			}
+		}
+		if self.rx_fin == Some(self.next_rx_seq)
+		{
+			// The FIN takes up a sequence number, and is ACKed immediately
+			self.next_rx_seq = self.next_rx_seq + SeqNum(1);
+			self.send_ack(quad, "FIN");
+			// Wake readers, so they see the end of the stream
+			self.rx_waiters.signal();
+			true
+		}
+		else {
+			false
+		}
+	}
+	/// Drop all received data (after the user shuts down receiving)
+	fn discard_rx(&mut self)
+	{
+		let mut buf = [0; 64];
+		loop
+		{
+			let len = self.rx_buffer.take(&mut buf);
+			if len == 0 {
+				break;
+			}
+			self.rx_buffer_seq = self.rx_buffer_seq + SeqNum(len as u32);
+		}
+	}
+
+	/// Handle a received RST (RFC 9293 3.10.7, with the exact sequence number check from RFC 5961 3.2)
+	fn handle_rst(&mut self, quad: &Quad, hdr: &super::PktHeader)
+	{
+		let new_state = match self.state
+			{
+			ConnectionState::SynSent => {
+				// Only valid if it ACKs our SYN, in which case the port is closed
+				if hdr.flags & FLAG_ACK == 0 || SeqNum(hdr.acknowledgement_number) != self.tx_state.next_tx_seq {
+					log_debug!("{:?} Ignoring RST with unacceptable ACK {}", quad, SeqNum(hdr.acknowledgement_number));
+					return ;
+				}
+				ConnectionState::Aborted(ConnError::RemoteRefused)
+				},
+			// TIME-WAIT ignores RSTs, to avoid an early close ("TIME-WAIT assassination", RFC 1337)
+			ConnectionState::TimeWait => return,
+			ConnectionState::Timeout
+			| ConnectionState::ForceClose
+			| ConnectionState::Aborted(_)
+			| ConnectionState::Finished => return,
+			_ => {
+				let seq = SeqNum(hdr.sequence_number);
+				if seq != self.next_rx_seq {
+					// In the window, but not exact: Could be forged, so send a "challenge ACK" (a real peer will reply with an exact RST)
+					if (seq - self.next_rx_seq).0 < self.rx_window_size {
+						self.send_ack(quad, "Challenge ACK");
+					}
+					else {
+						log_debug!("{:?} Ignoring RST {} outside window (next {})", quad, seq, self.next_rx_seq);
+					}
+					return ;
+				}
+				match self.state
+				{
+				// The connection was closing anyway, nothing is lost
+				ConnectionState::Closing
+				| ConnectionState::LastAck => ConnectionState::Finished,
+				_ => ConnectionState::ForceClose,
+				}
+				},
+			};
+		log_notice!("{:?} Connection reset by peer ({:?})", quad, self.state);
+		self.tx_state.retransmit_timer.clear();
+		self.tx_state.nagle_timer.clear();
+		self.tx_state.ack_timer.clear();
+		self.tx_state.pending_ack = false;
+		self.state_update(quad, new_state);
+		self.tx_waiters.signal();
+		self.rx_waiters.signal();
+		self.conn_waiters.signal();
+	}
 
-		ConnectionState::Closing =>
-			if hdr.flags & FLAG_ACK != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				ConnectionState::TimeWait
+	/// Handle the acknowledgement number of an incoming packet
+	fn handle_ack(&mut self, quad: &Quad, hdr: &super::PktHeader, opts: &Options, data_len: usize)
+	{
+		let ack = SeqNum(hdr.acknowledgement_number);
+		log_trace!("{:?} ACK {} vs {}", quad, ack, self.tx_state.next_tx_seq);
+		let outstanding = self.tx_state.outstanding();
+		// Determine how many sequence numbers are NOT acked by this packet
+		// - Which can be used to determine how many are ACKed
+		let in_flight = (self.tx_state.next_tx_seq - ack).0 as usize;
+		if in_flight > outstanding {
+			// Either an old ACK, or for something that hasn't been sent
+			log_debug!("{:?} Ignoring ACK {}: in_flight={} > outstanding={}", quad, ack, in_flight, outstanding);
+			return ;
+		}
+		let n_acked = outstanding - in_flight;
+
+		if n_acked == 0 {
+			// Duplicate ACK (RFC 5681 section 2): No data or window change, while data is outstanding
+			if outstanding > 0 && data_len == 0 && hdr.flags & (FLAG_SYN|FLAG_FIN) == 0 && self.peer_window(hdr) == self.tx_state.max_tx_window_size {
+				log_debug!("{:?} Duplicate ACK {}", quad, ack);
+				let high_seq = self.tx_state.next_tx_seq - SeqNum(1);
+				if self.tx_state.congestion.on_dup_ack(high_seq.0, outstanding as u32) == AckAction::Retransmit {
+					log_debug!("{:?} Fast retransmit", quad);
+					self.retransmit_first(quad);
+				}
+				// Window inflation during recovery can allow new data to be sent
+				if self.tx_state.congestion.in_recovery() {
+					WORKER_CV.wake_one();
+				}
 			}
-			else {
-				self.state
-			},
+			return ;
+		}
 
-		ConnectionState::ForceClose => self.state,
-		ConnectionState::TimeWait => self.state,
+		// Remove acknowledged data from the buffer
+		if self.tx_state.fin == TxFin::Sent && in_flight == 0 {
+			log_debug!("{:?} FIN ACKed", quad);
+			self.tx_state.fin = TxFin::Acked;
+		}
+		let n_bytes = n_acked.min(self.tx_state.sent_bytes);
+		log_debug!("{:?} ACK {} bytes", quad, n_bytes);
+		for _ in 0 .. n_bytes {
+			self.tx_state.buffer.pop_front();
+		}
+		self.tx_state.sent_bytes -= n_bytes;
+		self.tx_state.retransmit_attempts = 0;
 
-		ConnectionState::Finished => return,
-		};
+		// Update the RTT estimate
+		// - With timestamps, any ACK can be timed using the echoed value (RFC 7323 4), otherwise only the single timed segment
+		match (self.negotiated.ts_recent, opts.timestamp)
+		{
+		(Some(_), Some((_, ecr))) if ecr != 0 => {
+			self.tx_state.rtt.add_sample( options::timestamp_now().wrapping_sub(ecr) );
+			self.tx_state.rtt_sample = None;
+			},
+		_ => if let Some((seq, sent)) = self.tx_state.rtt_sample {
+			if !seq.is_after(ack) {
+				self.tx_state.rtt.add_sample( (::kernel::time::ticks() - sent) as u32 );
+				self.tx_state.rtt_sample = None;
+			}
+			},
+		}
 
-		self.state_update(quad, new_state);
+		let action = self.tx_state.congestion.on_new_ack(ack.0, n_acked as u32, outstanding as u32);
+		if in_flight == 0 {
+			self.tx_state.retransmit_timer.clear();
+		}
+		else if action == AckAction::Retransmit {
+			// Partial ACK during fast recovery, the next segment was also lost
+			log_debug!("{:?} Partial ACK, retransmitting", quad);
+			self.retransmit_first(quad);
+		}
+		else {
+			// Restart the timer for the remaining data (RFC 6298 5.3)
+			self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto());
+		}
+		// There may be space in the window to send more
+		WORKER_CV.wake_one();
 	}
 
 	fn state_update(&mut self, quad: &Quad, new_state: ConnectionState)
 	{
+		// Without a user handle, there's nobody to report an error to
+		let new_state = match new_state
+			{
+			ConnectionState::Timeout
+			| ConnectionState::ForceClose
+			| ConnectionState::Aborted(_) if self.handle_released => ConnectionState::Finished,
+			s => s,
+			};
 		if self.state != new_state
 		{
 			log_trace!("{:?} {:?} -> {:?}", quad, self.state, new_state);
 			self.state = new_state;
 
-			// TODO: If transitioning to `Finished`, release the local port?
-			// - Only for client connections.
-			if let ConnectionState::Finished = self.state
+			match self.state
 			{
-				super::release_port(&quad.local_addr, quad.local_port);
+			ConnectionState::TimeWait => {
+				// Wait for any delayed segments to expire before the quad can be reused (RFC 9293 3.4.2)
+				self.tx_state.retransmit_timer.clear();
+				self.close_timer.reset(2*MSL_MS);
+				WORKER_CV.wake_one();
+				},
+			ConnectionState::FinWait2 if self.handle_released => {
+				// The peer might never send its FIN, and there's no user to close the connection
+				self.close_timer.reset(2*MSL_MS);
+				WORKER_CV.wake_one();
+				},
+			ConnectionState::Finished => {
+				// The connection (and its local port) is released by the worker once the user handle is dropped
+				self.tx_state.retransmit_timer.clear();
+				self.tx_state.nagle_timer.clear();
+				self.close_timer.clear();
+				self.rx_waiters.signal();
+				if self.handle_released {
+					WORKER_CV.wake_one();
+				}
+				},
+			_ => {},
 			}
 		}
 	}
+	/// Check if the connection can be removed (it has finished, and the user has dropped the handle)
+	pub(super) fn can_remove(&self) -> bool {
+		self.handle_released && self.state == ConnectionState::Finished
+	}
 
+	/// Get the error (if any) for sending in the current state
 	fn state_to_error(&self) -> Result<(), ConnError>
 	{
 		match self.state
 		{
-		ConnectionState::SynSent => {
-			todo!("(quad=?) send/recv before established");
-			},
-		ConnectionState::Timeout => Err( ConnError::TimedOut ),
-		ConnectionState::Established => Ok( () ),
+		ConnectionState::SynSent => Err( ConnError::NotConnected ),
+		ConnectionState::Timeout => Err( self.soft_error.unwrap_or(ConnError::TimedOut) ),
+		// - The peer closing its side doesn't stop us sending
+		ConnectionState::Established
+		| ConnectionState::CloseWait => Ok( () ),
 		ConnectionState::FinWait1
 		| ConnectionState::FinWait2
 		| ConnectionState::Closing
-		| ConnectionState::TimeWait => Err( ConnError::LocalClosed ),
+		| ConnectionState::TimeWait
+		| ConnectionState::LastAck => Err( ConnError::LocalClosed ),
 
 		ConnectionState::ForceClose => Err( ConnError::RemoteReset ),
-		ConnectionState::CloseWait | ConnectionState::LastAck => Err( ConnError::RemoteClosed ),
+		ConnectionState::Aborted(e) => Err( e ),
 
 		ConnectionState::Finished => Err( ConnError::LocalClosed ),
 		}
 	}
 
+	/// Handle an ICMP error for a packet sent on this connection
+	///
+	/// Errors while connecting, and "hard" errors (RFC 1122 4.2.3.9) abort the connection.
+	/// Other errors are recorded and reported if the connection later times out.
+	pub(super) fn handle_icmp_error(&mut self, quad: &Quad, err: ConnError, is_hard: bool)
+	{
+		let abort = match self.state
+			{
+			ConnectionState::SynSent => true,
+			ConnectionState::Established
+			| ConnectionState::FinWait1
+			| ConnectionState::FinWait2
+			| ConnectionState::Closing
+			| ConnectionState::CloseWait
+			| ConnectionState::LastAck => is_hard,
+			_ => return,
+			};
+		if abort {
+			log_notice!("{:?} Connection aborted by ICMP error: {:?}", quad, err);
+			self.tx_state.retransmit_timer.clear();
+			self.tx_state.nagle_timer.clear();
+			self.state_update(quad, ConnectionState::Aborted(err));
+			self.tx_waiters.signal();
+			self.rx_waiters.signal();
+			self.conn_waiters.signal();
+		}
+		else {
+			log_debug!("{:?} Soft error: {:?}", quad, err);
+			self.soft_error = Some(err);
+		}
+	}
+
 	/// Indicates that the socket is (or was) connected
 	pub(super) fn connection_complete(&self) -> bool {
 		match self.state
@@ -486,32 +867,21 @@ impl Connection
 			return Ok(0);
 		}
 		self.state_to_error()?;
-		// 1. Determine how much data we can send (based on the TX window)
-		let max_len = usize::saturating_sub(self.tx_state.cur_tx_window_size as usize, self.tx_state.buffer.len());
-		let rv = ::core::cmp::min(buf.len(), max_len);
+		// 1. Determine how much data we can buffer (the TX worker sends it as the windows allow)
+		let rv = ::core::cmp::min(buf.len(), self.tx_state.buffer.space());
 		log_debug!("{:?} send_data({}/{})", _quad, rv, buf.len());
 		// Add the data to the TX buffer
 		for &b in &buf[..rv] {
-			self.tx_state.buffer.push_back(b).expect("Incorrectly calculated `max_len` in tcp::Connection::send_data");
+			self.tx_state.buffer.push_back(b).expect("Incorrectly calculated space in tcp::Connection::send_data");
 		}
 		
 		// Nagle algorithm!
-		// Only send if:
-		// - There's no unsent data in the buffer, OR
-		// - There's more than 1MSS unsent in the buffer
-		if self.tx_state.sent_bytes == 0 || self.tx_state.buffer.len() - self.tx_state.sent_bytes >= MSS
+		// - Partial segments are only sent when there's no unacknowledged data, so if there is start a timer to
+		//   send anyway (in case the peer is delaying its ACKs)
+		if self.tx_state.sent_bytes > 0 && self.tx_state.nagle_timer.get_expiry().is_none()
 		{
-			log_trace!("{:?} forcing a send", _quad);
-			// Force a TX
-			self.tx_state.force_tx = true;
-		}
-		else
-		{
-			// Just enqueue the data, the RX logic will trigger a re-send on ACK
 			log_trace!("{:?} waiting for nagle", _quad);
-			if self.tx_state.nagle_timer.get_expiry().is_none() {
-				self.tx_state.nagle_timer.reset(100);
-			}
+			self.tx_state.nagle_timer.reset(NAGLE_TIMEOUT_MS);
 		}
 		WORKER_CV.wake_one();
 		Ok(rv)
@@ -519,10 +889,8 @@ impl Connection
 	pub(super) fn send_ready(&self) -> bool {
 		match self.state
 		{
-		ConnectionState::Established => {
-			let max_len = usize::saturating_sub(self.tx_state.cur_tx_window_size as usize, self.tx_state.buffer.len());
-			max_len > 0
-		},
+		ConnectionState::Established
+		| ConnectionState::CloseWait => self.tx_state.buffer.space() > 0,
 		_ => false,
 		}
 	}
@@ -538,18 +906,42 @@ impl Connection
 	}
 
 	/// Pull data from the received buffer
+	///
+	/// Returns `Ok(0)` at the end of the stream (after the peer's FIN), and `Err(NoData)` if no data is available yet
 	pub(super) fn recv_data(&mut self, _quad: &Quad, buf: &mut [u8]) -> Result<usize, ConnError>
 	{
-		if let ConnectionState::SynSent = self.state {
-			return Ok(0);
-		}
-		self.state_to_error()?;
+		// Data received before a FIN or an error can still be read
 		let rv = self.rx_buffer.take(buf);
-		self.rx_buffer_seq = self.rx_buffer_seq + SeqNum(rv as u32);
-		Ok( rv )
+		if rv > 0 {
+			self.rx_buffer_seq = self.rx_buffer_seq + SeqNum(rv as u32);
+			return Ok( rv );
+		}
+		match self.state
+		{
+		ConnectionState::SynSent
+		| ConnectionState::Established
+		| ConnectionState::FinWait1
+		| ConnectionState::FinWait2 => if self.rx_shutdown { Ok(0) } else { Err(ConnError::NoData) },
+		ConnectionState::CloseWait
+		| ConnectionState::Closing
+		| ConnectionState::LastAck
+		| ConnectionState::TimeWait
+		| ConnectionState::Finished => Ok(0),
+		ConnectionState::Timeout => Err( self.soft_error.unwrap_or(ConnError::TimedOut) ),
+		ConnectionState::ForceClose => Err( ConnError::RemoteReset ),
+		ConnectionState::Aborted(e) => Err( e ),
+		}
 	}
+	/// Returns true if a receive won't block (there's data, or no more will arrive)
 	pub(super) fn recv_ready(&mut self) -> bool {
-		self.rx_buffer.valid_len() > 0
+		self.rx_buffer.valid_len() > 0 || match self.state
+			{
+			ConnectionState::SynSent
+			| ConnectionState::Established
+			| ConnectionState::FinWait1
+			| ConnectionState::FinWait2 => self.rx_shutdown,
+			_ => true,
+			}
 	}
 	pub(super) fn recv_wait_bind(&mut self, obj: &::kernel::threads::SleepObject) {
 		self.rx_waiters.add(obj);
@@ -563,114 +955,245 @@ impl Connection
 	}
 
 	/// Run TX tasks (from the TX worker)
-	pub(super) fn run_tasks(&mut self, quad: &Quad) -> Option<::kernel::time::TickCount>
+	pub(super) fn run_tasks(&mut self, quad: &Quad) -> Option<TickCount>
 	{
-		use ::kernel::futures::block_on;
+		if self.close_timer.is_expired() {
+			self.close_timer.clear();
+			log_debug!("{:?} Close timeout in {:?}", quad, self.state);
+			match self.state
+			{
+			ConnectionState::TimeWait
+			| ConnectionState::FinWait2 => self.state_update(quad, ConnectionState::Finished),
+			_ => {},
+			}
+		}
+		if self.tx_state.retransmit_timer.is_expired() {
+			self.tx_state.retransmit_timer.clear();
+			self.retransmit_timeout(quad);
+		}
+
+		self.send_new_data(quad);
+
+		// Send an ACK if one is due and it wasn't sent along with data
+		if self.tx_state.pending_ack && (self.tx_state.ack_now || self.tx_state.ack_timer.is_expired()) {
+			self.send_empty_packet(quad, FLAG_ACK);
+		}
 
-		let flags = {
-			let mut flags = 0;
-			// NOTE: slirp ignores any packet that isn't an ACK :(
-			if ::core::mem::replace(&mut self.tx_state.pending_ack, false) || true {
-				flags |= FLAG_ACK;
+		let mut rv = None;
+		super::earliest_timestamp(&mut rv, self.tx_state.retransmit_timer.get_expiry());
+		super::earliest_timestamp(&mut rv, self.tx_state.nagle_timer.get_expiry());
+		super::earliest_timestamp(&mut rv, self.tx_state.ack_timer.get_expiry());
+		super::earliest_timestamp(&mut rv, self.close_timer.get_expiry());
+		rv
+	}
+
+	/// Handle expiry of the retransmit timer
+	fn retransmit_timeout(&mut self, quad: &Quad)
+	{
+		self.tx_state.retransmit_attempts += 1;
+		match self.state {
+		ConnectionState::SynSent => {
+			if self.tx_state.retransmit_attempts >= MAX_SYN_ATTEMPTS {
+				log_trace!("{:?} Connection timeout: SynSent", quad);
+				self.timed_out(quad);
 			}
-			// TODO: Use a better method of picking when to PSH
-			// - Want to set PSH when sending the last bytes of a usermode `send` call
-			// - However, that may be after several packets have been sent (if it was a big write)
-			// - Could have a variable for `push_at` that is set to the sequence number for the next PSH
-			//   - Should this support a queue? OR, should it be updated on every `send` call?
-			if self.tx_state.buffer.len() - self.tx_state.sent_bytes > 0 && self.tx_state.force_tx {
-				flags |= FLAG_PSH;
+			else {
+				// Re-send the SYN (with a longer timeout)
+				log_trace!("{:?} Retransmit initial SYN", quad);
+				self.tx_state.rtt.backoff();
+				self.send_syn(quad);
 			}
-			flags
-			};
+			},
+		ConnectionState::Established
+		| ConnectionState::CloseWait
+		| ConnectionState::FinWait1
+		| ConnectionState::Closing
+		| ConnectionState::LastAck => {
+			let outstanding = self.tx_state.outstanding();
+			if outstanding == 0 {
+				// Nothing to retransmit
+			}
+			else if self.tx_state.retransmit_attempts > MAX_RETRANSMIT_ATTEMPTS {
+				log_trace!("{:?} Connection timeout: {:?}", quad, self.state);
+				self.timed_out(quad);
+			}
+			else {
+				// A timeout indicates (heavy) congestion: Shrink the window, and back off the timer (RFC 5681 3.1, RFC 6298 5.5)
+				self.tx_state.congestion.on_timeout(outstanding as u32);
+				self.tx_state.rtt.backoff();
+				self.retransmit_first(quad);
+			}
+			},
+		_ => {},
+		}
+	}
+	fn timed_out(&mut self, quad: &Quad)
+	{
+		self.state_update(quad, ConnectionState::Timeout);
+		self.tx_waiters.signal();
+		self.rx_waiters.signal();
+		self.conn_waiters.signal();
+	}
 
-		if self.tx_state.retransmit_timer.is_expired() {
-			self.tx_state.retransmit_timer.clear();
-			self.tx_state.retransmit_attempts += 1;
-			match self.state {
-			ConnectionState::SynSent => {
-				if self.tx_state.retransmit_attempts == MAX_CONN_ATTEMPTS {
-					log_trace!("{:?} Connection timeout: SynSent", quad);
-					self.state = ConnectionState::Timeout;
-					self.tx_waiters.signal();
-					self.rx_waiters.signal();
-					self.conn_waiters.signal();
-				}
-				else {
-					// Re-send the SYN
-					log_trace!("{:?} Retransmit initial SYN", quad);
-					self.send_empty_packet(quad, FLAG_SYN);
-					self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64 * (self.tx_state.retransmit_attempts + 1) as u64);
-				}
-				},
-			ConnectionState::Established => {
-				if self.tx_state.retransmit_attempts == MAX_CONN_ATTEMPTS {
-					log_trace!("{:?} Connection timeout: Established", quad);
-					self.state = ConnectionState::Timeout;
-					self.tx_waiters.signal();
-					self.rx_waiters.signal();
-					self.conn_waiters.signal();
-				}
-				else {
-					// Re-send any pending data (and reduce our TX window size?)
-					let len = self.tx_state.buffer.len().min(MSS);
-					//assert!(self.tx_state.sent_bytes >= len);	// This could fail, if data has been queued but not sent
-					let len = len.min(self.tx_state.sent_bytes);
-					if len > 0 {
-						log_trace!("{:?} Retransmit {:#x} {} bytes", quad, flags, len);
-						let data = self.tx_state.buffer.get_slices(0..len);
-						// `next_tx_seq` is the sequence number of the next new byte to be sent
-						// - I.e. the byte at `buffer[sent_bytes]`
-						// - So, we want to subtract the number of bytes between `data.len()` and `sent_bytes`
-						let seq = self.tx_state.next_tx_seq - SeqNum(self.tx_state.sent_bytes as u32);
-						block_on(quad.send_packet(seq.0, self.next_rx_seq.0, flags, self.rx_window_size as u16, data.0, data.1));
-						// TODO: Double this timer each time we need to resend (and halve it on successful reception)
-						self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64 * (self.tx_state.retransmit_attempts + 1) as u64);
-					}
+	/// Re-send the first unacknowledged segment (or the FIN)
+	fn retransmit_first(&mut self, quad: &Quad)
+	{
+		let outstanding = self.tx_state.outstanding();
+		let seq = self.tx_state.next_tx_seq - SeqNum(outstanding as u32);
+		let len = self.tx_state.sent_bytes.min(self.tx_state.mss);
+		// Include the FIN if it directly follows this data
+		let flags = if self.tx_state.fin == TxFin::Sent && len == self.tx_state.sent_bytes { FLAG_ACK|FLAG_FIN } else { FLAG_ACK };
+		if len == 0 && flags & FLAG_FIN == 0 {
+			return ;
+		}
+		log_trace!("{:?} Retransmit {} {:#x} {} bytes", quad, seq, flags, len);
+		// Karn's algorithm: Don't use any outstanding segment for RTT estimation
+		self.tx_state.rtt_sample = None;
+		let data = self.tx_state.buffer.get_slices(0..len);
+		self.send_segment(quad, seq, flags, data.0, data.1);
+		self.ack_sent();
+		self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto());
+	}
+
+	/// Send new data (and a queued FIN), as far as the send and congestion windows allow
+	fn send_new_data(&mut self, quad: &Quad)
+	{
+		match self.state
+		{
+		ConnectionState::Established
+		| ConnectionState::CloseWait
+		| ConnectionState::FinWait1
+		| ConnectionState::Closing
+		| ConnectionState::LastAck => {},
+		_ => return,
+		}
+
+		loop
+		{
+			let unsent = self.tx_state.buffer.len() - self.tx_state.sent_bytes;
+			let window = u32::min(self.tx_state.congestion.cwnd(), self.tx_state.max_tx_window_size) as usize;
+			let nbytes = unsent.min(self.tx_state.mss).min( window.saturating_sub(self.tx_state.sent_bytes) );
+			if nbytes == 0 {
+				break;
+			}
+			// Nagle: Only send a partial segment if nothing is waiting for an ACK (or when flushing)
+			if nbytes < self.tx_state.mss && self.tx_state.sent_bytes > 0 && !self.tx_state.force_tx && !self.tx_state.nagle_timer.is_expired() {
+				if self.tx_state.nagle_timer.get_expiry().is_none() {
+					self.tx_state.nagle_timer.reset(NAGLE_TIMEOUT_MS);
 				}
-				},
-			_ => {},
+				break;
 			}
-		}
-		else if ::core::mem::replace(&mut self.tx_state.force_tx, false) || self.tx_state.nagle_timer.is_expired() {
-			// Send the new data
-			let nbytes = self.tx_state.buffer.len() - self.tx_state.sent_bytes;
-			let nbytes = nbytes.min(MSS);
-			let data = self.tx_state.buffer.get_slices(self.tx_state.sent_bytes .. self.tx_state.sent_bytes + nbytes);
+			// Set PSH when this empties the buffer
+			let flags = if nbytes == unsent { FLAG_ACK|FLAG_PSH } else { FLAG_ACK };
 			let seq = self.tx_state.next_tx_seq;
-			log_trace!("{:?} TX {} {:#x} {} bytes", quad, if self.tx_state.nagle_timer.is_expired() { "ready" } else { "forced" }, flags, nbytes);
-			block_on(quad.send_packet(seq.0, self.next_rx_seq.0, flags, self.rx_window_size as u16, data.0, data.1));
-			// TODO: Some flags act as a pseudo-byte if in an empty packet
+			log_trace!("{:?} TX {} {:#x} {} bytes", quad, seq, flags, nbytes);
+			let data = self.tx_state.buffer.get_slices(self.tx_state.sent_bytes .. self.tx_state.sent_bytes + nbytes);
+			self.send_segment(quad, seq, flags, data.0, data.1);
+			self.ack_sent();
 			self.tx_state.next_tx_seq = self.tx_state.next_tx_seq + SeqNum( nbytes as u32 );
 			self.tx_state.sent_bytes += nbytes;
-			self.tx_state.nagle_timer.clear();
+			if self.tx_state.rtt_sample.is_none() {
+				self.tx_state.rtt_sample = Some( (self.tx_state.next_tx_seq, ::kernel::time::ticks()) );
+			}
 			// If the retransmit timer is stopped, start it again
 			if self.tx_state.retransmit_timer.get_expiry().is_none() {
-				self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
+				self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto());
 			}
 		}
-		else {
-			// Nothing to do.
-		}
+		if self.tx_state.sent_bytes == self.tx_state.buffer.len() {
+			self.tx_state.force_tx = false;
+			self.tx_state.nagle_timer.clear();
 
-		let mut rv = None;
-		super::earliest_timestamp(&mut rv, self.tx_state.retransmit_timer.get_expiry());
-		super::earliest_timestamp(&mut rv, self.tx_state.nagle_timer.get_expiry());
-		rv
+			// All data is sent, send the FIN if requested
+			if self.tx_state.fin == TxFin::Queued {
+				log_debug!("{:?} Send FIN {}", quad, self.tx_state.next_tx_seq);
+				self.send_empty_packet(quad, FLAG_FIN|FLAG_ACK);
+				self.tx_state.next_tx_seq = self.tx_state.next_tx_seq + SeqNum(1);
+				self.tx_state.fin = TxFin::Sent;
+				if self.tx_state.retransmit_timer.get_expiry().is_none() {
+					self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto());
+				}
+			}
+		}
 	}
 
 	fn send_empty_packet(&mut self, quad: &Quad, flags: u8)
 	{
 		log_debug!("{:?} send_packet({:02x})", quad, flags);
+		self.send_segment(quad, self.tx_state.next_tx_seq, flags, &[], &[]);
+		if flags & FLAG_ACK != 0 {
+			self.ack_sent();
+		}
+	}
+	/// Send a segment, with the current ACK number, window, and options
+	fn send_segment(&self, quad: &Quad, seq: SeqNum, flags: u8, data1: &[u8], data2: &[u8])
+	{
+		let mut options = OptionsBuf::new();
+		if let Some(ts_recent) = self.negotiated.ts_recent {
+			options.timestamp(options::timestamp_now(), ts_recent);
+		}
+		if self.negotiated.sack {
+			let mut blocks = [(0,0); options::MAX_SACK_BLOCKS];
+			let n = self.sack_blocks(&mut blocks);
+			options.sack(&blocks[..n]);
+		}
 		// TODO: Enqueue instead of blocking?
-		::kernel::futures::block_on(quad.send_packet(self.tx_state.next_tx_seq.0, self.next_rx_seq.0, flags, self.rx_window_size as u16, &[], &[]));
+		::kernel::futures::block_on(quad.send_packet(seq.0, self.next_rx_seq.0, flags, self.advertised_window(), options.as_bytes(), data1, data2));
 	}
+	/// Get SACK blocks describing out-of-order data in the RX buffer, the most recently received first (RFC 2018 4)
+	fn sack_blocks(&self, out: &mut [(u32,u32); options::MAX_SACK_BLOCKS]) -> usize
+	{
+		let mut ranges = [(0,0); options::MAX_SACK_BLOCKS];
+		let n = self.rx_buffer.valid_ranges((self.next_rx_seq - self.rx_buffer_seq).0 as usize, &mut ranges);
+		for (dst, &(start, end)) in Iterator::zip(out.iter_mut(), ranges[..n].iter())
+		{
+			*dst = ( (self.rx_buffer_seq + SeqNum(start as u32)).0, (self.rx_buffer_seq + SeqNum(end as u32)).0 );
+		}
+		if let Some(recent) = self.rx_last_ooo {
+			if let Some(i) = out[..n].iter().position(|&(s,e)| !SeqNum(s).is_after(recent) && SeqNum(e).is_after(recent)) {
+				out[..=i].rotate_right(1);
+			}
+		}
+		n
+	}
+	/// Request that an ACK be sent immediately
 	fn send_ack(&mut self, quad: &Quad, msg: &str)
 	{
 		log_debug!("{:?} send_ack({:?})", quad, msg);
-		// - TODO: Cancel any pending ACK
-		// - Send a new ACK
 		self.tx_state.pending_ack = true;
+		self.tx_state.ack_now = true;
+		WORKER_CV.wake_one();
+	}
+	/// Schedule an ACK for received data (RFC 1122 4.2.3.2)
+	///
+	/// The ACK is sent with the next outgoing data, or when the timer expires. Every second segment is ACKed immediately.
+	fn delayed_ack(&mut self, quad: &Quad)
+	{
+		self.tx_state.pending_ack = true;
+		self.tx_state.unacked_segments += 1;
+		if self.tx_state.unacked_segments >= 2 {
+			self.send_ack(quad, "Second segment");
+		}
+		else if self.tx_state.ack_timer.get_expiry().is_none() {
+			log_trace!("{:?} Delaying ACK", quad);
+			self.tx_state.ack_timer.reset(DELAYED_ACK_MS);
+			WORKER_CV.wake_one();
+		}
+	}
+	/// An ACK of all received data has been sent (either alone, or with data)
+	fn ack_sent(&mut self)
+	{
+		self.last_rx_ack = self.next_rx_seq;
+		self.tx_state.pending_ack = false;
+		self.tx_state.ack_now = false;
+		self.tx_state.ack_timer.clear();
+		self.tx_state.unacked_segments = 0;
+	}
+	/// Queue a FIN to be sent once all buffered data has been sent
+	fn queue_fin(&mut self)
+	{
+		self.tx_state.fin = TxFin::Queued;
+		// Flush any data held back by Nagle
 		self.tx_state.force_tx = true;
 		WORKER_CV.wake_one();
 	}
@@ -690,20 +1213,59 @@ impl Connection
 			ConnectionState::Finished => return Err( ConnError::LocalClosed ),
 
 			ConnectionState::CloseWait => {
-				self.send_empty_packet(quad, FLAG_FIN|FLAG_ACK);
+				self.queue_fin();
 				ConnectionState::LastAck
 				},
-			ConnectionState::ForceClose => {
+			ConnectionState::ForceClose
+			| ConnectionState::Aborted(_) => {
 				ConnectionState::Finished
 				},
+			// Not connected, so there's nothing to send
 			ConnectionState::SynSent
-			| ConnectionState::Timeout
-			| ConnectionState::Established => {
-				self.send_empty_packet(quad, FLAG_FIN);
+			| ConnectionState::Timeout => {
+				self.tx_state.retransmit_timer.clear();
+				ConnectionState::Finished
+				},
+			ConnectionState::Established => {
+				self.queue_fin();
 				ConnectionState::FinWait1
 				},
 			};
 		self.state_update(quad, new_state);
 		Ok( () )
 	}
+	/// User shuts down receiving, any received data is discarded and reads return end-of-stream
+	pub(super) fn shutdown_recv(&mut self, _quad: &Quad) -> Result<(), ConnError>
+	{
+		if self.rx_shutdown {
+			return Err( ConnError::LocalClosed );
+		}
+		self.rx_shutdown = true;
+		self.discard_rx();
+		self.rx_waiters.signal();
+		Ok( () )
+	}
+	/// The user's handle has been dropped: close (if not already closing), and allow removal once finished
+	pub(super) fn release(&mut self, quad: &Quad)
+	{
+		self.handle_released = true;
+		match self.state
+		{
+		ConnectionState::Established
+		| ConnectionState::CloseWait
+		| ConnectionState::SynSent
+		| ConnectionState::Timeout
+		| ConnectionState::ForceClose
+		| ConnectionState::Aborted(_) => { let _ = self.close(quad); },
+		ConnectionState::FinWait2 => {
+			self.close_timer.reset(2*MSL_MS);
+			WORKER_CV.wake_one();
+			},
+		ConnectionState::FinWait1
+		| ConnectionState::Closing
+		| ConnectionState::LastAck
+		| ConnectionState::TimeWait => {},
+		ConnectionState::Finished => WORKER_CV.wake_one(),
+		}
+	}
 }
\ No newline at end of file
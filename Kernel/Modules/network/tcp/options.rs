@@ -0,0 +1,194 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/tcp/options.rs
+//! TCP header options: MSS (RFC 9293), window scaling and timestamps (RFC 7323), and SACK (RFC 2018)
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMP: u8 = 8;
+
+/// Maximum length of the options (the header length field allows at most 60 bytes)
+pub const MAX_LEN: usize = 40;
+/// MSS assumed if the peer doesn't send the option (RFC 9293 3.7.1)
+pub const DEFAULT_MSS: u16 = 536;
+/// Window scale sent by us, allows a window of up to 2MiB
+pub const LOCAL_WINDOW_SHIFT: u8 = 5;
+/// Largest shift allowed (RFC 7323 2.3)
+const MAX_WINDOW_SHIFT: u8 = 14;
+/// Maximum number of SACK blocks that fit in a segment (only three fit if timestamps are also in use)
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// Current value for `TSval` (a millisecond clock, RFC 7323 5.4 allows a tick between 1ms and 1s)
+pub fn timestamp_now() -> u32
+{
+	::kernel::time::ticks() as u32
+}
+
+/// Options parsed from a received header
+#[derive(Default,Debug)]
+pub struct Options
+{
+	pub mss: Option<u16>,
+	pub window_shift: Option<u8>,
+	pub sack_permitted: bool,
+	/// Timestamp value and echo reply (`TSval`, `TSecr`)
+	pub timestamp: Option<(u32, u32)>,
+}
+impl Options
+{
+	/// Read the options from the header (`len` is the header length minus the fixed 20 bytes)
+	pub fn read(reader: &mut crate::nic::PacketReader, len: usize) -> Options
+	{
+		let mut buf = [0; MAX_LEN];
+		let buf = &mut buf[..len.min(MAX_LEN)];
+		if buf.len() > 0 && reader.read(buf).is_err() {
+			return Options::default();
+		}
+		Options::parse(buf)
+	}
+	/// Parse options from a byte slice, ignoring malformed or unknown options
+	pub fn parse(mut buf: &[u8]) -> Options
+	{
+		let mut rv = Options::default();
+		while let Some(&kind) = buf.first()
+		{
+			match kind
+			{
+			KIND_END => break,
+			KIND_NOP => { buf = &buf[1..]; continue },
+			_ => {},
+			}
+			let len = match buf.get(1)
+				{
+				Some(&l) if l >= 2 && l as usize <= buf.len() => l as usize,
+				_ => {
+					log_debug!("Malformed TCP option {} in {:x?}", kind, buf);
+					break;
+					},
+				};
+			let data = &buf[2..len];
+			match (kind, data.len())
+			{
+			(KIND_MSS, 2) => rv.mss = Some(u16::from_be_bytes([data[0], data[1]])),
+			(KIND_WINDOW_SCALE, 1) => rv.window_shift = Some(data[0].min(MAX_WINDOW_SHIFT)),
+			(KIND_SACK_PERMITTED, 0) => rv.sack_permitted = true,
+			// TODO: Use received SACK blocks to avoid re-sending data
+			(KIND_SACK, _) => {},
+			(KIND_TIMESTAMP, 8) => rv.timestamp = Some((
+				u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+				u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+				)),
+			_ => log_debug!("Unknown TCP option {} len {}", kind, len),
+			}
+			buf = &buf[len..];
+		}
+		rv
+	}
+}
+
+/// Connection parameters negotiated by the SYN and SYN-ACK
+#[derive(Copy,Clone,Debug)]
+pub struct Negotiated
+{
+	/// Largest segment that can be sent
+	pub mss: u16,
+	/// Shift applied to windows received from the peer (zero if scaling isn't in use)
+	pub tx_window_shift: u8,
+	/// Shift applied to windows sent to the peer
+	pub rx_window_shift: u8,
+	/// Peer accepts SACK blocks
+	pub sack: bool,
+	/// Most recent timestamp from the peer (`TS.Recent`), `None` if timestamps aren't in use
+	pub ts_recent: Option<u32>,
+}
+impl Negotiated
+{
+	/// Determine the parameters from the options in the peer's SYN (or SYN-ACK)
+	///
+	/// Our SYN always offers every option, so an option is in use whenever the peer includes it.
+	pub fn from_syn(local_mss: u16, opts: &Options) -> Negotiated
+	{
+		Negotiated {
+			mss: u16::min(opts.mss.unwrap_or(DEFAULT_MSS), local_mss),
+			tx_window_shift: opts.window_shift.unwrap_or(0),
+			rx_window_shift: if opts.window_shift.is_some() { LOCAL_WINDOW_SHIFT } else { 0 },
+			sack: opts.sack_permitted,
+			ts_recent: opts.timestamp.map(|(val, _)| val),
+		}
+	}
+}
+
+/// Options to be sent in a header (each option is padded with NOPs to keep 32-bit alignment)
+pub struct OptionsBuf
+{
+	data: [u8; MAX_LEN],
+	len: usize,
+}
+impl OptionsBuf
+{
+	pub fn new() -> OptionsBuf {
+		OptionsBuf { data: [0; MAX_LEN], len: 0 }
+	}
+	/// Options for a SYN (if `peer` is `None`) or a SYN-ACK replying to the options in `peer`
+	pub fn syn(local_mss: u16, peer: Option<&Options>, ts_val: u32) -> OptionsBuf
+	{
+		let mut rv = OptionsBuf::new();
+		rv.push(&[], KIND_MSS, &local_mss.to_be_bytes());
+		if peer.map(|o| o.window_shift.is_some()).unwrap_or(true) {
+			rv.push(&[KIND_NOP], KIND_WINDOW_SCALE, &[LOCAL_WINDOW_SHIFT]);
+		}
+		if peer.map(|o| o.sack_permitted).unwrap_or(true) {
+			rv.push(&[KIND_NOP,KIND_NOP], KIND_SACK_PERMITTED, &[]);
+		}
+		match peer
+		{
+		None => rv.timestamp(ts_val, 0),
+		Some(&Options { timestamp: Some((peer_val, _)), .. }) => rv.timestamp(ts_val, peer_val),
+		Some(_) => {},
+		}
+		rv
+	}
+	pub fn timestamp(&mut self, val: u32, ecr: u32)
+	{
+		let mut data = [0; 8];
+		data[..4].copy_from_slice(&val.to_be_bytes());
+		data[4..].copy_from_slice(&ecr.to_be_bytes());
+		self.push(&[KIND_NOP,KIND_NOP], KIND_TIMESTAMP, &data);
+	}
+	/// Add SACK blocks (as many as fit in the remaining space), each block is the start and end sequence number
+	pub fn sack(&mut self, blocks: &[(u32, u32)])
+	{
+		let max = (MAX_LEN - self.len).saturating_sub(4) / 8;
+		let blocks = &blocks[..blocks.len().min(max)];
+		if blocks.len() == 0 {
+			return ;
+		}
+		let mut data = [0; MAX_SACK_BLOCKS * 8];
+		for (d, (start, end)) in Iterator::zip(data.chunks_mut(8), blocks.iter())
+		{
+			d[..4].copy_from_slice(&start.to_be_bytes());
+			d[4..].copy_from_slice(&end.to_be_bytes());
+		}
+		self.push(&[KIND_NOP,KIND_NOP], KIND_SACK, &data[..blocks.len() * 8]);
+	}
+	fn push(&mut self, pad: &[u8], kind: u8, data: &[u8])
+	{
+		let len = pad.len() + 2 + data.len();
+		assert!(self.len + len <= MAX_LEN, "TCP options overflow");
+		let dst = &mut self.data[self.len..][..len];
+		dst[..pad.len()].copy_from_slice(pad);
+		dst[pad.len()] = kind;
+		dst[pad.len() + 1] = (2 + data.len()) as u8;
+		dst[pad.len() + 2..].copy_from_slice(data);
+		self.len += len;
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.data[..self.len]
+	}
+}
@@ -0,0 +1,68 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/tcp/rto.rs
+//! TCP retransmission timeout estimation (RFC 6298)
+
+/// Timeout used before any RTT samples are taken (RFC 6298 2.1)
+const INITIAL_RTO_MS: u32 = 1000;
+/// Timeout used once data starts flowing if the SYN had to be retransmitted (RFC 6298 5.7)
+const FALLBACK_RTO_MS: u32 = 3000;
+/// Lower bound on the timeout
+///
+/// NOTE: RFC 6298 recommends 1s, but (like most stacks) a lower value is used so a lost packet on a fast link isn't so costly
+const MIN_RTO_MS: u32 = 200;
+/// Upper bound on the timeout (RFC 6298 2.5 allows any value above 60s)
+const MAX_RTO_MS: u32 = 60*1000;
+/// Clock granularity (`G` in RFC 6298)
+const CLOCK_GRANULARITY_MS: u32 = 1;
+
+pub struct RttEstimator
+{
+	/// Smoothed round-trip time and variance (in milliseconds), `None` until the first sample
+	srtt: Option<(u32, u32)>,
+	/// Current retransmit timeout (including any backoff)
+	rto: u32,
+}
+impl RttEstimator
+{
+	pub fn new() -> Self {
+		RttEstimator {
+			srtt: None,
+			rto: INITIAL_RTO_MS,
+		}
+	}
+
+	/// Current retransmission timeout in milliseconds
+	pub fn rto(&self) -> u64 {
+		self.rto as u64
+	}
+
+	/// Add a round-trip time measurement (RFC 6298 2.2 and 2.3)
+	pub fn add_sample(&mut self, rtt: u32) {
+		let (srtt, rttvar) = match self.srtt
+			{
+			None => (rtt, rtt / 2),
+			Some((srtt, rttvar)) => {
+				// alpha = 1/8, beta = 1/4
+				let rttvar = (3 * rttvar + (srtt as i32 - rtt as i32).unsigned_abs()) / 4;
+				let srtt = (7 * srtt + rtt) / 8;
+				(srtt, rttvar)
+				},
+			};
+		self.srtt = Some((srtt, rttvar));
+		self.rto = (srtt + u32::max(CLOCK_GRANULARITY_MS, 4 * rttvar)).max(MIN_RTO_MS).min(MAX_RTO_MS);
+	}
+
+	/// Double the timeout after a retransmission (RFC 6298 5.5)
+	pub fn backoff(&mut self) {
+		self.rto = (self.rto * 2).min(MAX_RTO_MS);
+	}
+
+	/// The connection was established after the SYN was retransmitted (RFC 6298 5.7)
+	pub fn syn_retransmitted(&mut self) {
+		if self.srtt.is_none() {
+			self.rto = FALLBACK_RTO_MS;
+		}
+	}
+}
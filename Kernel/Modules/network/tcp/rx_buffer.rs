@@ -101,6 +101,41 @@ impl RxBuffer
 		}
 		len as usize
 	}
+	/// Get the ranges of populated bytes at or after offset `start` (e.g. out-of-order data past the first gap)
+	///
+	/// Returns the number of `(start, end)` ranges written to `out`
+	pub fn valid_ranges(&self, start: usize, out: &mut [(usize,usize)]) -> usize
+	{
+		let mut count = 0;
+		let mut cur_start = None;
+		let mut i = start;
+		while i < self.size && count < out.len()
+		{
+			let ofs = (self.read_pos + i) % self.size;
+			let bitmap_byte = self.data[self.size..][ofs / 8];
+			// Skip over empty bitmap entries quickly
+			if cur_start.is_none() && ofs % 8 == 0 && bitmap_byte == 0 {
+				i += 8;
+				continue ;
+			}
+			match (cur_start, bitmap_byte & 1 << (ofs % 8) != 0)
+			{
+			(None, true) => cur_start = Some(i),
+			(Some(s), false) => {
+				out[count] = (s, i);
+				count += 1;
+				cur_start = None;
+				},
+			_ => {},
+			}
+			i += 1;
+		}
+		if let Some(s) = cur_start {
+			out[count] = (s, self.size);
+			count += 1;
+		}
+		count
+	}
 	/// Resize the buffer
 	pub fn resize(&mut self, new_size: usize) {
 		self.compact();
@@ -228,6 +263,25 @@ fn oversize()
 	}
 }
 
+#[test]
+// Locate out-of-order data
+fn ranges()
+{
+	let mut buf = RxBuffer::new(32);
+	buf.insert(0, b"ab").unwrap();
+	buf.insert(4, b"cd").unwrap();
+	buf.insert(9, b"0123456789").unwrap();
+	let mut r = [(0,0); 4];
+	assert_eq!(buf.valid_ranges(2, &mut r), 2);
+	assert_eq!(&r[..2], &[(4,6), (9,19)]);
+	assert_eq!(buf.valid_ranges(0, &mut r[..1]), 1);
+	assert_eq!(r[0], (0,2));
+	// Ranges are relative to the read position
+	{ let mut b = [0; 2]; buf.take(&mut b); }
+	assert_eq!(buf.valid_ranges(0, &mut r), 2);
+	assert_eq!(&r[..2], &[(2,4), (7,17)]);
+}
+
 #[test]
 // Check wrapping behavior
 fn wrapping()
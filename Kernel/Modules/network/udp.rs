@@ -5,33 +5,51 @@ use kernel::lib::mem::Arc;
 use kernel::vec::Vec;
 use crate::Address;
 use crate::nic::SparsePacket;
+use crate::port_pool::PortPool;
 
 const IPV4_PROTO_UDP: u8 = 17;
 /// Opened sockets
 static SOCKETS: RwLock<Vec< Arc<SocketInfo> >> = RwLock::new(Vec::new());
+/// Ephemeral ports allocated to sockets opened without a local port
+static S_PORTS: Mutex<PortPool> = Mutex::new(PortPool::new());
 
 
 pub fn init() {
 	crate::ipv4::register_handler(IPV4_PROTO_UDP, |int,src_addr,pkt|{
-		rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
+		if rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt) {
+			Ok( () )
+		}
+		else {
+			Err(crate::ipv4::UnreachableCode::Port)
+		}
 	}).unwrap();
 	crate::ipv6::register_handler(IPV4_PROTO_UDP, |int,src_addr,pkt|{
-		rx_handler(Address::Ipv6(src_addr), Address::Ipv6(int.addr()), pkt)
+		// TODO: ICMPv6 port unreachable
+		let _ = rx_handler(Address::Ipv6(src_addr), Address::Ipv6(int.addr()), pkt);
 	}).unwrap();
 }
-fn rx_handler(src_addr: Address, dst_addr: Address, mut pkt: crate::nic::PacketReader)
+/// Handle a received packet, returning `false` if there was no socket for it (so the port is closed)
+fn rx_handler(src_addr: Address, dst_addr: Address, mut pkt: crate::nic::PacketReader) -> bool
 {
 	let hdr = match PktHeader::read(&mut pkt)
 		{
 		Ok(v) => v,
 		Err(_) => {
 			log_error!("Undersized packet: Ran out of data reading header");
-			return ;
+			return true;
 			},
 		};
 	log_trace!("rx_handler: {}->{}: {:?}", src_addr, dst_addr, hdr);
 	// Check checksum.
+	// - A zero checksum means that the sender didn't calculate one, which is only allowed for IPv4 (RFC 8200 8.1)
 	if hdr.checksum == 0
+	{
+		if let Address::Ipv6(_) = src_addr {
+			log_notice!("Dropping IPv6 packet with no checksum: {}->{}", src_addr, dst_addr);
+			return true;
+		}
+	}
+	else
 	{
 		let cksum = calc_checksum(
 			&hdr.encode(),
@@ -39,38 +57,44 @@ fn rx_handler(src_addr: Address, dst_addr: Address, mut pkt: crate::nic::PacketR
 			{ let mut p = pkt.clone(); ::core::iter::from_fn(move || p.read_u8().ok()) }
 		);
 		if cksum != 0 {
+			log_notice!("Dropping packet with incorrect checksum: {}->{}: {:#x}", src_addr, dst_addr, cksum);
+			return true;
 		}
 	}
 	let pkt_data = pkt.clone();
+	let mut delivered = false;
 	for sock in SOCKETS.read().iter() {
-		if sock.key.local_port != hdr.dest_port {
-			log_trace!("Local Port: {} != {}", sock.key.local_port, hdr.dest_port);
+		if !sock.key.matches(dst_addr, hdr.dest_port, src_addr, hdr.source_port) {
 			continue ;
 		}
-		match sock.key.local_address {
-		Some(a) if a != dst_addr => {
-			log_trace!("Local Addr: {} != {}", a, dst_addr);
-			continue
-		},
-		_ => {},
-		}
-		if sock.key.remote_mask.0 != src_addr.mask_network(sock.key.remote_mask.1) {
-			log_trace!("Remote Mask: {}/{} != {}", sock.key.remote_mask.0, sock.key.remote_mask.1, src_addr);
-			continue ;
-		}
-		match sock.key.remote_port {
-		Some(a) if a != hdr.source_port => {
-			log_trace!("Remote Port: {} != {}", a, hdr.source_port);
-			continue
-		},
-		_ => {},
-		}
 		// Matches!
 		log_debug!("Pushed to {:?}", sock.key);
 		sock.rx_buffer.push_packet(dst_addr, src_addr, hdr.source_port, pkt_data.clone());
+		delivered = true;
 	}
+	delivered
 }
 
+/// Handle an ICMP error received for a packet sent from `local` to `remote`
+pub(crate) fn handle_icmp_error(local: (Address, u16), remote: (Address, u16), kind: crate::ipv4::icmp::ErrorKind)
+{
+	use crate::ipv4::icmp::ErrorKind;
+	let err = match kind
+		{
+		ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => Error::ConnectionRefused,
+		ErrorKind::NetUnreachable | ErrorKind::HostUnreachable | ErrorKind::TimeExceeded => Error::NoRouteToHost,
+		ErrorKind::FragmentationNeeded => return,
+		};
+	for sock in SOCKETS.read().iter() {
+		if sock.key.matches(local.0, local.1, remote.0, remote.1) {
+			log_debug!("ICMP {:?} for {:?}", kind, sock.key);
+			*sock.pending_error.lock() = Some(err);
+			sock.rx_buffer.wake_all();
+		}
+	}
+}
+
+#[derive(Debug)]
 pub enum Error {
 	/// Cannot create a new socket, the chosen local address is in use
 	AddressInUse,
@@ -82,6 +106,8 @@ pub enum Error {
 	IncompatibleAddresses,
 	/// No available route to the selected host
 	NoRouteToHost,
+	/// The remote host reported that the port is closed (ICMP port unreachable)
+	ConnectionRefused,
 }
 
 /// Exposed handle to a registered socket
@@ -89,15 +115,26 @@ pub struct SocketHandle {
 	inner: Arc<SocketInfo>,
 }
 impl SocketHandle {
+	/// Open a socket, if `local_port` is zero then an ephemeral port is allocated
 	pub fn new(
 		local_address: Option<crate::Address>,
 		local_port: u16,
 		remote_mask: (crate::Address, u8),
 		remote_port: Option<u16>,
 	) -> Result<Self, Error> {
-		if local_port == 0 {
-			todo!("Allocate a local port");
+		let mut lh = SOCKETS.write();
+		let ephemeral_port = local_port == 0;
+		let local_port = if ephemeral_port {
+			// Skip ports already explicitly bound by another socket
+			match S_PORTS.lock().allocate(|p| lh.iter().any(|s| s.key.local_port == p))
+			{
+			Some(p) => p,
+			None => return Err(Error::AddressInUse),
+			}
 		}
+		else {
+			local_port
+		};
 		let key = SocketKey {
 			local_address,
 			local_port,
@@ -105,7 +142,6 @@ impl SocketHandle {
 			remote_port,
 		};
 		// Check for an overlapping socket
-		let mut lh = SOCKETS.write();
 		for sock in lh.iter() {
 			if sock.key.overlaps_with(&key) {
 				return Err(Error::AddressInUse);
@@ -113,24 +149,37 @@ impl SocketHandle {
 		}
 		let rv = Arc::new(SocketInfo {
 			key,
+			ephemeral_port,
 			rx_buffer: Default::default(),
+			pending_error: Default::default(),
 		});
 		lh.push(rv.clone());
 		Ok(SocketHandle { inner: rv })
 	}
 	pub fn register_wait(&self, so: &::kernel::threads::SleepObject) -> bool {
 		self.inner.rx_buffer.register_wait(so);
-		self.inner.rx_buffer.has_packet()
+		self.inner.rx_buffer.has_packet() || self.has_error()
 	}
 	pub fn clear_wait(&self, so: &::kernel::threads::SleepObject) -> bool {
 		self.inner.rx_buffer.clear_wait(so);
-		self.inner.rx_buffer.has_packet()
+		self.inner.rx_buffer.has_packet() || self.has_error()
+	}
+	fn has_error(&self) -> bool {
+		self.inner.pending_error.lock().is_some()
 	}
-	pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Address, u16)> {
+	/// Take an error reported by the remote (via ICMP) since the last send/receive
+	pub fn take_error(&self) -> Option<Error> {
+		self.inner.pending_error.lock().take()
+	}
+	/// Receive a packet, returning any pending error first
+	pub fn try_recv_from(&self, buf: &mut [u8]) -> Result<Option<(usize, Address, u16)>, Error> {
+		if let Some(e) = self.take_error() {
+			return Err(e);
+		}
 		match self.inner.rx_buffer.pop_packet(buf) {
-		None => None,
+		None => Ok(None),
 		Some((_dst,src,port,len)) => {
-			Some((len, src, port))
+			Ok(Some((len, src, port)))
 		}
 		}
 	}
@@ -145,10 +194,13 @@ impl SocketHandle {
 	}
 	/// Send a datagram over this socket
 	pub fn send_to(&self, addr: Address, port: u16, buf: SparsePacket) -> Result<(),Error> {
+		if let Some(e) = self.take_error() {
+			return Err(e);
+		}
 		// Check if the target address matches the remote mask
 		// TODO: Is this actually needed/right?
-		let (d_addr,bits) = self.inner.key.remote_mask;
-		if addr.mask_network(bits) != d_addr.mask_network(bits) {
+		if !self.inner.key.remote_matches(addr) {
+			let (d_addr,bits) = self.inner.key.remote_mask;
 			log_info!("Trying to send to {addr} but mask is {d_addr}/{bits}");
 			return Err(Error::InvalidRemote);
 		}
@@ -168,26 +220,33 @@ impl SocketHandle {
 			length: 8 + buf.total_len() as u16,
 			checksum: 0,
 		};
+		// - If not bound to a local address, use the address of the interface that reaches the destination
 		let local_addr = match self.inner.key.local_address {
 			Some(a) => a,
 			None => {
-				match d_addr {
-				Address::Ipv4(d_addr) => match crate::ipv4::route_lookup(Default::default(),d_addr)
+				match addr {
+				Address::Ipv4(addr) => match crate::ipv4::route_lookup(Default::default(),addr)
 					{
 					Some(v) => Address::Ipv4(v.source_ip),
 					None => return Err(Error::NoRouteToHost),
 					},
-				Address::Ipv6(_) => todo!(),
+				Address::Ipv6(addr) => match crate::ipv6::route_lookup(crate::ipv6::Address::zero(),addr)
+					{
+					Some(v) => Address::Ipv6(v.source_ip),
+					None => return Err(Error::NoRouteToHost),
+					},
 				}
 			},
 		};
 		// - incl. checksum (if no offload)
 		if true {
-			hdr.checksum = calc_checksum(
+			let cksum = calc_checksum(
 				&hdr.encode(),
 				&local_addr, &addr, buf.total_len(),
 				buf.into_iter().map(|v| v.iter().copied()).flatten()
 			);
+			// Zero means "no checksum", so is sent as the equivalent all-ones value (RFC 768)
+			hdr.checksum = if cksum == 0 { 0xFFFF } else { cksum };
 		}
 		let hdr_enc = hdr.encode();
 		let pkt = SparsePacket::new_chained(&hdr_enc, &buf);
@@ -211,12 +270,19 @@ impl ::core::ops::Drop for SocketHandle {
 	fn drop(&mut self) {
 		let mut lh = SOCKETS.write();
 		lh.retain(|v| !Arc::ptr_eq(v, &self.inner));
+		if self.inner.ephemeral_port {
+			S_PORTS.lock().release(self.inner.key.local_port);
+		}
 	}
 }
 /// Underlying information on an open/listening socket
 struct SocketInfo {
 	key: SocketKey,
+	/// The local port was allocated from `S_PORTS` (and is released when the socket closes)
+	ephemeral_port: bool,
 	rx_buffer: MessagePool,
+	/// Error reported by ICMP, returned by the next send/receive
+	pending_error: Mutex<Option<Error>>,
 }
 /// Ports and addresses for the socket
 #[derive(Debug)]
@@ -232,6 +298,46 @@ struct SocketKey {
 	remote_port: Option<u16>,
 }
 impl SocketKey {
+	/// Check if a packet between these addresses is for this socket
+	fn matches(&self, local_addr: Address, local_port: u16, remote_addr: Address, remote_port: u16) -> bool {
+		if self.local_port != local_port {
+			log_trace!("Local Port: {} != {}", self.local_port, local_port);
+			return false;
+		}
+		match self.local_address {
+		Some(a) if a != local_addr => {
+			log_trace!("Local Addr: {} != {}", a, local_addr);
+			return false;
+		},
+		_ => {},
+		}
+		if !self.remote_matches(remote_addr) {
+			log_trace!("Remote Mask: {}/{} != {}", self.remote_mask.0, self.remote_mask.1, remote_addr);
+			return false;
+		}
+		match self.remote_port {
+		Some(a) if a != remote_port => {
+			log_trace!("Remote Port: {} != {}", a, remote_port);
+			return false;
+		},
+		_ => {},
+		}
+		true
+	}
+	/// Check if the socket is bound to the IPv6 wildcard (`[::]`, accepting from `::/0`), which also accepts IPv4
+	fn is_dual_stack(&self) -> bool {
+		self.local_address.is_none() && self.remote_mask == (Address::Ipv6(crate::ipv6::Address::zero()), 0)
+	}
+	/// Check if a remote address is within the receive mask
+	fn remote_matches(&self, remote_addr: Address) -> bool {
+		match (self.remote_mask.0, remote_addr)
+		{
+		(Address::Ipv4(_), Address::Ipv4(_))
+		| (Address::Ipv6(_), Address::Ipv6(_)) => self.remote_mask.0 == remote_addr.mask_network(self.remote_mask.1),
+		(Address::Ipv6(_), Address::Ipv4(_)) => self.is_dual_stack(),
+		(Address::Ipv4(_), Address::Ipv6(_)) => false,
+		}
+	}
 	fn overlaps_with(&self, other: &SocketKey) -> bool {
 		// Local port: if the local port is different, then this cannot overlap
 		if self.local_port != other.local_port {
@@ -248,8 +354,16 @@ impl SocketKey {
 		_ => {},
 		}
 		// Remote: Check if the spans covered by the mask are disjoint.
-		let min_bits = u8::min(self.remote_mask.1, other.remote_mask.1);
-		self.remote_mask.0.mask_network(min_bits) == other.remote_mask.0.mask_network(min_bits)
+		match (self.remote_mask.0, other.remote_mask.0)
+		{
+		(Address::Ipv4(_), Address::Ipv4(_))
+		| (Address::Ipv6(_), Address::Ipv6(_)) => {
+			let min_bits = u8::min(self.remote_mask.1, other.remote_mask.1);
+			self.remote_mask.0.mask_network(min_bits) == other.remote_mask.0.mask_network(min_bits)
+			},
+		// A dual-stack socket covers all IPv4 addresses
+		_ => self.is_dual_stack() || other.is_dual_stack(),
+		}
 	}
 }
 /// A pool of messages, stored as u16 length-delimited data in a ring-buf
@@ -294,7 +408,7 @@ impl MessagePool {
 			}
 			Address::Ipv6(dest_addr) => {
 				let Address::Ipv6(src_addr) = src_addr else { panic!("Mismatched address types") };
-				make_hdr(&mut hdr_buf, len, src_port, 0, &dest_addr.to_bytes(), &src_addr.to_bytes())
+				make_hdr(&mut hdr_buf, len, src_port, 1, &dest_addr.to_bytes(), &src_addr.to_bytes())
 			}
 		};
 
@@ -324,6 +438,11 @@ impl MessagePool {
 			!v.is_from(so)
 		})
 	}
+	fn wake_all(&self) {
+		for v in self.waiters.lock().drain(..) {
+			v.signal();
+		}
+	}
 	fn has_packet(&self) -> bool {
 		!self.inner.lock().is_empty()
 	}
@@ -349,12 +468,26 @@ impl MessagePool {
 						Address::Ipv4(crate::ipv4::Address(sa)),
 					)
 				}
+				1 => {	// IPv6
+					let mut da = [0; 16];
+					let mut sa = [0; 16];
+					for b in da.iter_mut().chain(sa.iter_mut()) {
+						*b = lh.pop_front().unwrap();
+					}
+					(
+						Address::Ipv6(crate::ipv6::Address::from_bytes(da)),
+						Address::Ipv6(crate::ipv6::Address::from_bytes(sa)),
+					)
+				}
 				_ => panic!("Unknown address type in packet queue"),
 				};
-			// Get data
+			// Get data (discarding anything that doesn't fit in the buffer)
 			assert!(lh.len() >= len);
-			for dst in buf.iter_mut().take(len) {
-				*dst = lh.pop_front().unwrap();
+			for i in 0 .. len {
+				let b = lh.pop_front().unwrap();
+				if let Some(dst) = buf.get_mut(i) {
+					*dst = b;
+				}
 			}
 			Some((dst, src, port, len))
 		}
@@ -404,7 +537,16 @@ pub fn calc_checksum(hdr: &[u8], src_addr: &Address, dst_addr: &Address, data_le
 		];
 		calc_checksum_inner(hdr, &ph, data)
 	},
-	Address::Ipv6(_) => todo!(),
+	Address::Ipv6(src_addr) => {
+		let Address::Ipv6(dst_addr) = dst_addr else { panic!("Mismatched address types") };
+		let pkt_len = ((hdr.len() + data_len) as u32).to_be_bytes();
+		let mut ph = [0; 16+16+4+4];
+		ph[0..][..16].copy_from_slice(&src_addr.to_bytes());
+		ph[16..][..16].copy_from_slice(&dst_addr.to_bytes());
+		ph[32..][..4].copy_from_slice(&pkt_len);
+		ph[39] = IPV4_PROTO_UDP;
+		calc_checksum_inner(hdr, &ph, data)
+	},
 	}
 }
 fn calc_checksum_inner(hdr: &[u8], ph: &[u8], data: impl Iterator<Item=u8>) -> u16 
@@ -322,6 +322,13 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			let mut data: ::kernel::memory::freeze::FreezeMut<::syscall_values::NetworkRoute> = args.get()?;
 			network_calls::get_route(NET_ENUM_ROUTE { index, data: &mut data })?
 			},
+		// === 5: VFS
+		VFS_ENUM_MOUNTS => {
+			let index: usize = args.get()?;
+			let mut data: FreezeMut<::syscall_values::VFSMountInfo> = args.get()?;
+			let mut location: FreezeMut<[u8]> = args.get()?;
+			vfs::get_mount(VFS_ENUM_MOUNTS { index, data: &mut data, location: &mut location })
+			},
 		// === *: Default
 		_ => {
 			log_error!("Unknown syscall {:05x}", call_id);
@@ -59,6 +59,7 @@ fn addr_from_socket(sa: &SocketAddress) -> Result<::network::Address,super::Erro
 	{
 	Err(_) => return Err(super::Error::BadValue),
 	Ok(SocketAddressType::Ipv4) => Ok(::network::Address::Ipv4(make_ipv4(&sa.addr))),
+	Ok(SocketAddressType::Ipv6) => Ok(::network::Address::Ipv6(make_ipv6(&sa.addr))),
 	_ => todo!("Other address socket types - #{}", sa.addr_ty),
 	}
 }
@@ -145,6 +146,7 @@ pub fn new_free_socket(local_address: SocketAddress, remote_mask: crate::values:
 					},
 				_ => todo!("Handle other address types"),
 				},
+			// NOTE: Binding to the IPv6 wildcard (`[::]:port` accepting from `::/0`) also receives IPv4 (dual-stack)
 			SocketPortType::Udp => Ok(crate::objects::new_object(traits::FreeSocketWrapper({
 				let source = addr_from_socket(&local_address).map_err(|_| ::syscall_values::SocketError::InvalidValue)?;
 				let dest = addr_from_socket(&remote_mask.addr).map_err(|_| ::syscall_values::SocketError::InvalidValue)?;
@@ -16,10 +16,12 @@ fn map_tcp_err(e: ::network::tcp::ConnError) -> crate::values::SocketError {
 	S::NoRoute       => D::NoRoute,
 	S::TimedOut      => D::Timeout,
 	S::LocalClosed   => D::SocketClosed,
-	S::RemoteRefused => D::ConnectionReset,
+	S::RemoteRefused => D::ConnectionRefused,
 	S::RemoteClosed  => D::SocketClosed,
 	S::RemoteReset   => D::ConnectionReset,
 	S::NoPortAvailable => todo!("TCP error {:?}", e),
+	S::NoData        => D::NoData,
+	S::NotConnected  => D::NotConnected,
 	}
 }
 fn from_tcp_result(r: Result<usize, ::network::tcp::ConnError>) -> u64 {
@@ -119,7 +121,11 @@ impl crate::objects::Object for TcpSocket
 		{
 		crate::values::NET_CONNSOCK_SHUTDOWN => {
 			let what = crate::values::SocketShutdownSide::try_from(args.get::<u8>()?).map_err(|_| crate::Error::BadValue)?;
-			todo!("NET_CONNSOCK_SHUTDOWN({:?})", what);
+			from_tcp_result(match what
+				{
+				crate::values::SocketShutdownSide::Transmit => self.inner.close(),
+				crate::values::SocketShutdownSide::Receive => self.inner.shutdown_recv(),
+				}.map(|()| 0))
 			},
 		crate::values::NET_CONNSOCK_SEND => {
 			let data: Freeze<[u8]> = args.get()?;
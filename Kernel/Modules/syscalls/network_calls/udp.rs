@@ -9,11 +9,12 @@ fn map_err(e: ::network::udp::Error) -> crate::values::SocketError {
 	use ::network::udp::Error as I;
 	use crate::values::SocketError as O;
 	match e {
-	I::AddressInUse => todo!(),
-	I::UnboundSocket => todo!(),
-	I::InvalidRemote => todo!(),
-	I::IncompatibleAddresses => todo!(),
+	I::AddressInUse => O::AlreadyInUse,
+	I::UnboundSocket => O::InvalidValue,
+	I::InvalidRemote => O::InvalidValue,
+	I::IncompatibleAddresses => O::InvalidValue,
 	I::NoRouteToHost => O::NoRoute,
+	I::ConnectionRefused => O::ConnectionRefused,
 	}
 }
 
@@ -48,14 +49,15 @@ impl super::traits::FreeSocket for Udp {
 	fn recv_from(&self, data: &mut [u8], out_addr: &mut SocketAddress) -> Result<u64, crate::Error> {
 		Ok(crate::from_result(match self.inner.try_recv_from(data)
 		{
-		Some((len,addr,port)) => {
+		Err(e) => Err(map_err(e)),
+		Ok(Some((len,addr,port))) => {
 			log_debug!("recv_from: {} port {} - {} bytes", addr, port, len);
 			out_addr.addr_ty = super::from_addr(&mut out_addr.addr, addr);
 			out_addr.port_ty = crate::values::SocketPortType::Udp as _;
 			out_addr.port = port;
 			Ok(len as u32)
 		},
-		None => Err(crate::values::SocketError::NoData),
+		Ok(None) => Err(crate::values::SocketError::NoData),
 		}))
 	}
 	fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool {
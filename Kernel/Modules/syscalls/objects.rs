@@ -313,6 +313,20 @@ pub fn call_object_val(handle: u32, call: u16, args: &mut Args) -> Result<u64,su
 		obj.handle_syscall_val(call, args)
 		})
 }
+/// Borrow another of this process's objects (e.g. when an object handle is passed as an argument)
+pub fn with_object_ref<T: Object+'static, R>(handle: u32, fcn: impl FnOnce(&T)->Result<R,super::Error>) -> Result<R,super::Error>
+{
+	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
+		match obj.as_any().downcast_ref::<T>()
+		{
+		Some(v) => fcn(v),
+		None => {
+			log_notice!("with_object_ref: #{} is {}, not {}", handle, obj.type_name(), type_name!(T));
+			Err( super::Error::BadValue )
+			},
+		}
+		})
+}
 #[inline(never)]
 pub fn get_class(handle: u32) -> Result<u64, super::Error>
 {
@@ -122,6 +122,21 @@ impl crate::objects::Object for ProtoProcess
 			let handle: u32 = args.get()?;
 			crate::objects::give_object(&self.0, &tag, handle).map(|_| 0)
 			}
+		values::CORE_PROTOPROCESS_SETCREDENTIALS => {
+			let uid: u32 = args.get()?;
+			let gid: u32 = args.get()?;
+			let creds = ::kernel::threads::Credentials { uid, gid };
+			let cur_creds = ::kernel::threads::get_credentials();
+			log_debug!("CORE_PROTOPROCESS_SETCREDENTIALS({})", creds);
+			if cur_creds.can_switch_to(creds) {
+				self.0.set_credentials(creds);
+				Ok(0)
+			}
+			else {
+				log_notice!("CORE_PROTOPROCESS_SETCREDENTIALS: {} cannot switch to {}", cur_creds, creds);
+				Ok(1)
+			}
+			},
 		_ => crate::objects::object_has_no_such_method_ref("threads::ProtoProcess", call),
 		}
 	}
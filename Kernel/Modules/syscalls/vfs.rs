@@ -14,6 +14,8 @@ use ::vfs::handle;
 use ::vfs::node_cache::NodeClass;
 use ::vfs::Path;
 
+unsafe impl crate::args::Pod for values::VFSMetadata { }
+
 fn map_open_mode(v: values::VFSFileOpenMode) -> handle::FileOpenMode {
 	use crate::values::VFSFileOpenMode;
 	use ::vfs::handle::FileOpenMode;
@@ -27,6 +29,10 @@ fn map_open_mode(v: values::VFSFileOpenMode) -> handle::FileOpenMode {
 	}
 }
 
+/// Largest transfer done by a single read/write call, so the byte count fits in an encoded result (below 2^31)
+/// - Larger requests are short reads/writes, the caller repeats for the rest
+const MAX_IO_SIZE: usize = 0x7FFF_F000;
+
 /// Convert a VFS result into an encoded syscall result
 fn to_result<T>(r: Result<T, ::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into({
@@ -39,12 +45,47 @@ fn to_result<T>(r: Result<T, ::vfs::Error>) -> Result<T, u32> {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked        => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", e),
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::NonDirComponent => VFSError::TypeError,
+		Error::RecursionDepthExceeded => VFSError::MalformedPath,
+		Error::DirectoryNotEmpty => VFSError::DirectoryNotEmpty,
+		Error::CrossFilesystem => VFSError::CrossFilesystem,
+		Error::ReadOnlyFilesystem => VFSError::ReadOnlyFilesystem,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::FileLocked,
+		Error::BlockIoError(_)
+		|Error::InconsistentFilesystem => {
+			log_notice!("VFS IO error: {:?}", e);
+			VFSError::IoError
+			},
+		Error::Unknown(reason) => {
+			log_warning!("VFS Error Unknown - '{}'", reason);
+			VFSError::IoError
+			},
 		}
 	}) )
 }
 
+/// Convert VFS metadata into the syscall structure
+fn encode_metadata(m: ::vfs::node::Metadata) -> values::VFSMetadata {
+	let time = |t: Option<::vfs::node::Timestamp>| t.unwrap_or(values::VFSMetadata::NO_TIME);
+	values::VFSMetadata {
+		size: m.size,
+		allocated_size: m.allocated_size,
+		create_time: time(m.create_time),
+		modify_time: time(m.modify_time),
+		access_time: time(m.access_time),
+		change_time: time(m.change_time),
+		owner: m.owner,
+		group: m.group,
+		link_count: m.link_count,
+		mode: m.mode,
+		_pad: 0,
+		}
+}
+
 pub fn init_handles(init_handle: ::vfs::handle::File) {
 	// #1: Read-only root
 	objects::push_as_unclaimed("ro:/", objects::new_object(Dir::new( {
@@ -53,7 +94,7 @@ pub fn init_handles(init_handle: ::vfs::handle::File) {
 		root
 		})) );
 	// #2: Initial file handle
-	objects::new_object( File(init_handle) );
+	objects::new_object( File::new(init_handle) );
 
 	// - Read-write handle to /
 	objects::push_as_unclaimed("RwRoot", objects::new_object( Dir::new( handle::Dir::open(Path::new("/")).unwrap() ) ) );
@@ -72,7 +113,7 @@ impl objects::Object for Node
 	fn try_clone(&self) -> Option<u32> {
 		Some( objects::new_object( Node(self.0.clone()) ) )
 	}
-	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
@@ -86,6 +127,11 @@ impl objects::Object for Node
 				}.into();
 			Ok( v32 as u64 )
 			},
+		values::VFS_NODE_GETMETADATA => {
+			let mut dst: FreezeMut<values::VFSMetadata> = args.get()?;
+			log_debug!("VFS_NODE_GETMETADATA()");
+			Ok( super::from_result(to_result( self.0.get_metadata() ).map(|m| { *dst = encode_metadata(m); 0u32 })) )
+			},
 		_ => objects::object_has_no_such_method_ref("vfs::Node", call),
 		}
 	}
@@ -106,7 +152,7 @@ impl objects::Object for Node
 			log_debug!("VFS_NODE_TOFILE({:?})", mode);
 
 			let objres = to_result(inner.into_file(map_open_mode(mode)))
-				.map( |h| objects::new_object(File(h)) );
+				.map( |h| objects::new_object(File::new(h)) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TODIR => {
@@ -131,39 +177,57 @@ impl objects::Object for Node
 //
 // --------------------------------------------------------------------
 
-struct File(::vfs::handle::File);
+/// File handle, and the mappings made through it
+/// - The handle is only `None` after being taken by `get_file_handle`
+struct File(Option<::vfs::handle::File>, ::kernel::sync::Mutex<Vec<handle::MemoryMapRaw>>);
+impl File
+{
+	fn new(h: ::vfs::handle::File) -> File {
+		File(Some(h), Default::default())
+	}
+	fn file(&self) -> &::vfs::handle::File {
+		self.0.as_ref().expect("File handle taken")
+	}
+	/// Write back and stop tracking all mappings made through this handle (they stay mapped)
+	fn release_maps(&self) {
+		for raw in self.1.lock().drain(..) {
+			// SAFE: `raw` was created by `memory_map` on this handle, and has just been removed from the list
+			unsafe { handle::MemoryMapHandle::from_raw(self.file(), raw).detach(); }
+		}
+	}
+}
+impl ::core::ops::Drop for File
+{
+	fn drop(&mut self) {
+		self.release_maps();
+	}
+}
 impl objects::Object for File
 {
 	fn class(&self) -> u16 { values::CLASS_VFS_FILE }
 	fn as_any(&self) -> &dyn Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( crate::objects::new_object( File(self.0.clone()) ) )
+		Some( crate::objects::new_object( File::new(self.file().clone()) ) )
 	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_FILE_GETSIZE => {
-			Ok( self.0.size() )
+			Ok( self.file().size() )
 			},
 		values::VFS_FILE_READAT => {
 			let ofs: u64 = args.get()?;
 			let mut dest: FreezeMut<[u8]> = args.get()?;
 			log_debug!("File::readat({}, {:p}+{} bytes)", ofs, dest.as_ptr(), dest.len());
-			match self.0.read(ofs, &mut dest)
-			{
-			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall READAT Error {:?}", e),
-			}
+			let len = usize::min(dest.len(), MAX_IO_SIZE);
+			Ok( super::from_result(to_result( self.file().read(ofs, &mut dest[..len]) ).map(|count| count as u32)) )
 			},
 		values::VFS_FILE_WRITEAT => {
 			let ofs: u64 = args.get()?;
 			let src: Freeze<[u8]> = args.get()?;
 			log_debug!("File::writeat({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
-			match self.0.write(ofs, &src)
-			{
-			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
-			}
+			let len = usize::min(src.len(), MAX_IO_SIZE);
+			Ok( super::from_result(to_result( self.file().write(ofs, &src[..len]) ).map(|count| count as u32)) )
 			},
 		values::VFS_FILE_MEMMAP => {
 			let ofs: u64 = args.get()?;
@@ -182,18 +246,58 @@ impl objects::Object for File
 				};
 			log_debug!("VFS_FILE_MEMMAP({:#x}, {:#x}+{}, {:?})", ofs, addr, size, mode);
 			
-			match self.0.memory_map(addr, ofs, size, mode)
-			{
-			Ok(h) => {
-				// TODO: I would like the map handle to be available, but I'd like the user to be able to "forget" it
-				// (so it becomes an indelible part of the address space).
-				// - That would likely need a new system call similar to Drop
-				// - XXX: The handle here has borrow of the file handle, so can't be stored as-is
-				::core::mem::forget(h);
-				Ok(0)
-				},
-			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
-			}
+			let res = to_result( self.file().memory_map(addr, ofs, size, mode) )
+				.map(|h| {
+					// The mapping stays until VFS_FILE_MEMUNMAP (or forever, if the user just deallocates the memory)
+					// - When this handle is closed, the mappings are written back and forgotten
+					self.1.lock().push( h.into_raw() );
+					0u32
+					});
+			Ok( super::from_result(res) )
+			},
+		values::VFS_FILE_MEMSYNC => {
+			let addr: usize = args.get()?;
+			let size: usize = args.get()?;
+			log_debug!("VFS_FILE_MEMSYNC({:#x}+{:#x})", addr, size);
+			let end = addr.checked_add(size).ok_or(Error::BadValue)?;
+			let res = self.1.lock().iter()
+				.filter(|m| m.writeback && m.base < end && addr < m.base + m.len)
+				.map(|m| {
+					let start = usize::max(m.base, addr);
+					let file_ofs = m.first_page * ::kernel::PAGE_SIZE as u64 + (start - m.base) as u64;
+					self.file().memory_sync(file_ofs, usize::min(m.base + m.len, end) - start)
+					})
+				.collect::<Result<(),_>>();
+			Ok( super::from_result(to_result(res).map(|_| 0u32)) )
+			},
+		values::VFS_FILE_MEMUNMAP => {
+			let addr: usize = args.get()?;
+			log_debug!("VFS_FILE_MEMUNMAP({:#x})", addr);
+			let raw = {
+				let mut lh = self.1.lock();
+				match lh.iter().position(|m| m.base <= addr && addr < m.base + m.len)
+				{
+				Some(i) => Some(lh.remove(i)),
+				None => None,
+				}
+				};
+			let res = match raw
+				{
+				// SAFE: `raw` was created by `memory_map` on this handle, and has just been removed from the list
+				Some(raw) => { drop(unsafe { handle::MemoryMapHandle::from_raw(self.file(), raw) }); Ok( () ) },
+				None => Err( ::vfs::Error::InvalidParameter ),
+				};
+			Ok( super::from_result(to_result(res).map(|_| 0u32)) )
+			},
+		values::VFS_FILE_SETSIZE => {
+			let size: u64 = args.get()?;
+			log_debug!("VFS_FILE_SETSIZE({:#x})", size);
+			Ok( super::from_result(to_result( self.file().set_size(size) ).map(|_| 0u32)) )
+			},
+		values::VFS_FILE_GETMETADATA => {
+			let mut dst: FreezeMut<values::VFSMetadata> = args.get()?;
+			log_debug!("VFS_FILE_GETMETADATA()");
+			Ok( super::from_result(to_result( self.file().get_metadata() ).map(|m| { *dst = encode_metadata(m); 0u32 })) )
 			},
 		_ => crate::objects::object_has_no_such_method_ref("vfs::File", call),
 		}
@@ -202,11 +306,40 @@ impl objects::Object for File
 	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
 }
 
+use ::syscall_values::VFS_ENUM_MOUNTS;
+pub fn get_mount(VFS_ENUM_MOUNTS { index, data, location }: VFS_ENUM_MOUNTS) -> u64 {
+	fn copy_str(dst: &mut [u8], src: &[u8]) {
+		let len = ::core::cmp::min(dst.len(), src.len());
+		dst[..len].copy_from_slice(&src[..len]);
+	}
+	match ::vfs::mount::get_mount(index)
+	{
+	None => !0,
+	Some(None) => 1,
+	Some(Some(info)) => {
+		let loc: &[u8] = AsRef::<[u8]>::as_ref(&*info.location);
+		*data = values::VFSMountInfo {
+			location_len: loc.len() as u32,
+			flags: if info.flags.read_only { values::VFSMountInfo::FLAG_READONLY } else { 0 }
+				| if info.flags.no_exec { values::VFSMountInfo::FLAG_NOEXEC } else { 0 },
+			..Default::default()
+			};
+		copy_str(&mut data.filesystem, info.filesystem.as_bytes());
+		copy_str(&mut data.source, info.source.as_ref());
+		copy_str(location, loc);
+		0
+		},
+	}
+}
+
 #[cfg(feature="native")]
 /// Used by the native "kernel" to get a file object for `new_process`
 pub fn get_file_handle(obj: u32) -> Result<::vfs::handle::File, crate::Error> {
 	crate::objects::take_object::<crate::vfs::File>(obj)
-		.map(|f| f.0)
+		.map(|mut f| {
+			f.release_maps();
+			f.0.take().expect("File handle taken")
+			})
 }
 
 
@@ -259,6 +392,71 @@ impl objects::Object for Dir
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_CREATEFILE => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_CREATEFILE({:?})", name);
+			super::from_result(
+				to_result( self.handle.create_file(name) )
+					.map( |h| objects::new_object(File::new(h)) )
+				)
+			},
+		values::VFS_DIR_MKDIR => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_MKDIR({:?})", name);
+			super::from_result(
+				to_result( self.handle.mkdir(name) )
+					.map( |h| objects::new_object(Dir::new(h)) )
+				)
+			},
+		values::VFS_DIR_SYMLINK => {
+			let name: Freeze<[u8]> = args.get()?;
+			let target: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			let target = Path::new(&target);
+			log_debug!("VFS_DIR_SYMLINK({:?}, {:?})", name, target);
+			super::from_result( to_result( self.handle.symlink(name, target) ).map(|_| 0u32) )
+			},
+		values::VFS_DIR_UNLINK => {
+			let name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_UNLINK({:?})", name);
+			super::from_result( to_result( self.handle.unlink(name) ).map(|_| 0u32) )
+			},
+		values::VFS_DIR_RENAME => {
+			let name: Freeze<[u8]> = args.get()?;
+			let new_dir: u32 = args.get()?;
+			let new_name: Freeze<[u8]> = args.get()?;
+
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			let new_name = ::kernel::lib::byte_str::ByteStr::new(&*new_name);
+			log_debug!("VFS_DIR_RENAME({:?}, #{}, {:?})", name, new_dir, new_name);
+			objects::with_object_ref(new_dir, |new_dir: &Dir| {
+				Ok( super::from_result( to_result( self.handle.rename(name, &new_dir.handle, new_name) ).map(|_| 0u32) ) )
+				})?
+			},
+		values::VFS_DIR_WATCH => {
+			log_debug!("VFS_DIR_WATCH()");
+			super::from_result(
+				to_result( self.handle.watch() )
+					.map( |h| objects::new_object(Watch(h)) )
+				)
+			},
+		values::VFS_DIR_RESTRICT => {
+			let uid: u32 = args.get()?;
+			let gid: u32 = args.get()?;
+			let creds = ::kernel::threads::Credentials { uid, gid };
+			log_debug!("VFS_DIR_RESTRICT({})", creds);
+			super::from_result(
+				to_result( self.handle.restrict(creds) )
+					.map( |h| objects::new_object(Dir::new(h)) )
+				)
+			},
 		_ => return crate::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -318,6 +516,68 @@ impl objects::Object for DirIter
 	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
 }
 
+struct Watch(::vfs::watch::Watch);
+impl objects::Object for Watch
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_WATCH }
+	fn as_any(&self) -> &dyn Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		Ok(match call
+		{
+		values::VFS_WATCH_READ => {
+			let mut event: FreezeMut<values::VFSWatchEvent> = args.get()?;
+			let mut name: FreezeMut<[u8]> = args.get()?;
+			log_debug!("VFS_WATCH_READ({:p}+{})", name.as_ptr(), name.len());
+			match self.0.pop()
+			{
+			None => 0,
+			Some(change) => {
+				use ::vfs::watch::ChangeKind;
+				let len = ::core::cmp::min(name.len(), change.name.len());
+				name[..len].copy_from_slice(&change.name.as_bytes()[..len]);
+				*event = values::VFSWatchEvent {
+					kind: match change.kind
+						{
+						ChangeKind::Created => values::VFSChangeKind::Created,
+						ChangeKind::Removed => values::VFSChangeKind::Removed,
+						ChangeKind::RenamedFrom => values::VFSChangeKind::RenamedFrom,
+						ChangeKind::RenamedTo => values::VFSChangeKind::RenamedTo,
+						ChangeKind::Modified => values::VFSChangeKind::Modified,
+						ChangeKind::Overflow => values::VFSChangeKind::Overflow,
+						} as u8,
+					name_len: change.name.len() as u32,
+					..Default::default()
+					};
+				1
+				},
+			}
+			},
+		_ => return crate::objects::object_has_no_such_method_ref("vfs::Watch", call),
+		})
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_CHANGE != 0 {
+			self.0.wait_upon(obj);
+			ret |= values::EV_VFS_WATCH_CHANGE;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_CHANGE != 0 {
+			self.0.clear_wait(obj);
+			if self.0.has_changes() {
+				ret |= values::EV_VFS_WATCH_CHANGE;
+			}
+		}
+		ret
+	}
+}
+
 type DirEnt = (::vfs::node::InodeId, ::kernel::lib::byte_str::ByteString);
 
 struct DirInner {
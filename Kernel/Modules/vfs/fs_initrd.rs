@@ -113,7 +113,7 @@ impl super::mount::Driver for InitrdDriver {
 		}
 	}
 
-	fn mount(&self, vol: VolumeHandle, _self_handle: crate::mount::SelfHandle) -> crate::Result<Box<dyn crate::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _self_handle: crate::mount::SelfHandle, _options: &[&str]) -> crate::Result<Box<dyn crate::mount::Filesystem>> {
 		let mut tmp = [0; 4096];
 		::kernel::futures::block_on(vol.read_blocks(!0, &mut tmp))?;
 		if tmp[..4] != repr::MAGIC_NUMBER.to_le_bytes() {
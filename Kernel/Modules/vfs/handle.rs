@@ -7,25 +7,33 @@
 use ::kernel::prelude::*;
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::kernel::PAGE_SIZE;
-use super::node::{NodeType};
+use super::node::{NodeType,perm};
 use super::node_cache::{CacheHandle};
 use super::Path;
+use super::watch;
+use ::kernel::threads::Credentials;
 
 #[derive(Debug,Clone)]
 /// Open without caring what the file type is (e.g. enumeration)
 pub struct Any {
 	node: CacheHandle,
+	/// Identity that access through this handle is limited to (see [Dir::restrict])
+	restrict: Option<Credentials>,
 }
 #[derive(Debug,Clone)]
 /// Normal file
 pub struct File {
 	node: super::node_cache::CacheHandleFile,
 	mode: FileOpenMode,
+	/// Private copy of modified data (only for `UniqueRW`)
+	cow: Option<::kernel::lib::mem::Arc<super::node_cache::CowFile>>,
 }
 #[derive(Debug,Clone)]
 /// Directory (for enumeration)
 pub struct Dir {
 	node: super::node_cache::CacheHandleDir,
+	/// Identity that access through this handle is limited to (see [Dir::restrict])
+	restrict: Option<Credentials>,
 }
 #[derive(Debug,Clone)]
 /// Symbolic link (allows reading the link contents)
@@ -88,22 +96,26 @@ impl Any
 	pub fn open(path: &Path) -> super::Result<Any> {
 		log_trace!("Any::open({:?})", path);
 		let node = CacheHandle::from_path(path)?;
-		Ok(Any { node: node })
+		Ok(Any { node: node, restrict: None })
 	}
 
 	/// Get the node class of the handle
 	pub fn get_class(&self) -> super::node_cache::NodeClass {
 		self.node.get_class()
 	}
+	/// Get the node's metadata (timestamps, owner, ...)
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.node.get_metadata()
+	}
 	
 	/// Upgrade the handle to a directory handle
 	pub fn into_dir(self) -> super::Result<Dir> {
-		Ok(Dir { node: self.node.into_dir()? })
+		Ok(Dir { node: self.node.into_dir()?, restrict: self.restrict })
 	}
 
 	/// Upgrade the handle to a file handle
 	pub fn into_file(self, mode: FileOpenMode) -> super::Result<File> {
-		Ok(File::from_node(self.node.into_file()?, mode)?)
+		Ok(File::from_node(self.node.into_file()?, mode, self.restrict)?)
 	}
 	
 	/// Upgrade the handle to a symlink handle
@@ -117,11 +129,42 @@ impl Any
 	}
 }
 
+/// Check that the current process has `want` access (see `node::perm`) to a node
+///
+/// If the handle is restricted (see [Dir::restrict]), the restricted identity must also have access.
+pub(crate) fn check_permission(restrict: Option<Credentials>, want: u16, get_meta: impl FnOnce()->super::Result<super::node::Metadata>) -> super::Result<()> {
+	let creds = ::kernel::threads::get_credentials();
+	if want == 0 || (creds.is_root() && restrict.map_or(true, |r| r.is_root())) {
+		return Ok( () );
+	}
+	let meta = get_meta()?;
+	for c in Some(creds).into_iter().chain(restrict)
+	{
+		if !meta.permits(c, want) {
+			log_debug!("Permission denied: {} wants {:o}, mode={:o} {}:{}", c, want, meta.mode, meta.owner, meta.group);
+			return Err(super::Error::PermissionDenied);
+		}
+	}
+	Ok( () )
+}
+
 pub struct MemoryMapHandle<'a>
 {
 	handle: &'a File,
-	base: *mut (),
-	len: usize,
+	raw: MemoryMapRaw,
+}
+#[derive(Debug,Copy,Clone)]
+/// Location of a mapping (see [MemoryMapHandle::into_raw])
+pub struct MemoryMapRaw
+{
+	/// Page-aligned base address
+	pub base: usize,
+	/// Length in bytes (whole pages)
+	pub len: usize,
+	/// File page at the start of the mapping
+	pub first_page: u64,
+	/// Shared writable mapping
+	pub writeback: bool,
 }
 
 impl File
@@ -129,32 +172,84 @@ impl File
 	/// Open the specified path as a file
 	pub fn open(path: &Path, mode: FileOpenMode) -> super::Result<File> {
 		let node = CacheHandle::from_path(path)?.into_file()?;
-		Self::from_node(node, mode)
+		Self::from_node(node, mode, None)
 	}
 
-	fn from_node(node: super::node_cache::CacheHandleFile, mode: FileOpenMode) -> super::Result<File> {
+	fn from_node(node: super::node_cache::CacheHandleFile, mode: FileOpenMode, restrict: Option<Credentials>) -> super::Result<File> {
+		let want = match mode
+			{
+			FileOpenMode::NoDataAccess => 0,
+			FileOpenMode::SharedRO => perm::READ,
+			FileOpenMode::Append => perm::WRITE,
+			FileOpenMode::Execute => perm::EXECUTE,
+			// Writes go to a private copy, so the file itself only needs to be readable
+			FileOpenMode::UniqueRW => perm::READ,
+			FileOpenMode::ExclRW
+			|FileOpenMode::Unsynch => perm::READ|perm::WRITE,
+			};
+		check_permission(restrict, want, || node.get_metadata())?;
+		let mount_flags = node.get_mount_flags();
+		match mode
+		{
+		FileOpenMode::Append
+		|FileOpenMode::ExclRW
+		|FileOpenMode::Unsynch if mount_flags.read_only => return Err(super::Error::ReadOnlyFilesystem),
+		FileOpenMode::Execute if mount_flags.no_exec => return Err(super::Error::PermissionDenied),
+		_ => {},
+		}
 		match mode
 		{
 		FileOpenMode::NoDataAccess => {},
-		// TODO: Check permissions (must be readable in current context)
 		FileOpenMode::SharedRO => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
 		FileOpenMode::Append => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be executable in current context)
 		FileOpenMode::Execute => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
 		FileOpenMode::ExclRW => { node.file_lock_exclusive()?; }
 		FileOpenMode::Unsynch => { node.file_lock_unsynch()?; }
-		FileOpenMode::UniqueRW => todo!("UniqueRW - CoW"),
+		// Unique RW holds the original shared (so it can't change), and writes go to private pages
+		FileOpenMode::UniqueRW => { node.file_lock_shared()?; },
 		}
-		Ok(File { node: node, mode: mode })
+		let cow = match mode
+			{
+			FileOpenMode::UniqueRW => Some(::kernel::lib::mem::Arc::new(super::node_cache::CowFile::new(&node))),
+			_ => None,
+			};
+		Ok(File { node: node, mode: mode, cow: cow })
 	}
 	
 	pub fn size(&self) -> u64 {
-		self.node.get_valid_size()
+		match self.cow
+		{
+		Some(ref cow) => cow.size(),
+		None => self.node.get_valid_size(),
+		}
+	}
+	/// Get the file's metadata (timestamps, owner, ...)
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		let mut rv = self.node.get_metadata()?;
+		if let Some(ref cow) = self.cow {
+			rv.size = cow.size();
+		}
+		Ok(rv)
+	}
+	/// Truncate the file to zero bytes
+	pub fn truncate(&self) -> super::Result<()> {
+		self.set_size(0)?;
+		Ok( () )
 	}
-	pub fn truncate(&self) {
-		todo!("Handle::truncate()");
+	/// Change the size of the file (extending with zeroes), returning the new size
+	pub fn set_size(&self, newsize: u64) -> super::Result<u64> {
+		match self.mode
+		{
+		FileOpenMode::UniqueRW => self.cow.as_ref().unwrap().truncate(newsize),
+		FileOpenMode::ExclRW
+		|FileOpenMode::Unsynch => {
+			self.check_writable()?;
+			let rv = self.node.truncate(newsize)?;
+			watch::notify_file(self.node.get_ids());
+			Ok(rv)
+			},
+		_ => Err(super::Error::PermissionDenied),
+		}
 	}
 
 	/// Read data from the file at the specified offset
@@ -165,25 +260,45 @@ impl File
 		match self.mode
 		{
 		FileOpenMode::NoDataAccess => return Err(super::Error::PermissionDenied),
+		FileOpenMode::UniqueRW => self.cow.as_ref().unwrap().read(&self.node, ofs, dst),
 		_ => self.node.read(ofs, dst),
 		}
 	}
 	/// Write data to the file (offset is ignored if open for appending)
 	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
-		match self.mode
-		{
-		FileOpenMode::NoDataAccess => return Err(super::Error::PermissionDenied),
-		FileOpenMode::SharedRO => return Err(super::Error::PermissionDenied),
-		FileOpenMode::Execute => return Err(super::Error::PermissionDenied),
-		FileOpenMode::Append => self.node.append(src),
-		FileOpenMode::ExclRW
-		|FileOpenMode::UniqueRW
-		|FileOpenMode::Unsynch => self.node.write(ofs, src),
+		let rv = match self.mode
+			{
+			FileOpenMode::NoDataAccess => return Err(super::Error::PermissionDenied),
+			FileOpenMode::SharedRO => return Err(super::Error::PermissionDenied),
+			FileOpenMode::Execute => return Err(super::Error::PermissionDenied),
+			// - Private writes aren't visible to anything else, so aren't reported to watches
+			FileOpenMode::UniqueRW => return self.cow.as_ref().unwrap().write(&self.node, ofs, src),
+			FileOpenMode::Append => { self.check_writable()?; self.node.append(src)? },
+			FileOpenMode::ExclRW
+			|FileOpenMode::Unsynch => { self.check_writable()?; self.node.write(ofs, src)? },
+			};
+		watch::notify_file(self.node.get_ids());
+		Ok(rv)
+	}
+	/// Check that the volume hasn't been remounted read-only since the file was opened
+	fn check_writable(&self) -> super::Result<()> {
+		if self.node.get_mount_flags().read_only {
+			Err(super::Error::ReadOnlyFilesystem)
+		}
+		else {
+			Ok( () )
 		}
 	}
 
 	
 	/// Map a file into the address space
+	///
+	/// The mapping covers every page touched by `address`+`size` (which must have the same offset within a page
+	/// as `ofs`). Data past the end of the file reads as zero, and for private mappings (anything other than
+	/// `WriteBack`) the portion of the final page past the end of the requested region is also zeroed.
+	///
+	/// Pages are shared with any other mappings of the same file, except for private copies (the final partial
+	/// page, and `UniqueRW` handles).
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle<'_>> {
 		log_debug!("memory_map(self={{mode:{:?}}}, address={:#x}, ofs={:#x}, size={:#x}, mode={:?})",
 			self.mode, address, ofs, size, mode);
@@ -205,37 +320,43 @@ impl File
 			FileOpenMode::Execute => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
-		// COW - Execute mode only
+		// COW - Execute mode, or a private copy
 		// - TODO: Could allow COW of readonly files? (as soon as it's written, the page is detached from the file)
 		MemoryMapMode::COW => match self.mode
 			{
 			FileOpenMode::Execute => {},
+			FileOpenMode::UniqueRW => {},
 			//FileOpenMode::SharedRO => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
-		// Writeback - Requires exclusive access to the file (or a copy)
+		// Writeback - Requires write access to the file (pages are shared, so other writers see the changes)
 		MemoryMapMode::WriteBack => match self.mode
 			{
-			//FileOpenMode::ExclRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
-			//FileOpenMode::UniqueRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
+			FileOpenMode::ExclRW
+			|FileOpenMode::Unsynch => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
 		}
 		
-		// TODO: Handle unaligned mappings somehow
-		// - Depends on several qirks:
-		//  > Unaligned address could write to an existing page (converting it to a private) - But how would that interact with existing mappings?
-		//  > Unaligned sizes would usually cause a new anon mapping, but if its unaligned becuase of EOF, it should just be COW as usual
-		assert!(address % PAGE_SIZE == 0, "TODO: Unaligned memory_map (address={})", address);
-		assert!(size % PAGE_SIZE == 0, "TODO: Unaligned memory_map (size={})", size);
 		if address % PAGE_SIZE != (ofs % PAGE_SIZE as u64) as usize {
 			return Err( super::Error::Unknown("memory_map alignment mismatch") );
 		}
-		// - Limit checking (ofs + size must be within size of the file)
-		// TODO: Limit checking
+		if size == 0 {
+			return Err( super::Error::InvalidParameter );
+		}
+		// - Expand the region to cover whole pages
+		let lead = address % PAGE_SIZE;
+		let base = address - lead;
+		let first_page = ofs / PAGE_SIZE as u64;
+		let page_count = ::kernel::lib::num::div_up(lead + size, PAGE_SIZE);
+		// Number of valid bytes in the final page (zero if the region ends on a page boundary)
+		let tail = (lead + size) % PAGE_SIZE;
+		// - Limit checking (the start must be within the file)
+		if ofs > self.size() {
+			return Err( super::Error::InvalidParameter );
+		}
 		// - Reserve the region to be mapped (reserve sticks a zero page in)
-		let page_count = size / PAGE_SIZE;
-		let mut resv = match ::kernel::memory::virt::reserve(address as *mut (), page_count)
+		let mut resv = match ::kernel::memory::virt::reserve(base as *mut (), page_count)
 			{
 			Ok(v) => v,
 			Err(e) => {
@@ -243,24 +364,34 @@ impl File
 				return Err( super::Error::Locked );
 				},
 			};
+		let writeback = match mode { MemoryMapMode::WriteBack => true, _ => false };
 		// - Obtain handles to each cached page, and map into the reservation
 		for i in 0 .. page_count {
-			let page = ofs / PAGE_SIZE as u64 + i as u64;
-			// 1. Search the node for this particular page
-			//let lh = self.page_cache.read();
-			//  - If found, map over region
-			// 2. Drop lock, read data from file, and try again
-			//drop(lh)
-			self.node.read(page * PAGE_SIZE as u64, resv.get_mut_page(i))?;
-			// 3. Acquire write on lock, and attempt to insert a handle to this page
-			//let lh = self.page_cache.write();
-			//match lh.try_insert(pag, self.get_page_handle(i))
-			//{
-			//Ok(_) => {},	// Inserted correctly
-			//Err(h) => {	// Another handle made a page for this first
-			//	resv.map_at(i, h);	// - Map over our original attempt
-			//	},
-			//}
+			let page = first_page + i as u64;
+			if self.cow.is_some() || (i == page_count - 1 && tail != 0 && !writeback) {
+				// Private page, read the data into the placeholder page and zero past the end of the region
+				let valid = if i == page_count - 1 && tail != 0 { tail } else { PAGE_SIZE };
+				let dst = resv.get_mut_page(i);
+				let len = if page * (PAGE_SIZE as u64) < self.size() {
+						self.read(page * PAGE_SIZE as u64, &mut dst[..valid])?
+					}
+					else {
+						0
+					};
+				dst[len..].fill(0);
+			}
+			else {
+				match self.node.get_mapped_page(page, writeback)
+				{
+				Ok(frame) => resv.map_at(i, frame),
+				Err(e) => {
+					if writeback && i > 0 {
+						self.node.release_mapped_writeback(first_page, i);
+					}
+					return Err(e);
+					},
+				}
+			}
 		}
 		resv.finalise( match mode
 			{
@@ -270,13 +401,23 @@ impl File
 			MemoryMapMode::WriteBack => ::kernel::memory::virt::ProtectionMode::UserRW,
 			})
 			.unwrap();
-		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * PAGE_SIZE);
+		log_debug!("- Mapped at {:p} + {:#x}", base as *mut (), page_count * PAGE_SIZE);
 		Ok(MemoryMapHandle {
 			handle: self,
-			base: address as *mut (),
-			len: page_count * PAGE_SIZE,
+			raw: MemoryMapRaw {
+				base: base,
+				len: page_count * PAGE_SIZE,
+				first_page,
+				writeback,
+				},
 			})
 	}
+	/// Write changes in shared writable mappings of the region `ofs`+`size` back to the file
+	pub fn memory_sync(&self, ofs: u64, size: usize) -> super::Result<()> {
+		let first_page = ofs / PAGE_SIZE as u64;
+		let end_page = ::kernel::lib::num::div_up(ofs + size as u64, PAGE_SIZE as u64);
+		self.node.sync_mapped(first_page, (end_page - first_page) as usize)
+	}
 }
 impl ::core::ops::Drop for File
 {
@@ -286,25 +427,62 @@ impl ::core::ops::Drop for File
 		FileOpenMode::NoDataAccess => {},
 		FileOpenMode::SharedRO
 		| FileOpenMode::Append
-		| FileOpenMode::Execute => self.node.file_unlock_shared(),
+		| FileOpenMode::Execute
+		// - Private pages are released when the last handle to the copy is dropped
+		| FileOpenMode::UniqueRW => self.node.file_unlock_shared(),
 		FileOpenMode::ExclRW => self.node.file_unlock_exclusive(),
 		FileOpenMode::Unsynch => self.node.file_unlock_unsync(),
-		FileOpenMode::UniqueRW => todo!("File::drop() - mode={:?}", self.mode),
 		}
 	}
 }
 
+impl<'a> MemoryMapHandle<'a>
+{
+	/// Write changes back to the file (only does anything for `WriteBack` mappings)
+	pub fn sync(&self) -> super::Result<()> {
+		if self.raw.writeback {
+			self.handle.node.sync_mapped(self.raw.first_page, self.raw.len / PAGE_SIZE)?;
+		}
+		Ok( () )
+	}
+	/// Leave the region mapped, returning a description that can be used to unmap it later
+	pub fn into_raw(self) -> MemoryMapRaw {
+		let rv = self.raw;
+		::core::mem::forget(self);
+		rv
+	}
+	/// Write back changes and leave the region mapped, but stop tracking it (later changes aren't written back)
+	pub fn detach(self) {
+		if self.raw.writeback {
+			if let Err(e) = self.sync() {
+				log_warning!("Failed to write back mapping {:#x}+{:#x}: {:?}", self.raw.base, self.raw.len, e);
+			}
+			self.handle.node.release_mapped_writeback(self.raw.first_page, self.raw.len / PAGE_SIZE);
+		}
+		::core::mem::forget(self);
+	}
+	/// Re-create a handle from [MemoryMapHandle::into_raw]
+	///
+	/// UNSAFE: The caller must ensure that the region is still mapped, that `raw` came from a mapping of `handle`'s
+	/// file, and that only one handle is created
+	pub unsafe fn from_raw(handle: &'a File, raw: MemoryMapRaw) -> MemoryMapHandle<'a> {
+		MemoryMapHandle { handle, raw }
+	}
+}
 impl<'a> Drop for MemoryMapHandle<'a>
 {
 	fn drop(&mut self)
 	{
-		let _ = self.handle;
-		assert_eq!(self.len % PAGE_SIZE, 0, "TODO: Handle unaligned lengths in MemoryMapHandle::drop");
-		assert_eq!(self.base as usize % PAGE_SIZE, 0, "TODO: Handle unaligned addresses in MemoryMapHandle::drop");
-		let npages = self.len / PAGE_SIZE;
+		let npages = self.raw.len / PAGE_SIZE;
+		if self.raw.writeback {
+			if let Err(e) = self.sync() {
+				log_warning!("Failed to write back mapping {:#x}+{:#x}: {:?}", self.raw.base, self.raw.len, e);
+			}
+			self.handle.node.release_mapped_writeback(self.raw.first_page, npages);
+		}
 		// SAFE: This is a uniquely owned handle
 		unsafe {
-			::kernel::memory::virt::unmap(self.base, npages);
+			::kernel::memory::virt::unmap(self.raw.base as *mut (), npages);
 		}
 	}
 }
@@ -315,6 +493,22 @@ impl Dir
 	pub fn open(path: &Path) -> super::Result<Dir> {
 		Any::open(path)?.into_dir()
 	}
+
+	/// Obtain a copy of this handle that can only access what `creds` could
+	///
+	/// Access through the returned handle (and any handles opened from it) is checked against both the caller and
+	/// `creds`. The caller (and any existing restriction) must be able to switch to `creds`.
+	pub fn restrict(&self, creds: Credentials) -> super::Result<Dir> {
+		if !::kernel::threads::get_credentials().can_switch_to(creds) {
+			return Err(super::Error::PermissionDenied);
+		}
+		if let Some(r) = self.restrict {
+			if !r.can_switch_to(creds) {
+				return Err(super::Error::PermissionDenied);
+			}
+		}
+		Ok(Dir { node: self.node.clone(), restrict: Some(creds) })
+	}
 	
 	/// Iterate names within the directory
 	pub fn iter(&self) -> DirIter<'_> {
@@ -330,36 +524,79 @@ impl Dir
 		}
 	}
 	
+	/// Check that the current process can add/remove entries in this directory
+	fn check_modify(&self) -> super::Result<()> {
+		if self.node.get_mount_flags().read_only {
+			return Err(super::Error::ReadOnlyFilesystem);
+		}
+		check_permission(self.restrict, perm::WRITE|perm::EXECUTE, || self.node.get_metadata())
+	}
+
 	/// Create a new directory
 	pub fn mkdir(&self, name: impl AsRef<ByteStr>) -> super::Result<Dir> {
+		self.check_modify()?;
 		let node = self.node.create(name.as_ref(), NodeType::Dir)?;
-		Ok( Dir { node: node.into_dir()? } )
+		watch::notify_dir(self.node.get_ids(), watch::ChangeKind::Created, name.as_ref(), Some(node.get_ids().1));
+		Ok( Dir { node: node.into_dir()?, restrict: self.restrict } )
 	}
 	/// Create a new symbolic link
 	pub fn symlink(&self, name: impl AsRef<ByteStr>, target: &Path) -> super::Result<()> {
-		self.node.create(name.as_ref(), NodeType::Symlink(target))?;
+		self.check_modify()?;
+		let node = self.node.create(name.as_ref(), NodeType::Symlink(target))?;
+		watch::notify_dir(self.node.get_ids(), watch::ChangeKind::Created, name.as_ref(), Some(node.get_ids().1));
 		Ok( () )
 	}
 	/// Create a new file (opened exclusively)
 	pub fn create_file(&self, name: impl AsRef<ByteStr>) -> super::Result<File> {
+		self.check_modify()?;
 		let node = self.node.create(name.as_ref(), NodeType::File)?;
-		File::from_node(node.into_file()?, FileOpenMode::ExclRW)
+		watch::notify_dir(self.node.get_ids(), watch::ChangeKind::Created, name.as_ref(), Some(node.get_ids().1));
+		File::from_node(node.into_file()?, FileOpenMode::ExclRW, self.restrict)
+	}
+
+	/// Remove a name from this directory
+	pub fn unlink(&self, name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.check_modify()?;
+		self.node.unlink(name.as_ref())?;
+		watch::notify_dir(self.node.get_ids(), watch::ChangeKind::Removed, name.as_ref(), None);
+		Ok( () )
+	}
+	/// Move an entry to another directory (or to a new name in this directory)
+	pub fn rename(&self, name: impl AsRef<ByteStr>, new_dir: &Dir, new_name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.check_modify()?;
+		new_dir.check_modify()?;
+		self.node.rename(name.as_ref(), &new_dir.node, new_name.as_ref())?;
+		if watch::is_active() {
+			let inode = new_dir.node.open_child(new_name.as_ref()).ok().map(|h| h.get_ids().1);
+			watch::notify_dir(self.node.get_ids(), watch::ChangeKind::RenamedFrom, name.as_ref(), None);
+			watch::notify_dir(new_dir.node.get_ids(), watch::ChangeKind::RenamedTo, new_name.as_ref(), inode);
+		}
+		Ok( () )
+	}
+
+	/// Start watching this directory for changes
+	pub fn watch(&self) -> super::Result<watch::Watch> {
+		check_permission(self.restrict, perm::READ, || self.node.get_metadata())?;
+		watch::Watch::new(self.node.clone())
 	}
 
 	/// Open a child of this node
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<Any> {
+		check_permission(self.restrict, perm::EXECUTE, || self.node.get_metadata())?;
 		let node = self.node.open_child(name)?;
-		Ok(Any { node: node })
+		Ok(Any { node: node, restrict: self.restrict })
 	}
 
+	/// Open a node relative to this directory (EXECUTE permission is checked on each directory traversed)
 	pub fn open_child_path(&self, path: &Path) -> super::Result<Any> {
-		let node = CacheHandle::from_path_at_node(self.node.clone(), path)?;
-		Ok(Any{ node: node })
+		let node = CacheHandle::from_path_at_node(self.node.clone(), path, self.restrict)?;
+		Ok(Any{ node: node, restrict: self.restrict })
 	}
 
 
 	/// RETURN: next position
 	pub fn read_ents(&self, pos: usize, ents: &mut super::node::ReadDirCallback) -> super::Result<usize> {
+		check_permission(self.restrict, perm::READ, || self.node.get_metadata())?;
 		self.node.read_dir(pos, ents)
 	}
 }
@@ -38,6 +38,10 @@ pub enum Error
 	NonDirComponent,
 	/// Symbolic link recursion limit reached
 	RecursionDepthExceeded,
+	/// Directory cannot be removed while it has entries
+	DirectoryNotEmpty,
+	/// Operation would span multiple mounted filesystems (e.g. rename)
+	CrossFilesystem,
 
 
 	/// Block-level IO Error
@@ -79,6 +83,7 @@ pub mod node;
 pub mod node_cache;
 pub mod mount;
 pub mod handle;
+pub mod watch;
 mod fs_initrd;
 mod path;
 mod ramfs;
@@ -102,6 +107,8 @@ fn init()
 		};
 	root.mkdir("system").unwrap();
 	root.mkdir("volumes").unwrap();
-	root.mkdir("temp").unwrap();
+	root.mkdir("tmp").unwrap();
+	// 4. Temporary files get their own (size limited) ramfs
+	mount::mount("/tmp".as_ref(), VolumeHandle::new_ramdisk(0), "ramfs", &["size=16M"]).expect("Unable to mount /tmp");
 }
 
@@ -4,11 +4,12 @@
 // Core/vfs/mount.rs
 //! Mountpoint management
 use ::kernel::prelude::*;
-use super::path::Path;
+use super::path::{Path,PathBuf};
 use super::node::{InodeId,Node};
-use super::node_cache::CacheHandle;
+use super::node_cache::{CacheHandle,CacheHandleDir};
 use ::kernel::sync::RwLock;
 use ::kernel::lib::{LazyStatic,SparseVec,VecMap};
+use ::kernel::lib::byte_str::ByteString;
 
 use ::kernel::metadevs::storage::VolumeHandle;
 
@@ -25,10 +26,49 @@ pub struct SelfHandle(usize);
 /// Internal representation of a mounted volume
 struct MountedVolume
 {
-	mountpoint_node: super::node_cache::CacheHandleDir,
-	fs: Box<dyn Filesystem>,
+	/// Directory that the volume is mounted over (`None` for the root)
+	mountpoint_node: Option<CacheHandleDir>,
+	location: PathBuf,
+	/// Name of the filesystem driver ("bind" for bind mounts)
+	fs_name: &'static str,
+	/// Volume name, or source path for bind mounts
+	source: ByteString,
+	flags: MountFlags,
+	kind: MountKind,
+}
+enum MountKind
+{
+	/// A volume mounted using a filesystem driver
+	Filesystem(Box<dyn Filesystem>),
+	/// A directory from another mount (see [bind])
+	Bind {
+		mount: usize,
+		inode: InodeId,
+	},
 }
 
+/// Generic mount options (parsed from the option list by the VFS, instead of the driver)
+#[derive(Debug,Default,Copy,Clone,PartialEq)]
+pub struct MountFlags
+{
+	/// Deny modification of the volume (`ro`, cleared by `rw`)
+	pub read_only: bool,
+	/// Deny execution of files on the volume (`noexec`, cleared by `exec`)
+	pub no_exec: bool,
+}
+
+/// Description of a mount table entry (see [get_mount])
+#[derive(Debug)]
+pub struct MountInfo
+{
+	pub location: PathBuf,
+	/// Name of the filesystem driver ("bind" for bind mounts)
+	pub filesystem: &'static str,
+	/// Volume name, or source path for bind mounts
+	pub source: ByteString,
+	/// Options in effect (for bind mounts, those of the source)
+	pub flags: MountFlags,
+}
 
 /// Filesystem instance trait (i.e. the instance)
 pub trait Filesystem:
@@ -56,17 +96,19 @@ pub trait Driver:
 	/// Mount the provided volume as this filesystem
 	///
 	/// NOTE: `handle` isn't actually usable until after this function returns
-	fn mount(&self, vol: VolumeHandle, handle: SelfHandle) -> super::Result<Box<dyn Filesystem>>;
+	///
+	/// `options` contains the driver-specific mount options, plus `ro` for read-only mounts (unknown options should be ignored)
+	fn mount(&self, vol: VolumeHandle, handle: SelfHandle, options: &[&str]) -> super::Result<Box<dyn Filesystem>>;
 }
 
 pub struct DriverRegistration(&'static str);
 
 /// Known drivers
 static S_DRIVERS: LazyStatic<RwLock< VecMap<&'static str, &'static dyn Driver> >> = lazystatic_init!();
-/// Mounted volumes
+/// Mounted volumes (mount ID is index+1)
 static S_VOLUMES: LazyStatic<RwLock< SparseVec<MountedVolume> >> = lazystatic_init!();
 /// Root mount
-static S_ROOT_VOLUME: RwLock<Option<Box<dyn Filesystem>>> = RwLock::new(None);
+static S_ROOT_VOLUME: RwLock<Option<MountedVolume>> = RwLock::new(None);
 
 pub fn init()
 {
@@ -74,36 +116,98 @@ pub fn init()
 	S_VOLUMES.prep( || Default::default() );
 }
 
+/// Split the generic options out of a mount option list, returning the remainder (for the driver)
+///
+/// Each entry can contain multiple comma-separated options.
+fn parse_options<'a>(flags: &mut MountFlags, options: &[&'a str]) -> Vec<&'a str>
+{
+	let mut rv = Vec::new();
+	for opt in options.iter().flat_map(|o| o.split(','))
+	{
+		match opt
+		{
+		"" => {},
+		"ro" => flags.read_only = true,
+		"rw" => flags.read_only = false,
+		"noexec" => flags.no_exec = true,
+		"exec" => flags.no_exec = false,
+		_ => rv.push(opt),
+		}
+	}
+	rv
+}
+
+/// Locate the mount ID for the volume mounted at `location`
+fn find_mount(location: &Path) -> Option<usize>
+{
+	if location == Path::new("/") {
+		return Some(0);
+	}
+	let lh = S_VOLUMES.read();
+	(0 .. lh.len()).find(|&i| lh.get(i).map(|v| &*v.location == location).unwrap_or(false)).map(|i| i + 1)
+}
+
+/// Obtain an unused directory to mount on
+fn acquire_mountpoint(location: &Path) -> Result<CacheHandleDir,MountError>
+{
+	if find_mount(location).is_some() {
+		return Err(MountError::MountpointUsed);
+	}
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let nh = nh.into_dir().map_err(|_| MountError::InvalidMountpoint)?;
+	if nh.is_mountpoint() {
+		return Err(MountError::MountpointUsed);
+	}
+	Ok(nh)
+}
+
 /// Mount a volume at the provided location
-// TODO: Parse options
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+///
+/// Generic options (`ro`, `rw`, `noexec`, `exec`) are handled by the VFS, all others are passed to the driver (along
+/// with `ro` if the volume is read-only)
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
+	let mut flags = MountFlags::default();
+	let mut driver_options = parse_options(&mut flags, options);
+	if flags.read_only {
+		driver_options.push("ro");
+	}
+
 	let drivers = S_DRIVERS.read();
 	// 1. (maybe) detect filesystem
-	let driver = if fs == "" {
+	let (fs_name, driver) = if fs == "" {
 			match drivers.iter()
 				.filter_map(|(n,fs)| fs.detect(&vol).ok().map(|r| (r, n, fs)))
 				.max_by_key(|&(l,_,_)| l)
 			{
 			Some((0,_,_)) => return Err(MountError::NoHandler),
-			Some((_,_name,fs)) => fs,
+			Some((_,name,fs)) => (*name, fs),
 			None => return Err(MountError::NoHandler),
 			}
 		}
 		else {
-			match drivers.get(fs)
+			match drivers.iter().find(|(n,_)| **n == fs)
 			{
-			Some(d) => d,
+			Some((name,d)) => (*name, d),
 			None => {
 				log_notice!("Filesystem '{}' not registered", fs);
 				return Err(MountError::UnknownFilesystem);
 				},
 			}
 		};
+	let source: ByteString = vol.name().bytes().collect();
 	
 	if location == Path::new("/")
 	{
-		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
+		// NOTE: The root can't be replaced, but its options can be changed with `remount`
+		if S_ROOT_VOLUME.read().is_some() {
+			return Err(MountError::MountpointUsed);
+		}
+		let fs: Box<_> = match driver.mount(vol, SelfHandle(0), &driver_options)
 			{
 			Ok(v) => v,
 			Err(e) => {
@@ -113,51 +217,202 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 			};
 		let mut lh = S_ROOT_VOLUME.write();
 		if lh.is_some() {
-			log_warning!("TODO: Support remounting /");
 			return Err(MountError::MountpointUsed);
 		}
-		*lh = Some(fs);
+		*lh = Some(MountedVolume {
+			mountpoint_node: None,
+			location: PathBuf::from(location),
+			fs_name, source, flags,
+			kind: MountKind::Filesystem(fs),
+			});
 	}
 	else
 	{
 		// 2. Acquire mountpoint
-		let nh = match CacheHandle::from_path(location)
-			{
-			Ok(nh) => nh,
-			Err(_) => return Err(MountError::InvalidMountpoint),
-			};
-		let nh = nh.into_dir().map_err(|_| MountError::InvalidMountpoint)?;
-		if nh.is_mountpoint() {
-			return Err(MountError::MountpointUsed);
-		}
+		let nh = acquire_mountpoint(location)?;
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		let vidx = S_VOLUMES.write().insert(MountedVolume {
+			mountpoint_node: Some(nh),
+			location: PathBuf::from(location),
+			fs_name, source, flags,
+			kind: MountKind::Filesystem(Box::new(NullFs)),
+			});
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		let fs = match driver.mount(vol, SelfHandle(vidx + 1), &driver_options)
 			{
 			Ok(v) => v,
 			Err(e) => {
 				log_error!("Mount failure: {:?}", e);
+				// Release the reservation (dropping the entry outside the lock, as it holds a node)
+				let v = S_VOLUMES.write().remove(vidx);
+				drop(v);
 				return Err(MountError::CallFailed)
 				},
 			};
 
 		// 5. Store and bind to mountpoint
+		let mut lh = S_VOLUMES.write();
+		lh[vidx].kind = MountKind::Filesystem(fs);
+		if lh[vidx].mountpoint_node.as_ref().unwrap().mount(vidx + 1) == false {
+			let v = lh.remove(vidx);
+			drop(lh);
+			drop(v);
+			return Err(MountError::MountpointUsed);
+		}
+	}
+
+	Ok( () )
+}
+
+/// Make the directory `source` (and everything below it) also visible at `location`
+///
+/// The bound directory is the same node as the source, so it shares the source volume's options.
+pub fn bind(location: &Path, source: &Path) -> Result<(),MountError>
+{
+	let src = CacheHandle::from_path(source).map_err(|_| MountError::InvalidSource)?;
+	let src = src.into_dir().map_err(|_| MountError::InvalidSource)?;
+	let (src_mount, src_inode) = src.get_ids();
+	let nh = acquire_mountpoint(location)?;
+	// Binding a directory over itself would cause an infinite loop when following the mount
+	if nh.get_ids() == (src_mount, src_inode) {
+		return Err(MountError::InvalidMountpoint);
+	}
+
+	let mut lh = S_VOLUMES.write();
+	let vidx = lh.insert(MountedVolume {
+		mountpoint_node: Some(nh),
+		location: PathBuf::from(location),
+		fs_name: "bind",
+		source: AsRef::<[u8]>::as_ref(source).iter().copied().collect(),
+		flags: MountFlags::default(),
+		kind: MountKind::Bind { mount: src_mount, inode: src_inode },
+		});
+	if lh[vidx].mountpoint_node.as_ref().unwrap().mount(vidx + 1) == false {
+		let v = lh.remove(vidx);
+		drop(lh);
+		drop(v);
+		return Err(MountError::MountpointUsed);
+	}
+	Ok( () )
+}
+
+/// Remove the mount at `location`
+///
+/// Fails with `Busy` if any node on the volume is in use (including by other mounts, or bind mounts of it)
+pub fn unmount(location: &Path) -> Result<(),MountError>
+{
+	let id = match find_mount(location)
 		{
-			let mut lh = S_VOLUMES.write();
-			lh[vidx].fs = fs;
-			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
-				lh.remove(vidx);
-				return Err(MountError::MountpointUsed);
-			}
+		Some(0) => return Err(MountError::Busy),
+		Some(id) => id,
+		None => return Err(MountError::NotMounted),
+		};
+	let (mountpoint_node, is_bind) = {
+		let lh = S_VOLUMES.read();
+		let bound = (0 .. lh.len())
+			.filter_map(|i| lh.get(i))
+			.any(|v| match v.kind { MountKind::Bind { mount, .. } => mount == id, _ => false });
+		if bound {
+			return Err(MountError::Busy);
 		}
+		let v = &lh[id - 1];
+		(v.mountpoint_node.clone().unwrap(), match v.kind { MountKind::Bind { .. } => true, _ => false })
+		};
+
+	// Detach from the mountpoint first (so new lookups can't enter the volume), then check for existing users
+	// - Bind mounts don't have nodes of their own
+	if !mountpoint_node.unmount(id) {
+		return Err(MountError::NotMounted);
+	}
+	if !is_bind {
+		if super::node_cache::mount_in_use(id) {
+			mountpoint_node.mount(id);
+			return Err(MountError::Busy);
+		}
+		super::node_cache::forget_mount_data(id);
 	}
 
+	// Drop the volume (releasing the filesystem and volume handle) outside the lock
+	let v = S_VOLUMES.write().remove(id - 1);
+	drop(v);
+	log_log!("Unmounted {:?}", location);
 	Ok( () )
 }
+
+/// Change the generic options (`ro`/`rw`, `noexec`/`exec`) of a mounted volume
+///
+/// Switching to read-only fails with `Busy` if a file on the volume is open for writing, and writes back all cached
+/// data.
+pub fn remount(location: &Path, options: &[&str]) -> Result<(),MountError>
+{
+	let id = find_mount(location).ok_or(MountError::NotMounted)?;
+	let h = Handle(id);
+	let old_flags = h.with_volume(|v| match v.kind
+		{
+		MountKind::Filesystem(_) => Ok(v.flags),
+		// Bind mounts share the source's options
+		MountKind::Bind { .. } => Err(MountError::InvalidOptions),
+		})?;
+	let mut flags = old_flags;
+	if parse_options(&mut flags, options).len() > 0 {
+		// Driver options can only be set when mounting
+		return Err(MountError::InvalidOptions);
+	}
+
+	// Apply the flags before checking for writers, so new writers are refused
+	h.set_flags(flags);
+	if flags.read_only && !old_flags.read_only {
+		if super::node_cache::mount_has_writers(id) {
+			h.set_flags(old_flags);
+			return Err(MountError::Busy);
+		}
+		super::node_cache::sync_mount(id);
+	}
+	Ok( () )
+}
+
+/// Get information on the mount with the specified ID (zero is the root)
+///
+/// Returns `None` past the end of the table, and `Some(None)` for unused IDs
+pub fn get_mount(id: usize) -> Option<Option<MountInfo>>
+{
+	let get_info = |v: &MountedVolume| MountInfo {
+		location: PathBuf::from(&*v.location),
+		filesystem: v.fs_name,
+		source: v.source.clone(),
+		flags: v.flags,
+		};
+	let (info, bind_mount) = if id == 0 {
+			match *S_ROOT_VOLUME.read()
+			{
+			Some(ref v) => (get_info(v), None),
+			None => return Some(None),
+			}
+		}
+		else {
+			let lh = S_VOLUMES.read();
+			if id - 1 >= lh.len() {
+				return None;
+			}
+			match lh.get(id - 1)
+			{
+			// Skip volumes that are still being mounted
+			Some(v) if v.mountpoint_node.as_ref().map(|n| n.is_mountpoint()) == Some(true) => {
+				(get_info(v), match v.kind { MountKind::Bind { mount, .. } => Some(mount), _ => None })
+				},
+			_ => return Some(None),
+			}
+		};
+	// Bind mounts use the source's options
+	Some(Some(match bind_mount
+		{
+		Some(m) => MountInfo { flags: Handle(m).flags(), ..info },
+		None => info,
+		}))
+}
 #[derive(Debug)]
 pub enum MountError
 {
@@ -166,6 +421,14 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	/// A bind mount's source wasn't a directory
+	InvalidSource,
+	/// Nothing is mounted at the specified location
+	NotMounted,
+	/// The volume is in use
+	Busy,
+	/// Unsupported options were passed to `remount`
+	InvalidOptions,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -176,6 +439,10 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::InvalidSource => "The bind source was not a directory",
+			&MountError::NotMounted => "Nothing is mounted at the specified location",
+			&MountError::Busy => "The volume is in use",
+			&MountError::InvalidOptions => "The options can't be applied to this mount",
 			})
 	}
 }
@@ -197,7 +464,13 @@ impl DriverRegistration
 }
 impl Drop for DriverRegistration {
 	fn drop(&mut self) {
-		log_error!("TODO: De-register VFS driver {:?}", self.0);
+		let is_used = |v: &MountedVolume| v.fs_name == self.0;
+		let in_use = S_ROOT_VOLUME.read().as_ref().map(is_used).unwrap_or(false)
+			|| S_VOLUMES.read().iter().any(is_used);
+		if in_use {
+			log_error!("VFS driver {:?} de-registered while volumes are still mounted", self.0);
+		}
+		S_DRIVERS.write().remove(&self.0);
 	}
 }
 
@@ -221,19 +494,47 @@ impl Handle
 	pub fn root_inode(&self) -> InodeId {
 		self.with_fs(|fs| fs.root_inode())
 	}
+	/// Mount ID and inode of the directory that should be visible at this mount's location
+	pub fn root_ids(&self) -> (usize, InodeId) {
+		match self.with_volume(|v| match v.kind { MountKind::Bind { mount, inode } => Some((mount, inode)), _ => None })
+		{
+		Some(ids) => ids,
+		None => (self.0, self.root_inode()),
+		}
+	}
+	/// Generic options in effect for this mount
+	pub fn flags(&self) -> MountFlags {
+		self.with_volume(|v| v.flags)
+	}
 	
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
 		self.with_fs(|fs| fs.get_node_by_inode(id))
 	}
 
-	fn with_fs<R, F: FnOnce(&dyn Filesystem)->R>(&self, f: F) -> R {
+	fn set_flags(&self, flags: MountFlags) {
+		if self.0 == 0 {
+			S_ROOT_VOLUME.write().as_mut().unwrap().flags = flags;
+		}
+		else {
+			S_VOLUMES.write()[self.0 - 1].flags = flags;
+		}
+	}
+	fn with_volume<R, F: FnOnce(&MountedVolume)->R>(&self, f: F) -> R {
 		if self.0 == 0 {
-			f(&**S_ROOT_VOLUME.read().as_ref().unwrap())
+			f(S_ROOT_VOLUME.read().as_ref().unwrap())
 		}
 		else {
-			f(&*S_VOLUMES.read().get(self.0 - 1).unwrap().fs)
+			f(S_VOLUMES.read().get(self.0 - 1).unwrap())
 		}
 	}
+	fn with_fs<R, F: FnOnce(&dyn Filesystem)->R>(&self, f: F) -> R {
+		self.with_volume(|v| match v.kind
+			{
+			MountKind::Filesystem(ref fs) => f(&**fs),
+			// Nodes are never opened on a bind mount's ID (`root_ids` redirects to the source mount)
+			MountKind::Bind { .. } => panic!("Handle::with_fs - ID {} is a bind mount", self.0),
+			})
+	}
 }
 
 
@@ -243,4 +544,3 @@ impl SelfHandle
 		CacheHandle::from_ids(self.0, inode)
 	}
 }
-
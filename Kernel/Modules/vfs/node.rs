@@ -17,12 +17,90 @@ pub enum NodeType<'a> {
 	Symlink(&'a super::Path),
 }
 
+/// Timestamp (seconds since 1970-01-01 00:00 UTC)
+pub type Timestamp = i64;
+
+/// Node metadata, as returned by `NodeBase::get_metadata`
+///
+/// Fields that the filesystem doesn't store are left as zero/`None`
+#[derive(Debug,Default,Clone)]
+pub struct Metadata
+{
+	/// Size of the file's data in bytes (filled by the VFS, zero for non-files)
+	pub size: u64,
+	/// Number of bytes of storage used by the node
+	pub allocated_size: u64,
+	/// Number of directory entries referring to this node
+	pub link_count: u32,
+	/// Owning user ID
+	pub owner: u32,
+	/// Owning group ID
+	pub group: u32,
+	/// POSIX-style permission bits (`0o7777` mask)
+	pub mode: u16,
+
+	/// Creation time
+	pub create_time: Option<Timestamp>,
+	/// Last modification of the node's data
+	pub modify_time: Option<Timestamp>,
+	/// Last access to the node's data
+	pub access_time: Option<Timestamp>,
+	/// Last change to the node's metadata
+	pub change_time: Option<Timestamp>,
+}
+
+/// Permission bits (in the "other" position, shifted for owner/group by `Metadata::permits`)
+pub mod perm {
+	pub const READ: u16 = 4;
+	pub const WRITE: u16 = 2;
+	pub const EXECUTE: u16 = 1;
+}
+impl Metadata
+{
+	/// Check if the given user/group has all of the requested `perm` bits
+	pub fn permits(&self, creds: ::kernel::threads::Credentials, want: u16) -> bool {
+		if creds.is_root() {
+			return true;
+		}
+		let bits = if creds.uid == self.owner {
+				self.mode >> 6
+			}
+			else if creds.gid == self.group {
+				self.mode >> 3
+			}
+			else {
+				self.mode
+			};
+		bits & want == want
+	}
+}
+
+/// Convert a calendar date and time (UTC, proleptic Gregorian) into a timestamp
+pub fn timestamp_from_date(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Timestamp
+{
+	// Days since 1970-01-01, using a March-based year (so the leap day is at the end)
+	let (y, m) = if month <= 2 { (year - 1, month as i64 + 9) } else { (year, month as i64 - 3) };
+	let era = y.div_euclid(400);
+	let yoe = y - era * 400;
+	let doy = (153 * m + 2) / 5 + day as i64 - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	let days = era * 146097 + doe - 719468;
+
+	days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not necessarily same as `self`, up to the driver)
 	fn get_any(&self) -> &dyn Any;
+	/// Return the node's metadata (timestamps, ownership, ...)
+	///
+	/// The default is for filesystems with no permission information, and allows all access
+	fn get_metadata(&self) -> Result<Metadata> {
+		Ok( Metadata { link_count: 1, mode: 0o777, ..Default::default() } )
+	}
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
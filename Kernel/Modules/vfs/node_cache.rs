@@ -14,10 +14,70 @@ static S_NODE_CACHE: LazyMutex<::kernel::lib::VecMap<(usize,InodeId),Box<CachedN
 
 mod file;
 mod dir;
+mod cow;
+mod pages;
+
+pub use self::cow::CowFile;
 
 pub fn init()
 {
 	S_NODE_CACHE.init(|| Default::default());
+	pages::init();
+}
+
+/// Release cached file data for a mountpoint (e.g. when it's unmounted)
+pub fn forget_mount_data(mountpoint: usize)
+{
+	pages::forget_mount(mountpoint);
+}
+
+/// Check if any nodes on a mountpoint are in use
+pub fn mount_in_use(mountpoint: usize) -> bool
+{
+	S_NODE_CACHE.lock().iter().any(|(k, _)| k.0 == mountpoint)
+}
+
+/// Check if any files on a mountpoint are open for writing
+pub fn mount_has_writers(mountpoint: usize) -> bool
+{
+	S_NODE_CACHE.lock().iter()
+		.filter(|(k, _)| k.0 == mountpoint)
+		.any(|(_, n)| match n.node
+			{
+			CacheNodeInfo::File(ref info) => info.is_open_for_write(),
+			_ => false,
+			})
+}
+
+/// Write back cached data for all open files on a mountpoint
+pub fn sync_mount(mountpoint: usize)
+{
+	sync_files(Some(mountpoint))
+}
+
+/// Write back cached data for all open files
+fn sync_all_files()
+{
+	sync_files(None)
+}
+
+fn sync_files(mountpoint: Option<usize>)
+{
+	// Take a reference to each file with the cache locked, then write back with it unlocked
+	let handles: Vec<CacheHandleFile> = S_NODE_CACHE.lock().iter()
+		.filter(|(k, _)| mountpoint.map_or(true, |m| k.0 == m))
+		.filter(|(_, n)| matches!(n.node, CacheNodeInfo::File(_)))
+		.map(|(&(mountpt, inode), n)| {
+			n.refcount.fetch_add(1, atomic::Ordering::Relaxed);
+			CacheHandleFile(CacheHandle { mountpt, inode, ptr: &**n })
+			})
+		.collect();
+	for h in handles
+	{
+		if let Err(e) = h.sync() {
+			log_warning!("Writeback of {}:{:#x} failed: {:?}", h.0.mountpt, h.0.inode, e);
+		}
+	}
 }
 
 #[derive(Debug,PartialEq)]
@@ -42,11 +102,12 @@ enum CacheNodeInfo
 		fsnode: Box<dyn super::node::Special>
 		},
 }
-impl_from!{
-	From<super::node::Node>(v) for CacheNodeInfo {
+impl CacheNodeInfo
+{
+	fn new(v: super::node::Node, mountpoint: usize, inode: InodeId) -> CacheNodeInfo {
 		match v
 		{
-		super::node::Node::File(f) => CacheNodeInfo::File(file::CacheNodeInfoFile::new(f)),
+		super::node::Node::File(f) => CacheNodeInfo::File(file::CacheNodeInfoFile::new(f, pages::get(mountpoint, inode))),
 		super::node::Node::Dir(f) => CacheNodeInfo::Dir(dir::CacheNodeInfoDir::new(f)),
 		super::node::Node::Symlink(f) => CacheNodeInfo::Symlink { target: f.read(), fsnode: f },
 		super::node::Node::Special(f) => CacheNodeInfo::Special { fsnode: f },
@@ -104,6 +165,28 @@ impl Clone for CacheHandle
 	}
 }
 
+impl Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		// Decrement with the cache locked, so a concurrent `from_ids` can't resurrect the node
+		let node = {
+			let mut lh = S_NODE_CACHE.lock();
+			// SAFE: self.ptr is valid until the entry is removed (which only happens here)
+			if unsafe { (*self.ptr).refcount.fetch_sub(1, atomic::Ordering::Relaxed) } == 1 {
+				lh.remove( &(self.mountpt, self.inode) )
+			}
+			else {
+				None
+			}
+			};
+		// Drop the node outside the lock (the filesystem may need to open other nodes to clean up)
+		if let Some(node) = node {
+			log_trace!("CacheHandle::drop - Released {}:{:#x}", self.mountpt, self.inode);
+			drop(node);
+		}
+	}
+}
+
 impl CacheHandle
 {
 	/// Obtain a node handle using a mountpoint ID and inode number
@@ -121,7 +204,7 @@ impl CacheHandle
 			Entry::Vacant(e) =>
 				match super::mount::Handle::from_id(mountpoint).get_node(inode)
 				{
-				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
+				Some(node) => e.insert(Box::new(CachedNode { node: CacheNodeInfo::new(node, mountpoint, inode), refcount: AtomicUsize::new(1) })),
 				None => return Err( super::Error::NotFound ),
 				},
 			};
@@ -142,7 +225,8 @@ impl CacheHandle
 			let new_mountpoint = info.mountpoint.load(atomic::Ordering::Relaxed);
 			if new_mountpoint != 0 {
 				// Then recurse (hopefully only once) with the new mountpoint
-				let new_inode = super::mount::Handle::from_id(new_mountpoint).root_inode();
+				// - Bind mounts resolve to a node on another mount, so use the IDs that the mount provides
+				let (new_mountpoint, new_inode) = super::mount::Handle::from_id(new_mountpoint).root_ids();
 				log_trace!("CacheHandle::from_ids({},{}) => Mount {}, {}",
 					mountpoint, inode,  new_mountpoint, new_inode);
 				return CacheHandle::from_ids(new_mountpoint, new_inode);
@@ -155,7 +239,9 @@ impl CacheHandle
 	
 	
 	/// Obtain a node handle using a parent directory node and a relative path
-	pub fn from_path_at_node(node_h: CacheHandleDir, path: &Path) -> super::Result<CacheHandle>
+	///
+	/// `restrict` is an additional identity that must be allowed to traverse each directory (see `handle::Dir::restrict`)
+	pub fn from_path_at_node(node_h: CacheHandleDir, path: &Path, restrict: Option<::kernel::threads::Credentials>) -> super::Result<CacheHandle>
 	{
 		let mut node_h = node_h.0;
 		log_function!("CacheHandle::from_path_at_node(node_h={:?}, {:?})", node_h, path);
@@ -177,7 +263,7 @@ impl CacheHandle
 					//log_debug!("- seg={:?} : SYMLINK {:?}", seg, name);
 					let linkpath = Path::new(&target);
 					if linkpath.is_absolute() {
-						CacheHandle::from_path(linkpath)?
+						CacheHandle::from_path_at_node(CacheHandle::root()?, linkpath, restrict)?
 					}
 					else {
 						//TODO: To make this work (or any path-relative symlink), the current position in
@@ -204,6 +290,8 @@ impl CacheHandle
 				{
 				CacheNodeInfo::Dir(ref info) => {
 					//log_debug!("- seg={:?} : DIR", seg);
+					// Searching a directory needs EXECUTE permission
+					super::handle::check_permission(restrict, super::node::perm::EXECUTE, || node_h.get_metadata())?;
 					let next_id = match info.fsnode.lookup(seg)
 						{
 						Ok(v) => v,
@@ -231,11 +319,14 @@ impl CacheHandle
 			return Err(super::Error::MalformedPath);
 		}
 
-		// Acquire the root vnode
-		let mph = super::mount::Handle::from_id(0);
-		let node_h = CacheHandle::from_ids( mph.id(), mph.root_inode() )?;
+		CacheHandle::from_path_at_node(CacheHandle::root()?, path, None)
+	}
 
-		CacheHandle::from_path_at_node(node_h.into_dir()?, path)
+	/// Acquire the root vnode
+	fn root() -> super::Result<CacheHandleDir>
+	{
+		let mph = super::mount::Handle::from_id(0);
+		CacheHandle::from_ids( mph.id(), mph.root_inode() )?.into_dir()
 	}
 	
 	pub fn get_class(&self) -> NodeClass {
@@ -259,6 +350,22 @@ impl CacheHandle
 	pub fn is_file(&self) -> bool {
 		self.get_class() == NodeClass::File
 	}
+	/// Mount ID and inode number of this node
+	pub fn get_ids(&self) -> (usize, InodeId) {
+		(self.mountpt, self.inode)
+	}
+	/// Generic options of the volume containing this node
+	pub fn get_mount_flags(&self) -> super::mount::MountFlags {
+		super::mount::Handle::from_id(self.mountpt).flags()
+	}
+	/// Write back cached data (if this is a file)
+	pub fn sync_data(&self) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInfo::File(_) => CacheHandleFile(self.clone()).sync(),
+		_ => Ok( () ),
+		}
+	}
 	pub fn into_file(self) -> super::Result<CacheHandleFile> {
 		match self.get_class() {
 		NodeClass::File => Ok(CacheHandleFile(self)),
@@ -269,6 +376,24 @@ impl CacheHandle
 		self.get_class() == NodeClass::Symlink
 	}
 
+	/// Obtain the filesystem's node (used when passing it back to the filesystem, e.g. `Dir::link`)
+	pub fn get_node_base(&self) -> &dyn super::node::NodeBase {
+		match self.as_ref()
+		{
+		&CacheNodeInfo::Dir(ref inner) => &*inner.fsnode,
+		&CacheNodeInfo::File(ref inner) => &*inner.fsnode,
+		&CacheNodeInfo::Special { ref fsnode, .. } => &**fsnode,
+		&CacheNodeInfo::Symlink { ref fsnode, .. } => &**fsnode,
+		}
+	}
+	/// Obtain the node's metadata (with the size filled in for files)
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		let mut rv = self.get_node_base().get_metadata()?;
+		if let CacheNodeInfo::File(ref inner) = *self.as_ref() {
+			rv.size = inner.fsnode.size();
+		}
+		Ok(rv)
+	}
 	pub fn get_node_any(&self) -> &dyn Any {
 		match self.as_ref()
 		{
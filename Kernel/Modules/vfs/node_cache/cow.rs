@@ -0,0 +1,162 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/vfs/node_cache/cow.rs
+//! Private copy-on-write view of a file (used by `FileOpenMode::UniqueRW`)
+use ::kernel::prelude::*;
+use ::kernel::PAGE_SIZE;
+use ::kernel::lib::VecMap;
+use ::kernel::memory::phys::FrameHandle;
+use ::kernel::memory::page_cache::S_PAGE_CACHE;
+use super::CacheHandleFile;
+use crate as vfs;
+
+/// Private modifications to a file
+///
+/// Unmodified pages are read from the backing file (which is held with a shared lock, so it can't change), and the
+/// first write to a page copies it into a private frame. The backing file is never written.
+pub struct CowFile
+{
+	inner: ::kernel::sync::Mutex<CowFileInner>,
+}
+struct CowFileInner
+{
+	/// Size of the private view
+	size: u64,
+	/// Backing data at or after this offset has been truncated away, and reads as zero
+	backing_limit: u64,
+	/// Privately modified pages, indexed by page number
+	pages: VecMap<u64,FrameHandle>,
+}
+
+impl CowFile
+{
+	pub fn new(node: &CacheHandleFile) -> CowFile {
+		let size = node.get_valid_size();
+		CowFile {
+			inner: ::kernel::sync::Mutex::new(CowFileInner {
+				size: size,
+				backing_limit: size,
+				pages: VecMap::new(),
+				}),
+		}
+	}
+
+	/// Size of the private view
+	pub fn size(&self) -> u64 {
+		self.inner.lock().size
+	}
+
+	/// Change the size of the private view (extending with zeroes)
+	pub fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		let mut lh = self.inner.lock();
+		if newsize < lh.size {
+			// Drop private pages past the end, and zero the tail of the last page (so it reads as zero if extended)
+			let first_free = ::kernel::lib::num::div_up(newsize, PAGE_SIZE as u64);
+			let to_free: Vec<u64> = lh.pages.iter().map(|(&k,_)| k).filter(|&k| k >= first_free).collect();
+			for k in &to_free {
+				lh.pages.remove(k);
+			}
+			let tail_ofs = (newsize % PAGE_SIZE as u64) as usize;
+			if tail_ofs != 0 {
+				if let Some(frame) = lh.pages.get(&(newsize / PAGE_SIZE as u64)) {
+					let mut mapping = S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::OutOfMemory)?;
+					mapping.data_mut()[tail_ofs..].fill(0);
+				}
+			}
+			lh.backing_limit = u64::min(lh.backing_limit, newsize);
+		}
+		lh.size = newsize;
+		Ok(newsize)
+	}
+
+	pub fn read(&self, node: &CacheHandleFile, ofs: u64, dst: &mut [u8]) -> vfs::Result<usize> {
+		let lh = self.inner.lock();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let len = ::core::cmp::min(dst.len() as u64, lh.size - ofs) as usize;
+		let mut pos = 0;
+		while pos < len
+		{
+			let cur = ofs + pos as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, len - pos);
+			let dst = &mut dst[pos..][..bytes];
+			match lh.pages.get(&(cur / PAGE_SIZE as u64))
+			{
+			Some(frame) => {
+				let mapping = S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::OutOfMemory)?;
+				dst.copy_from_slice(&mapping.data()[page_ofs..][..bytes]);
+				},
+			None => lh.read_backing(node, cur, dst)?,
+			}
+			pos += bytes;
+		}
+		Ok(len)
+	}
+
+	pub fn write(&self, node: &CacheHandleFile, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
+		let mut lh = self.inner.lock();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut pos = 0;
+		while pos < src.len()
+		{
+			let cur = ofs + pos as u64;
+			let page = cur / PAGE_SIZE as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, src.len() - pos);
+			if lh.pages.get(&page).is_none() {
+				// First write to this page, take a private copy of the backing data
+				let mut new_page = match S_PAGE_CACHE.create()
+					{
+					Ok(v) => v,
+					// Return a short write if some data was written
+					Err(_) if pos > 0 => break,
+					Err(_) => return Err(vfs::Error::OutOfMemory),
+					};
+				lh.read_backing(node, page * PAGE_SIZE as u64, new_page.data_mut())?;
+				lh.pages.insert(page, new_page.get_frame_handle());
+			}
+			let mut mapping = S_PAGE_CACHE.map(lh.pages.get(&page).unwrap()).map_err(|_| vfs::Error::OutOfMemory)?;
+			mapping.data_mut()[page_ofs..][..bytes].copy_from_slice(&src[pos..][..bytes]);
+			pos += bytes;
+			lh.size = u64::max(lh.size, cur + bytes as u64);
+		}
+		Ok(pos)
+	}
+}
+
+impl ::core::fmt::Debug for CowFile
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		let lh = self.inner.lock();
+		write!(f, "CowFile {{ size: {:#x}, {} private pages }}", lh.size, lh.pages.iter().count())
+	}
+}
+
+impl CowFileInner
+{
+	/// Read unmodified data from the backing file, zero filling past the visible end of the backing data
+	fn read_backing(&self, node: &CacheHandleFile, ofs: u64, dst: &mut [u8]) -> vfs::Result<()> {
+		let valid = if ofs >= self.backing_limit {
+				0
+			}
+			else {
+				::core::cmp::min(dst.len() as u64, self.backing_limit - ofs) as usize
+			};
+		let mut pos = 0;
+		while pos < valid
+		{
+			match node.read(ofs + pos as u64, &mut dst[pos..valid])?
+			{
+			0 => break,
+			n => pos += n,
+			}
+		}
+		dst[pos..].fill(0);
+		Ok( () )
+	}
+}
@@ -3,7 +3,7 @@ use ::kernel::prelude::*;
 use super::{CacheHandleDir};
 use crate as vfs;
 use ::core::sync::atomic::{self,AtomicUsize};
-use ::kernel::lib::byte_str::{ByteStr};
+use ::kernel::lib::byte_str::{ByteStr,ByteString};
 
 pub struct CacheNodeInfoDir
 {
@@ -31,6 +31,16 @@ impl CacheHandleDir
 		_ => Err( vfs::Error::Unknown("BUG: CacheHandleDir for non-directory") ),
 		}
 	}
+	pub fn get_metadata(&self) -> vfs::Result<vfs::node::Metadata> {
+		self.0.get_metadata()
+	}
+	pub fn get_mount_flags(&self) -> vfs::mount::MountFlags {
+		self.0.get_mount_flags()
+	}
+	/// Mount ID and inode number of this directory
+	pub fn get_ids(&self) -> (usize, vfs::node::InodeId) {
+		self.0.get_ids()
+	}
 	pub fn create(&self, name: &ByteStr, ty: vfs::node::NodeType) -> vfs::Result<super::CacheHandle> {
 		let inode = self.get_info()?.fsnode.create(name, ty)?;
 		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
@@ -42,6 +52,100 @@ impl CacheHandleDir
 		let inode = self.get_info()?.fsnode.lookup(name)?;
 		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
 	}
+	/// Open a child, failing with `Locked` if it's an active mountpoint
+	fn open_child_nomount(&self, name: &ByteStr) -> vfs::Result<super::CacheHandle> {
+		let inode = self.get_info()?.fsnode.lookup(name)?;
+		let child = super::CacheHandle::from_ids(self.0.mountpt, inode)?;
+		// `from_ids` follows mounts, so the IDs will differ (a bind mount can be on the same mount ID)
+		if child.get_ids() != (self.0.mountpt, inode) {
+			return Err(vfs::Error::Locked);
+		}
+		Ok(child)
+	}
+	pub fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
+		// Can't remove an active mountpoint
+		let child = self.open_child_nomount(name)?;
+		child.sync_data()?;
+		self.get_info()?.fsnode.unlink(name)?;
+		// The inode number could be reused, so detach any cached data from it
+		super::pages::forget(child.mountpt, child.inode);
+		Ok( () )
+	}
+	/// Move an entry from this directory to `new_dir` (which must be on the same filesystem)
+	///
+	/// Fails with `AlreadyExists` if `new_name` is already present.
+	pub fn rename(&self, name: &ByteStr, new_dir: &CacheHandleDir, new_name: &ByteStr) -> vfs::Result<()> {
+		if self.0.mountpt != new_dir.0.mountpt {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		for n in [name, new_name] {
+			if n == "" || n == "." || n == ".." {
+				return Err(vfs::Error::InvalidParameter);
+			}
+		}
+		// Can't move an active mountpoint
+		let child = self.open_child_nomount(name)?;
+		if self.0.inode == new_dir.0.inode && name == new_name {
+			return Ok( () );
+		}
+		// A directory can't be moved into itself
+		if child.is_dir() && self.0.inode != new_dir.0.inode {
+			if child.inode == new_dir.0.inode || child.clone().into_dir()?.subtree_contains(new_dir.0.inode, 0)? {
+				return Err(vfs::Error::InvalidParameter);
+			}
+		}
+
+		// Add the new name, then remove the old one (undoing the link if that fails)
+		// - Cached data is written back first, as the moved file might not keep its inode number
+		child.sync_data()?;
+		new_dir.get_info()?.fsnode.link(new_name, child.get_node_base())?;
+		if let Err(e) = self.get_info()?.fsnode.unlink(name) {
+			if let Err(e2) = new_dir.get_info()?.fsnode.unlink(new_name) {
+				log_error!("rename({:?} -> {:?}): Unable to undo link after {:?} - {:?}", name, new_name, e, e2);
+			}
+			return Err(e);
+		}
+		super::pages::forget(child.mountpt, child.inode);
+		Ok( () )
+	}
+
+	/// Check if `inode` is a directory within this directory's subtree (not crossing mountpoints)
+	fn subtree_contains(&self, inode: vfs::node::InodeId, depth: usize) -> vfs::Result<bool> {
+		const MAX_DEPTH: usize = 64;
+		if depth == MAX_DEPTH {
+			return Err(vfs::Error::RecursionDepthExceeded);
+		}
+		let mut children = Vec::new();
+		let mut ofs = 0;
+		loop
+		{
+			let mut count = 0;
+			ofs = self.read_dir(ofs, &mut |id, name| {
+				count += 1;
+				let name: ByteString = name.collect();
+				if &*name != "." && &*name != ".." {
+					children.push(id);
+				}
+				true
+				})?;
+			if count == 0 {
+				break;
+			}
+		}
+		for id in children
+		{
+			if id == inode {
+				return Ok(true);
+			}
+			let h = super::CacheHandle::from_ids(self.0.mountpt, id)?;
+			if h.mountpt == self.0.mountpt && h.is_dir() {
+				if h.into_dir()?.subtree_contains(inode, depth+1)? {
+					return Ok(true);
+				}
+			}
+		}
+		Ok(false)
+	}
 }
 /// Directory methods (mountpoint)
 impl CacheHandleDir
@@ -63,4 +167,14 @@ impl CacheHandleDir
 		_ => false,
 		}
 	}
+	/// Returns `true` if `filesystem_id` was bound here (and has been removed)
+	pub fn unmount(&self, filesystem_id: usize) -> bool {
+		match self.get_info()
+		{
+		Ok(info) => {
+			info.mountpoint.compare_exchange(filesystem_id, 0, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed).is_ok()
+			},
+		_ => false,
+		}
+	}
 }
@@ -1,24 +1,45 @@
 
 use ::kernel::prelude::*;
+use ::kernel::lib::mem::Arc;
+use ::kernel::memory::phys::FrameHandle;
 use super::CacheHandleFile;
+use super::pages::FilePages;
 use crate as vfs;
 
 pub struct CacheNodeInfoFile
 {
 	pub fsnode: Box<dyn vfs::node::File>,
-	//mapped_pages: HashMap<u64,FrameHandle>,
+	/// Cached file data (shared with later opens of the same file)
+	pages: Arc<FilePages>,
 	lock_info: ::kernel::sync::Mutex<CacheNodeInfoFileLock>,
 	append_lock: ::kernel::sync::Mutex<()>,
 }
 impl CacheNodeInfoFile {
-	pub fn new(fsnode: Box<dyn vfs::node::File>) -> Self {
+	pub fn new(fsnode: Box<dyn vfs::node::File>, pages: Arc<FilePages>) -> Self {
 		CacheNodeInfoFile {
 			fsnode,
+			pages,
 			lock_info: Default::default(),
 			append_lock: Default::default()
 		}
 	}
 }
+impl CacheNodeInfoFile {
+	/// Check if the file is locked for writing (exclusive or unsynchronised)
+	///
+	/// NOTE: Append handles share the reader lock, so aren't detected
+	pub fn is_open_for_write(&self) -> bool {
+		matches!(*self.lock_info.lock(), CacheNodeInfoFileLock::Unique | CacheNodeInfoFileLock::Unsynch(_))
+	}
+}
+impl ::core::ops::Drop for CacheNodeInfoFile {
+	fn drop(&mut self) {
+		// The cache can outlive the node, so write back anything outstanding while the filesystem node is available
+		if let Err(e) = self.pages.sync(&*self.fsnode, 0, !0) {
+			log_error!("Failed to write back cached file data: {:?}", e);
+		}
+	}
+}
 #[derive(Default)]
 enum CacheNodeInfoFileLock {
 	/// Nothing has the file open, but references may exist (as `Any` handles)
@@ -126,21 +147,66 @@ impl CacheHandleFile
 		}
 	}
 
+	pub fn get_metadata(&self) -> vfs::Result<vfs::node::Metadata> {
+		self.0.get_metadata()
+	}
+	pub fn get_mount_flags(&self) -> vfs::mount::MountFlags {
+		self.0.get_mount_flags()
+	}
+	/// Mount ID and inode number of this file
+	pub fn get_ids(&self) -> (usize, vfs::node::InodeId) {
+		self.0.get_ids()
+	}
 	/// Valid size = maximum offset in the file
 	pub fn get_valid_size(&self) -> u64 {
 		self.get_info().map(|v| v.fsnode.size()).unwrap_or(0)
 	}
+	/// Set the file size, returning the new size
+	pub fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		let info = self.get_info()?;
+		info.pages.truncate(&*info.fsnode, newsize)
+	}
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> vfs::Result<usize> {
-		Ok( self.get_info()?.fsnode.read(ofs, dst)? )
+		let info = self.get_info()?;
+		info.pages.read(&*info.fsnode, ofs, dst)
 	}
 	pub fn write(&self, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
 		// TODO: Ensure that the handle is writable?
-		Ok( self.get_info()?.fsnode.write(ofs, src)? )
+		let info = self.get_info()?;
+		info.pages.write(&*info.fsnode, ofs, src)
 	}
 	pub fn append(&self, data: &[u8]) -> vfs::Result<usize> {
 		let info = self.get_info()?;
 		let _lh = info.append_lock.lock();
 		let ofs = info.fsnode.size();
-		Ok( info.fsnode.write(ofs, data)? )
+		info.pages.write(&*info.fsnode, ofs, data)
+	}
+	/// Write all cached changes back to the filesystem
+	pub fn sync(&self) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		info.pages.sync(&*info.fsnode, 0, !0)
+	}
+}
+
+/// Shared memory-mapped pages
+impl CacheHandleFile
+{
+	/// Get the shared frame for a page of the file (reading it in if not already present)
+	///
+	/// Data past the end of the file reads as zero. If `writeback` is set, the page is registered as having a
+	/// writable mapping (released by [CacheHandleFile::release_mapped_writeback]).
+	pub fn get_mapped_page(&self, page: u64, writeback: bool) -> vfs::Result<FrameHandle> {
+		let info = self.get_info()?;
+		info.pages.get_page(&*info.fsnode, page, writeback)
+	}
+	/// Write modified pages back to the file (only the portion within the file's current size)
+	pub fn sync_mapped(&self, first_page: u64, page_count: usize) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		info.pages.sync(&*info.fsnode, first_page, page_count)
+	}
+	/// Release writable mapping registrations taken by [CacheHandleFile::get_mapped_page]
+	pub fn release_mapped_writeback(&self, first_page: u64, page_count: usize) {
+		let info = self.get_info().expect("CacheHandleFile::release_mapped_writeback get_info");
+		info.pages.release_writeback(first_page, page_count)
 	}
 }
@@ -0,0 +1,353 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/vfs/node_cache/pages.rs
+//! File data page cache
+//!
+//! Each file (keyed by the same mountpoint/inode pair as the node cache) has a set of cached pages, which are used
+//! for reads, writes and memory mappings. The cache outlives the node cache entry (so repeated opens of a file don't
+//! re-read it), but dirty pages are written back before the node is released.
+//!
+//! - Sequential reads trigger readahead
+//! - Writes within the file are cached (and written back periodically, or on sync/release), writes that extend the
+//!   file go straight to the filesystem so it can allocate space.
+//! - Clean pages that aren't mapped are released when the PMM runs out of memory
+use ::kernel::prelude::*;
+use ::kernel::PAGE_SIZE;
+use ::kernel::lib::VecMap;
+use ::kernel::lib::mem::Arc;
+use ::kernel::sync::Mutex;
+use ::kernel::memory::phys::FrameHandle;
+use ::kernel::memory::page_cache::S_PAGE_CACHE;
+use crate::node::InodeId;
+use crate as vfs;
+
+/// Maximum number of pages read in one go when reading sequentially
+const READAHEAD_PAGES: usize = 8;
+/// Interval between writebacks of dirty pages (milliseconds)
+const WRITEBACK_INTERVAL_MS: usize = 5000;
+
+/// All cached files
+static S_FILE_PAGES: Mutex<VecMap<(usize,InodeId),Arc<FilePages>>> = Mutex::new(VecMap::new());
+static S_RECLAIM: PageReclaim = PageReclaim;
+
+/// Cached data for a single file
+pub struct FilePages
+{
+	inner: Mutex<FilePagesInner>,
+}
+struct FilePagesInner
+{
+	pages: VecMap<u64,CachedPage>,
+	/// Page after the end of the last read-in (to detect sequential reads)
+	next_sequential: u64,
+}
+struct CachedPage
+{
+	frame: FrameHandle,
+	/// Number of writable (writeback) mappings of this page, if non-zero the page may be newer than the file
+	writeback_count: usize,
+	/// The page has been written since it was last written back
+	dirty: bool,
+	/// Accessed since the last reclaim scan
+	accessed: bool,
+}
+
+pub fn init()
+{
+	::kernel::memory::phys::register_reclaimable(&S_RECLAIM);
+	::core::mem::forget(::kernel::threads::WorkerThread::new("VFS Writeback", || {
+		loop
+		{
+			::kernel::futures::block_on( ::kernel::futures::msleep(WRITEBACK_INTERVAL_MS) );
+			super::sync_all_files();
+		}
+		}));
+}
+
+/// Get the cached data for a file (creating an empty cache if needed)
+pub fn get(mountpoint: usize, inode: InodeId) -> Arc<FilePages>
+{
+	S_FILE_PAGES.lock().entry( (mountpoint, inode) )
+		.or_insert_with(|| Arc::new(FilePages { inner: Mutex::new(FilePagesInner { pages: VecMap::new(), next_sequential: 0 }) }))
+		.clone()
+}
+/// Detach the cache from a file ID (e.g. after the file is removed), so a later file with the same ID starts empty
+///
+/// Existing users of the cache (open nodes) keep using it.
+pub fn forget(mountpoint: usize, inode: InodeId)
+{
+	S_FILE_PAGES.lock().remove( &(mountpoint, inode) );
+}
+/// Release the cache for all files on a mountpoint
+pub fn forget_mount(mountpoint: usize)
+{
+	S_FILE_PAGES.lock().retain(|k, _| k.0 != mountpoint);
+}
+
+impl FilePages
+{
+	/// Read file data (via the cache)
+	pub fn read(&self, fsnode: &dyn vfs::node::File, ofs: u64, dst: &mut [u8]) -> vfs::Result<usize> {
+		let size = fsnode.size();
+		if ofs >= size {
+			// Let the filesystem decide how to handle reading at/past the end
+			return fsnode.read(ofs, dst);
+		}
+		let len = ::core::cmp::min(dst.len() as u64, size - ofs) as usize;
+		let mut lh = self.inner.lock();
+		let mut pos = 0;
+		while pos < len
+		{
+			let cur = ofs + pos as u64;
+			let page = cur / PAGE_SIZE as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, len - pos);
+			if lh.pages.get(&page).is_none() {
+				let count = if page == 0 || page == lh.next_sequential { READAHEAD_PAGES } else { 1 };
+				match lh.fill(fsnode, page, count, size)
+				{
+				Ok(_) => {},
+				// Return a short read if some data was read
+				Err(_) if pos > 0 => break,
+				Err(e) => return Err(e),
+				}
+			}
+			let p = lh.pages.get_mut(&page).unwrap();
+			p.accessed = true;
+			let mapping = S_PAGE_CACHE.map(&p.frame).map_err(|_| vfs::Error::OutOfMemory)?;
+			dst[pos..][..bytes].copy_from_slice(&mapping.data()[page_ofs..][..bytes]);
+			pos += bytes;
+		}
+		Ok(pos)
+	}
+
+	/// Write file data (cached if within the file, otherwise passed to the filesystem)
+	pub fn write(&self, fsnode: &dyn vfs::node::File, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
+		let mut lh = self.inner.lock();
+		let size = fsnode.size();
+		if ofs + src.len() as u64 > size {
+			// Extending the file, write through so the filesystem can allocate space (and write back anything
+			// cached first, so the filesystem sees a consistent file)
+			lh.sync(fsnode, 0, !0)?;
+			let rv = fsnode.write(ofs, src)?;
+			lh.update(ofs, &src[..rv])?;
+			return Ok(rv);
+		}
+		let mut pos = 0;
+		while pos < src.len()
+		{
+			let cur = ofs + pos as u64;
+			let page = cur / PAGE_SIZE as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, src.len() - pos);
+			if lh.pages.get(&page).is_none() {
+				let res = if bytes == PAGE_SIZE {
+						// Whole page is being overwritten, no need to read it
+						S_PAGE_CACHE.create().map_err(|_| vfs::Error::OutOfMemory)
+							.map(|p| { lh.pages.insert(page, CachedPage::new(p.get_frame_handle())); })
+					}
+					else {
+						lh.fill(fsnode, page, 1, size).map(|_| ())
+					};
+				match res
+				{
+				Ok(_) => {},
+				// Return a short write if some data was written
+				Err(_) if pos > 0 => break,
+				Err(e) => return Err(e),
+				}
+			}
+			let p = lh.pages.get_mut(&page).unwrap();
+			p.accessed = true;
+			p.dirty = true;
+			let mut mapping = S_PAGE_CACHE.map(&p.frame).map_err(|_| vfs::Error::OutOfMemory)?;
+			mapping.data_mut()[page_ofs..][..bytes].copy_from_slice(&src[pos..][..bytes]);
+			pos += bytes;
+		}
+		Ok(pos)
+	}
+
+	/// Change the file size
+	pub fn truncate(&self, fsnode: &dyn vfs::node::File, newsize: u64) -> vfs::Result<u64> {
+		let mut lh = self.inner.lock();
+		// Write back first, so the filesystem has all the data (if extending) or none of the removed data is written later
+		lh.sync(fsnode, 0, !0)?;
+		let rv = fsnode.truncate(newsize)?;
+		// Pages past the end read as zero (existing mappings keep their frames, but they're no longer shared)
+		let first_free = ::kernel::lib::num::div_up(rv, PAGE_SIZE as u64);
+		lh.pages.retain(|&k, _| k < first_free);
+		let tail_ofs = (rv % PAGE_SIZE as u64) as usize;
+		if tail_ofs != 0 {
+			if let Some(p) = lh.pages.get(&(rv / PAGE_SIZE as u64)) {
+				let mut mapping = S_PAGE_CACHE.map(&p.frame).map_err(|_| vfs::Error::OutOfMemory)?;
+				mapping.data_mut()[tail_ofs..].fill(0);
+			}
+		}
+		Ok(rv)
+	}
+
+	/// Get the frame for a page of the file (for memory mapping)
+	///
+	/// Data past the end of the file reads as zero. If `writeback` is set, the page is registered as having a
+	/// writable mapping (released by [FilePages::release_writeback]).
+	pub fn get_page(&self, fsnode: &dyn vfs::node::File, page: u64, writeback: bool) -> vfs::Result<FrameHandle> {
+		let mut lh = self.inner.lock();
+		if lh.pages.get(&page).is_none() {
+			lh.fill(fsnode, page, 1, fsnode.size())?;
+		}
+		let p = lh.pages.get_mut(&page).unwrap();
+		p.accessed = true;
+		if writeback {
+			p.writeback_count += 1;
+		}
+		Ok( p.frame.clone() )
+	}
+	/// Release writable mapping registrations taken by [FilePages::get_page]
+	pub fn release_writeback(&self, first_page: u64, page_count: usize) {
+		let mut lh = self.inner.lock();
+		for page in first_page .. first_page + page_count as u64
+		{
+			// NOTE: The page may have been removed by a truncate
+			if let Some(p) = lh.pages.get_mut(&page) {
+				assert!(p.writeback_count > 0);
+				p.writeback_count -= 1;
+				// The mapping may have written to the page
+				p.dirty = true;
+			}
+		}
+	}
+
+	/// Write modified pages in the given range back to the file (only the portion within the file's current size)
+	pub fn sync(&self, fsnode: &dyn vfs::node::File, first_page: u64, page_count: usize) -> vfs::Result<()> {
+		self.inner.lock().sync(fsnode, first_page, page_count)
+	}
+}
+
+impl FilePagesInner
+{
+	/// Read `count` pages (stopping at the end of the file, or at an already cached page) into the cache
+	fn fill(&mut self, fsnode: &dyn vfs::node::File, first_page: u64, count: usize, file_size: u64) -> vfs::Result<usize> {
+		let mut n_read = 0;
+		for page in first_page .. first_page + count as u64
+		{
+			let page_ofs = page * PAGE_SIZE as u64;
+			if n_read > 0 && (page_ofs >= file_size || self.pages.get(&page).is_some()) {
+				break;
+			}
+			let mut new_page = match S_PAGE_CACHE.create()
+				{
+				Ok(v) => v,
+				// Readahead isn't required
+				Err(_) if n_read > 0 => break,
+				Err(_) => return Err(vfs::Error::OutOfMemory),
+				};
+			let data = new_page.data_mut();
+			let valid = if page_ofs >= file_size { 0 } else { ::core::cmp::min(PAGE_SIZE as u64, file_size - page_ofs) as usize };
+			let mut pos = 0;
+			while pos < valid
+			{
+				match fsnode.read(page_ofs + pos as u64, &mut data[pos..valid])?
+				{
+				0 => break,
+				n => pos += n,
+				}
+			}
+			data[pos..].fill(0);
+			self.pages.insert(page, CachedPage::new(new_page.get_frame_handle()));
+			n_read += 1;
+		}
+		self.next_sequential = first_page + n_read as u64;
+		Ok(n_read)
+	}
+
+	/// Copy data written directly to the filesystem into cached pages
+	fn update(&mut self, ofs: u64, data: &[u8]) -> vfs::Result<()> {
+		let mut pos = 0;
+		while pos < data.len()
+		{
+			let cur = ofs + pos as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, data.len() - pos);
+			if let Some(p) = self.pages.get(&(cur / PAGE_SIZE as u64)) {
+				let mut mapping = S_PAGE_CACHE.map(&p.frame).map_err(|_| vfs::Error::OutOfMemory)?;
+				mapping.data_mut()[page_ofs..][..bytes].copy_from_slice(&data[pos..][..bytes]);
+			}
+			pos += bytes;
+		}
+		Ok( () )
+	}
+
+	fn sync(&mut self, fsnode: &dyn vfs::node::File, first_page: u64, page_count: usize) -> vfs::Result<()> {
+		let file_size = fsnode.size();
+		let end_page = first_page.saturating_add(page_count as u64);
+		for (&page, p) in self.pages.iter_mut()
+		{
+			if page < first_page || page >= end_page || !(p.dirty || p.writeback_count > 0) {
+				continue ;
+			}
+			let page_ofs = page * PAGE_SIZE as u64;
+			if page_ofs < file_size {
+				let len = ::core::cmp::min(PAGE_SIZE as u64, file_size - page_ofs) as usize;
+				let mapping = S_PAGE_CACHE.map(&p.frame).map_err(|_| vfs::Error::OutOfMemory)?;
+				fsnode.write(page_ofs, &mapping.data()[..len])?;
+			}
+			// Pages with writable mappings stay dirty (they could be written at any time)
+			p.dirty = p.writeback_count > 0;
+		}
+		Ok( () )
+	}
+}
+
+impl CachedPage
+{
+	fn new(frame: FrameHandle) -> CachedPage {
+		CachedPage { frame, writeback_count: 0, dirty: false, accessed: true }
+	}
+	/// Can this page be released (it's clean, and not mapped anywhere)
+	fn is_reclaimable(&self) -> bool {
+		!self.dirty && self.writeback_count == 0 && self.frame.is_unique()
+	}
+}
+
+/// Releases clean, unmapped pages when the PMM runs out of memory
+struct PageReclaim;
+impl ::kernel::memory::phys::Reclaimable for PageReclaim
+{
+	fn reclaim(&self, count: usize) -> usize {
+		// NOTE: Only `try_lock` is used, as the allocation could have come from inside the cache
+		let mut files = match S_FILE_PAGES.try_lock()
+			{
+			Some(v) => v,
+			None => return 0,
+			};
+		let mut rv = 0;
+		// Two passes: first releases pages that haven't been accessed since the last scan (clearing the flag on the
+		// rest), the second releases anything possible.
+		for pass in 0 .. 2
+		{
+			for (_, f) in files.iter()
+			{
+				if rv >= count {
+					break;
+				}
+				let Some(mut lh) = f.inner.try_lock() else { continue };
+				lh.pages.retain(|_, p| {
+					if rv >= count || !p.is_reclaimable() {
+						true
+					}
+					else if pass == 0 && ::core::mem::replace(&mut p.accessed, false) {
+						true
+					}
+					else {
+						rv += 1;
+						false
+					}
+					});
+			}
+		}
+		// Drop empty caches that aren't in use
+		files.retain(|_, f| Arc::strong_count(f) > 1 || f.inner.try_lock().map(|lh| lh.pages.iter().next().is_some()).unwrap_or(true));
+		rv
+	}
+}
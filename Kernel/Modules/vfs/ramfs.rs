@@ -6,17 +6,22 @@
 use ::kernel::prelude::*;
 use crate as vfs;
 use super::{mount, node};
+use ::kernel::PAGE_SIZE;
 use ::kernel::metadevs::storage::VolumeHandle;
 use ::kernel::lib::{VecMap,SparseVec};
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
-use ::kernel::lib::mem::aref::{Aref,ArefInner,ArefBorrow};
+use ::kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use ::kernel::lib::mem::Arc;
+use ::kernel::memory::phys::FrameHandle;
+use ::kernel::memory::page_cache::S_PAGE_CACHE;
+use ::core::sync::atomic::{AtomicUsize,Ordering};
 
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
 
 enum RamFile
 {
-	//File(RamFileFile),
+	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
 }
@@ -30,13 +35,19 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
-//#[derive(Default)]
-//struct RamFileFile
-//{
-//	ofs: usize,
-//	size: usize,
-//}
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default)]
+struct RamFileFile
+{
+	data: ::kernel::sync::RwLock<RamFileData>,
+}
+#[derive(Default)]
+struct RamFileData
+{
+	size: u64,
+	/// Backing frames, indexed by page number (missing pages read as zero)
+	pages: VecMap<u64,FrameHandle>,
+}
+struct FileRef(ArefBorrow<RamFSInner>,Arc<RamFile>,node::InodeId);
 
 struct RamFS
 {
@@ -47,7 +58,24 @@ struct RamFSInner
 	_vh: VolumeHandle,
 	// TODO: Store as much data (and metadata) as possible on the volume
 	// - Possibly by using an allocation pool backed onto the volume
-	nodes: ::kernel::sync::Mutex< SparseVec<Aref<RamFile>> >,
+	nodes: ::kernel::sync::Mutex< SparseVec<RamNode> >,
+	space: SpaceLimit,
+}
+/// Inode table entry
+struct RamNode
+{
+	node: Arc<RamFile>,
+	/// Number of directory entries referring to this node
+	link_count: usize,
+	/// Number of `FileRef`s (i.e. node cache entries) referring to this node
+	open_count: usize,
+}
+/// Tracks the number of pages used by file data
+struct SpaceLimit
+{
+	/// Maximum number of pages (zero for no limit)
+	max_pages: usize,
+	used_pages: AtomicUsize,
 }
 
 pub fn init()
@@ -62,20 +90,56 @@ impl mount::Driver for Driver
 		// RAMFS should never bind to an arbitrary volume
 		Ok(0)
 	}
-	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle, options: &[&str]) -> super::Result<Box<dyn mount::Filesystem>> {
+		let mut max_size = 0;
+		for opt in options
+		{
+			match opt.split_once('=')
+			{
+			Some(("size", v)) => {
+				max_size = match parse_size(v)
+					{
+					Some(v) => v,
+					None => {
+						log_notice!("ramfs: Malformed size option {:?}", v);
+						return Err(vfs::Error::InvalidParameter);
+						},
+					};
+				},
+			_ => log_notice!("ramfs: Unknown option {:?}", opt),
+			}
+		}
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
 				_vh: vol,
 				nodes: Default::default(),
+				space: SpaceLimit {
+					max_pages: ::kernel::lib::num::div_up(max_size, PAGE_SIZE as u64) as usize,
+					used_pages: AtomicUsize::new(0),
+					},
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( RamNode::new(RamFile::Dir(Default::default())) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
 }
 
+/// Parse a size with an optional `K`/`M`/`G` suffix
+fn parse_size(v: &str) -> Option<u64>
+{
+	let (digits, shift) = match v.as_bytes().last()
+		{
+		Some(b'k'|b'K') => (&v[..v.len()-1], 10),
+		Some(b'm'|b'M') => (&v[..v.len()-1], 20),
+		Some(b'g'|b'G') => (&v[..v.len()-1], 30),
+		_ => (v, 0),
+		};
+	let val: u64 = digits.parse().ok()?;
+	val.checked_mul(1 << shift)
+}
+
 impl mount::Filesystem for RamFS
 {
 	fn root_inode(&self) -> node::InodeId {
@@ -83,26 +147,103 @@ impl mount::Filesystem for RamFS
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("RamFS::get_node_by_inode({})", id);
-		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
+		let mut nodes = self.inner.nodes.lock();
+		if id >= nodes.len() as node::InodeId || nodes.get(id as usize).is_none() {
 			log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
 			None
 		}
 		else {
+			let ent = &mut nodes[id as usize];
+			ent.open_count += 1;
 			let fr = Box::new(FileRef(
 				self.inner.borrow(),
-				nodes[id as usize].borrow()
+				ent.node.clone(),
+				id
 				));
-			match *nodes[id as usize]
+			match *ent.node
 			{
 			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
 			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
-			//RamFile::File(_) => todo!("normal files"),
+			RamFile::File(_) => Some(node::Node::File(fr)),
 			}
 		}
 	}
 }
 
+impl RamNode
+{
+	fn new(node: RamFile) -> RamNode {
+		RamNode {
+			node: Arc::new(node),
+			link_count: 1,
+			open_count: 0,
+		}
+	}
+}
+impl RamFSInner
+{
+	/// Remove a node from the inode table once it has no names and no open handles
+	///
+	/// Must be called with the node table locked.
+	fn release_if_unused(&self, nodes: &mut SparseVec<RamNode>, inode: usize) {
+		let ent = &nodes[inode];
+		if ent.link_count == 0 && ent.open_count == 0 {
+			log_debug!("RamFS: Releasing inode {}", inode);
+			if let RamFile::File(ref f) = *ent.node {
+				f.data.write().free_pages(&self.space, 0);
+			}
+			nodes.remove(inode);
+		}
+	}
+}
+
+impl SpaceLimit
+{
+	/// Allocate a zeroed page for file data
+	fn alloc_page(&self) -> vfs::Result<FrameHandle> {
+		let reserved = self.used_pages.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v|
+			if self.max_pages != 0 && v >= self.max_pages { None } else { Some(v + 1) }
+			);
+		if reserved.is_err() {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		match S_PAGE_CACHE.create()
+		{
+		Ok(mut page) => {
+			page.data_mut().fill(0);
+			Ok( page.get_frame_handle() )
+			},
+		Err(_) => {
+			self.used_pages.fetch_sub(1, Ordering::Relaxed);
+			Err(vfs::Error::OutOfMemory)
+			},
+		}
+	}
+	fn release_pages(&self, count: usize) {
+		self.used_pages.fetch_sub(count, Ordering::Relaxed);
+	}
+}
+
+impl RamFileData
+{
+	/// Release all backing pages at or after `first_page`
+	fn free_pages(&mut self, space: &SpaceLimit, first_page: u64) {
+		let to_free: Vec<u64> = self.pages.iter().map(|(&k,_)| k).filter(|&k| k >= first_page).collect();
+		for k in &to_free {
+			self.pages.remove(k);
+		}
+		space.release_pages(to_free.len());
+	}
+	/// Zero `len` bytes starting at `ofs` within a page (if the page is allocated)
+	fn zero_in_page(&mut self, page: u64, ofs: usize, len: usize) -> vfs::Result<()> {
+		if let Some(frame) = self.pages.get(&page) {
+			let mut mapping = S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::OutOfMemory)?;
+			mapping.data_mut()[ofs..][..len].fill(0);
+		}
+		Ok( () )
+	}
+}
+
 impl FileRef {
 	fn dir(&self) -> &RamFileDir {
 		match &*self.1
@@ -118,14 +259,43 @@ impl FileRef {
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
 		}
 	}
+	fn file(&self) -> &RamFileFile {
+		match &*self.1
+		{
+		&RamFile::File(ref e) => e,
+		_ => panic!("Called FileRef::file() on non-file"),
+		}
+	}
+}
+impl ::core::ops::Drop for FileRef {
+	fn drop(&mut self) {
+		let mut nodes = self.0.nodes.lock();
+		let inode = self.2 as usize;
+		nodes[inode].open_count -= 1;
+		// Unlinked nodes are freed when the last handle is closed
+		self.0.release_if_unused(&mut nodes, inode);
+	}
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2
 	}
 	fn get_any(&self) -> &dyn ::core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let allocated_pages = match *self.1
+			{
+			RamFile::File(ref f) => f.data.read().pages.iter().count(),
+			_ => 0,
+			};
+		Ok(node::Metadata {
+			link_count: self.0.nodes.lock()[self.2 as usize].link_count as u32,
+			allocated_size: (allocated_pages * PAGE_SIZE) as u64,
+			mode: 0o777,
+			..Default::default()
+			})
+	}
 }
 impl node::Dir for FileRef {
 	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
@@ -136,7 +306,7 @@ impl node::Dir for FileRef {
 		None => Err(vfs::Error::NotFound),
 		}
 	}
-	
+
 	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
 		let lh = self.dir().ents.read();
 		let mut count = 0;
@@ -150,9 +320,12 @@ impl node::Dir for FileRef {
 		}
 		Ok(start_ofs + count)
 	}
-	
+
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
 		use ::kernel::lib::vec_map::Entry;
+		if name == "" || name == "." || name == ".." {
+			return Err(vfs::Error::InvalidParameter);
+		}
 		let mut lh = self.dir().ents.write();
 		match lh.entry(From::from(name))
 		{
@@ -161,22 +334,85 @@ impl node::Dir for FileRef {
 			let nn = match nodetype
 				{
 				node::NodeType::Dir  => RamFile::Dir (Default::default()),
-				//node::NodeType::File => RamFile::File(Default::default()),
-				node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
+				node::NodeType::File => RamFile::File(Default::default()),
 				node::NodeType::Symlink(v) =>
 					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			let mut nodes = self.0.nodes.lock();
+			// Can't create new entries in a removed directory
+			if nodes[self.2 as usize].link_count == 0 {
+				return Err(vfs::Error::NotFound);
+			}
+			let inode = nodes.insert( RamNode::new(nn) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
+		use ::kernel::lib::vec_map::Entry;
+		if name == "" || name == "." || name == ".." {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let Some(target) = node.get_any().downcast_ref::<FileRef>() else {
+			return Err(vfs::Error::TypeMismatch);
+			};
+		if !::core::ptr::eq(&*target.0, &*self.0) {
+			log_notice!("link({:?}): Linking across filesystems", name);
+			return Err(vfs::Error::InvalidParameter);
+		}
+		// NOTE: Directories can be linked (so they can be moved), the VFS ensures that no loops are created
+
+		let mut lh = self.dir().ents.write();
+		match lh.entry(From::from(name))
+		{
+		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
+		Entry::Vacant(e) => {
+			let mut nodes = self.0.nodes.lock();
+			if nodes[self.2 as usize].link_count == 0 {
+				return Err(vfs::Error::NotFound);
+			}
+			// An open node that has had all of its names removed can't be re-linked
+			let ent = &mut nodes[target.2 as usize];
+			if ent.link_count == 0 {
+				return Err(vfs::Error::NotFound);
+			}
+			ent.link_count += 1;
+			e.insert(target.2 as usize);
+			Ok( () )
+			},
+		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.write();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		let (child, other_links) = {
+			let nodes = self.0.nodes.lock();
+			(nodes[inode].node.clone(), nodes[inode].link_count > 1)
+			};
+		// Directories must be empty before their last name is removed (the child's entries are locked until the link count is updated)
+		let _child_lh = match *child
+			{
+			RamFile::Dir(_) if other_links => None,
+			RamFile::Dir(ref d) => {
+				let child_lh = d.ents.read();
+				if child_lh.iter().next().is_some() {
+					return Err(vfs::Error::DirectoryNotEmpty);
+				}
+				Some(child_lh)
+				},
+			_ => None,
+			};
+		lh.remove(name);
+
+		let mut nodes = self.0.nodes.lock();
+		nodes[inode].link_count -= 1;
+		self.0.release_if_unused(&mut nodes, inode);
+		Ok( () )
 	}
 }
 impl node::Symlink for FileRef {
@@ -184,4 +420,101 @@ impl node::Symlink for FileRef {
 		ByteString::from( ByteStr::new(&*self.symlink().target) )
 	}
 }
-
+impl node::File for FileRef {
+	fn size(&self) -> u64 {
+		self.file().data.read().size
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		let mut lh = self.file().data.write();
+		if newsize < lh.size {
+			// Free whole pages past the end, and zero the tail of the last page (so it reads as zero if the file is extended)
+			let first_free = ::kernel::lib::num::div_up(newsize, PAGE_SIZE as u64);
+			lh.free_pages(&self.0.space, first_free);
+			let tail_ofs = (newsize % PAGE_SIZE as u64) as usize;
+			if tail_ofs != 0 {
+				lh.zero_in_page(newsize / PAGE_SIZE as u64, tail_ofs, PAGE_SIZE - tail_ofs)?;
+			}
+		}
+		// Extending doesn't allocate, missing pages read as zero
+		lh.size = newsize;
+		Ok(newsize)
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		let mut lh = self.file().data.write();
+		if ofs > lh.size || size > lh.size - ofs {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let end = ofs + size;
+		let mut pos = ofs;
+		while pos < end
+		{
+			let page = pos / PAGE_SIZE as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let len = ::core::cmp::min(PAGE_SIZE - page_ofs, (end - pos) as usize);
+			if len == PAGE_SIZE {
+				// Whole page, release it
+				if lh.pages.remove(&page).is_some() {
+					self.0.space.release_pages(1);
+				}
+			}
+			else {
+				lh.zero_in_page(page, page_ofs, len)?;
+			}
+			pos += len as u64;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let lh = self.file().data.read();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let len = ::core::cmp::min(buf.len() as u64, lh.size - ofs) as usize;
+		let mut pos = 0;
+		while pos < len
+		{
+			let cur = ofs + pos as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, len - pos);
+			let dst = &mut buf[pos..][..bytes];
+			match lh.pages.get(&(cur / PAGE_SIZE as u64))
+			{
+			Some(frame) => {
+				let mapping = S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::OutOfMemory)?;
+				dst.copy_from_slice(&mapping.data()[page_ofs..][..bytes]);
+				},
+			None => dst.fill(0),
+			}
+			pos += bytes;
+		}
+		Ok(len)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		let mut lh = self.file().data.write();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut pos = 0;
+		while pos < buf.len()
+		{
+			let cur = ofs + pos as u64;
+			let page = cur / PAGE_SIZE as u64;
+			let page_ofs = (cur % PAGE_SIZE as u64) as usize;
+			let bytes = ::core::cmp::min(PAGE_SIZE - page_ofs, buf.len() - pos);
+			if lh.pages.get(&page).is_none() {
+				match self.0.space.alloc_page()
+				{
+				Ok(frame) => { lh.pages.insert(page, frame); },
+				// Return a short write if some data was written
+				Err(_) if pos > 0 => break,
+				Err(e) => return Err(e),
+				}
+			}
+			let mut mapping = S_PAGE_CACHE.map(lh.pages.get(&page).unwrap()).map_err(|_| vfs::Error::OutOfMemory)?;
+			mapping.data_mut()[page_ofs..][..bytes].copy_from_slice(&buf[pos..][..bytes]);
+			pos += bytes;
+			lh.size = u64::max(lh.size, cur + bytes as u64);
+		}
+		Ok(pos)
+	}
+}
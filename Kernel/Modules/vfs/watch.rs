@@ -0,0 +1,206 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/vfs/watch.rs
+//! Directory change notifications
+use ::kernel::prelude::*;
+use ::kernel::lib::mem::Arc;
+use ::kernel::lib::VecDeque;
+use ::kernel::lib::byte_str::{ByteStr,ByteString};
+use ::kernel::sync::Mutex;
+use super::node::InodeId;
+use super::node_cache::CacheHandleDir;
+
+/// Maximum number of changes queued on a watch before it reports an overflow
+const MAX_QUEUED: usize = 64;
+
+/// All active watches
+static S_WATCHES: Mutex<Vec<Arc<WatchInner>>> = Mutex::new(Vec::new());
+
+/// Type of change reported by a [Watch]
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ChangeKind
+{
+	/// A new entry was created
+	Created,
+	/// An entry was removed
+	Removed,
+	/// An entry was renamed (or moved) away, the new name follows as `RenamedTo` if it's in the same directory
+	RenamedFrom,
+	/// An entry was renamed (or moved) into this name
+	RenamedTo,
+	/// The contents of a file were changed
+	Modified,
+	/// Changes were lost because the queue was full (the directory should be re-read)
+	Overflow,
+}
+/// A change to a watched directory
+#[derive(Debug)]
+pub struct Change
+{
+	pub kind: ChangeKind,
+	/// Name of the changed entry (empty for `Overflow`)
+	pub name: ByteString,
+}
+
+/// A watch on a directory (see [super::handle::Dir::watch])
+///
+/// Changes made through the VFS handles are queued until read with [Watch::pop].
+pub struct Watch
+{
+	/// Keeps the directory (and its inode number) alive
+	_dir: CacheHandleDir,
+	inner: Arc<WatchInner>,
+}
+struct WatchInner
+{
+	dir: (usize, InodeId),
+	state: Mutex<WatchState>,
+	waiters: ::kernel::user_async::Queue,
+}
+struct WatchState
+{
+	/// Directory entries, used to find the names of modified files
+	entries: Vec<(InodeId, ByteString)>,
+	changes: VecDeque<Change>,
+	overflowed: bool,
+}
+
+impl Watch
+{
+	pub(crate) fn new(dir: CacheHandleDir) -> super::Result<Watch> {
+		let mut entries = Vec::new();
+		let mut ofs = 0;
+		loop
+		{
+			let mut count = 0;
+			ofs = dir.read_dir(ofs, &mut |inode, name| {
+				count += 1;
+				let name: ByteString = name.collect();
+				if &*name != "." && &*name != ".." {
+					entries.push( (inode, name) );
+				}
+				true
+				})?;
+			if count == 0 {
+				break;
+			}
+		}
+		let inner = Arc::new(WatchInner {
+			dir: dir.get_ids(),
+			state: Mutex::new(WatchState {
+				entries,
+				changes: VecDeque::new(),
+				overflowed: false,
+				}),
+			waiters: Default::default(),
+			});
+		S_WATCHES.lock().push(inner.clone());
+		Ok(Watch { _dir: dir, inner })
+	}
+
+	/// Take the oldest queued change
+	pub fn pop(&self) -> Option<Change> {
+		let mut lh = self.inner.state.lock();
+		let rv = lh.changes.pop_front();
+		if lh.changes.is_empty() {
+			lh.overflowed = false;
+		}
+		rv
+	}
+	pub fn has_changes(&self) -> bool {
+		!self.inner.state.lock().changes.is_empty()
+	}
+
+	pub fn wait_upon(&self, waiter: &mut ::kernel::threads::SleepObject) {
+		self.inner.waiters.wait_upon(waiter);
+		if self.has_changes() {
+			waiter.signal();
+		}
+	}
+	pub fn clear_wait(&self, waiter: &mut ::kernel::threads::SleepObject) {
+		self.inner.waiters.clear_wait(waiter);
+	}
+}
+impl ::core::ops::Drop for Watch
+{
+	fn drop(&mut self) {
+		S_WATCHES.lock().retain(|w| !Arc::ptr_eq(w, &self.inner));
+	}
+}
+impl ::core::fmt::Debug for Watch
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "Watch({}:{:#x})", self.inner.dir.0, self.inner.dir.1)
+	}
+}
+
+impl WatchInner
+{
+	fn push(&self, kind: ChangeKind, name: &ByteStr, inode: Option<InodeId>) {
+		{
+			let mut lh = self.state.lock();
+			match kind
+			{
+			ChangeKind::Created|ChangeKind::RenamedTo => if let Some(inode) = inode {
+				lh.entries.push( (inode, ByteString::from(name)) );
+				},
+			ChangeKind::Removed|ChangeKind::RenamedFrom => lh.entries.retain(|e| &*e.1 != name),
+			ChangeKind::Modified|ChangeKind::Overflow => {},
+			}
+
+			// Repeated writes to a file only need to be reported once
+			if kind == ChangeKind::Modified {
+				if let Some(last) = lh.changes.back() {
+					if last.kind == kind && &*last.name == name {
+						return ;
+					}
+				}
+			}
+			if lh.overflowed {
+				return ;
+			}
+			if lh.changes.len() >= MAX_QUEUED {
+				lh.overflowed = true;
+				lh.changes.push_back(Change { kind: ChangeKind::Overflow, name: ByteString::new() });
+			}
+			else {
+				lh.changes.push_back(Change { kind, name: ByteString::from(name) });
+			}
+		}
+		self.waiters.wake_all();
+	}
+}
+
+/// Report a change to an entry in a directory (`inode` is needed for new entries)
+pub(crate) fn notify_dir(dir: (usize, InodeId), kind: ChangeKind, name: &ByteStr, inode: Option<InodeId>)
+{
+	let lh = S_WATCHES.lock();
+	for w in lh.iter().filter(|w| w.dir == dir)
+	{
+		w.push(kind, name, inode);
+	}
+}
+
+/// Report a change to the contents of a file (to watches on all directories containing it)
+pub(crate) fn notify_file(file: (usize, InodeId))
+{
+	let lh = S_WATCHES.lock();
+	for w in lh.iter().filter(|w| w.dir.0 == file.0)
+	{
+		let names: Vec<ByteString> = w.state.lock().entries.iter()
+			.filter(|e| e.0 == file.1)
+			.map(|e| e.1.clone())
+			.collect();
+		for name in names
+		{
+			w.push(ChangeKind::Modified, &name, None);
+		}
+	}
+}
+
+/// Check if any watches exist (so callers can skip extra work needed to report changes)
+pub(crate) fn is_active() -> bool
+{
+	!S_WATCHES.lock().is_empty()
+}
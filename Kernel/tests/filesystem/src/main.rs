@@ -22,10 +22,13 @@ fn main()
     ::kernel::memory::page_cache::init();
     (::kernel::metadevs::storage::S_MODULE.init)();
     (::kernel::hw::mapper_mbr::S_MODULE.init)();
+    (::kernel::hw::mapper_gpt::S_MODULE.init)();
     (::vfs::S_MODULE.init)();
 
     modules::use_mods();
 
+    // Active directory watches (see `watch` and `changes`)
+    let mut watches = ::std::collections::HashMap::new();
     let cmd_stream = ::std::io::stdin();
     loop
     {
@@ -54,8 +57,18 @@ fn main()
                 "persistent" => virt_storage::OverlayType::Persistent,
                 _ => panic!("`add_disk`: Invalid `overlay` argument"),
                 };
-            log_log!("CMD add_disk {} := {} {:?}", name, path, overlay);
-            match crate::virt_storage::add_volume(name, path.as_ref(), overlay)
+            // Optional block size (defaults to 512 byte sectors)
+            let block_size = match args.next()
+                {
+                None => 512,
+                Some(v) => match v.parse::<usize>()
+                    {
+                    Ok(v) if v.is_power_of_two() && v >= 512 => v,
+                    _ => panic!("`add_disk`: Invalid `block_size` argument"),
+                    },
+                };
+            log_log!("CMD add_disk {} := {} {:?} bs={}", name, path, overlay, block_size);
+            match crate::virt_storage::add_volume(name, path.as_ref(), overlay, block_size)
             {
             Ok(()) => {},
             Err(e) => panic!("`add_disk`: Unable to open {} as {}: {:?}", path, name, e),
@@ -79,6 +92,48 @@ fn main()
             Err(e) => panic!("`mount`: Unable to mount {} from {}: {:?}", mountpt, volume, e),
             }
             },
+        // Make a directory visible at another location
+        "bind" => {
+            let Some(mountpt) = args.next() else { panic!("`bind`: missing `mountpt` argument") };
+            let Some(source) = args.next() else { panic!("`bind`: missing `source` argument") };
+            log_log!("COMMAND: bind {mountpt:?} := {source:?}");
+            match ::vfs::mount::bind(mountpt.as_ref(), source.as_ref())
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("`bind`: Unable to bind {} to {}: {:?}", source, mountpt, e),
+            }
+            },
+        "unmount" => {
+            let Some(mountpt) = args.next() else { panic!("`unmount`: missing `mountpt` argument") };
+            log_log!("COMMAND: unmount {mountpt:?}");
+            match ::vfs::mount::unmount(mountpt.as_ref())
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("`unmount`: Unable to unmount {}: {:?}", mountpt, e),
+            }
+            },
+        "remount" => {
+            let Some(mountpt) = args.next() else { panic!("`remount`: missing `mountpt` argument") };
+            let options = args.next().map(|v| v.split(",").collect::<Vec<_>>()).unwrap_or_default();
+            log_log!("COMMAND: remount {mountpt:?} options={options:?}");
+            match ::vfs::mount::remount(mountpt.as_ref(), &options)
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("`remount`: Unable to remount {}: {:?}", mountpt, e),
+            }
+            },
+        // Print the mount table
+        "mounts" => {
+            log_log!("COMMAND: mounts");
+            let mut id = 0;
+            while let Some(ent) = ::vfs::mount::get_mount(id)
+            {
+                if let Some(info) = ent {
+                    println!("{}: {:?} {} {:?} {:?}", id, info.location, info.filesystem, info.source, info.flags);
+                }
+                id += 1;
+            }
+            },
         // List directory
         "ls" => {
             let dir = ::vfs::Path::new( args.next().expect("ls dir") );
@@ -101,6 +156,98 @@ fn main()
                 },
             }
             },
+        // Start watching a directory for changes
+        "watch" => {
+            let dir = args.next().expect("`watch` dir");
+            log_log!("COMMAND: watch {:?}", dir);
+            match vfs_handle::Dir::open(::vfs::Path::new(dir)).and_then(|h| h.watch())
+            {
+            Ok(w) => { watches.insert(dir.to_owned(), w); },
+            Err(e) => log_error!("`watch`: Cannot watch {:?}: {:?}", dir, e),
+            }
+            },
+        // Print (and clear) the changes seen by a watch
+        "changes" => {
+            let dir = args.next().expect("`changes` dir");
+            log_log!("COMMAND: changes {:?}", dir);
+            let w = watches.get(dir).expect("`changes` on a directory that isn't watched");
+            while let Some(c) = w.pop()
+            {
+                println!("{:?} {:?}", c.kind, c.name);
+            }
+            },
+        // Print the metadata of a node
+        "stat" => {
+            let path = ::vfs::Path::new( args.next().expect("`stat` path") );
+            log_log!("COMMAND: stat {:?}", path);
+            match vfs_handle::Any::open(path).and_then(|h| h.get_metadata())
+            {
+            Ok(m) => println!("{:?}: {:?}", path, m),
+            Err(e) => log_error!("`stat`: {:?} cannot be opened: {:?}", path, e),
+            }
+            },
+        // Change the user/group used for permission checks (only root can switch to a different identity)
+        "credentials" => {
+            let uid: u32 = args.next().expect("`credentials` uid").parse().expect("`credentials` uid invalid");
+            let gid: u32 = args.next().expect("`credentials` gid").parse().expect("`credentials` gid invalid");
+            let creds = ::kernel::threads::Credentials { uid, gid };
+            log_log!("COMMAND: credentials {}", creds);
+            match ::kernel::threads::set_credentials(creds)
+            {
+            Ok(()) => {},
+            Err(()) => log_error!("`credentials`: {} cannot switch to {}", ::kernel::threads::get_credentials(), creds),
+            }
+            },
+        // Open a file, printing the result (used to check permissions)
+        // - With a uid/gid, the file is opened through a root handle restricted to that user
+        "open" => {
+            let path: &::vfs::Path = args.next().expect("`open` path").as_ref();
+            let mode = match args.next().expect("`open` mode")
+                {
+                "ro" => vfs_handle::FileOpenMode::SharedRO,
+                "rw" => vfs_handle::FileOpenMode::ExclRW,
+                "append" => vfs_handle::FileOpenMode::Append,
+                "exec" => vfs_handle::FileOpenMode::Execute,
+                _ => panic!("`open`: Invalid `mode` argument"),
+                };
+            let restrict = args.next().map(|uid| ::kernel::threads::Credentials {
+                uid: uid.parse().expect("`open` uid invalid"),
+                gid: args.next().expect("`open` gid").parse().expect("`open` gid invalid"),
+                });
+            log_log!("COMMAND: open {:?} {:?} {:?}", path, mode, restrict);
+            let res = match restrict
+                {
+                None => vfs_handle::File::open(path, mode),
+                Some(creds) => vfs_handle::Dir::open(::vfs::Path::new("/"))
+                    .and_then(|root| root.restrict(creds))
+                    .and_then(|root| root.open_child_path(path))
+                    .and_then(|h| h.into_file(mode)),
+                };
+            match res
+            {
+            Ok(_) => println!("{:?}: Opened", path),
+            Err(e) => println!("{:?}: {:?}", path, e),
+            }
+            },
+        // Create an empty file
+        "create" => {
+            let path = ::vfs::Path::new( args.next().expect("`create` path") );
+            let (dir,name) = path.split_off_last().expect("`create` path invalid");
+            log_log!("COMMAND: create {:?} {:?}", dir, name);
+            let h = match ::vfs::handle::Dir::open(dir)
+                {
+                Ok(h) => h,
+                Err(e) => {
+                    log_error!("`create`: {:?} cannot be opened: {:?}", dir, e);
+                    continue
+                    },
+                };
+            match h.create_file(name)
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("cannot create {:?} in '{:?}': {:?}", name, dir, e),
+            }
+            },
         // Create a directory
         "mkdir" => {
             let path = ::vfs::Path::new( args.next().expect("`mkdir` path") );
@@ -120,6 +267,107 @@ fn main()
             Err(e) => log_error!("cannot create {:?} in '{:?}': {:?}", dirname, dir, e),
             }
             },
+        // Remove a file or (empty) directory
+        "unlink" => {
+            let path = ::vfs::Path::new( args.next().expect("`unlink` path") );
+            let (dir,name) = path.split_off_last().expect("`unlink` path invalid");
+            log_log!("COMMAND: unlink {:?} {:?}", dir, name);
+            let h = match ::vfs::handle::Dir::open(dir)
+                {
+                Ok(h) => h,
+                Err(e) => {
+                    log_error!("`unlink`: {:?} cannot be opened: {:?}", dir, e);
+                    continue
+                    },
+                };
+            match h.unlink(name)
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("cannot remove {:?} from '{:?}': {:?}", name, dir, e),
+            }
+            },
+        // Move a file or directory (within a filesystem)
+        "rename" => {
+            let src = ::vfs::Path::new( args.next().expect("`rename` src") );
+            let dst = ::vfs::Path::new( args.next().expect("`rename` dst") );
+            let (src_dir,src_name) = src.split_off_last().expect("`rename` src invalid");
+            let (dst_dir,dst_name) = dst.split_off_last().expect("`rename` dst invalid");
+            log_log!("COMMAND: rename {:?} {:?}", src, dst);
+            let (src_h, dst_h) = match (::vfs::handle::Dir::open(src_dir), ::vfs::handle::Dir::open(dst_dir))
+                {
+                (Ok(s), Ok(d)) => (s, d),
+                (Err(e), _) => { log_error!("`rename`: {:?} cannot be opened: {:?}", src_dir, e); continue },
+                (_, Err(e)) => { log_error!("`rename`: {:?} cannot be opened: {:?}", dst_dir, e); continue },
+                };
+            match src_h.rename(src_name, &dst_h, dst_name)
+            {
+            Ok(_) => {},
+            Err(e) => log_error!("cannot rename {:?} to {:?}: {:?}", src, dst, e),
+            }
+            },
+        // Change the size of a file
+        "truncate" => {
+            let path: &::vfs::Path = args.next().expect("`truncate` path").as_ref();
+            let size: u64 = args.next().expect("`truncate` size").parse().expect("`truncate` size invalid");
+            log_log!("COMMAND: truncate {:?} {}", path, size);
+            let h = match vfs_handle::File::open(path, vfs_handle::FileOpenMode::ExclRW)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`truncate`: Cannot open {:?}: {:?}", path, e),
+                };
+            match h.set_size(size)
+            {
+            Ok(v) if v == size => {},
+            Ok(v) => panic!("`truncate`: Size of {:?} is {} not {}", path, v, size),
+            Err(e) => panic!("`truncate`: Failed to resize {:?}: {:?}", path, e),
+            }
+            },
+        // Write a string at an offset in a file
+        "write" => {
+            let path: &::vfs::Path = args.next().expect("`write` path").as_ref();
+            let ofs: u64 = args.next().expect("`write` offset").parse().expect("`write` offset invalid");
+            let data = args.next().expect("`write` data");
+            log_log!("COMMAND: write {:?} {} {:?}", path, ofs, data);
+            let h = match vfs_handle::File::open(path, vfs_handle::FileOpenMode::ExclRW)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`write`: Cannot open {:?}: {:?}", path, e),
+                };
+            match h.write(ofs, data.as_bytes())
+            {
+            Ok(v) if v == data.len() => {},
+            Ok(v) => panic!("`write`: Short write to {:?}: {} != exp {}", path, v, data.len()),
+            Err(e) => panic!("`write`: Failed to write to {:?}: {:?}", path, e),
+            }
+            },
+        // Write to a private (copy-on-write) view of a file, checking that the write is visible through the handle
+        "cow_write" => {
+            let path: &::vfs::Path = args.next().expect("`cow_write` path").as_ref();
+            let ofs: u64 = args.next().expect("`cow_write` offset").parse().expect("`cow_write` offset invalid");
+            let data = args.next().expect("`cow_write` data");
+            log_log!("COMMAND: cow_write {:?} {} {:?}", path, ofs, data);
+            let h = match vfs_handle::File::open(path, vfs_handle::FileOpenMode::UniqueRW)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`cow_write`: Cannot open {:?}: {:?}", path, e),
+                };
+            let orig_size = h.size();
+            match h.write(ofs, data.as_bytes())
+            {
+            Ok(v) if v == data.len() => {},
+            Ok(v) => panic!("`cow_write`: Short write to {:?}: {} != exp {}", path, v, data.len()),
+            Err(e) => panic!("`cow_write`: Failed to write to {:?}: {:?}", path, e),
+            }
+            assert_eq!(h.size(), u64::max(orig_size, ofs + data.len() as u64));
+            let mut buf = vec![0; data.len()];
+            match h.read(ofs, &mut buf)
+            {
+            Ok(v) if v == data.len() => {},
+            Ok(v) => panic!("`cow_write`: Short read from {:?}: {} != exp {}", path, v, data.len()),
+            Err(e) => panic!("`cow_write`: Failed to read from {:?}: {:?}", path, e),
+            }
+            assert_eq!(buf, data.as_bytes());
+            },
         // Copy a file from local to remote
         "store" => {
             let src: &::std::path::Path = args.next().expect("`store` src").as_ref();
@@ -144,7 +392,9 @@ fn main()
                     Ok(h) => match h.into_file(::vfs::handle::FileOpenMode::ExclRW)
                         {
                         Ok(h) => {
-                            h.truncate();
+                            if let Err(e) = h.truncate() {
+                                panic!("`store`: Cannot truncate {:?}: {:?}", dst, e);
+                            }
                             h
                             },
                         Err(e) => panic!("`store`: Cannot create {:?}: {:?}", dst, e),
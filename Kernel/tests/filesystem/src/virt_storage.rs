@@ -16,10 +16,9 @@ pub enum OverlayType
     Persistent,
 }
 
-pub fn add_volume(name: &str, path: &::std::path::Path, overlay_ty: OverlayType) -> Result<()/*::kernel::metadevs::storage::PhysicalVolumeReg*/, ::std::io::Error>
+pub fn add_volume(name: &str, path: &::std::path::Path, overlay_ty: OverlayType, block_size: usize) -> Result<()/*::kernel::metadevs::storage::PhysicalVolumeReg*/, ::std::io::Error>
 {
     use ::std::io::Seek;
-    let block_size = 512;
 
     let name = name.to_owned();
 
@@ -114,6 +114,13 @@ impl TcpConnection {
             Ok(rv)
         }
     }
+
+    /// Close the sending side of the connection (data can still be received)
+    pub fn shutdown_tx(&self) -> Result<(),crate::Error> {
+        unsafe {
+            crate::Error::check_unit(::lwip_sys::netconn_shutdown(self.conn, 0, 1))
+        }
+    }
 }
 impl ::core::ops::Drop for TcpConnection {
     fn drop(&mut self) {
@@ -23,6 +23,44 @@ pub fn parse_addr(s: &str) -> Option<IpAddr>
 	}
 }
 
+/// Address of either family (for UDP, which supports IPv6)
+pub type NetAddr = ::network::Address;
+pub type UdpSocket = ::network::udp::SocketHandle;
+
+/// Parse an IPv4 or IPv6 address (IPv6 can use `::` to skip zero words)
+pub fn parse_net_addr(s: &str) -> Option<NetAddr>
+{
+	if s.contains(":") {
+		fn parse_words(s: &str) -> Option<Vec<u16>> {
+			if s == "" {
+				Some(Vec::new())
+			}
+			else {
+				s.split(':').map(|w| u16::from_str_radix(w, 16).ok()).collect()
+			}
+		}
+		let (head, tail) = match s.find("::")
+			{
+			Some(i) => (parse_words(&s[..i])?, parse_words(&s[i+2..])?),
+			None => (parse_words(s)?, Vec::new()),
+			};
+		if head.len() + tail.len() > 8 || (!s.contains("::") && head.len() != 8) {
+			return None;
+		}
+		let mut bytes = [0; 16];
+		for (i,w) in head.iter().enumerate() {
+			bytes[i*2..][..2].copy_from_slice(&w.to_be_bytes());
+		}
+		for (i,w) in tail.iter().enumerate() {
+			bytes[(8 - tail.len() + i)*2..][..2].copy_from_slice(&w.to_be_bytes());
+		}
+		Some( ::network::Address::Ipv6(::network::ipv6::Address::from_bytes(bytes)) )
+	}
+	else {
+		parse_addr(s).map(::network::Address::Ipv4)
+	}
+}
+
 pub fn init() {
     ::kernel::threads::init();
     (::network::S_MODULE.init)();
@@ -49,8 +87,42 @@ pub fn tcp_connect(ip: IpAddr, port: u16) -> ::network::tcp::ConnectionHandle {
 pub fn tcp_listen(port: u16) -> ::network::tcp::ServerHandle {
     ::network::tcp::ServerHandle::listen(port).unwrap()
 }
+pub fn tcp_close(h: &::network::tcp::ConnectionHandle) {
+    h.close().unwrap()
+}
+pub fn ipv6_add(mac: [u8; 6], addr: NetAddr, mask_bits: u8) {
+    let ::network::Address::Ipv6(addr) = addr else { panic!("{} isn't an IPv6 address", addr) };
+    ::network::ipv6::add_interface(mac, addr, mask_bits).expect("Failed to add address");
+}
+/// Open a UDP socket accepting from any remote address (binding to `::` also accepts IPv4)
+pub fn udp_bind(addr: NetAddr, port: u16) -> UdpSocket {
+    let (local, any) = match addr
+        {
+        ::network::Address::Ipv4(a) => (a != ::network::ipv4::Address::zero(), ::network::Address::Ipv4(::network::ipv4::Address::zero())),
+        ::network::Address::Ipv6(a) => (a != ::network::ipv6::Address::zero(), ::network::Address::Ipv6(::network::ipv6::Address::zero())),
+        };
+    ::network::udp::SocketHandle::new(if local { Some(addr) } else { None }, port, (any, 0), None).unwrap()
+}
+pub fn udp_send_to(sock: &UdpSocket, addr: NetAddr, port: u16, data: &[u8]) {
+    sock.send_to(addr, port, ::network::nic::SparsePacket::new_root(data)).unwrap();
+}
+pub fn udp_recv(sock: &UdpSocket, buf: &mut [u8]) -> Option<(usize, NetAddr, u16)> {
+    sock.try_recv_from(buf).unwrap()
+}
+pub fn udp_send(ip: IpAddr, port: u16, data: &[u8]) {
+    // No local address or port, and accepting any remote
+    let any = (::network::Address::Ipv4(::network::ipv4::Address::zero()), 0);
+    let sock = ::network::udp::SocketHandle::new(None, 0, any, None).unwrap();
+    sock.send_to(::network::Address::Ipv4(ip), port, ::network::nic::SparsePacket::new_root(data)).unwrap();
+}
 
 
+/// MTU reported by the test NIC (set by the `nic-mtu` command)
+static NIC_MTU: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(::network::nic::DEFAULT_MTU);
+pub fn set_mtu(mtu: usize) {
+    NIC_MTU.store(mtu, ::std::sync::atomic::Ordering::SeqCst);
+}
+
 pub struct TestNic
 {
 	number: u32,
@@ -91,6 +163,9 @@ impl network::nic::Interface for TestNic
 		log_notice!("TX #{} {:?}", self.number, HexDump(&buf));
         self.stream.send(self.number, &buf).unwrap();
     }
+    fn mtu(&self) -> usize {
+        NIC_MTU.load(::std::sync::atomic::Ordering::SeqCst)
+    }
     //fn tx_async<'a,'s>(&'s self, _: kernel::_async3::ObjectHandle, _: kernel::_async3::StackPush<'a, 's>, _: network::nic::SparsePacket<'_>) -> Result<(), network::nic::Error> {
     //    todo!("TestNic::tx_async")
     //}
@@ -45,12 +45,22 @@ pub fn run_blocking<T>(f: impl FnOnce()->T) -> T {
     f()
 }
 
+pub fn set_mtu(mtu: usize) {
+    ::lwip::os_mode::callback(move || {
+        // SAFE: Called on the lwip thread, and the default interface is set by `create_interface`
+        unsafe { (*::lwip::sys::netif_default).mtu = mtu as u16; }
+    });
+}
+
 pub fn tcp_connect(ip: IpAddr, port: u16) -> client_socket::ClientSocket {
     client_socket::ClientSocket::connect(ip, port).unwrap()
 }
 pub fn tcp_listen(port: u16) -> Server {
     Server( ::lwip::netconn::TcpServer::listen_with_backlog(port, 2).unwrap() )
 }
+pub fn tcp_close(h: &client_socket::ClientSocket) {
+    h.close_send().unwrap()
+}
 
 
 pub struct Server(::lwip::netconn::TcpServer);
@@ -22,6 +22,9 @@ impl ClientSocket {
     pub fn send_data(&self, bytes: &[u8]) -> Result<usize,::lwip::Error> {
         self.conn.send(bytes)
     }
+    pub fn close_send(&self) -> Result<(),::lwip::Error> {
+        self.conn.shutdown_tx()
+    }
     pub fn recv_data(&mut self, buf: &mut [u8]) -> Result<usize,::lwip::Error> {
 
         fn partial_read(dst: &mut [u8], dst_ofs: &mut usize, src: &::lwip::netconn::Netbuf, src_ofs: &mut usize) -> Result<(), ::lwip::Error>
@@ -109,6 +109,9 @@ fn main()
 	// Monitor stdin for commands
 	let mut tcp_conn_handles = ::std::collections::HashMap::new();
 	let mut tcp_server_handles = ::std::collections::HashMap::new();
+	// UDP and IPv6 are only wired up for the kernel stack
+	#[cfg(not(feature="lwip"))]
+	let mut udp_handles = ::std::collections::HashMap::new();
 	
     loop
     {
@@ -132,6 +135,22 @@ fn main()
 			},
 		"ipv4-add" => {
 			},
+		// Change the MTU of the test interface
+		"nic-mtu" => {
+			let mtu: usize = it.next().unwrap().parse().unwrap();
+			log_notice!("nic-mtu {}", mtu);
+			backend::set_mtu(mtu);
+			println!("OK");
+			},
+		// Add an IPv6 address to the test interface
+		#[cfg(not(feature="lwip"))]
+		"ipv6-add" => {
+			let addr = backend::parse_net_addr(it.next().expect("Missing IP")).unwrap();
+			let mask_bits: u8 = it.next().unwrap().parse().unwrap();
+			log_notice!("ipv6-add {:?}/{}", addr, mask_bits);
+			backend::ipv6_add(mac, addr, mask_bits);
+			println!("OK");
+			},
 		// Listen on a port/interface
 		"tcp-listen" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
@@ -158,10 +177,19 @@ fn main()
 			tcp_conn_handles.insert(index, backend::tcp_connect(ip, port));
 			println!("OK");
 			},
-		// Close a TCP connection
+		// Close the sending side of a TCP connection (the handle stays open)
 		"tcp-close" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
-			todo!("tcp-close {}", index);
+			log_notice!("tcp-close {}", index);
+			backend::tcp_close(&tcp_conn_handles[&index]);
+			println!("OK");
+			},
+		// Release the handle to a TCP connection
+		"tcp-drop" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			log_notice!("tcp-drop {}", index);
+			tcp_conn_handles.remove(&index).expect("BUG: Bad connection index");
+			println!("OK");
 			},
 		"tcp-send" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
@@ -185,6 +213,75 @@ fn main()
 			assert_eq!(&buf[..len], &exp_bytes[..]);
 			println!("OK");
 			},
+		// Check that the remote has closed the connection (and all data has been read)
+		"tcp-recv-eof" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			log_notice!("tcp-recv-eof {}", index);
+			let h = tcp_conn_handles.get_mut(&index).unwrap();
+
+			let mut buf = [0; 1];
+			let len = h.recv_data(&mut buf).unwrap();
+			assert_eq!(len, 0, "Data received, expected end of stream");
+			println!("OK");
+			},
+		// Send a UDP datagram from a socket without a bound port
+		#[cfg(not(feature="lwip"))]
+		"udp-send" => {
+			let ip = backend::parse_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			let bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			log_notice!("udp-send {:?}:{} {:?}", ip, port, bytes);
+			backend::udp_send(ip, port, &bytes);
+			println!("OK");
+			},
+		// Open a UDP socket on a local address and port (`::` receives both IPv4 and IPv6)
+		#[cfg(not(feature="lwip"))]
+		"udp-bind" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let addr = backend::parse_net_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			log_notice!("udp-bind {} = {:?}:{}", index, addr, port);
+			udp_handles.insert(index, backend::udp_bind(addr, port));
+			println!("OK");
+			},
+		#[cfg(not(feature="lwip"))]
+		"udp-send-to" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let addr = backend::parse_net_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			let bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			log_notice!("udp-send-to {} {:?}:{} {:?}", index, addr, port, bytes);
+			backend::udp_send_to(&udp_handles[&index], addr, port, &bytes);
+			println!("OK");
+			},
+		// Receive a datagram, and check the source and contents
+		#[cfg(not(feature="lwip"))]
+		"udp-recv-assert" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let exp_addr = backend::parse_net_addr(it.next().expect("Missing IP")).unwrap();
+			let exp_port: u16 = it.next().unwrap().parse().unwrap();
+			let exp_bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			log_notice!("udp-recv-assert {} {:?}:{} == {:?}", index, exp_addr, exp_port, exp_bytes);
+			let h = &udp_handles[&index];
+
+			// Received packets are processed on another thread, so might not have arrived yet
+			let mut buf = [0; 1500];
+			let mut tries = 0;
+			let (len, addr, port) = loop {
+				match backend::udp_recv(h, &mut buf)
+				{
+				Some(v) => break v,
+				None if tries < 100 => {
+					backend::run_blocking(|| ::std::thread::sleep(::std::time::Duration::from_millis(10)));
+					tries += 1;
+					},
+				None => panic!("No datagram received"),
+				}
+				};
+			assert_eq!((addr, port), (exp_addr, exp_port));
+			assert_eq!(&buf[..len], &exp_bytes[..]);
+			println!("OK");
+			},
 		_ => panic!("ERROR: Unknown command '{}'", cmd),
 		}
     }
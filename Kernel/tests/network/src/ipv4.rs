@@ -2,6 +2,9 @@
 use std::io::Cursor;
 use std::mem::size_of;
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Copy,Clone,PartialEq)]
 pub struct Addr(pub [u8; 4]);
 impl ::core::fmt::Debug for Addr {
@@ -102,3 +105,76 @@ pub fn prime_arp(fw: &crate::TestFramework, dst: Addr, src: Addr)
     // Short sleep for processing
     ::std::thread::sleep(::std::time::Duration::new(0,250*1000));
 }
+
+/// Send a payload as a sequence of IPv4 fragments
+/// 
+/// `frags` is the byte range of the payload sent in each fragment (in the order they're sent)
+pub fn send_fragments(fw: &crate::TestFramework, src: Addr, dst: Addr, proto: u8, identification: u16, payload: &[u8], frags: &[(usize,usize)])
+{
+    for &(start,end) in frags
+    {
+        assert!(start % 8 == 0, "Fragment offsets must be a multiple of 8");
+        let mut h = Header::new_simple(src, dst, proto, end - start);
+        h.identification = identification;
+        h.fragment_info = (start / 8) as u16 | if end < payload.len() { FRAG_MORE } else { 0 };
+        h.set_checksum();
+        fw.send_ethernet_direct(0x0800, &[&h.encode(), &payload[start..end]]);
+    }
+}
+
+/// "More Fragments" flag in [Header::fragment_info]
+pub const FRAG_MORE: u16 = 0x2000;
+
+/// Combine received IPv4 fragments (full ethernet frames) into a single unfragmented frame
+pub fn reassemble(frames: &[Vec<u8>]) -> Vec<u8>
+{
+    let mut first = None;
+    let mut data = Vec::new();
+    let mut ranges = Vec::new();
+    let mut total_len = None;
+    for f in frames
+    {
+        let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(f);
+        assert_eq!(ether_hdr.proto, 0x0800, "Fragment isn't IPv4");
+        let (ip_hdr, ip_options, tail) = Header::parse(tail);
+        assert_eq!(ip_options.len(), 0);
+        assert_eq!(ip_hdr.calculate_checksum(), 0, "Bad IPv4 header checksum");
+        let body = &tail[.. ip_hdr.total_length as usize - 20];
+        let ofs = (ip_hdr.fragment_info & 0x1FFF) as usize * 8;
+        if ip_hdr.fragment_info & FRAG_MORE != 0 {
+            assert!(body.len() % 8 == 0, "Non-final fragment isn't a multiple of 8 bytes ({})", body.len());
+        }
+        else {
+            total_len = Some(ofs + body.len());
+        }
+        if data.len() < ofs + body.len() {
+            data.resize(ofs + body.len(), 0);
+        }
+        data[ofs..][..body.len()].copy_from_slice(body);
+        ranges.push((ofs, ofs + body.len()));
+
+        match first
+        {
+        None => first = Some((ether_hdr, ip_hdr)),
+        Some((_, ref h)) => assert_eq!(h.identification, ip_hdr.identification, "Fragment identification mismatch"),
+        }
+    }
+    let total_len = total_len.expect("No final fragment");
+    ranges.sort();
+    let mut pos = 0;
+    for (s,e) in ranges {
+        assert!(s <= pos, "Gap in fragments at {}", pos);
+        pos = pos.max(e);
+    }
+    assert_eq!(pos, total_len);
+
+    let (ether_hdr, mut ip_hdr) = first.expect("No fragments");
+    ip_hdr.total_length = (20 + total_len) as u16;
+    ip_hdr.fragment_info = 0;
+    ip_hdr.set_checksum();
+    let mut rv = Vec::new();
+    rv.extend(ether_hdr.encode());
+    rv.extend(ip_hdr.encode());
+    rv.extend(data);
+    rv
+}
@@ -0,0 +1,178 @@
+//! IPv4 tests
+use crate::ipv4::Addr as IpAddr4;
+use crate::tcp::{TcpConn, TCP_ACK, TCP_PSH};
+
+const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+/// Options sent with the SYN (MSS=1460)
+const SYN_OPTIONS: [u8; 4] = [2,4,0x05,0xB4];
+
+/// Reassembly of received fragments (out of order, and overlapping)
+#[test]
+fn fragment_rx()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_fragment_rx");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let mut conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &SYN_OPTIONS);
+
+    // 20 byte TCP header + 100 bytes of data, sent as three fragments (last first, and with the middle overlapping the first)
+    let testblob: Vec<u8> = (0 .. 100).collect();
+    conn.raw_send_fragmented(TCP_ACK|TCP_PSH, &testblob, &[(96,120), (0,48), (40,96)]);
+    conn.local_seq += testblob.len() as u32;
+    fw.send_command( &format!("tcp-recv-assert 0 {} {}", testblob.len(), crate::HexString(&testblob)) );
+
+    // The reply acknowledges the reassembled data
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    let hdr = conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    assert_eq!(hdr.ack, conn.local_seq, "Reassembled data not acknowledged");
+}
+
+/// Fragmentation of packets larger than the interface MTU
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn fragment_tx()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_fragment_tx");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    // Reduce the MTU after connecting, so the MSS doesn't already avoid fragmentation
+    let conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &SYN_OPTIONS);
+    fw.send_command("nic-mtu 576");
+
+    // 20+700 bytes doesn't fit in a 576 byte MTU, so is sent as two fragments
+    let testblob: Vec<u8> = (0 .. 700).map(|v| v as u8).collect();
+    fw.send_command( &format!("tcp-send 0 {}", crate::HexString(&testblob)) );
+    let frags: Vec<Vec<u8>> = (0 .. 2)
+        .map(|_| fw.wait_packet(std::time::Duration::from_millis(1000)).expect("Fragment not received"))
+        .collect();
+    for f in &frags {
+        assert!(f.len() - 14 <= 576, "Fragment larger than the MTU ({} bytes)", f.len() - 14);
+    }
+    conn.check_rx(&crate::ipv4::reassemble(&frags), TCP_ACK|TCP_PSH, &testblob);
+}
+
+/// Send a packet with a simple (unfragmented) IPv4 header
+fn send_simple(fw: &crate::TestFramework, proto: u8, data: &[u8])
+{
+    let mut h = crate::ipv4::Header::new_simple(LOCAL_ADDR, REMOTE_ADDR, proto, data.len());
+    h.set_checksum();
+    fw.send_ethernet_direct(0x0800, &[&h.encode(), data]);
+}
+/// Wait for an ICMP message, returning the type, code, and the data after the checksum
+fn wait_icmp(fw: &crate::TestFramework) -> (u8, u8, Vec<u8>)
+{
+    let pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No ICMP message received");
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&pkt);
+    assert_eq!(ether_hdr.proto, 0x0800, "Not IPv4");
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 1, "Not ICMP");
+    assert_eq!(ip_hdr.dst_addr, LOCAL_ADDR.0);
+    let body = &tail[.. ip_hdr.total_length as usize - 20];
+    let sum = crate::ipv4::calculate_ip_checksum(body.chunks(2).map(|v| u16::from_be_bytes([v[0], *v.get(1).unwrap_or(&0)])));
+    assert_eq!(sum, 0, "Bad ICMP checksum");
+    (body[0], body[1], body[4..].to_vec())
+}
+fn icmp_encode(ty: u8, code: u8, data: &[u8]) -> Vec<u8>
+{
+    let mut rv = vec![ty, code, 0, 0];
+    rv.extend_from_slice(data);
+    let sum = crate::ipv4::calculate_ip_checksum(rv.chunks(2).map(|v| u16::from_be_bytes([v[0], *v.get(1).unwrap_or(&0)])));
+    rv[2..4].copy_from_slice(&sum.to_be_bytes());
+    rv
+}
+
+/// ICMP echo requests are answered with the same data
+#[test]
+fn icmp_echo()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_icmp_echo");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    // Identifier, sequence number, then some data
+    let data = [0x12,0x34, 0x00,0x01, b'p',b'i',b'n',b'g', 1,2,3];
+    send_simple(&fw, 1, &icmp_encode(8, 0, &data));
+    let (ty, code, reply) = wait_icmp(&fw);
+    assert_eq!((ty, code), (0, 0), "Expected an echo reply");
+    assert_eq!(reply, data, "Echo reply data mismatch");
+}
+
+/// UDP to a closed port elicits an ICMP port unreachable, quoting the original packet
+#[test]
+fn icmp_udp_port_unreachable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_icmp_udp_unreachable");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let udp = [0x30,0x39, 0x23,0x29, 0,12, 0,0, 1,2,3,4];   // 12345 -> 9001, no checksum
+    send_simple(&fw, 17, &udp);
+    let (ty, code, data) = wait_icmp(&fw);
+    assert_eq!((ty, code), (3, 3), "Expected a port unreachable");
+    // Quoted IP header, then the first 8 bytes of the UDP packet
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(&data);
+    assert_eq!(ip_hdr.protocol, 17);
+    assert_eq!(ip_hdr.src_addr, LOCAL_ADDR.0);
+    assert_eq!(ip_hdr.dst_addr, REMOTE_ADDR.0);
+    assert_eq!(&tail[..8], &udp[..8]);
+}
+
+/// Packets for an unknown protocol elicit an ICMP protocol unreachable
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn icmp_protocol_unreachable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_icmp_proto_unreachable");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    send_simple(&fw, 253, &[0; 16]);   // 253 = "Use for experimentation and testing"
+    let (ty, code, data) = wait_icmp(&fw);
+    assert_eq!((ty, code), (3, 2), "Expected a protocol unreachable");
+    let (ip_hdr, _, _) = crate::ipv4::Header::parse(&data);
+    assert_eq!(ip_hdr.protocol, 253);
+}
+
+/// A UDP socket opened without a local port can send, using a random ephemeral port
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn udp_send_unbound()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_udp_send_unbound");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let data = [1,2,3,4,5];
+    fw.send_command(&format!("udp-send {} 9001 {}", LOCAL_ADDR, crate::HexString(&data)));
+    let pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No UDP packet received");
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&pkt);
+    assert_eq!(ether_hdr.proto, 0x0800, "Not IPv4");
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 17, "Not UDP");
+    assert_eq!(ip_hdr.src_addr, REMOTE_ADDR.0);
+    assert_eq!(ip_hdr.dst_addr, LOCAL_ADDR.0);
+    let udp = &tail[.. ip_hdr.total_length as usize - 20];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    assert!(src_port >= 0xC000, "Source port {} not in the dynamic range", src_port);
+    assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), 9001);
+    assert_eq!(u16::from_be_bytes([udp[4], udp[5]]) as usize, udp.len());
+    assert_eq!(&udp[8..], &data);
+    // Checksum covers the pseudo-header (addresses, protocol, and length) and the UDP packet
+    let mut ph = Vec::new();
+    ph.extend_from_slice(&REMOTE_ADDR.0);
+    ph.extend_from_slice(&LOCAL_ADDR.0);
+    ph.extend_from_slice(&[0, 17]);
+    ph.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    ph.extend_from_slice(udp);
+    let sum = crate::ipv4::calculate_ip_checksum(ph.chunks(2).map(|v| u16::from_be_bytes([v[0], *v.get(1).unwrap_or(&0)])));
+    assert_eq!(sum, 0, "Bad UDP checksum");
+}
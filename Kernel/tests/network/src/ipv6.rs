@@ -0,0 +1,64 @@
+use std::io::Cursor;
+
+#[derive(Copy,Clone,PartialEq)]
+pub struct Addr(pub [u8; 16]);
+impl ::core::fmt::Debug for Addr {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		::std::fmt::Display::fmt(self, f)
+	}
+}
+impl ::core::fmt::Display for Addr {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		for (i,w) in self.0.chunks(2).enumerate() {
+			if i > 0 {
+				f.write_str(":")?;
+			}
+			write!(f, "{:x}", u16::from_be_bytes([w[0], w[1]]))?;
+		}
+		Ok( () )
+	}
+}
+
+#[derive(serde::Deserialize,::serde::Serialize)]
+#[derive(Debug)]
+pub struct Header
+{
+	pub ver_tc_fl: u32,
+	pub payload_length: u16,
+	pub next_header: u8,
+	pub hop_limit: u8,
+	pub src_addr: [u8; 16],
+	pub dst_addr: [u8; 16],
+}
+impl Header
+{
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse IPv6 header");
+        assert_eq!(rv.ver_tc_fl >> 28, 6, "Bad IP version");
+        (rv, buf)
+    }
+	pub fn new_simple(src: Addr, dst: Addr, next_header: u8, data_len: usize) -> Self
+	{
+		Header {
+			ver_tc_fl: 6 << 28,
+			payload_length: data_len as u16,
+			next_header: next_header,
+			hop_limit: 18,	// Doesn't need to be high, not routed here
+			src_addr: src.0,
+			dst_addr: dst.0,
+			}
+	}
+	pub fn encode(&self) -> [u8; 40]
+	{
+		let mut buf = [0; 40];
+		crate::ser_be(&mut Cursor::new(&mut buf[..]), self);
+		buf
+	}
+}
+
+/// Send a packet with a simple (no extension headers) IPv6 header
+pub fn send_packet(fw: &crate::TestFramework, src: Addr, dst: Addr, next_header: u8, data: &[u8])
+{
+    let h = Header::new_simple(src, dst, next_header, data.len());
+    fw.send_ethernet_direct(0x86DD, &[&h.encode(), data]);
+}
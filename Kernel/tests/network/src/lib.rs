@@ -6,7 +6,9 @@ const REMOTE_MAC: [u8; 6] = *b"RSK\x12\x34\x56";
 const LOCAL_MAC: [u8; 6] = *b"RSK\xFE\xFE\xFE";
 
 pub mod tcp;
+pub mod udp;
 pub mod ipv4;
+pub mod ipv6;
 pub mod ethernet;
 pub mod arp;
 
@@ -303,6 +305,17 @@ impl<'a> ::std::fmt::Debug for HexDump<'a>
 	}
 }
 
+/// Helper to create a string of hex-encoded bytes
+pub struct HexString<'a>(pub &'a [u8]);
+impl ::std::fmt::Display for HexString<'_> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct MessageStream(::std::sync::Arc<::std::net::TcpStream>);
 impl MessageStream {
@@ -119,6 +119,35 @@ pub const TCP_RST: u8 = 0x04;
 pub const TCP_PSH: u8 = 0x08;
 pub const TCP_ACK: u8 = 0x10;
 
+/// Find an option in the options from a TCP header, returning its data
+pub fn find_option(mut options: &[u8], kind: u8) -> Option<&[u8]>
+{
+    while let Some(&k) = options.first()
+    {
+        match k
+        {
+        0 => break,
+        1 => { options = &options[1..]; continue },
+        _ => {},
+        }
+        let len = options[1] as usize;
+        assert!(len >= 2 && len <= options.len(), "Malformed TCP option: {:x?}", options);
+        if k == kind {
+            return Some(&options[2..len]);
+        }
+        options = &options[len..];
+    }
+    None
+}
+/// Encode a timestamp option (with two NOPs for alignment)
+pub fn opt_timestamp(val: u32, ecr: u32) -> [u8; 12]
+{
+    let mut rv = [1, 1, 8, 10, 0,0,0,0, 0,0,0,0];
+    rv[4..8].copy_from_slice(&val.to_be_bytes());
+    rv[8..].copy_from_slice(&ecr.to_be_bytes());
+    rv
+}
+
 pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, mut header: Header, options: &[u8], data: &[u8])
 {
     assert!(options.len() % 4 == 0);
@@ -137,11 +166,11 @@ pub struct TcpConn<'a>
 {
     pub fw: &'a crate::TestFramework,
     /// Framework address, testee address
-    addrs: (crate::ipv4::Addr, crate::ipv4::Addr),
+    pub addrs: (crate::ipv4::Addr, crate::ipv4::Addr),
     /// Testee port
-    remote_port: u16, 
+    pub remote_port: u16, 
     /// Framework port
-    local_port: u16,
+    pub local_port: u16,
 
     /// Framework's RX window
     pub rx_window: u16,
@@ -166,14 +195,60 @@ impl TcpConn<'_>
             };
         send_packet_raw(self.fw, self.addrs.0, self.addrs.1, hdr, options, data);
     }
+    /// Send a packet split into IPv4 fragments (see [crate::ipv4::send_fragments])
+    pub fn raw_send_fragmented(&self, flags: u8, data: &[u8], frags: &[(usize,usize)])
+    {
+        let mut hdr = Header {
+            src_port: self.local_port,
+            dst_port: self.remote_port,
+            seq: self.local_seq,
+            ack: self.remote_seq,
+            data_ofs: (20/4 << 4) as u8,
+            flags: flags,
+            window: self.rx_window,
+            checksum: 0,
+            urg_ptr: 0,
+            };
+        hdr.set_checksum_v4(self.addrs.0, self.addrs.1, &[], data);
+        let payload: Vec<u8> = hdr.encode().iter().chain(data.iter()).copied().collect();
+        crate::ipv4::send_fragments(self.fw, self.addrs.0, self.addrs.1, 6, 0x1234, &payload, frags);
+    }
     #[track_caller]
     pub fn wait_rx_check(&self, flags: u8, data: &[u8]) -> Header
+    {
+        self.wait_rx_check_within(std::time::Duration::from_millis(1000), flags, data)
+    }
+    /// Wait for a packet (with a specified timeout), and check its contents
+    #[track_caller]
+    pub fn wait_rx_check_within(&self, timeout: std::time::Duration, flags: u8, data: &[u8]) -> Header
+    {
+        let data_handle = match self.fw.wait_packet(timeout)
+            {
+            Some(v) => v,
+            None => panic!("No packet received within {:?}", timeout),
+            };
+        self.check_rx(&data_handle, flags, data)
+    }
+    /// Wait for a packet, and check its contents (returning the header and options)
+    #[track_caller]
+    pub fn wait_rx_check_opts(&self, flags: u8, data: &[u8]) -> (Header, Vec<u8>)
     {
         let data_handle = match self.fw.wait_packet(std::time::Duration::from_millis(1000))
             {
             Some(v) => v,
             None => panic!("No packet received"),
             };
+        self.check_rx_opts(&data_handle, flags, data)
+    }
+    /// Check a received (unfragmented) packet
+    #[track_caller]
+    pub fn check_rx(&self, data_handle: &[u8], flags: u8, data: &[u8]) -> Header
+    {
+        self.check_rx_opts(data_handle, flags, data).0
+    }
+    #[track_caller]
+    fn check_rx_opts(&self, data_handle: &[u8], flags: u8, data: &[u8]) -> (Header, Vec<u8>)
+    {
         let tail = &data_handle[..];
         // 1. Check the ethernet header
         let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(tail);
@@ -188,12 +263,10 @@ impl TcpConn<'_>
         let (tcp_hdr,tcp_options, tail) = Header::parse(tail);
         assert!(tcp_hdr.dst_port == self.local_port, "TCP destination port mismatch: Exp {} got {}", tcp_hdr.dst_port, self.local_port);
         assert!(tcp_hdr.src_port == self.remote_port, "TCP source port mismatch: Exp {} got {}", tcp_hdr.src_port, self.remote_port);
-        //assert!(tcp_options.len() == 0, "Expected no TCP options, got {:?}", tcp_options);
-        let _ = tcp_options;
         assert!(tcp_hdr.flags == flags, "Header flags mismatch: Expected {:#x} got {:#x}", flags, tcp_hdr.flags);
         // 4. Check the data
         assert_eq!(tail, data, "Data mismatch");
-        tcp_hdr
+        (tcp_hdr, tcp_options.to_vec())
     }
     #[track_caller]
     pub fn wait_rx_none(&self)
@@ -251,7 +324,38 @@ impl TcpConn<'_>
             rx_window: 0x1000,
 
             local_seq: 0x10000,
-            remote_seq: tcp_hdr.seq + 1,
+            remote_seq: tcp_hdr.seq.wrapping_add(1),
             }
     }
+
+    /// Send a SYN (with the given options) to a listening socket on the testee, returning the options from the SYN-ACK
+    pub fn start_server_conn<'a>(fw: &'a crate::TestFramework, local_addr: crate::ipv4::Addr, remote_addr: crate::ipv4::Addr, syn_options: &[u8]) -> (TcpConn<'a>, Vec<u8>)
+    {
+        fw.send_command("tcp-listen 0 80");
+        let mut conn = TcpConn {
+            fw: fw,
+            addrs: (local_addr, remote_addr),
+            remote_port: 80,
+            local_port: 11200,
+
+            rx_window: 0x1000,
+
+            local_seq: 0x1000,
+            remote_seq: 0x1000,
+            };
+        conn.raw_send_packet(TCP_SYN, syn_options, &[]);
+        conn.local_seq = conn.local_seq.wrapping_add(1);
+        let (hdr, options) = conn.wait_rx_check_opts(TCP_SYN|TCP_ACK, &[]);
+        conn.remote_seq = hdr.seq.wrapping_add(1);
+        (conn, options)
+    }
+    /// Open a connection to a listening socket on the testee (accepted as connection #0)
+    pub fn open_server_conn<'a>(fw: &'a crate::TestFramework, local_addr: crate::ipv4::Addr, remote_addr: crate::ipv4::Addr, syn_options: &[u8]) -> TcpConn<'a>
+    {
+        let (conn, _) = TcpConn::start_server_conn(fw, local_addr, remote_addr, syn_options);
+        conn.raw_send_packet(TCP_ACK, &[], &[]);
+        conn.wait_rx_none();
+        fw.send_command("tcp-accept 0 0");
+        conn
+    }
 }
@@ -1,6 +1,7 @@
 //! TCP tests
 use crate::ipv4::Addr as IpAddr4;
 use super::*;
+use crate::HexString;
 
 /// TCP State CLOSED
 /// 
@@ -138,9 +139,10 @@ fn client()
     // Get the client to send data
     fw.send_command("tcp-send 0 \"00 01 02 03\"");
     fw.send_command("tcp-send 0 \"05 06 07 08\"");
-    conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK|TCP_PSH } else { TCP_PSH }, &[0,1,2,3])
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3])
         .assert_seq(conn.remote_seq);
-    conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK|TCP_PSH } else { 0 }, &[5,6,7,8])
+    // - The second send is held by Nagle's algorithm until the first is ACKed (or a timeout)
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[5,6,7,8])
         .assert_seq(conn.remote_seq+4);
 }
 
@@ -162,8 +164,8 @@ fn retransmits()
 
     // Expects the SYN
     let mut conn = TcpConn::from_rx_conn(&fw, 80, IpAddr4([192,168,1,2]));
-    // Wait some time, then expect a new SYN
-    conn.wait_rx_check(TCP_SYN, &[]);
+    // Wait some time (the initial 1s RTO), then expect a new SYN
+    conn.wait_rx_check_within(std::time::Duration::from_millis(1500), TCP_SYN, &[]);
 
     // Send SYN,ACK
     conn.raw_send_packet(TCP_SYN|TCP_ACK, &[], &[]);
@@ -172,9 +174,10 @@ fn retransmits()
     conn.wait_rx_check(TCP_ACK, &[]);
     // Get the client to send data
     fw.send_command("tcp-send 0 \"00 01 02 03\"");
-    conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK|TCP_PSH } else { TCP_PSH }, &[0,1,2,3])
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3])
         .assert_seq(conn.remote_seq);
-    conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK } else { 0 }, &[0,1,2,3])
+    // - The SYN was retransmitted, so the RTO is 3s until a RTT is measured (RFC 6298 5.7)
+    conn.wait_rx_check_within(std::time::Duration::from_millis(3500), TCP_ACK, &[0,1,2,3])
         .assert_seq(conn.remote_seq);
     conn.remote_seq += 4;
     conn.raw_send_packet(TCP_ACK, &[], &[]);
@@ -182,13 +185,356 @@ fn retransmits()
 }
 // */
 
-/// Helper to create a string of hex-encoded bytes
-struct HexString<'a>(&'a [u8]);
-impl ::std::fmt::Display for HexString<'_> {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        for b in self.0 {
-            write!(f, "{:02x}", b)?;
-        }
-        Ok(())
+/// The retransmit timeout doubles on each unanswered retransmission
+#[test]
+fn retransmit_backoff()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_retransmit_backoff");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    let t0 = std::time::Instant::now();
+    conn.wait_rx_check_within(std::time::Duration::from_millis(2500), TCP_ACK, &[0,1,2,3])
+        .assert_seq(conn.remote_seq);
+    let t1 = std::time::Instant::now();
+    conn.wait_rx_check_within(std::time::Duration::from_millis(4500), TCP_ACK, &[0,1,2,3])
+        .assert_seq(conn.remote_seq);
+    let t2 = std::time::Instant::now();
+    assert!(t2 - t1 > (t1 - t0) * 3 / 2, "Retransmit timeout didn't back off: {:?} then {:?}", t1 - t0, t2 - t1);
+}
+
+/// Three duplicate ACKs trigger a retransmit before the timer expires
+#[test]
+fn fast_retransmit()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_fast_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    // Acknowledge nothing three times (as if later segments arrived, but not this one)
+    for _ in 0 .. 3 {
+        conn.raw_send_packet(TCP_ACK, &[], &[]);
+    }
+    conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &[0,1,2,3])
+        .assert_seq(conn.remote_seq);
+}
+
+/// ACKs for received data are delayed (but not for too long)
+#[test]
+fn delayed_ack()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_delayed_ack");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let mut conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], &[1,2,3,4]);
+    conn.local_seq += 4;
+    conn.wait_rx_none();
+    // RFC 1122 requires the delay to be less than 500ms
+    let hdr = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "Data not acknowledged");
+}
+
+/// MSS, window scaling and timestamps are negotiated, and timestamps protect against old segments
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn options_negotiation()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_options_negotiation");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    // MSS=100, window scale of 2, SACK permitted, and a timestamp
+    let mut syn_options = vec![2,4,0,100, 1,3,3,2, 1,1,4,2];
+    syn_options.extend_from_slice(&opt_timestamp(1000, 0));
+    let (mut conn, options) = TcpConn::start_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &syn_options);
+    assert_eq!(find_option(&options, 2), Some(&1460u16.to_be_bytes()[..]), "SYN-ACK MSS should be from the 1500 byte MTU");
+    let window_shift = find_option(&options, 3).expect("No window scale in SYN-ACK")[0];
+    assert!(find_option(&options, 4).is_some(), "No SACK permitted in SYN-ACK");
+    let ts = find_option(&options, 8).expect("No timestamp in SYN-ACK");
+    assert_eq!(&ts[4..], &1000u32.to_be_bytes(), "Timestamp not echoed");
+    let remote_ts = u32::from_be_bytes([ts[0], ts[1], ts[2], ts[3]]);
+    conn.raw_send_packet(TCP_ACK, &opt_timestamp(1001, remote_ts), &[]);
+    conn.wait_rx_none();
+    fw.send_command("tcp-accept 0 0");
+
+    // Sent data is split at the MSS, and carries timestamps and a scaled window
+    let testblob: Vec<u8> = (0 .. 250).map(|v| v as u8).collect();
+    fw.send_command(&format!("tcp-send 0 {}", HexString(&testblob)));
+    for (i, chunk) in testblob.chunks(100).enumerate()
+    {
+        let (hdr, options) = conn.wait_rx_check_opts(if i == 2 { TCP_ACK|TCP_PSH } else { TCP_ACK }, chunk);
+        hdr.assert_seq(conn.remote_seq);
+        conn.remote_seq += chunk.len() as u32;
+        let ts = find_option(&options, 8).expect("No timestamp in data");
+        assert_eq!(&ts[4..], &1001u32.to_be_bytes(), "Timestamp not echoed");
+        assert!((hdr.window as u32) << window_shift > 0xFFFF, "Window not scaled: {:#x} << {}", hdr.window, window_shift);
+        // NOTE: The final partial segment is held by Nagle's algorithm until a short timeout
     }
-}
\ No newline at end of file
+    conn.raw_send_packet(TCP_ACK, &opt_timestamp(1002, remote_ts), &[]);
+    conn.wait_rx_none();
+
+    // PAWS: A segment with an older timestamp is dropped (and the current state ACKed)
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &opt_timestamp(500, remote_ts), &[1,2,3,4]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "Data with an old timestamp was accepted");
+}
+
+/// Out-of-order data is reported using SACK blocks
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn sack_blocks()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_sack_blocks");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    // MSS and SACK permitted
+    let (mut conn, options) = TcpConn::start_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[2,4,0x05,0xB4, 1,1,4,2]);
+    assert!(find_option(&options, 4).is_some(), "No SACK permitted in SYN-ACK");
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+    fw.send_command("tcp-accept 0 0");
+
+    // Send data after a gap, the ACK reports it
+    let testblob: Vec<u8> = (0 .. 20).collect();
+    let base_seq = conn.local_seq;
+    conn.local_seq += 10;
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], &testblob[10..]);
+    conn.local_seq = base_seq;
+    let (hdr, options) = conn.wait_rx_check_opts(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, base_seq, "ACK should be for the start of the gap");
+    let sack = find_option(&options, 5).expect("No SACK blocks");
+    assert_eq!(sack, &[(base_seq + 10).to_be_bytes(), (base_seq + 20).to_be_bytes()].concat()[..], "Incorrect SACK block");
+
+    // Fill the gap, which is ACKed immediately (with no SACK blocks)
+    conn.raw_send_packet(TCP_ACK, &[], &testblob[..10]);
+    conn.local_seq += 20;
+    let (hdr, options) = conn.wait_rx_check_opts(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "Filled gap not ACKed");
+    assert!(find_option(&options, 5).is_none(), "Unexpected SACK blocks");
+    fw.send_command( &format!("tcp-recv-assert 0 {} {}", testblob.len(), HexString(&testblob)) );
+}
+
+/// The advertised MSS is derived from the interface MTU
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn mss_from_mtu()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_mss_from_mtu");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("nic-mtu 576");
+    let (_conn, options) = TcpConn::start_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[2,4,0x05,0xB4]);
+    assert_eq!(find_option(&options, 2), Some(&(576u16 - 40).to_be_bytes()[..]), "Incorrect MSS");
+}
+
+/// Initial sequence numbers are unpredictable (RFC 6528), differing between connections
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn isn_unpredictable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_isn_unpredictable");
+        fw.add_handler(crate::arp::ArpHandler::new(IpAddr4([192,168,1,2])));
+        fw
+        };
+    let (conn1, _) = TcpConn::start_server_conn(&fw, IpAddr4([192,168,1,2]), IpAddr4([192,168,1,1]), &[]);
+    // A second SYN from a different port
+    let conn2 = TcpConn {
+        fw: &fw,
+        addrs: conn1.addrs,
+        remote_port: 80,
+        local_port: 11201,
+
+        rx_window: 0x1000,
+
+        local_seq: 0x1000,
+        remote_seq: 0x1000,
+        };
+    conn2.raw_send_packet(TCP_SYN, &[], &[]);
+    let (hdr2, _) = conn2.wait_rx_check_opts(TCP_SYN|TCP_ACK, &[]);
+    let isn1 = conn1.remote_seq.wrapping_sub(1);
+    let isn2 = hdr2.seq;
+    assert_ne!(isn1, 1, "Fixed ISN");
+    // - The clock component only advances by 250 per millisecond, so close values indicate a predictable ISN
+    let diff = isn2.wrapping_sub(isn1);
+    assert!(diff > 0x10000 && diff < 0u32.wrapping_sub(0x10000), "ISNs too close: {:#x} and {:#x}", isn1, isn2);
+}
+
+/// Client connections use randomly selected ports in the dynamic range (RFC 6056)
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn client_ephemeral_port()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_client_ephemeral_port");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let conn1 = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    fw.send_command(&format!("tcp-connect 1 {my_ip} 80"));
+    let conn2 = TcpConn::from_rx_conn(&fw, 80, my_ip);
+    for c in [&conn1, &conn2] {
+        assert!(c.remote_port >= 0xC000, "Port {} not in the dynamic range", c.remote_port);
+    }
+    assert_ne!(conn2.remote_port, conn1.remote_port.wrapping_add(1), "Ports allocated sequentially");
+}
+
+/// Passive close: The remote's FIN ends the stream once all data is read, then closing sends our FIN
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn close_passive()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_close_passive");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let mut conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    // Data with a FIN, both are ACKed immediately
+    conn.raw_send_packet(TCP_ACK|TCP_PSH|TCP_FIN, &[], &[1,2,3,4]);
+    conn.local_seq += 4 + 1;
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "FIN not acknowledged");
+    fw.send_command("tcp-recv-assert 0 16 \"01 02 03 04\"");
+    fw.send_command("tcp-recv-eof 0");
+
+    // CLOSE-WAIT: Sending still works
+    fw.send_command("tcp-send 0 \"05 06\"");
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &[5,6]);
+    conn.remote_seq += 2;
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+
+    // Close, and acknowledge the FIN (LAST-ACK -> CLOSED)
+    fw.send_command("tcp-close 0");
+    conn.wait_rx_check(TCP_ACK|TCP_FIN, &[]).assert_seq(conn.remote_seq);
+    conn.remote_seq += 1;
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+    fw.send_command("tcp-drop 0");
+    conn.wait_rx_none();
+
+    // The connection is gone, so anything further is reset
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], &[7,8]);
+    conn.wait_rx_check(TCP_ACK|TCP_RST, &[]).assert_seq(conn.remote_seq);
+}
+
+/// Active close: After our FIN the remote can keep sending, and its FIN is ACKed (again if retransmitted) in TIME-WAIT
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn close_active()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_close_active");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let mut conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    fw.send_command("tcp-close 0");
+    conn.wait_rx_check(TCP_ACK|TCP_FIN, &[]).assert_seq(conn.remote_seq);
+    conn.remote_seq += 1;
+
+    // FIN-WAIT-2: ACK the FIN and send data, which can still be received
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], &[1,2,3,4]);
+    conn.local_seq += 4;
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "Data not acknowledged");
+    fw.send_command("tcp-recv-assert 0 16 \"01 02 03 04\"");
+
+    // Remote FIN (-> TIME-WAIT)
+    conn.raw_send_packet(TCP_ACK|TCP_FIN, &[], &[]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq + 1, "FIN not acknowledged");
+    fw.send_command("tcp-recv-eof 0");
+    fw.send_command("tcp-drop 0");
+
+    // A retransmitted FIN (as if our ACK was lost) is ACKed again
+    conn.raw_send_packet(TCP_ACK|TCP_FIN, &[], &[]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq + 1, "Retransmitted FIN not acknowledged");
+
+    // A RST is ignored in TIME-WAIT (RFC 1337), so the connection still ACKs the FIN
+    conn.local_seq += 1;
+    conn.raw_send_packet(TCP_RST, &[], &[]);
+    conn.wait_rx_none();
+    conn.local_seq -= 1;
+    conn.raw_send_packet(TCP_ACK|TCP_FIN, &[], &[]);
+    conn.wait_rx_check(TCP_ACK, &[]);
+}
+
+/// A FIN received before earlier data isn't processed until the gap is filled
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn fin_out_of_order()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_fin_out_of_order");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    let mut conn = TcpConn::open_server_conn(&fw, LOCAL_ADDR, REMOTE_ADDR, &[]);
+
+    // Second segment (with the FIN) first
+    let first_seq = conn.local_seq;
+    conn.local_seq += 4;
+    conn.raw_send_packet(TCP_ACK|TCP_PSH|TCP_FIN, &[], &[5,6,7,8]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, first_seq, "Out of order segment acknowledged");
+
+    // Fill the gap, the data and FIN are then ACKed together
+    conn.local_seq = first_seq;
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], &[1,2,3,4]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, first_seq + 8 + 1, "Data and FIN not acknowledged");
+    fw.send_command("tcp-recv-assert 0 16 \"01 02 03 04 05 06 07 08\"");
+    fw.send_command("tcp-recv-eof 0");
+}
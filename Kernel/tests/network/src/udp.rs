@@ -0,0 +1,116 @@
+//! UDP datagrams over IPv4 and IPv6
+use crate::ipv4::Addr as IpAddr4;
+use crate::ipv6::Addr as IpAddr6;
+
+#[cfg(test)]
+mod tests;
+
+pub const PROTO_UDP: u8 = 17;
+
+/// A received datagram
+#[derive(Debug)]
+pub struct Datagram
+{
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub data: Vec<u8>,
+}
+
+fn pseudo_header_v4(src: IpAddr4, dst: IpAddr4, len: usize) -> Vec<u8>
+{
+    let mut rv = Vec::new();
+    rv.extend_from_slice(&src.0);
+    rv.extend_from_slice(&dst.0);
+    rv.extend_from_slice(&[0, PROTO_UDP]);
+    rv.extend_from_slice(&(len as u16).to_be_bytes());
+    rv
+}
+fn pseudo_header_v6(src: IpAddr6, dst: IpAddr6, len: usize) -> Vec<u8>
+{
+    let mut rv = Vec::new();
+    rv.extend_from_slice(&src.0);
+    rv.extend_from_slice(&dst.0);
+    rv.extend_from_slice(&(len as u32).to_be_bytes());
+    rv.extend_from_slice(&[0, 0, 0, PROTO_UDP]);
+    rv
+}
+/// Checksum of the pseudo-header and the datagram (zero if a received datagram is valid)
+fn checksum(ph: &[u8], udp: &[u8]) -> u16
+{
+    let bytes: Vec<u8> = ph.iter().chain(udp.iter()).copied().collect();
+    crate::ipv4::calculate_ip_checksum(bytes.chunks(2).map(|v| u16::from_be_bytes([v[0], *v.get(1).unwrap_or(&0)])))
+}
+/// Encode a datagram (header and data), with the checksum calculated over the pseudo-header `ph`
+fn encode(ph: &[u8], src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8>
+{
+    let mut rv = Vec::new();
+    rv.extend_from_slice(&src_port.to_be_bytes());
+    rv.extend_from_slice(&dst_port.to_be_bytes());
+    rv.extend_from_slice(&(8 + data.len() as u16).to_be_bytes());
+    rv.extend_from_slice(&[0, 0]);
+    rv.extend_from_slice(data);
+    let sum = match checksum(ph, &rv) { 0 => 0xFFFF, v => v };
+    rv[6..8].copy_from_slice(&sum.to_be_bytes());
+    rv
+}
+/// Check the length and checksum of a received datagram, and decode it
+#[track_caller]
+fn decode(ph: &[u8], udp: &[u8]) -> Datagram
+{
+    assert!(udp.len() >= 8, "Undersized UDP datagram");
+    let len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+    assert_eq!(len, udp.len(), "UDP length field mismatch");
+    assert_ne!(&udp[6..8], &[0,0], "No UDP checksum");
+    assert_eq!(checksum(ph, udp), 0, "Bad UDP checksum");
+    Datagram {
+        src_port: u16::from_be_bytes([udp[0], udp[1]]),
+        dst_port: u16::from_be_bytes([udp[2], udp[3]]),
+        data: udp[8..].to_vec(),
+    }
+}
+
+pub fn encode_v6(src: IpAddr6, dst: IpAddr6, src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8>
+{
+    encode(&pseudo_header_v6(src, dst, 8 + data.len()), src_port, dst_port, data)
+}
+
+/// Send a datagram over IPv4
+pub fn send_v4(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, src_port: u16, dst_port: u16, data: &[u8])
+{
+    let udp = encode(&pseudo_header_v4(src, dst, 8 + data.len()), src_port, dst_port, data);
+    let mut h = crate::ipv4::Header::new_simple(src, dst, PROTO_UDP, udp.len());
+    h.set_checksum();
+    fw.send_ethernet_direct(0x0800, &[&h.encode(), &udp]);
+}
+/// Send a datagram over IPv6
+pub fn send_v6(fw: &crate::TestFramework, src: IpAddr6, dst: IpAddr6, src_port: u16, dst_port: u16, data: &[u8])
+{
+    crate::ipv6::send_packet(fw, src, dst, PROTO_UDP, &encode_v6(src, dst, src_port, dst_port, data));
+}
+
+/// Wait for a datagram over IPv4, checking the addresses and checksum
+#[track_caller]
+pub fn wait_rx_v4(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4) -> Datagram
+{
+    let pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No UDP datagram received");
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&pkt);
+    assert_eq!(ether_hdr.proto, 0x0800, "Not IPv4");
+    let (ip_hdr, _, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, PROTO_UDP, "Not UDP");
+    assert_eq!((ip_hdr.src_addr, ip_hdr.dst_addr), (src.0, dst.0), "Incorrect addresses");
+    let udp = &tail[.. ip_hdr.total_length as usize - 20];
+    decode(&pseudo_header_v4(src, dst, udp.len()), udp)
+}
+/// Wait for a datagram over IPv6, checking the addresses and checksum
+#[track_caller]
+pub fn wait_rx_v6(fw: &crate::TestFramework, src: IpAddr6, dst: IpAddr6) -> Datagram
+{
+    let pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No UDP datagram received");
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&pkt);
+    assert_eq!(ether_hdr.proto, 0x86DD, "Not IPv6");
+    let (ip_hdr, tail) = crate::ipv6::Header::parse(tail);
+    assert_eq!(ip_hdr.next_header, PROTO_UDP, "Not UDP");
+    assert_eq!((IpAddr6(ip_hdr.src_addr), IpAddr6(ip_hdr.dst_addr)), (src, dst), "Incorrect addresses");
+    let udp = &tail[.. ip_hdr.payload_length as usize];
+    decode(&pseudo_header_v6(src, dst, udp.len()), udp)
+}
@@ -0,0 +1,58 @@
+//! UDP tests
+use crate::ipv4::Addr as IpAddr4;
+use crate::ipv6::Addr as IpAddr6;
+use super::*;
+
+const REMOTE_ADDR4: IpAddr4 = IpAddr4([192,168,1,1]);
+const LOCAL_ADDR4: IpAddr4 = IpAddr4([192,168,1,2]);
+const REMOTE_ADDR6: IpAddr6 = IpAddr6([0xfd,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,1]);
+const LOCAL_ADDR6: IpAddr6 = IpAddr6([0xfd,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,2]);
+
+/// Datagrams are sent and received over IPv6, with the (mandatory) checksum covering the IPv6 pseudo-header
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn ipv6()
+{
+    let fw = crate::TestFramework::new("udp_ipv6");
+    fw.send_command(&format!("ipv6-add {} 64", REMOTE_ADDR6));
+    fw.send_command(&format!("udp-bind 0 {} 5000", REMOTE_ADDR6));
+
+    // A datagram without a checksum is dropped
+    let mut no_checksum = encode_v6(LOCAL_ADDR6, REMOTE_ADDR6, 6000, 5000, &[9,9,9]);
+    no_checksum[6..8].copy_from_slice(&[0,0]);
+    crate::ipv6::send_packet(&fw, LOCAL_ADDR6, REMOTE_ADDR6, PROTO_UDP, &no_checksum);
+    send_v6(&fw, LOCAL_ADDR6, REMOTE_ADDR6, 6000, 5000, &[1,2,3]);
+    fw.send_command(&format!("udp-recv-assert 0 {} 6000 \"01 02 03\"", LOCAL_ADDR6));
+
+    fw.send_command(&format!("udp-send-to 0 {} 6000 \"04 05\"", LOCAL_ADDR6));
+    let d = wait_rx_v6(&fw, REMOTE_ADDR6, LOCAL_ADDR6);
+    assert_eq!((d.src_port, d.dst_port), (5000, 6000));
+    assert_eq!(d.data, [4,5]);
+}
+
+/// A socket bound to the IPv6 wildcard (`::`) sends and receives over both IPv4 and IPv6
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn dual_stack()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_dual_stack");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR4));
+        fw
+        };
+    fw.send_command(&format!("ipv6-add {} 64", REMOTE_ADDR6));
+    fw.send_command("udp-bind 0 :: 5000");
+
+    send_v4(&fw, LOCAL_ADDR4, REMOTE_ADDR4, 6000, 5000, &[1,2,3]);
+    fw.send_command(&format!("udp-recv-assert 0 {} 6000 \"01 02 03\"", LOCAL_ADDR4));
+    send_v6(&fw, LOCAL_ADDR6, REMOTE_ADDR6, 6001, 5000, &[4,5,6]);
+    fw.send_command(&format!("udp-recv-assert 0 {} 6001 \"04 05 06\"", LOCAL_ADDR6));
+
+    // Replies use the protocol of the destination address
+    fw.send_command(&format!("udp-send-to 0 {} 6000 \"07\"", LOCAL_ADDR4));
+    let d = wait_rx_v4(&fw, REMOTE_ADDR4, LOCAL_ADDR4);
+    assert_eq!((d.src_port, d.dst_port, &d.data[..]), (5000, 6000, &[7][..]));
+    fw.send_command(&format!("udp-send-to 0 {} 6001 \"08\"", LOCAL_ADDR6));
+    let d = wait_rx_v6(&fw, REMOTE_ADDR6, LOCAL_ADDR6);
+    assert_eq!((d.src_port, d.dst_port, &d.data[..]), (5000, 6001, &[8][..]));
+}
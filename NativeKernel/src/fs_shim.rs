@@ -37,7 +37,7 @@ impl mount::Driver for NativeFsDriver
 	fn detect(&self, _vol: &VolumeHandle) -> ::vfs::Result<usize> {
         Ok(0)
     }
-	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle, _options: &[&str]) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
         // TODO: Can this get the path from the volume handle?
         let root_path: PathBuf = ".native_fs".into();
         let mut rv = NativeFs::default();
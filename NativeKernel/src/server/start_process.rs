@@ -129,6 +129,20 @@ pub fn handle_syscall(gs: &super::GlobalStateRef, mut args: &[usize]) -> Result<
 				let lh = self.gs.lock().unwrap();
 				::syscalls::native_exports::give_object(&lh.process_handles[&self.pid], &tag, handle).map(|_| 0)
 				},
+			v::CORE_PROTOPROCESS_SETCREDENTIALS => {
+				let uid: u32 = args.get()?;
+				let gid: u32 = args.get()?;
+				let creds = ::kernel::threads::Credentials { uid, gid };
+				log_debug!("CORE_PROTOPROCESS_SETCREDENTIALS: {}", creds);
+				let cur_creds = ::kernel::threads::get_credentials();
+				if !cur_creds.can_switch_to(creds) {
+					return Ok(1);
+				}
+				self.wait_until_tracked();
+				let lh = self.gs.lock().unwrap();
+				lh.process_handles[&self.pid].set_credentials(creds);
+				Ok(0)
+				},
 			_ => ::syscalls::native_exports::object_has_no_such_method_ref("ProtoProcess", call),
 			}
 		}
@@ -115,6 +115,16 @@ def_groups! {
 		/// - `Some(true)` when `data` is populated
 		/// - `Some(false)` when the index points to a non-poulated entry
 		=6: NET_ENUM_ROUTE<'a>(index: usize, data: &'a mut NetworkRoute) -> Option<bool>,
+	},
+	/// Filesystem
+	=5: GROUP_VFS = {
+		/// Get the details of a mount table entry, with the mount location written to `location`
+		/// 
+		/// Returns:
+		/// - `None` when index is too large
+		/// - `Some(true)` when `data` is populated
+		/// - `Some(false)` when the index points to a non-poulated entry
+		=0: VFS_ENUM_MOUNTS<'a>(index: usize, data: &'a mut VFSMountInfo, location: &'a mut [u8]) -> Option<bool>,
 	}
 }
 
@@ -143,6 +153,8 @@ def_classes! {
 		/// Give the process one of this process's objects
 		/// This method blocks if the child process hasn't popped the previous object
 		=0: CORE_PROTOPROCESS_SENDOBJ(tag: FixedStr8, object_handle: u32),
+		/// Set the user/group the process runs as (only allowed for a root caller)
+		=1: CORE_PROTOPROCESS_SETCREDENTIALS(uid: u32, gid: u32) -> Result<(),()>,
 		--
 		/// Start the process executing
 		=0: CORE_PROTOPROCESS_START(ip: usize, sp: usize) -> CLASS_CORE_PROCESS,
@@ -167,6 +179,8 @@ def_classes! {
 	/// Opened node
 	=3: CLASS_VFS_NODE = {
 		=0: VFS_NODE_GETTYPE() -> VFSNodeType,
+		/// Get the node's metadata (size, timestamps, ownership, ...)
+		=1: VFS_NODE_GETMETADATA<'a>(data: &'a mut VFSMetadata) -> Result<(), VFSError>,
 		--
 		=0: VFS_NODE_TOFILE(mode: VFSFileOpenMode) -> CLASS_VFS_FILE,
 		=1: VFS_NODE_TODIR() -> CLASS_VFS_DIR,
@@ -182,7 +196,17 @@ def_classes! {
 		/// Write to the specified position in the file
 		=2: VFS_FILE_WRITEAT<'a>(ofs: u64, data: &'a [u8]),
 		/// Map part of the file into the current address space
+		///
+		/// `addr` must have the same offset within a page as `ofs`, the mapping is expanded to whole pages.
 		=3: VFS_FILE_MEMMAP(ofs: u64, size: usize, addr: usize, mode: VFSMemoryMapMode),
+		/// Change the size of the file (extending with zeroes, or truncating)
+		=4: VFS_FILE_SETSIZE(size: u64) -> Result<(), VFSError>,
+		/// Get the file's metadata (same as [const@VFS_NODE_GETMETADATA])
+		=5: VFS_FILE_GETMETADATA<'a>(data: &'a mut VFSMetadata) -> Result<(), VFSError>,
+		/// Write changes in writeback mappings (made with this handle) overlapping the region back to the file
+		=6: VFS_FILE_MEMSYNC(addr: usize, size: usize) -> Result<(), VFSError>,
+		/// Remove a mapping made with this handle (`addr` must be within the mapping), writing back any changes
+		=7: VFS_FILE_MEMUNMAP(addr: usize) -> Result<(), VFSError>,
 		--
 	}|{
 	},
@@ -194,6 +218,20 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD<'a>(name: &'a [u8]) -> Result<CLASS_VFS_NODE, VFSError>,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH<'a>(path: &'a [u8]) -> Result<CLASS_VFS_NODE, VFSError>,
+		/// Create a new file (returned opened for exclusive read-write)
+		=3: VFS_DIR_CREATEFILE<'a>(name: &'a [u8]) -> Result<CLASS_VFS_FILE, VFSError>,
+		/// Create a new sub-directory
+		=4: VFS_DIR_MKDIR<'a>(name: &'a [u8]) -> Result<CLASS_VFS_DIR, VFSError>,
+		/// Create a symbolic link
+		=5: VFS_DIR_SYMLINK<'a>(name: &'a [u8], target: &'a [u8]) -> Result<(), VFSError>,
+		/// Remove a name (directories must be empty)
+		=6: VFS_DIR_UNLINK<'a>(name: &'a [u8]) -> Result<(), VFSError>,
+		/// Move an entry to another directory handle on the same filesystem (`new_dir` can be this handle)
+		=7: VFS_DIR_RENAME<'a>(name: &'a [u8], new_dir: u32, new_name: &'a [u8]) -> Result<(), VFSError>,
+		/// Create a handle that reports changes to the directory's entries
+		=8: VFS_DIR_WATCH() -> Result<CLASS_VFS_WATCH, VFSError>,
+		/// Create a handle that can only access what the given user/group could (the caller must be able to switch to them)
+		=9: VFS_DIR_RESTRICT(uid: u32, gid: u32) -> Result<CLASS_VFS_DIR, VFSError>,
 		--
 	}|{
 	},
@@ -334,6 +372,17 @@ def_classes! {
 		--
 	}|{
 		=0: EV_NET_MGMT_INTERFACE,
+	},
+	/// Directory change notifications
+	=15: CLASS_VFS_WATCH = {
+		/// Take the oldest change, writing the entry name to `name` (truncated if too long)
+		///
+		/// Returns `true` if a change was read, `false` if none are queued
+		=0: VFS_WATCH_READ<'a>(event: &'a mut VFSWatchEvent, name: &'a mut [u8]) -> bool,
+		--
+	}|{
+		/// Fires when changes are queued
+		=0: EV_VFS_WATCH_CHANGE,
 	}
 }
 
@@ -343,6 +392,14 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	AlreadyExists = 5,
+	DirectoryNotEmpty = 6,
+	InvalidParameter = 7,
+	ReadOnlyFilesystem = 8,
+	OutOfSpace = 9,
+	IoError = 10,
+	CrossFilesystem = 11,
+	OutOfMemory = 12,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -350,6 +407,79 @@ enum_to_from!{ VFSNodeType => u32:
 	Symlink = 2,
 	Special = 3,
 }
+/// Node metadata, as returned by [const@VFS_NODE_GETMETADATA]
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSMetadata
+{
+	/// Size of the file's data in bytes (zero for non-files)
+	pub size: u64,
+	/// Bytes of storage used by the node
+	pub allocated_size: u64,
+	/// Creation time (seconds since 1970, [VFSMetadata::NO_TIME] if not known)
+	pub create_time: i64,
+	/// Last modification time
+	pub modify_time: i64,
+	/// Last access time
+	pub access_time: i64,
+	/// Last metadata change time
+	pub change_time: i64,
+	pub owner: u32,
+	pub group: u32,
+	pub link_count: u32,
+	/// POSIX-style permission bits
+	pub mode: u16,
+	pub _pad: u16,
+}
+impl VFSMetadata {
+	/// Value used in the timestamp fields when the filesystem doesn't store that time
+	pub const NO_TIME: i64 = i64::MIN;
+}
+/// Mount table entry, as returned by [const@VFS_ENUM_MOUNTS]
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSMountInfo
+{
+	/// Filesystem driver name (NUL padded, "bind" for bind mounts)
+	pub filesystem: [u8; 16],
+	/// Volume name, or source path for bind mounts (NUL padded, truncated if too long)
+	pub source: [u8; 32],
+	/// Length of the mount location (can be larger than the passed buffer)
+	pub location_len: u32,
+	/// Mount flags (`VFSMountInfo::FLAG_*`)
+	pub flags: u32,
+}
+impl VFSMountInfo {
+	/// Volume is mounted read-only
+	pub const FLAG_READONLY: u32 = 1 << 0;
+	/// Files on the volume can't be executed
+	pub const FLAG_NOEXEC: u32 = 1 << 1;
+}
+/// Directory change record, as returned by [const@VFS_WATCH_READ]
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSWatchEvent
+{
+	/// Type of change (a [VFSChangeKind] value)
+	pub kind: u8,
+	pub _pad: [u8; 3],
+	/// Length of the entry name (can be larger than the passed buffer)
+	pub name_len: u32,
+}
+enum_to_from!{ VFSChangeKind => u8:
+	// /// A new entry was created
+	Created = 0,
+	// /// An entry was removed
+	Removed = 1,
+	// /// An entry was renamed/moved away from this name
+	RenamedFrom = 2,
+	// /// An entry was renamed/moved to this name
+	RenamedTo = 3,
+	// /// A file's contents changed
+	Modified = 4,
+	// /// Changes were lost (the directory should be re-read)
+	Overflow = 5,
+}
 enum_to_from!{ VFSFileOpenMode => u8:
 	ReadOnly = 1,
 	Execute  = 2,
@@ -444,6 +574,10 @@ enum_to_from!{ SocketError => u32:
 	ConnectionReset = 5,
 	/// Connection timed out
 	Timeout = 6,
+	/// The remote host refused the connection (nothing listening on the port)
+	ConnectionRefused = 7,
+	/// The connection hasn't been established yet
+	NotConnected = 8,
 }
 enum_to_from!{ SocketShutdownSide => u8:
 	Transmit = 0,
@@ -15,20 +15,28 @@ pub struct FileList<'a>
 	on_chdir: Box<dyn Fn(&mut dyn WindowTrait, &Path) + 'a>,
 
 	cur_paths: RefCell<Vec<OsString>>,
+	/// Currently displayed directory, and a watch used to refresh it when changed
+	cur_watch: RefCell<Option<(::syscalls::vfs::Dir, ::syscalls::vfs::Watch)>>,
 	
-	list: ListView<[&'static str; 2], FileEnt>,
+	list: ListView<[&'static str; 5], FileEnt>,
 }
 
 impl<'a> FileList<'a>
 {
 	pub fn new(root: &'a ::syscalls::vfs::Dir) -> FileList<'a>
 	{
+		let mut list = ListView::new(["T", "Filename", "Size", "Mode", "Modified"]);
+		list.set_column_width(1, 8*32+3);
+		list.set_column_width(2, 8*10+3);
+		list.set_column_width(3, 8*9+3);
+		list.set_column_width(4, 8*16+3);
 		FileList {
 			root: root,
 			on_open: Box::new(|_,_,_|()),
 			on_chdir: Box::new(|_,_|()),
 			cur_paths: Default::default(),
-			list: ListView::new(["T", "Filename"]),
+			cur_watch: Default::default(),
+			list: list,
 		}
 	}
 	
@@ -45,6 +53,44 @@ impl<'a> FileList<'a>
 		{
 			self.list.append_item( FileEnt::new(dir, name) );
 		}
+		*self.cur_watch.borrow_mut() = dir.watch().ok().map(|w| (dir.clone(), w));
+	}
+
+	/// Number of wait items added by `populate_waits`
+	pub fn wait_count(&self) -> usize {
+		if self.cur_watch.borrow().is_some() { 1 } else { 0 }
+	}
+	/// Add a wait on the current directory changing
+	pub fn populate_waits(&self, cb: &mut dyn FnMut(::syscalls::WaitItem)) {
+		if let Some((_, ref w)) = *self.cur_watch.borrow() {
+			cb( w.wait_change() );
+		}
+	}
+	/// Re-read the current directory if it has changed, returns true if the list needs to be redrawn
+	pub fn handle_waits(&self, events: &[::syscalls::WaitItem]) -> bool {
+		if events.iter().all(|e| e.flags == 0) {
+			return false;
+		}
+		let dir = {
+			let lh = self.cur_watch.borrow();
+			let (ref dir, ref w) = match *lh
+				{
+				Some(ref v) => v,
+				None => return false,
+				};
+			// Drain the queue, the whole directory is re-read anyway
+			let mut namebuf = [0; 512];
+			let mut changed = false;
+			while let Some(_) = w.read(&mut namebuf) {
+				changed = true;
+			}
+			if !changed {
+				return false;
+			}
+			dir.clone()
+			};
+		self.populate(&dir);
+		true
 	}
 
 	/// Bind to "Opening" a file (double-click or select+enter)
@@ -134,13 +180,36 @@ struct FileEnt
 	ty_str: &'static str,
 	name: OsString,
 	display_name: Option<String>,
+	size_str: String,
+	mode_str: String,
+	mtime_str: String,
 }
 impl FileEnt
 {
 	fn new(dir: &::syscalls::vfs::Dir, name: &[u8]) -> FileEnt {
 		let node = dir.open_child(name);
-		let node_ty = match node { Ok(n) => Some(n.class()), Err(_) => None };
+		let node_ty = match node { Ok(ref n) => Some(n.class()), Err(_) => None };
+		let meta = match node { Ok(ref n) => n.get_metadata().ok(), Err(_) => None };
 		FileEnt {
+			size_str: match meta
+				{
+				Some(ref m) => match node_ty
+					{
+					Some(::syscalls::vfs::NodeType::File) => format!("{}", m.size),
+					_ => String::new(),
+					},
+				None => String::new(),
+				},
+			mode_str: match meta
+				{
+				Some(ref m) => mode_string(m.mode),
+				None => String::new(),
+				},
+			mtime_str: match meta
+				{
+				Some(ref m) if m.modify_time != ::syscalls::vfs::Metadata::NO_TIME => time_string(m.modify_time),
+				_ => String::new(),
+				},
 			ty_str: match node_ty
 				{
 				Some(::syscalls::vfs::NodeType::File) => "f",
@@ -161,7 +230,7 @@ impl FileEnt
 }
 impl ::listview::Row for FileEnt {
 	fn count(&self) -> usize {
-		2
+		5
 	}
 	fn value(&self, col: usize) -> &str {
 		match col
@@ -173,8 +242,34 @@ impl ::listview::Row for FileEnt {
 			else {
 				self.name.to_str().unwrap()
 			},
+		2 => &self.size_str,
+		3 => &self.mode_str,
+		4 => &self.mtime_str,
 		_ => "",
 		}
 	}
 }
 
+/// Render permission bits as `rwxr-xr-x`
+fn mode_string(mode: u16) -> String {
+	let mut rv = String::with_capacity(9);
+	for i in (0 .. 9).rev() {
+		rv.push( if mode & (1 << i) == 0 { '-' } else { ['x','w','r'][i % 3] } );
+	}
+	rv
+}
+/// Render a unix timestamp as `YYYY-MM-DD HH:MM` (UTC)
+fn time_string(ts: i64) -> String {
+	let (days, secs) = (ts.div_euclid(86400), ts.rem_euclid(86400));
+	// Civil date from day count (Howard Hinnant's algorithm)
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z - era * 146097;
+	let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+	let doy = doe - (365*yoe + yoe/4 - yoe/100);
+	let mp = (5*doy + 2) / 153;
+	let d = doy - (153*mp + 2)/5 + 1;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 };
+	let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+	format!("{:04}-{:02}-{:02} {:02}:{:02}", y, m, d, secs / 3600, secs / 60 % 60)
+}
@@ -67,6 +67,11 @@ impl<Titles: Row, R: Row> ListView<Titles, R>
 			items: Default::default(),
 		}
 	}
+	/// Override the width (in pixels) of a column
+	pub fn set_column_width(&mut self, idx: usize, width: u32) {
+		self.column_widths[idx] = width;
+		self.widths_dirty.set(true);
+	}
 	/// Clear all state, ready for a fresh set of items
 	pub fn clear(&self) {
 		self.items_replaced.set(true);
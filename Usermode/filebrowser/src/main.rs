@@ -8,6 +8,7 @@ extern crate vec_ring;
 #[macro_use(kernel_log)]
 extern crate syscalls;
 extern crate loader;
+extern crate async;
 
 mod listview;
 mod filelist;
@@ -37,7 +38,31 @@ fn main()
 	window.focus(&fl);
 	window.show();
 
-	window.idle_loop();
+	// Wait on both window input and changes to the displayed directory
+	use async::WaitController;
+	struct Idle<'a, 'w: 'a, 'f: 'a> {
+		win: &'a mut ::wtk::Window<'w, ::wtk::decorator::Standard>,
+		fl: &'a ::filelist::FileList<'f>,
+	}
+	impl<'a, 'w, 'f> WaitController for Idle<'a, 'w, 'f> {
+		fn get_count(&self) -> usize {
+			self.win.get_count() + self.fl.wait_count()
+		}
+		fn populate(&self, cb: &mut dyn FnMut(::syscalls::WaitItem)) {
+			self.win.populate(cb);
+			self.fl.populate_waits(cb);
+		}
+		fn handle(&mut self, events: &[::syscalls::WaitItem]) {
+			let (a, b) = events.split_at(self.win.get_count());
+			self.win.handle(a);
+			if self.fl.handle_waits(b) {
+				self.win.rerender();
+			}
+		}
+	}
+	::wtk::idle_loop(&mut [
+		&mut Idle { win: &mut window, fl: &fl },
+		]);
 }
 
 fn get_app_exe(name: &[u8]) -> Result<::syscalls::vfs::File, ()> {
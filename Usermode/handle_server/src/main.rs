@@ -21,6 +21,7 @@ struct Connection
 fn main()
 {
 	// handle_server gets the read-write root handle for the session user
+	// - `login` restricts it (and this process's credentials) to that user, so it only reaches what they can
 	let filesystem_root: ::syscalls::vfs::Dir = ::syscalls::threads::S_THIS_PROCESS.receive_object("RwRoot").expect("Failed to receive FS root");
 
 	// Active handle set - pre-populated with connection to session leader
@@ -12,6 +12,30 @@ impl File
 		let p = path.as_ref();
 		Ok( File(super::Node::open(p)?.into_file()?) )
 	}
+	/// Open a file for writing, creating it if it doesn't exist (and truncating it if it does)
+	pub fn create<P: AsRef<Path>>(path: P) -> ::io::Result<File> {
+		let (dir, name) = super::open_parent(path.as_ref())?;
+		match dir.create_file(name)
+		{
+		Ok(f) => Ok( File(f) ),
+		Err(::syscalls::vfs::Error::AlreadyExists) => {
+			let f = super::Node(dir.open_child(name)?).into_file_mode(::syscalls::vfs::FileOpenMode::ExclRW)?;
+			f.set_size(0)?;
+			Ok( File(f) )
+			},
+		Err(e) => Err( e.into() ),
+		}
+	}
+
+	/// Get the file's metadata
+	pub fn metadata(&self) -> ::io::Result<super::Metadata> {
+		Ok( super::Metadata::new(::syscalls::vfs::NodeType::File, self.0.get_metadata()?) )
+	}
+
+	/// Truncate or extend (with zeroes) the file
+	pub fn set_len(&self, size: u64) -> ::io::Result<()> {
+		Ok( self.0.set_size(size)? )
+	}
 }
 
 impl ::io::Read for File
@@ -20,4 +44,12 @@ impl ::io::Read for File
 		::io::Read::read( &mut self.0, buf )
 	}
 }
-
+impl ::io::Write for File
+{
+	fn write(&mut self, buf: &[u8]) -> ::io::Result<usize> {
+		::io::Write::write( &mut self.0, buf )
+	}
+	fn flush(&mut self) -> ::io::Result<()> {
+		Ok( () )
+	}
+}
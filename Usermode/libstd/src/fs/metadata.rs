@@ -0,0 +1,71 @@
+//
+//
+//
+//! File metadata
+
+/// Type of a filesystem node
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FileType
+{
+	File,
+	Dir,
+	Symlink,
+	Special,
+}
+impl FileType
+{
+	pub fn is_dir(&self) -> bool { *self == FileType::Dir }
+	pub fn is_file(&self) -> bool { *self == FileType::File }
+	pub fn is_symlink(&self) -> bool { *self == FileType::Symlink }
+}
+
+/// Metadata for a node (returned by `fs::metadata` and `File::metadata`)
+///
+/// Timestamps are seconds since 1970-01-01 UTC, `None` if the filesystem doesn't record them
+#[derive(Clone,Debug)]
+pub struct Metadata
+{
+	ty: FileType,
+	inner: ::syscalls::vfs::Metadata,
+}
+impl Metadata
+{
+	pub(super) fn new(class: ::syscalls::vfs::NodeType, inner: ::syscalls::vfs::Metadata) -> Metadata {
+		use ::syscalls::vfs::NodeType;
+		Metadata {
+			ty: match class
+				{
+				NodeType::File => FileType::File,
+				NodeType::Dir => FileType::Dir,
+				NodeType::Symlink => FileType::Symlink,
+				NodeType::Special => FileType::Special,
+				},
+			inner: inner,
+			}
+	}
+
+	pub fn file_type(&self) -> FileType { self.ty }
+	pub fn is_dir(&self) -> bool { self.ty.is_dir() }
+	pub fn is_file(&self) -> bool { self.ty.is_file() }
+	/// Size of the file's data in bytes
+	pub fn len(&self) -> u64 { self.inner.size }
+	/// Number of bytes of storage used
+	pub fn allocated_size(&self) -> u64 { self.inner.allocated_size }
+	/// POSIX-style permission bits
+	pub fn mode(&self) -> u16 { self.inner.mode }
+	pub fn readonly(&self) -> bool { self.inner.mode & 0o222 == 0 }
+	pub fn uid(&self) -> u32 { self.inner.owner }
+	pub fn gid(&self) -> u32 { self.inner.group }
+	/// Number of hard links to the node
+	pub fn nlink(&self) -> u32 { self.inner.link_count }
+
+	pub fn created(&self) -> Option<i64> { Self::time(self.inner.create_time) }
+	pub fn modified(&self) -> Option<i64> { Self::time(self.inner.modify_time) }
+	pub fn accessed(&self) -> Option<i64> { Self::time(self.inner.access_time) }
+	/// Last change to the node's metadata
+	pub fn changed(&self) -> Option<i64> { Self::time(self.inner.change_time) }
+
+	fn time(v: i64) -> Option<i64> {
+		if v == ::syscalls::vfs::Metadata::NO_TIME { None } else { Some(v) }
+	}
+}
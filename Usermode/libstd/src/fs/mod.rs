@@ -4,6 +4,7 @@
 
 pub use self::path::Path;
 pub use self::file::File;
+pub use self::metadata::{Metadata,FileType};
 
 //static ROOT_HANDLE: Dir = 
 //static DIR_HANDLES: [::syscalls::vfs::Dir; 4] = [
@@ -47,14 +48,89 @@ impl Node
 	}
 	
 	fn into_file(self) -> ::io::Result<::syscalls::vfs::File> {
-		match self.0.into_file(::syscalls::vfs::FileOpenMode::ReadOnly)
+		self.into_file_mode(::syscalls::vfs::FileOpenMode::ReadOnly)
+	}
+	fn into_file_mode(self, mode: ::syscalls::vfs::FileOpenMode) -> ::io::Result<::syscalls::vfs::File> {
+		match self.0.into_file(mode)
 		{
 		Ok(v) => Ok(v),
 		Err(e) => Err( From::from(e) ),
 		}
 	}
+	fn into_dir(self) -> ::io::Result<::syscalls::vfs::Dir> {
+		Ok( self.0.into_dir()? )
+	}
+	fn metadata(&self) -> ::io::Result<Metadata> {
+		Ok( Metadata::new(self.0.class(), self.0.get_metadata()?) )
+	}
+}
+
+/// Open the directory containing `path`, and return it along with the final component
+fn open_parent(path: &Path) -> ::io::Result<(::syscalls::vfs::Dir, &[u8])> {
+	let pb: &[u8] = path.as_ref();
+	let (parent, name) = match pb.iter().rposition(|&c| c == b'/')
+		{
+		Some(pos) => (&pb[..pos], &pb[pos+1..]),
+		None => (&pb[..0], pb),
+		};
+	if name.len() == 0 {
+		return Err( ::syscalls::vfs::Error::MalformedPath.into() );
+	}
+	let dir = if parent.len() == 0 && path.is_absolute() {
+			::syscalls::vfs::root().clone()
+		}
+		else {
+			Node::open(Path::new(parent))?.into_dir()?
+		};
+	Ok( (dir, name) )
+}
+
+/// Get the metadata for the node at `path` (following symbolic links)
+pub fn metadata<P: AsRef<Path>>(path: P) -> ::io::Result<Metadata> {
+	Node::open(path.as_ref())?.metadata()
+}
+/// Get the metadata for the node at `path`, without following a final symbolic link
+pub fn symlink_metadata<P: AsRef<Path>>(path: P) -> ::io::Result<Metadata> {
+	let (dir, name) = open_parent(path.as_ref())?;
+	Node(dir.open_child(name)?).metadata()
+}
+
+/// Create a new (empty) directory
+pub fn create_dir<P: AsRef<Path>>(path: P) -> ::io::Result<()> {
+	let (dir, name) = open_parent(path.as_ref())?;
+	dir.mkdir(name)?;
+	Ok( () )
+}
+/// Remove a file (or symbolic link)
+pub fn remove_file<P: AsRef<Path>>(path: P) -> ::io::Result<()> {
+	let (dir, name) = open_parent(path.as_ref())?;
+	if matches!(dir.open_child(name)?.class(), ::syscalls::vfs::NodeType::Dir) {
+		return Err( ::syscalls::vfs::Error::TypeError.into() );
+	}
+	Ok( dir.unlink(name)? )
+}
+/// Remove an empty directory
+pub fn remove_dir<P: AsRef<Path>>(path: P) -> ::io::Result<()> {
+	let (dir, name) = open_parent(path.as_ref())?;
+	if !matches!(dir.open_child(name)?.class(), ::syscalls::vfs::NodeType::Dir) {
+		return Err( ::syscalls::vfs::Error::TypeError.into() );
+	}
+	Ok( dir.unlink(name)? )
+}
+/// Move/rename a file or directory (both paths must be on the same filesystem)
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> ::io::Result<()> {
+	let (src_dir, src_name) = open_parent(from.as_ref())?;
+	let (dst_dir, dst_name) = open_parent(to.as_ref())?;
+	Ok( src_dir.rename(src_name, &dst_dir, dst_name)? )
+}
+/// Create a symbolic link at `dst` pointing to `src`
+pub fn soft_link<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> ::io::Result<()> {
+	let (dir, name) = open_parent(dst.as_ref())?;
+	let target: &[u8] = src.as_ref().as_ref();
+	Ok( dir.symlink(name, target)? )
 }
 
 mod file;
+mod metadata;
 mod path;
 
@@ -57,6 +57,14 @@ impl ::core::fmt::Display for Error {
 		ErrorInner::VFS(Vfs::PermissionDenied) => f.write_str("Permission denied"),
 		ErrorInner::VFS(Vfs::FileLocked) => f.write_str("File is locked"),
 		ErrorInner::VFS(Vfs::MalformedPath) => f.write_str("Malformed path"),
+		ErrorInner::VFS(Vfs::AlreadyExists) => f.write_str("File already exists"),
+		ErrorInner::VFS(Vfs::DirectoryNotEmpty) => f.write_str("Directory not empty"),
+		ErrorInner::VFS(Vfs::InvalidParameter) => f.write_str("Invalid parameter"),
+		ErrorInner::VFS(Vfs::ReadOnlyFilesystem) => f.write_str("Read-only filesystem"),
+		ErrorInner::VFS(Vfs::OutOfSpace) => f.write_str("No space left on volume"),
+		ErrorInner::VFS(Vfs::IoError) => f.write_str("Input/output error"),
+		ErrorInner::VFS(Vfs::CrossFilesystem) => f.write_str("Operation spans multiple filesystems"),
+		ErrorInner::VFS(Vfs::OutOfMemory) => f.write_str("Out of memory"),
 		//ErrorInner::VFS(ref e) => write!(f, "Unknown VFS error {:?}", e),
 		ErrorInner::Net(Net::AlreadyInUse) => f.write_str("Address already in use"),
 		ErrorInner::Net(Net::InvalidValue) => f.write_str("Invalid parameter value"),
@@ -65,6 +73,7 @@ impl ::core::fmt::Display for Error {
 		ErrorInner::Net(Net::SocketClosed) => f.write_str("Socket closed"),
 		ErrorInner::Net(Net::ConnectionReset) => f.write_str("Connection reset"),
 		ErrorInner::Net(Net::Timeout) => f.write_str("Connection timed out"),
+		ErrorInner::Net(Net::ConnectionRefused) => f.write_str("Connection refused"),
 		}
 	}
 }
@@ -173,6 +182,14 @@ impl Read for ::syscalls::vfs::File {
 		Ok(try!( self.read(buf) ))
 	}
 }
+impl Write for ::syscalls::vfs::File {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		Ok( self.write(buf)? )
+	}
+	fn flush(&mut self) -> Result<()> {
+		Ok( () )
+	}
+}
 impl Seek for ::syscalls::vfs::File {
 	fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
 		match pos
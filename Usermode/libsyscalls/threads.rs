@@ -132,6 +132,16 @@ impl ProtoProcess
 		// SAFE: Syscall
 		unsafe { self.0.call_m(v::CORE_PROTOPROCESS_SENDOBJ { tag: v::FixedStr8::from(tag), object_handle: oh }); }
 	}
+	#[inline]
+	/// Set the user and group that the child will run as (requires the caller to be root)
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(),()> {
+		// SAFE: Syscall
+		match unsafe { self.0.call_m(v::CORE_PROTOPROCESS_SETCREDENTIALS { uid, gid }) }
+		{
+		0 => Ok( () ),
+		_ => Err( () ),
+		}
+	}
  
  	#[inline]
 	pub fn start(self, entry: usize, stack: usize) -> Process {
@@ -13,11 +13,16 @@ pub struct Dir(super::ObjectHandle);
 pub struct DirIter(::ObjectHandle);
 /// Symbolic link
 pub struct Symlink(super::ObjectHandle);
+/// Directory change watch
+pub struct Watch(super::ObjectHandle);
 
 pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSMetadata as Metadata;
+pub use ::values::VFSMountInfo as MountInfo;
+pub use ::values::VFSChangeKind as ChangeKind;
 
 pub fn root() -> &'static Dir {
 	use ::core::sync::atomic::{Ordering,AtomicBool};
@@ -34,6 +39,23 @@ pub fn root() -> &'static Dir {
 	}
 }
 
+/// Get a mount table entry, with the mount location written to `location`
+///
+/// Returns `None` at the end of the table, and `Some(None)` for an unused entry. The location is truncated if
+/// `location` is too small (the full length is in `MountInfo::location_len`).
+pub fn get_mount<'a>(index: usize, location: &'a mut [u8]) -> Option<Option<(MountInfo, &'a [u8])>> {
+	let mut info = MountInfo::default();
+	// SAFE: Correct arguments
+	match unsafe { ::syscall(::values::VFS_ENUM_MOUNTS { index, data: &mut info, location: &mut location[..] }) }
+	{
+	0 => {
+		let len = ::core::cmp::min(info.location_len as usize, location.len());
+		Some(Some( (info, &location[..len]) ))
+		},
+	1 => Some(None),
+	_ => None,
+	}
+}
 
 #[inline]
 fn to_obj(val: usize) -> Result<super::ObjectHandle, Error> {
@@ -52,6 +74,14 @@ impl Node
 		// SAFE: Syscall with no side-effects
 		NodeType::try_from( unsafe { self.0.call_m(::values::VFS_NODE_GETTYPE {}) } as u32 ).expect("Bad VFS Node Type")
 	}
+	/// Get the node's metadata (size, timestamps, ownership, ...)
+	#[inline]
+	pub fn get_metadata(&self) -> Result<Metadata,Error> {
+		let mut rv = Metadata::default();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_NODE_GETMETADATA { data: &mut rv }) } as usize )
+			.map( |_| rv )
+	}
 
 	/// Convert handle to a directory handle
 	#[inline]
@@ -118,6 +148,13 @@ impl File
 			.map(|v| v as usize)
 	}
 	
+	/// Write bytes at the cursor (incrementing)
+	#[inline]
+	pub fn write(&mut self, data: &[u8]) -> Result<usize,Error> {
+		let count = self.write_at(self.1, data)?;
+		self.1 += count as u64;
+		Ok(count)
+	}
 	/// Write to an arbitrary location in the file
 	#[inline]
 	pub fn write_at(&self, ofs: u64, data: &[u8]) -> Result<usize,Error> {
@@ -126,6 +163,22 @@ impl File
 			.map(|v| v as usize)
 	}
 	
+	/// Change the size of the file (extending with zeroes, or truncating)
+	#[inline]
+	pub fn set_size(&self, size: u64) -> Result<(),Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_FILE_SETSIZE { size }) } as usize )
+			.map( |_| () )
+	}
+	/// Get the file's metadata
+	#[inline]
+	pub fn get_metadata(&self) -> Result<Metadata,Error> {
+		let mut rv = Metadata::default();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_FILE_GETMETADATA { data: &mut rv }) } as usize )
+			.map( |_| rv )
+	}
+	
 	// Actually safe, as it uses the aliasing restrictions from the file, and ensures that the provided address is free
 	/// Map a portion of this file into this process's address space.
 	#[inline]
@@ -134,6 +187,21 @@ impl File
 		to_result( unsafe { self.0.call_m(::values::VFS_FILE_MEMMAP { ofs, size: read_size, addr: mem_addr as usize, mode }) } as usize )
 			.map( |_| () )
 	}
+	/// Write changes made to `WriteBack` mappings (within the given region) back to the file
+	#[inline]
+	pub fn memory_sync(&self, mem_addr: *const ::Void, size: usize) -> Result<(),Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_FILE_MEMSYNC { addr: mem_addr as usize, size }) } as usize )
+			.map( |_| () )
+	}
+	/// Remove a mapping made by [File::memory_map] (writing back any changes)
+	///
+	/// UNSAFE: Invalidates all references to the mapped memory
+	#[inline]
+	pub unsafe fn memory_unmap(&self, mem_addr: *const ::Void) -> Result<(),Error> {
+		to_result( self.0.call_m(::values::VFS_FILE_MEMUNMAP { addr: mem_addr as usize }) as usize )
+			.map( |_| () )
+	}
 }
 impl ::Object for File {
 	const CLASS: u16 = ::values::CLASS_VFS_FILE;
@@ -183,6 +251,65 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Create a new file in this directory (opened for exclusive read-write)
+	#[inline]
+	pub fn create_file<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<File, Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_m(::values::VFS_DIR_CREATEFILE { name }) } as usize )
+			.map(|h| File(h, 0))
+	}
+	/// Start watching the directory for changes to its entries
+	#[inline]
+	pub fn watch(&self) -> Result<Watch, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_m(::values::VFS_DIR_WATCH {}) } as usize )
+			.map(|h| Watch(h))
+	}
+	/// Obtain a handle that can only access what the specified user/group could
+	#[inline]
+	pub fn restrict(&self, uid: u32, gid: u32) -> Result<Dir, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_m(::values::VFS_DIR_RESTRICT { uid, gid }) } as usize )
+			.map(|h| Dir(h))
+	}
+	/// Create a new sub-directory
+	#[inline]
+	pub fn mkdir<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<Dir, Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_m(::values::VFS_DIR_MKDIR { name }) } as usize )
+			.map(|h| Dir(h))
+	}
+	/// Create a symbolic link pointing at `target`
+	#[inline]
+	pub fn symlink<P: ?Sized+AsRef<[u8]>, T: ?Sized+AsRef<[u8]>>(&self, name: &P, target: &T) -> Result<(), Error> {
+		let name = name.as_ref();
+		let target = target.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_DIR_SYMLINK { name, target }) } as usize )
+			.map(|_| ())
+	}
+	/// Remove an entry from this directory (directories must be empty)
+	#[inline]
+	pub fn unlink<P: ?Sized+AsRef<[u8]>>(&self, name: &P) -> Result<(), Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_DIR_UNLINK { name }) } as usize )
+			.map(|_| ())
+	}
+	/// Move an entry to `new_dir` (which can be this directory) with a new name
+	///
+	/// Both directories must be on the same filesystem
+	#[inline]
+	pub fn rename<P: ?Sized+AsRef<[u8]>, P2: ?Sized+AsRef<[u8]>>(&self, name: &P, new_dir: &Dir, new_name: &P2) -> Result<(), Error> {
+		let name = name.as_ref();
+		let new_name = new_name.as_ref();
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_m(::values::VFS_DIR_RENAME { name, new_dir: new_dir.0 .0, new_name }) } as usize )
+			.map(|_| ())
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -252,3 +379,41 @@ impl ::Object for Symlink {
 
 	type Waits = ();
 }
+
+impl Watch
+{
+	/// Take the oldest queued change, with the name of the changed entry written to `namebuf`
+	///
+	/// Returns `None` if no changes are queued. The name is truncated if `namebuf` is too small.
+	#[inline]
+	pub fn read<'a>(&self, namebuf: &'a mut [u8]) -> Option<(ChangeKind, &'a [u8])> {
+		let mut event = ::values::VFSWatchEvent::default();
+		// SAFE: Syscall
+		match unsafe { self.0.call_m(::values::VFS_WATCH_READ { event: &mut event, name: &mut namebuf[..] }) }
+		{
+		0 => None,
+		_ => {
+			let len = ::core::cmp::min(event.name_len as usize, namebuf.len());
+			Some( (ChangeKind::try_from(event.kind).expect("Bad VFS change kind"), &namebuf[..len]) )
+			},
+		}
+	}
+	/// Wait item that fires when changes are queued
+	pub fn wait_change(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_VFS_WATCH_CHANGE)
+	}
+}
+impl ::Object for Watch {
+	const CLASS: u16 = ::values::CLASS_VFS_WATCH;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		Watch(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = WatchWaits;
+}
+define_waits!{ WatchWaits => (
+	change:has_change = ::values::EV_VFS_WATCH_CHANGE,
+)}
@@ -51,6 +51,10 @@ impl ProtoProcess
 		self.0.send_obj( tag, obj );
 	}
 
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(),()> {
+		self.0.set_credentials(uid, gid)
+	}
+
 	pub fn start(self) -> ::syscalls::threads::Process {
 		// SAFE: FFI into rust code
 		unsafe {
@@ -7,23 +7,72 @@ pub enum Error
 
 pub struct UserInfo
 {
+	uid: u32,
+	gid: u32,
+	shell: String,
 }
 
+/// Account list, one `name:password:uid:gid:shell` entry per line
+const PASSWD_PATH: &str = "/sysroot/etc/passwd";
+
 pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
 {
-	// TODO: Use a proper auth infrastructure, something PAM-esque
-	if username == "root" && password == "password"
+	// TODO: Use a proper auth infrastructure, something PAM-esque (and don't store plain passwords)
+	let accounts = match read_accounts()
+		{
+		Ok(v) => v,
+		Err(e) => {
+			kernel_log!("Unable to read account list {} - {:?}", PASSWD_PATH, e);
+			return Err(Error::InvalidAuthentication);
+			},
+		};
+	for line in accounts.lines()
 	{
-		Ok(UserInfo {})
+		let line = line.trim();
+		if line == "" || line.starts_with('#') {
+			continue ;
+		}
+		match parse_account(line)
+		{
+		Some( (name, _, _) ) if name != username => {},
+		Some( (_, "!", _) ) => return Err(Error::Disabled),
+		Some( (_, pass, info) ) => return if pass == password { Ok(info) } else { Err(Error::InvalidAuthentication) },
+		None => kernel_log!("Malformed account entry '{}'", line),
+		}
 	}
-	else if username == "guest"
+	Err(Error::InvalidAuthentication)
+}
+
+fn read_accounts() -> Result<String, ::syscalls::vfs::Error>
+{
+	let mut fh = ::syscalls::vfs::root().open_child_path(PASSWD_PATH.as_bytes())?.into_file(::syscalls::vfs::FileOpenMode::SharedRO)?;
+	let mut data = vec![0; fh.get_size() as usize];
+	let mut ofs = 0;
+	while ofs < data.len()
 	{
-		Err(Error::Disabled)
+		match fh.read(&mut data[ofs..])?
+		{
+		0 => break,
+		n => ofs += n,
+		}
 	}
-	else
-	{
-		Err(Error::InvalidAuthentication)
+	data.truncate(ofs);
+	Ok( String::from_utf8_lossy(&data).into_owned() )
+}
+
+/// Split an account entry into its name, password, and session information
+fn parse_account(line: &str) -> Option<(&str, &str, UserInfo)>
+{
+	let mut it = line.split(':');
+	let name = it.next()?;
+	let password = it.next()?;
+	let uid = it.next()?.parse().ok()?;
+	let gid = it.next()?.parse().ok()?;
+	let shell = it.next()?;
+	if it.next().is_some() {
+		return None;
 	}
+	Some( (name, password, UserInfo { uid: uid, gid: gid, shell: shell.to_owned() }) )
 }
 
 
@@ -31,7 +80,12 @@ impl UserInfo
 {
 	pub fn get_shell(&self) -> &str
 	{
-		"/sysroot/bin/shell"
+		&self.shell
+	}
+	/// User and group IDs that the session runs as
+	pub fn get_ids(&self) -> (u32, u32)
+	{
+		(self.uid, self.gid)
 	}
 }
 
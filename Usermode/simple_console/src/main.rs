@@ -160,14 +160,24 @@ impl ShellState
 			else {
 				self.cwd_rel = String::new();
 			},
-		// 'ls' - Print the contents of a directory
-		Some("ls") =>
-			if let Some(dir) = args.next() {
+		// 'ls' - Print the contents of a directory (`-l` for details)
+		Some("ls") => {
+			let mut long = false;
+			let mut dir = None;
+			while let Some(a) = args.next() {
+				match a
+				{
+				"-l" => long = true,
+				_ => dir = Some(a),
+				}
+			}
+			if let Some(dir) = dir {
 				// TODO: Parse 'dir' as relative correctly
-				command_ls(term, &self.root_handle, dir);
+				command_ls(term, &self.root_handle, dir, long);
 			}
 			else {
-				command_ls(term, &self.root_handle, &format!("/{}", self.cwd_rel));
+				command_ls(term, &self.root_handle, &format!("/{}", self.cwd_rel), long);
+			}
 			},
 		// 'cat' - Dump the contents of a file
 		// TODO: Implement
@@ -188,7 +198,7 @@ impl ShellState
 }
 
 /// List the contents of a directory
-fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
+fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str, long: bool)
 {
 	use syscalls::vfs::{NodeType, FileOpenMode};
 	write!(term, "Listing {:?}\n", path);
@@ -224,18 +234,37 @@ fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
 
 		let name = ::std::str::from_utf8(name_bytes).expect("Filename not utf-8");
 
-		write!(term, "- {}", name);
-
 		let file_node = match handle.open_child(name)
 			{
 			Ok(v) => v,
 			Err(e) => {
-				write!(term, "(Error: {:?})\n", e);
+				write!(term, "- {}(Error: {:?})\n", name, e);
 				continue ;
 				},
 			};
+		if long {
+			let ty = match file_node.class()
+				{
+				NodeType::File => '-',
+				NodeType::Dir => 'd',
+				NodeType::Symlink => 'l',
+				NodeType::Special => 's',
+				};
+			match file_node.get_metadata()
+			{
+			Ok(m) => write!(term, "{}{} {:3} {:5} {:5} {:10} {} {}",
+				ty, ModeStr(m.mode), m.link_count, m.owner, m.group, m.size,
+				TimeStr(m.modify_time), name
+				),
+			Err(e) => write!(term, "{}????????? (Error: {:?}) {}", ty, e, name),
+			}
+		}
+		else {
+			write!(term, "- {}", name);
+		}
 		match file_node.class()
 		{
+		NodeType::File if long => {},
 		NodeType::File => {
 			let size = file_node.into_file(FileOpenMode::ReadOnly).and_then(|h| Ok(h.get_size())).unwrap_or(0);
 			write!(term, " ({})", size);
@@ -255,3 +284,41 @@ fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
 		write!(term, "\n");
 	}
 }
+
+/// Formats permission bits as `rwxrwxrwx`
+struct ModeStr(u16);
+impl ::std::fmt::Display for ModeStr {
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		for shift in [6, 3, 0].iter() {
+			let bits = self.0 >> shift;
+			write!(f, "{}{}{}",
+				if bits & 4 != 0 { 'r' } else { '-' },
+				if bits & 2 != 0 { 'w' } else { '-' },
+				if bits & 1 != 0 { 'x' } else { '-' },
+				)?;
+		}
+		Ok( () )
+	}
+}
+/// Formats a timestamp (seconds since 1970) as `YYYY-MM-DD HH:MM`
+struct TimeStr(i64);
+impl ::std::fmt::Display for TimeStr {
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		if self.0 == ::syscalls::vfs::Metadata::NO_TIME {
+			return f.write_str("????-??-?? ??:??");
+		}
+		let days = self.0.div_euclid(86400);
+		let secs = self.0.rem_euclid(86400);
+		// Convert days to a civil date (using a March-based year)
+		let z = days + 719468;
+		let era = z.div_euclid(146097);
+		let doe = z - era * 146097;
+		let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+		let doy = doe - (365*yoe + yoe/4 - yoe/100);
+		let mp = (5*doy + 2) / 153;
+		let day = doy - (153*mp + 2)/5 + 1;
+		let month = if mp < 10 { mp + 3 } else { mp - 9 };
+		let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+		write!(f, "{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs / 3600, secs / 60 % 60)
+	}
+}
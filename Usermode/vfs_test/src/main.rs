@@ -1,7 +1,7 @@
 // Tifflin OS - VFS Testing Application
 // - By John Hodge (thePowersGang)
 //
-//! Stress-tests the VFS by enumerating all directories and checksumming all files
+//! Stress-tests the VFS by enumerating all directories and checksumming all files, then checks file mappings
 
 #[macro_use]
 extern crate syscalls;
@@ -9,14 +9,24 @@ extern crate syscalls;
 extern crate crc;
 
 use syscalls::vfs::{Dir,File};
-use syscalls::vfs::{NodeType,FileOpenMode};
+use syscalls::vfs::{NodeType,FileOpenMode,MemoryMapMode};
+
+const PAGE_SIZE: usize = 0x1000;
+/// Free address used for test mappings (below the heap)
+#[cfg(target_arch="x86_64")] const MAP_BASE: usize = 0x0F00_0000_0000;
+#[cfg(target_arch="arm")] const MAP_BASE: usize = 0x0F00_0000;
+#[cfg(target_arch="aarch64")] const MAP_BASE: usize = 0x0F00_0000;
+#[cfg(target_arch="riscv64")] const MAP_BASE: usize = 0x0F_0000_0000;
 
 fn main()
 {
 	let root: Dir = ::syscalls::threads::S_THIS_PROCESS.receive_object("/").unwrap();
+	let tmp = root.open_child_path("tmp").and_then(|n| n.into_dir()).unwrap();
 
 	let mut buffer = [0; 256];
 	dump_dir(0, root, &mut buffer);
+
+	test_memory_map(&tmp);
 }
 
 //struct ChainEnt<'a>(&str, Option<&ChainEnt<'a>>);
@@ -89,6 +99,56 @@ fn dump_file(level: usize, mut handle: File)
 	kernel_log!("{}> CRC32={:#x}", Repeat(level," "), crc.finalise());
 }
 
+/// Checks file mappings, using a scratch file in `/tmp`
+fn test_memory_map(tmp: &Dir)
+{
+	const NAME: &str = "vfs_test_mmap.dat";
+	// Three pages of non-zero data
+	let data: Vec<u8> = (0 .. 3*PAGE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+	tmp.create_file(NAME).unwrap().write_at(0, &data).unwrap();
+	let open = |mode| tmp.open_child(NAME).and_then(|n| n.into_file(mode)).unwrap();
 
+	// Unaligned offset and size: the whole page is mapped, with the part past the requested region zeroed
+	{
+		let file = open(FileOpenMode::ReadOnly);
+		let (ofs, len) = (PAGE_SIZE + 0x10, 0x200);
+		file.memory_map(ofs as u64, len, (MAP_BASE + 0x10) as *const _, MemoryMapMode::ReadOnly).unwrap();
+		// SAFE: Mapped above, and not used after the unmap
+		let page = unsafe { ::std::slice::from_raw_parts(MAP_BASE as *const u8, PAGE_SIZE) };
+		assert!(page[.. 0x10 + len] == data[PAGE_SIZE .. ofs + len], "Mapped data doesn't match the file");
+		assert!(page[0x10 + len ..].iter().all(|&b| b == 0), "Mapping not zeroed past the requested region");
+		// SAFE: `page` isn't used again
+		unsafe { file.memory_unmap(MAP_BASE as *const _).unwrap(); }
+	}
 
+	// Shared writable mapping: changes reach the file when synced, and when unmapped
+	{
+		let file = open(FileOpenMode::ExclRW);
+		file.memory_map(0, 2*PAGE_SIZE, MAP_BASE as *const _, MemoryMapMode::WriteBack).unwrap();
+		// SAFE: Mapped read-write above, and not used after the unmap
+		let map = unsafe { ::std::slice::from_raw_parts_mut(MAP_BASE as *mut u8, 2*PAGE_SIZE) };
+		map[0x20 ..][..6].copy_from_slice(b"synced");
+		file.memory_sync(MAP_BASE as *const _, 2*PAGE_SIZE).unwrap();
+		check_file_data(&file, 0x20, b"synced");
+		map[PAGE_SIZE + 0x30 ..][..8].copy_from_slice(b"unmapped");
+		// SAFE: `map` isn't used again
+		unsafe { file.memory_unmap(MAP_BASE as *const _).unwrap(); }
+		check_file_data(&file, PAGE_SIZE + 0x30, b"unmapped");
+	}
+	{
+		let file = open(FileOpenMode::ReadOnly);
+		check_file_data(&file, 0x20, b"synced");
+		check_file_data(&file, PAGE_SIZE + 0x30, b"unmapped");
+		check_file_data(&file, 2*PAGE_SIZE, &data[2*PAGE_SIZE ..][..0x10]);
+	}
+
+	tmp.unlink(NAME).unwrap();
+	kernel_log!("Memory map tests passed");
+}
 
+fn check_file_data(file: &File, ofs: usize, exp: &[u8])
+{
+	let mut buf = vec![0; exp.len()];
+	assert_eq!(file.read_at(ofs as u64, &mut buf).unwrap(), exp.len());
+	assert!(&buf[..] == exp, "File data at {:#x} doesn't match", ofs);
+}